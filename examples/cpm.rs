@@ -0,0 +1,56 @@
+//
+// A CP/M .COM program loader.
+//
+// Runs a CP/M 2.2 program on the rz80::CPM runtime, with the
+// console mapped to stdin/stdout and the current directory
+// mounted as drive A:, for instance:
+//
+// > cargo run --release --example cpm -- MBASIC.COM
+// > cargo run --release --example cpm -- M80.COM =HELLO
+//
+// Console input is read line by line from stdin, the program
+// stops once it returns to CP/M or stdin is closed.
+
+extern crate rz80;
+
+use rz80::{CPM, CPMStatus};
+use std::fs::File;
+use std::io::{self, BufRead, Read};
+use std::path::Path;
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let path = match args.next() {
+        Some(path) => path,
+        None => panic!("Usage: cpm [program.com] [arguments...]"),
+    };
+    let mut prog = Vec::new();
+    if let Err(err) = File::open(&path).and_then(|mut f| f.read_to_end(&mut prog)) {
+        panic!("Unable to load program '{}': {}", path, err);
+    }
+    let cmd_args: Vec<String> = args.collect();
+
+    let cpm = CPM::new();
+    cpm.mount_dir(0, Path::new("."));
+    cpm.set_echo(true);
+    cpm.load_com(&prog, &cmd_args.join(" "));
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        match cpm.run(1000000) {
+            CPMStatus::Running => (),
+            CPMStatus::WaitingForInput => {
+                match lines.next() {
+                    Some(Ok(line)) => {
+                        cpm.input(line.as_bytes());
+                        cpm.input(b"\r");
+                    }
+                    _ => break,
+                }
+            }
+            CPMStatus::Exited => break,
+        }
+    }
+    println!();
+}
@@ -0,0 +1,179 @@
+//
+// A ZX Spectrum emulator.
+//
+// The complete machine emulation lives in the rz80 library
+// (rz80::ZXSpectrum), this example only provides a window and
+// keyboard input. The Spectrum ROMs are not included, the ROM
+// image must be passed on the command line, a 16 KByte ROM
+// starts a Spectrum 48K, a 32 KByte ROM a Spectrum 128:
+//
+// > cargo run --release --example zx -- 48.rom
+//
+// The Spectrum 48K uses single-key keyword entry, for instance
+// press 'p' for PRINT. Letters with shift-key pressed are typed
+// with CAPS SHIFT, symbols with SYMBOL SHIFT, Backspace is DELETE.
+// The numpad cursor keys and numpad 0 are a Kempston joystick.
+//
+// A session can be recorded into an input log file, and replayed
+// bit-identically from that file (host input is ignored during replay),
+// the final emulator state is checked against the recording:
+//
+// > cargo run --release --example zx -- 48.rom --record session.txt
+// > cargo run --release --example zx -- 48.rom --replay session.txt
+
+extern crate rz80;
+extern crate time;
+extern crate minifb;
+
+use rz80::{ZXSpectrum, ZXModel, ZX_DISPLAY_WIDTH, ZX_DISPLAY_HEIGHT, InputEvent, InputLog,
+           InputRecorder, InputPlayer};
+use minifb::{Key, Window, Scale, WindowOptions};
+use time::PreciseTime;
+use std::fs::File;
+use std::io::{Read, Write};
+
+// a mapping of all required minifb key codes to their ASCII values, the
+// first ASCII value is with shift-key released, the second with shift-key pressed
+static KEYS: &'static [(Key,u8,u8)] = &[
+    (Key::Key0,b'0',b')'), (Key::Key1,b'1',b'!'), (Key::Key2,b'2',b'@'), (Key::Key3,b'3',b'#'),
+    (Key::Key4,b'4',b'$'), (Key::Key5,b'5',b'%'), (Key::Key6,b'6',b'^'), (Key::Key7,b'7',b'&'),
+    (Key::Key8,b'8',b'*'), (Key::Key9,b'9',b'('), (Key::Minus,b'-',b'_'), (Key::Equal,b'=',b'+'),
+    (Key::A,b'a',b'A'), (Key::B,b'b',b'B'), (Key::C,b'c',b'C'), (Key::D,b'd',b'D'),
+    (Key::E,b'e',b'E'), (Key::F,b'f',b'F'), (Key::G,b'g',b'G'), (Key::H,b'h',b'H'),
+    (Key::I,b'i',b'I'), (Key::J,b'j',b'J'), (Key::K,b'k',b'K'), (Key::L,b'l',b'L'),
+    (Key::M,b'm',b'M'), (Key::N,b'n',b'N'), (Key::O,b'o',b'O'), (Key::P,b'p',b'P'),
+    (Key::Q,b'q',b'Q'), (Key::R,b'r',b'R'), (Key::S,b's',b'S'), (Key::T,b't',b'T'),
+    (Key::U,b'u',b'U'), (Key::V,b'v',b'V'), (Key::W,b'w',b'W'), (Key::X,b'x',b'X'),
+    (Key::Y,b'y',b'Y'), (Key::Z,b'z',b'Z'),
+    (Key::Comma,b',',b'<'), (Key::Period,b'.',b'>'), (Key::Slash,b'/',b'?'),
+    (Key::Semicolon,b';',b':'), (Key::Apostrophe,b'\'',b'"'),
+    (Key::Space,0x20,0x20), (Key::Left,0x08,0x08), (Key::Right,0x09,0x09), (Key::Down,0x0A,0x0A),
+    (Key::Up, 0x0B, 0x0B), (Key::Backspace,0x0C,0x0C), (Key::Enter,0x0D,0x0D),
+    (Key::Escape, 0x03, 0x03),
+];
+
+// the numpad keys mapped to the Kempston joystick
+static JOYSTICK: &'static [(Key,u8)] = &[
+    (Key::NumPad6,rz80::ZX_JOY_RIGHT), (Key::NumPad4,rz80::ZX_JOY_LEFT),
+    (Key::NumPad2,rz80::ZX_JOY_DOWN), (Key::NumPad8,rz80::ZX_JOY_UP),
+    (Key::NumPad0,rz80::ZX_JOY_FIRE),
+];
+
+fn main() {
+    // load the ROM image, the size decides the Spectrum model
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let path = match args.first() {
+        Some(path) => path.clone(),
+        None => panic!("Usage: zx [48K or 128 ROM image] [--record FILE | --replay FILE]"),
+    };
+    let option = |name: &str| args.iter().position(|a| a == name).and_then(|i| args.get(i + 1));
+    let record_path = option("--record");
+    let mut player = option("--replay").map(|path| {
+        let mut text = String::new();
+        if let Err(err) = File::open(path).and_then(|mut f| f.read_to_string(&mut text)) {
+            panic!("Unable to load input log '{}': {}", path, err);
+        }
+        match InputLog::from_text(&text) {
+            Ok(log) => InputPlayer::new(log),
+            Err(err) => panic!("Invalid input log '{}': {}", path, err),
+        }
+    });
+    let mut rom = Vec::new();
+    if let Err(err) = File::open(&path).and_then(|mut f| f.read_to_end(&mut rom)) {
+        panic!("Unable to load ROM image '{}': {}", path, err);
+    }
+    let model = if rom.len() == 0x8000 { ZXModel::Spectrum128 } else { ZXModel::Spectrum48K };
+
+    // create a window via minifb
+    let mut window = match Window::new("rz80 ZX Spectrum example",
+           ZX_DISPLAY_WIDTH, ZX_DISPLAY_HEIGHT,
+           WindowOptions {
+               resize: false,
+               scale: Scale::X2,
+               ..WindowOptions::default()
+           }) {
+        Ok(win) => win,
+        Err(err) => panic!("Unable to create minifb window: {}", err)
+    };
+
+    // the pixel frame buffer, written by ZXSpectrum::decode_framebuffer()
+    // and transfered to the minifb window
+    let mut frame_buffer = vec![0u32; ZX_DISPLAY_WIDTH*ZX_DISPLAY_HEIGHT];
+
+    let zx = ZXSpectrum::new(model, &rom);
+    zx.poweron();
+    let mut micro_seconds_per_frame: i64 = 0;
+    let mut last_ascii: u8 = 0;
+    let mut last_joystick: u8 = 0;
+    let mut recorder = InputRecorder::new();
+    let mut replay_done = false;
+    while window.is_open() {
+        let start = PreciseTime::now();
+
+        // get keyboard input from minifb, this is currently a bit crude...
+        let mut ascii: u8 = 0;
+        let shift = window.is_key_down(Key::LeftShift)|window.is_key_down(Key::RightShift);
+        for key in KEYS {
+            if window.is_key_down(key.0) {
+                ascii = if shift {key.2} else {key.1}
+            }
+        }
+        let mut joystick: u8 = 0;
+        for key in JOYSTICK {
+            if window.is_key_down(key.0) {
+                joystick |= key.1;
+            }
+        }
+
+        // host input goes through the recorder, so that it can be replayed
+        if player.is_none() {
+            if ascii != last_ascii {
+                if last_ascii != 0 {
+                    recorder.input(&zx, InputEvent::KeyUp(last_ascii));
+                }
+                if ascii != 0 {
+                    recorder.input(&zx, InputEvent::KeyDown(ascii));
+                }
+                last_ascii = ascii;
+            }
+            if joystick != last_joystick {
+                recorder.input(&zx, InputEvent::Joystick(joystick));
+                last_joystick = joystick;
+            }
+        }
+
+        // run the emulator for the current frame
+        match player {
+            Some(ref mut player) => {
+                if !player.is_finished(&zx) {
+                    player.step(&zx, micro_seconds_per_frame);
+                } else if !replay_done {
+                    match player.verify(&zx) {
+                        Ok(_) => println!("replay finished, emulator state matches"),
+                        Err(err) => println!("replay finished with different state: {}", err),
+                    }
+                    replay_done = true;
+                }
+            }
+            None => zx.step(micro_seconds_per_frame),
+        }
+
+        // this example has no audio output, throw the audio samples away
+        zx.audio_samples();
+
+        // update the window content
+        zx.decode_framebuffer(&mut frame_buffer);
+        window.update_with_buffer(&frame_buffer);
+
+        // measure the elapsed time to run emulator at the correct speed
+        let frame_time = start.to(PreciseTime::now());
+        micro_seconds_per_frame = frame_time.num_microseconds().unwrap();
+    }
+
+    if let Some(path) = record_path {
+        let text = recorder.finish(&zx).to_text();
+        if let Err(err) = File::create(path).and_then(|mut f| f.write_all(text.as_bytes())) {
+            panic!("Unable to write input log '{}': {}", path, err);
+        }
+    }
+}
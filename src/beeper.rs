@@ -0,0 +1,96 @@
+/// 1-bit beeper sound output
+///
+/// Many simple home computers generate sound by toggling a single
+/// output bit, either directly by the CPU or through a CTC channel.
+/// The Beeper samples this output bit at a host audio sample rate
+/// and collects the samples in a buffer which must be drained
+/// regularly by the host audio code.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::Beeper;
+///
+/// // 1 MHz CPU clock, 10 kHz sample rate (100 cycles per sample)
+/// let mut beeper = Beeper::new(1000, 10000);
+/// beeper.write(true);
+/// beeper.update(300);
+/// beeper.toggle();
+/// beeper.update(100);
+/// assert_eq!(beeper.take_samples(), [0.5, 0.5, 0.5, -0.5]);
+/// ```
+pub struct Beeper {
+    /// current level of the output bit
+    pub level: bool,
+    /// output volume (0.0 to 1.0)
+    pub volume: f32,
+    freq_hz: i64,
+    sample_rate: i64,
+    counter: i64, // counts sample_rate per CPU cycle, a sample is due at freq_hz
+    samples: Vec<f32>,
+}
+
+impl Beeper {
+    /// initialize a new beeper with CPU frequency and host sample rate
+    pub fn new(freq_khz: i64, sample_rate: i64) -> Beeper {
+        assert!(freq_khz > 0 && sample_rate > 0);
+        Beeper {
+            level: false,
+            volume: 0.5,
+            freq_hz: freq_khz * 1000,
+            sample_rate: sample_rate,
+            counter: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    /// set the output bit
+    pub fn write(&mut self, level: bool) {
+        self.level = level;
+    }
+
+    /// toggle the output bit
+    pub fn toggle(&mut self) {
+        self.level = !self.level;
+    }
+
+    /// advance the beeper by a number of CPU cycles, generating samples
+    pub fn update(&mut self, cycles: i64) {
+        self.counter += cycles * self.sample_rate;
+        while self.counter >= self.freq_hz {
+            self.counter -= self.freq_hz;
+            self.samples.push(if self.level { self.volume } else { -self.volume });
+        }
+    }
+
+    /// return the generated samples and clear the sample buffer
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        ::std::mem::take(&mut self.samples)
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn high_sample_rate() {
+        // 1 kHz CPU clock and 44.1 kHz sample rate: 44.1 samples per CPU cycle
+        let mut beeper = Beeper::new(1, 44100);
+        beeper.write(true);
+        beeper.update(3);
+        assert_eq!(132, beeper.take_samples().len());
+    }
+
+    #[test]
+    fn fractional_sample_rate() {
+        // KC87 clock: 55.7 CPU cycles per sample, one second must
+        // generate exactly 44100 samples
+        let mut beeper = Beeper::new(2458, 44100);
+        for _ in 0..50 {
+            beeper.update(49160);
+        }
+        assert_eq!(44100, beeper.take_samples().len());
+    }
+}
@@ -1,5 +1,21 @@
 use RegT;
 use CTC;
+use CRTC;
+
+/// the type of a CPU bus cycle, see **Bus::cpu_wait()**
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BusCycle {
+    /// opcode fetch (including prefix bytes)
+    M1,
+    /// memory read
+    MemRead,
+    /// memory write
+    MemWrite,
+    /// I/O port read
+    IoRead,
+    /// I/O port write
+    IoWrite,
+}
 
 /// system bus trait
 ///
@@ -27,7 +43,26 @@ pub trait Bus {
         0
     }
     /// notify interrupt daisy chain that CPU executed a RETI
+    ///
+    /// Not called while CPU::set_fetch_callback() is enabled, the RETI
+    /// must then be detected from the opcode fetches.
     fn irq_reti(&self) {}
+    /// CPU HALT output line has changed
+    fn cpu_halt(&self, halt: bool) {}
+    /// CPU has fetched an opcode byte in an M1 cycle
+    ///
+    /// Only called if enabled with CPU::set_fetch_callback(), for each
+    /// opcode byte including prefix bytes (but not the opcode byte in
+    /// DD CB d op and FD CB d op, which is read like an operand).
+    fn cpu_fetch(&self, addr: RegT, op: RegT) {}
+    /// return extra wait states for a CPU bus cycle
+    ///
+    /// Only called if enabled with CPU::set_wait_callback(), once for
+    /// each bus cycle of an instruction in the order of execution,
+    /// after the instruction has been executed.
+    fn cpu_wait(&self, cycle: BusCycle, addr: RegT) -> i64 {
+        0
+    }
 
     /// PIO output callback
     fn pio_outp(&self, pio: usize, chn: usize, data: RegT) {}
@@ -46,4 +81,17 @@ pub trait Bus {
     fn ctc_zero(&self, chn: usize, ctc: &CTC) {}
     /// interrupt request from CTC
     fn ctc_irq(&self, ctc: usize, chn: usize, int_vector: RegT) {}
+
+    /// VDP interrupt output line has changed
+    fn vdp_int(&self, vdp: usize, active: bool) {}
+
+    /// CRTC HSYNC output has changed
+    fn crtc_hsync(&self, id: usize, hsync: bool) {}
+    /// CRTC VSYNC output has changed
+    fn crtc_vsync(&self, id: usize, vsync: bool) {}
+    /// CRTC is about to advance by one character clock
+    fn crtc_tick(&self, id: usize, crtc: &CRTC) {}
+
+    /// tape player output level has changed
+    fn tape_edge(&self, level: bool) {}
 }
@@ -0,0 +1,1070 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use RegT;
+use bus::Bus;
+use cpu::{CPU, TrapAction};
+
+/// start address of .COM programs (the transient program area)
+pub const CPM_TPA_ADDR: RegT = 0x0100;
+/// the BDOS entry point, called through the jump at address 0x0005
+pub const CPM_BDOS_ADDR: RegT = 0xFC06;
+/// start of the BIOS jump table
+pub const CPM_BIOS_ADDR: RegT = 0xFE00;
+/// number of drives (A: to P:)
+pub const CPM_NUM_DRIVES: usize = 16;
+
+const BDOS_PAGE: RegT = 0xFC00;
+const NUM_BIOS_ENTRIES: RegT = 17;
+const DPH_ADDR: RegT = 0xFD00;
+const DPB_ADDR: RegT = 0xFE40;
+const CSV_ADDR: RegT = 0xFE50;
+const ALV_ADDR: RegT = 0xFE60;
+const DIRBUF_ADDR: RegT = 0xFE80;
+
+// page zero locations
+const IOBYTE_ADDR: RegT = 0x0003;
+const DRIVE_USER_ADDR: RegT = 0x0004;
+const FCB1_ADDR: RegT = 0x005C;
+const FCB2_ADDR: RegT = 0x006C;
+const DEFAULT_DMA_ADDR: RegT = 0x0080;
+
+const RECORD_SIZE: usize = 128;
+const EOF: u8 = 0x1A;
+
+// FCB field offsets
+const FCB_DR: RegT = 0;
+const FCB_NAME: RegT = 1;
+const FCB_EX: RegT = 12;
+const FCB_S2: RegT = 14;
+const FCB_RC: RegT = 15;
+const FCB_CR: RegT = 32;
+const FCB_R0: RegT = 33;
+
+// disk image geometry (8" single-sided, single-density IBM 3740 format)
+const SECTORS_PER_TRACK: usize = 26;
+const NUM_TRACKS: usize = 77;
+/// size of a CP/M disk image in bytes (8" SSSD format, 77 tracks, 26 sectors of 128 bytes)
+pub const CPM_DISK_IMAGE_SIZE: usize = NUM_TRACKS * SECTORS_PER_TRACK * RECORD_SIZE;
+// disk parameter block: SPT, BSH, BLM, EXM, DSM, DRM, AL0, AL1, CKS, OFF
+static DPB: [u8; 15] = [26, 0, 3, 7, 0, 242, 0, 63, 0, 0xC0, 0x00, 16, 0, 2, 0];
+
+/// execution status of a CP/M program
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CPMStatus {
+    /// the program is running
+    Running,
+    /// the program waits for console input, provide it with input()
+    WaitingForInput,
+    /// the program has returned to CP/M (warm boot)
+    Exited,
+}
+
+// a mounted drive
+enum Drive {
+    None,
+    Dir(PathBuf),
+    Image(Vec<u8>),
+}
+
+struct State {
+    status: CPMStatus,
+    echo: bool,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    drives: Vec<Drive>,
+    cur_drive: usize,
+    user: u8,
+    dma: RegT,
+    search_results: Vec<[u8; 11]>,
+    bios_drive: usize,
+    bios_track: usize,
+    bios_sector: usize,
+    bios_dma: RegT,
+}
+
+/// CP/M 2.2 runtime
+///
+/// The CPM struct runs CP/M .COM programs without a real CP/M system
+/// disk. The BDOS entry point and the BIOS jump table are CPU traps
+/// (see CPU::add_trap()) which are implemented in Rust, the trap handlers
+/// run right before the CPU would execute the trapped instruction:
+///
+/// - console I/O goes through an input queue and an output buffer
+///   (or stdout), so that programs can be driven headless
+/// - BDOS file calls work on host directories mounted as drives
+///   with **mount_dir()**, CP/M file names map to host file names
+///   like **NAME.EXT** (matched case-insensitively)
+/// - BIOS disk calls read and write raw 128-byte sectors on
+///   8" SSSD disk images mounted with **mount_image()**
+///
+/// The program has exited once it jumps to the warm boot vector
+/// at address 0. If the program waits for console input and the
+/// input queue is empty, the CPU is stopped at the BDOS call until
+/// more input is provided.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{CPM, CPMStatus};
+///
+/// // a program which reads a line and prints it back
+/// static PROG: &'static [u8] = &[
+///     0x0E, 0x0A,             // LD C,10 (read console buffer)
+///     0x11, 0x20, 0x01,       // LD DE,0x0120
+///     0xCD, 0x05, 0x00,       // CALL 5
+///     0x21, 0x21, 0x01,       // LD HL,0x0121
+///     0x5E,                   // LD E,(HL)
+///     0x16, 0x00,             // LD D,0
+///     0x19,                   // ADD HL,DE
+///     0x23,                   // INC HL
+///     0x36, 0x24,             // LD (HL),'$'
+///     0x0E, 0x09,             // LD C,9 (print string)
+///     0x11, 0x22, 0x01,       // LD DE,0x0122
+///     0xC3, 0x05, 0x00,       // JP 5
+/// ];
+///
+/// let cpm = CPM::new();
+/// let mut prog = PROG.to_vec();
+/// prog.resize(0x20, 0);
+/// prog.push(16);
+/// cpm.load_com(&prog, "");
+/// assert_eq!(cpm.run(1000000), CPMStatus::WaitingForInput);
+/// cpm.input(b"hello\r");
+/// assert_eq!(cpm.run(1000000), CPMStatus::Exited);
+/// assert_eq!(cpm.take_output(), b"hello\rhello");
+/// ```
+pub struct CPM {
+    pub cpu: RefCell<CPU>,
+    state: Rc<RefCell<State>>,
+}
+
+impl CPM {
+    /// initialize a new CP/M runtime without mounted drives
+    pub fn new() -> CPM {
+        let state = Rc::new(RefCell::new(State {
+                status: CPMStatus::Exited,
+                echo: false,
+                input: Vec::new(),
+                output: Vec::new(),
+                drives: (0..CPM_NUM_DRIVES).map(|_| Drive::None).collect(),
+                cur_drive: 0,
+                user: 0,
+                dma: DEFAULT_DMA_ADDR,
+                search_results: Vec::new(),
+                bios_drive: 0,
+                bios_track: 0,
+                bios_sector: 0,
+                bios_dma: DEFAULT_DMA_ADDR,
+            }));
+        let mut cpu = CPU::new_64k();
+        {
+            let state = state.clone();
+            cpu.add_trap(CPM_BDOS_ADDR, move |cpu| {
+                let mut state = state.borrow_mut();
+                let done = bdos(&mut state, cpu);
+                state.trap_action(done)
+            });
+        }
+        for func in 0..NUM_BIOS_ENTRIES {
+            let state = state.clone();
+            cpu.add_trap(CPM_BIOS_ADDR + func * 3, move |cpu| {
+                let mut state = state.borrow_mut();
+                let done = bios(&mut state, cpu, func as usize);
+                state.trap_action(done)
+            });
+        }
+        CPM {
+            cpu: RefCell::new(cpu),
+            state: state,
+        }
+    }
+
+    /// mount a host directory as drive (0 = A:, 1 = B:, ...)
+    pub fn mount_dir(&self, drive: usize, dir: &Path) {
+        self.state.borrow_mut().drives[drive] = Drive::Dir(dir.to_path_buf());
+    }
+
+    /// mount an 8" SSSD disk image as drive (0 = A:, 1 = B:, ...)
+    pub fn mount_image(&self, drive: usize, image: &[u8]) {
+        let mut data = image.to_vec();
+        data.resize(CPM_DISK_IMAGE_SIZE, 0xE5);
+        self.state.borrow_mut().drives[drive] = Drive::Image(data);
+    }
+
+    /// return the current content of a mounted disk image
+    pub fn image(&self, drive: usize) -> Option<Vec<u8>> {
+        match self.state.borrow().drives[drive] {
+            Drive::Image(ref data) => Some(data.clone()),
+            _ => None,
+        }
+    }
+
+    /// if enabled, console output is written to stdout instead of the output buffer
+    pub fn set_echo(&self, echo: bool) {
+        self.state.borrow_mut().echo = echo;
+    }
+
+    /// append bytes to the console input queue
+    pub fn input(&self, bytes: &[u8]) {
+        let mut state = self.state.borrow_mut();
+        state.input.extend_from_slice(bytes);
+        if state.status == CPMStatus::WaitingForInput {
+            state.status = CPMStatus::Running;
+        }
+    }
+
+    /// return the console output and clear the output buffer
+    pub fn take_output(&self) -> Vec<u8> {
+        ::std::mem::take(&mut self.state.borrow_mut().output)
+    }
+
+    /// return the current execution status
+    pub fn status(&self) -> CPMStatus {
+        self.state.borrow().status
+    }
+
+    /// load a .COM program with command line arguments and prepare it for running
+    ///
+    /// This initializes page zero, the BDOS and BIOS entry points, the
+    /// command tail at 0x0080 and the default FCBs at 0x005C and 0x006C
+    /// from the first two arguments.
+    pub fn load_com(&self, prog: &[u8], args: &str) {
+        let mut cpu = self.cpu.borrow_mut();
+        cpu.reset();
+        for b in cpu.mem.heap[..0x10000].iter_mut() {
+            *b = 0;
+        }
+        let mut state = self.state.borrow_mut();
+
+        // page zero: jumps to the BIOS warm boot and the BDOS
+        cpu.mem.w8(0x0000, 0xC3);
+        cpu.mem.w16(0x0001, CPM_BIOS_ADDR + 3);
+        cpu.mem.w8(IOBYTE_ADDR, 0);
+        cpu.mem.w8(DRIVE_USER_ADDR, (state.user << 4) as RegT | state.cur_drive as RegT);
+        cpu.mem.w8(0x0005, 0xC3);
+        cpu.mem.w16(0x0006, CPM_BDOS_ADDR);
+
+        // the trapped entry points contain RET instructions
+        cpu.mem.w8(CPM_BDOS_ADDR, 0xC9);
+        for i in 0..NUM_BIOS_ENTRIES {
+            cpu.mem.w8(CPM_BIOS_ADDR + i * 3, 0xC9);
+        }
+        cpu.mem.write(DPB_ADDR, &DPB);
+        for drive in 0..CPM_NUM_DRIVES as RegT {
+            let dph = DPH_ADDR + drive * 16;
+            cpu.mem.w16(dph + 8, DIRBUF_ADDR);
+            cpu.mem.w16(dph + 10, DPB_ADDR);
+            cpu.mem.w16(dph + 12, CSV_ADDR);
+            cpu.mem.w16(dph + 14, ALV_ADDR);
+        }
+
+        // command tail and default FCBs
+        let args = args.trim().to_uppercase();
+        let tail = if args.is_empty() { String::new() } else { format!(" {}", args) };
+        let tail = &tail.as_bytes()[..tail.len().min(126)];
+        cpu.mem.w8(DEFAULT_DMA_ADDR, tail.len() as RegT);
+        cpu.mem.write(DEFAULT_DMA_ADDR + 1, tail);
+        let mut words = args.split_whitespace();
+        for &fcb in &[FCB1_ADDR, FCB2_ADDR] {
+            let word = words.next().unwrap_or("");
+            let (drive, name) = parse_file_name(word);
+            cpu.mem.w8(fcb + FCB_DR, drive as RegT);
+            cpu.mem.write(fcb + FCB_NAME, &name);
+        }
+
+        cpu.mem.write(CPM_TPA_ADDR, prog);
+        // the program can return to CP/M with RET
+        cpu.reg.set_sp(BDOS_PAGE);
+        cpu.push(0x0000);
+        cpu.reg.set_pc(CPM_TPA_ADDR);
+
+        state.status = CPMStatus::Running;
+        state.dma = DEFAULT_DMA_ADDR;
+        state.bios_dma = DEFAULT_DMA_ADDR;
+        state.search_results.clear();
+    }
+
+    /// execute one instruction or a trapped BDOS/BIOS call, return number of cycles taken
+    ///
+    /// Returns 0 if the program has exited or waits for input.
+    pub fn step(&self) -> i64 {
+        if self.status() != CPMStatus::Running {
+            return 0;
+        }
+        let cycles = self.cpu.borrow_mut().step(self);
+        if self.status() == CPMStatus::Running { cycles } else { 0 }
+    }
+
+    /// run until the program exits, waits for input, or the cycle limit is reached
+    pub fn run(&self, max_cycles: i64) -> CPMStatus {
+        let mut cycles = 0;
+        while cycles < max_cycles && self.status() == CPMStatus::Running {
+            cycles += self.step();
+        }
+        self.status()
+    }
+}
+
+impl Default for CPM {
+    fn default() -> CPM {
+        CPM::new()
+    }
+}
+
+impl Bus for CPM {}
+
+/// handle a BDOS call, return false if waiting for input
+fn bdos(state: &mut State, cpu: &mut CPU) -> bool {
+    let func = cpu.reg.c();
+    let de = cpu.reg.de();
+    let e = cpu.reg.e();
+    let result: RegT = match func {
+        0 => {
+            state.status = CPMStatus::Exited;
+            0
+        }
+        1 => {
+            match state.read_char() {
+                Some(c) => {
+                    state.write_char(c);
+                    c as RegT
+                }
+                None => return false,
+            }
+        }
+        2 => {
+            state.write_char(e as u8);
+            0
+        }
+        3 => EOF as RegT,
+        4 | 5 => 0,
+        6 => {
+            match e {
+                0xFF => {
+                    if state.input.is_empty() {
+                        0
+                    } else {
+                        state.input.remove(0) as RegT
+                    }
+                }
+                0xFE => state.console_status(),
+                _ => {
+                    state.write_char(e as u8);
+                    0
+                }
+            }
+        }
+        7 => cpu.mem.r8(IOBYTE_ADDR),
+        8 => {
+            cpu.mem.w8(IOBYTE_ADDR, e);
+            0
+        }
+        9 => {
+            let mut addr = de;
+            loop {
+                let c = cpu.mem.r8(addr) as u8;
+                if c == b'$' {
+                    break;
+                }
+                state.write_char(c);
+                addr = (addr + 1) & 0xFFFF;
+            }
+            0
+        }
+        10 => {
+            if !state.read_line(cpu, de) {
+                return false;
+            }
+            0
+        }
+        11 => state.console_status(),
+        12 => 0x0022,
+        13 => {
+            state.cur_drive = 0;
+            state.dma = DEFAULT_DMA_ADDR;
+            0
+        }
+        14 => {
+            let drive = e as usize;
+            match state.drives.get(drive) {
+                None | Some(&Drive::None) => 0xFF,
+                Some(_) => {
+                    state.cur_drive = drive;
+                    0
+                }
+            }
+        }
+        15 => state.open_file(cpu, de),
+        16 => state.find_file(cpu, de).map_or(0xFF, |_| 0),
+        17 => state.search_first(cpu, de),
+        18 => state.search_next(cpu),
+        19 => state.delete_file(cpu, de),
+        20 => state.read_seq(cpu, de),
+        21 => state.write_seq(cpu, de),
+        22 => state.make_file(cpu, de),
+        23 => state.rename_file(cpu, de),
+        24 => {
+            (0..CPM_NUM_DRIVES)
+                .filter(|d| !matches!(state.drives[*d], Drive::None))
+                .fold(0, |v, d| v | (1 << d))
+        }
+        25 => state.cur_drive as RegT,
+        26 => {
+            state.dma = de;
+            0
+        }
+        27 => ALV_ADDR,
+        28 | 30 | 37 => 0,
+        29 => 0,
+        31 => DPB_ADDR,
+        32 => {
+            if e == 0xFF {
+                state.user as RegT
+            } else {
+                state.user = (e & 0x0F) as u8;
+                0
+            }
+        }
+        33 => state.read_random(cpu, de),
+        34 | 40 => state.write_random(cpu, de),
+        35 => state.file_size(cpu, de),
+        36 => {
+            let rec = seq_record(cpu, de);
+            set_random_record(cpu, de, rec);
+            0
+        }
+        _ => 0xFF,
+    };
+    // results are returned in HL, and in A and B
+    cpu.reg.set_hl(result);
+    cpu.reg.set_a(result & 0xFF);
+    cpu.reg.set_b((result >> 8) & 0xFF);
+    true
+}
+
+/// handle a BIOS call, return false if waiting for input
+fn bios(state: &mut State, cpu: &mut CPU, func: usize) -> bool {
+    let bc = cpu.reg.bc();
+    match func {
+        // BOOT, WBOOT
+        0 | 1 => state.status = CPMStatus::Exited,
+        // CONST
+        2 => cpu.reg.set_a(state.console_status()),
+        // CONIN
+        3 => {
+            match state.read_char() {
+                Some(c) => cpu.reg.set_a(c as RegT),
+                None => return false,
+            }
+        }
+        // CONOUT
+        4 => state.write_char(cpu.reg.c() as u8),
+        // LIST, PUNCH
+        5 | 6 => (),
+        // READER
+        7 => cpu.reg.set_a(EOF as RegT),
+        // HOME
+        8 => state.bios_track = 0,
+        // SELDSK
+        9 => {
+            let drive = cpu.reg.c() as usize;
+            let dph = match state.drives.get(drive) {
+                Some(&Drive::Image(_)) => {
+                    state.bios_drive = drive;
+                    DPH_ADDR + drive as RegT * 16
+                }
+                _ => 0,
+            };
+            cpu.reg.set_hl(dph);
+        }
+        // SETTRK
+        10 => state.bios_track = bc as usize,
+        // SETSEC
+        11 => state.bios_sector = bc as usize,
+        // SETDMA
+        12 => state.bios_dma = bc,
+        // READ, WRITE
+        13 | 14 => {
+            let result = state.rw_sector(cpu, func == 14);
+            cpu.reg.set_a(result);
+        }
+        // LISTST
+        15 => cpu.reg.set_a(0xFF),
+        // SECTRAN (no sector translation)
+        16 => cpu.reg.set_hl(bc),
+        _ => (),
+    }
+    true
+}
+
+/// parse a command line file name like 'B:NAME.EXT' into drive and 11-byte FCB name
+fn parse_file_name(word: &str) -> (u8, [u8; 11]) {
+    let mut name = [b' '; 11];
+    let bytes = word.as_bytes();
+    let (drive, bytes) = if bytes.len() >= 2 && bytes[1] == b':' {
+        (bytes[0].wrapping_sub(b'A').wrapping_add(1), &bytes[2..])
+    } else {
+        (0, bytes)
+    };
+    let mut parts = bytes.splitn(2, |c| *c == b'.');
+    for (part, start, len) in [(parts.next(), 0, 8), (parts.next(), 8, 3)] {
+        for (i, c) in part.unwrap_or(&[]).iter().take(len).enumerate() {
+            if *c == b'*' {
+                for n in name[start + i..start + len].iter_mut() {
+                    *n = b'?';
+                }
+                break;
+            }
+            name[start + i] = *c;
+        }
+    }
+    (drive, name)
+}
+
+/// convert a host file name to an 11-byte FCB name, if it fits the 8.3 format
+fn to_fcb_name(host: &str) -> Option<[u8; 11]> {
+    let upper = host.to_uppercase();
+    let mut parts = upper.splitn(2, '.');
+    let base = parts.next().unwrap_or("");
+    let ext = parts.next().unwrap_or("");
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || !upper.is_ascii() ||
+       ext.contains('.') {
+        return None;
+    }
+    let mut name = [b' '; 11];
+    name[..base.len()].copy_from_slice(base.as_bytes());
+    name[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(name)
+}
+
+/// convert an 11-byte FCB name to a host file name
+fn to_host_name(name: &[u8; 11]) -> String {
+    let base: String = name[..8].iter().map(|c| *c as char).collect();
+    let ext: String = name[8..].iter().map(|c| *c as char).collect();
+    let (base, ext) = (base.trim_end(), ext.trim_end());
+    if ext.is_empty() {
+        base.to_string()
+    } else {
+        format!("{}.{}", base, ext)
+    }
+}
+
+/// read the 11-byte name from an FCB (attribute bits removed)
+fn fcb_name(cpu: &CPU, fcb: RegT) -> [u8; 11] {
+    let mut name = [0u8; 11];
+    for (i, c) in name.iter_mut().enumerate() {
+        *c = (cpu.mem.r8(fcb + FCB_NAME + i as RegT) as u8 & 0x7F).to_ascii_uppercase();
+    }
+    name
+}
+
+/// match an FCB name against a pattern with '?' wildcards
+fn name_matches(pattern: &[u8; 11], name: &[u8; 11]) -> bool {
+    pattern.iter().zip(name.iter()).all(|(p, n)| *p == b'?' || *p == *n)
+}
+
+/// current sequential record number of an FCB
+fn seq_record(cpu: &CPU, fcb: RegT) -> usize {
+    let ex = (cpu.mem.r8(fcb + FCB_EX) & 0x1F) as usize;
+    let s2 = (cpu.mem.r8(fcb + FCB_S2) & 0x3F) as usize;
+    let cr = (cpu.mem.r8(fcb + FCB_CR) & 0x7F) as usize;
+    (s2 * 32 + ex) * 128 + cr
+}
+
+/// set the sequential record number of an FCB
+fn set_seq_record(cpu: &mut CPU, fcb: RegT, rec: usize) {
+    cpu.mem.w8(fcb + FCB_EX, ((rec >> 7) & 0x1F) as RegT);
+    cpu.mem.w8(fcb + FCB_S2, ((rec >> 12) & 0x3F) as RegT);
+    cpu.mem.w8(fcb + FCB_CR, (rec & 0x7F) as RegT);
+}
+
+/// the random record number of an FCB
+fn random_record(cpu: &CPU, fcb: RegT) -> usize {
+    (cpu.mem.r8(fcb + FCB_R0) as usize) | ((cpu.mem.r8(fcb + FCB_R0 + 1) as usize) << 8) |
+    ((cpu.mem.r8(fcb + FCB_R0 + 2) as usize & 3) << 16)
+}
+
+/// set the random record number of an FCB
+fn set_random_record(cpu: &mut CPU, fcb: RegT, rec: usize) {
+    cpu.mem.w8(fcb + FCB_R0, (rec & 0xFF) as RegT);
+    cpu.mem.w8(fcb + FCB_R0 + 1, ((rec >> 8) & 0xFF) as RegT);
+    cpu.mem.w8(fcb + FCB_R0 + 2, ((rec >> 16) & 0xFF) as RegT);
+}
+
+/// number of 128-byte records in a file
+fn num_records(path: &Path) -> usize {
+    let len = fs::metadata(path).map(|m| m.len() as usize).unwrap_or(0);
+    (len + RECORD_SIZE - 1) / RECORD_SIZE
+}
+
+impl State {
+    /// what the CPU does after a BDOS or BIOS call: return to the caller,
+    /// or stay at the entry point when the program waits for input or has exited
+    fn trap_action(&self, done: bool) -> TrapAction {
+        if done && self.status == CPMStatus::Running {
+            TrapAction::Return
+        } else {
+            TrapAction::Stay
+        }
+    }
+
+    fn read_char(&mut self) -> Option<u8> {
+        if self.input.is_empty() {
+            self.status = CPMStatus::WaitingForInput;
+            None
+        } else {
+            Some(self.input.remove(0))
+        }
+    }
+
+    fn write_char(&mut self, c: u8) {
+        if self.echo {
+            print!("{}", c as char);
+            let _ = io::stdout().flush();
+        } else {
+            self.output.push(c);
+        }
+    }
+
+    fn console_status(&self) -> RegT {
+        if self.input.is_empty() { 0 } else { 0xFF }
+    }
+
+    /// BDOS function 10, read a line into a console buffer
+    fn read_line(&mut self, cpu: &mut CPU, buf: RegT) -> bool {
+        let end = match self.input.iter().position(|c| *c == b'\r' || *c == b'\n') {
+            Some(end) => end,
+            None => {
+                self.status = CPMStatus::WaitingForInput;
+                return false;
+            }
+        };
+        let line: Vec<u8> = self.input.drain(..end + 1).collect();
+        let max_len = cpu.mem.r8(buf) as usize;
+        let line = &line[..end.min(max_len)];
+        cpu.mem.w8(buf + 1, line.len() as RegT);
+        cpu.mem.write(buf + 2, line);
+        for c in line {
+            self.write_char(*c);
+        }
+        self.write_char(b'\r');
+        true
+    }
+
+    /// the host directory of the drive in an FCB
+    fn fcb_dir(&self, cpu: &CPU, fcb: RegT) -> Option<PathBuf> {
+        let dr = cpu.mem.r8(fcb + FCB_DR) as usize;
+        let drive = if dr == 0 || dr > CPM_NUM_DRIVES { self.cur_drive } else { dr - 1 };
+        match self.drives[drive] {
+            Drive::Dir(ref dir) => Some(dir.clone()),
+            _ => None,
+        }
+    }
+
+    /// all files in the FCB's directory which match the FCB name (with wildcards)
+    fn match_files(&self, cpu: &CPU, fcb: RegT) -> Vec<(PathBuf, [u8; 11])> {
+        let mut files = Vec::new();
+        if let Some(dir) = self.fcb_dir(cpu, fcb) {
+            let pattern = fcb_name(cpu, fcb);
+            if let Ok(entries) = fs::read_dir(&dir) {
+                for entry in entries.filter_map(|e| e.ok()) {
+                    if !entry.path().is_file() {
+                        continue;
+                    }
+                    if let Some(name) = entry.file_name().to_str().and_then(to_fcb_name) {
+                        if name_matches(&pattern, &name) {
+                            files.push((entry.path(), name));
+                        }
+                    }
+                }
+            }
+        }
+        files.sort_by_key(|f| f.1);
+        files
+    }
+
+    /// the host path of an existing file named in an FCB
+    fn find_file(&self, cpu: &CPU, fcb: RegT) -> Option<PathBuf> {
+        self.match_files(cpu, fcb).into_iter().next().map(|(path, _)| path)
+    }
+
+    /// update the record count of the current extent in an FCB
+    fn update_rc(&self, cpu: &mut CPU, fcb: RegT, path: &Path) {
+        let extent = seq_record(cpu, fcb) / 128;
+        let recs = num_records(path).saturating_sub(extent * 128).min(128);
+        cpu.mem.w8(fcb + FCB_RC, recs as RegT);
+    }
+
+    fn open_file(&mut self, cpu: &mut CPU, fcb: RegT) -> RegT {
+        match self.find_file(cpu, fcb) {
+            Some(path) => {
+                self.update_rc(cpu, fcb, &path);
+                0
+            }
+            None => 0xFF,
+        }
+    }
+
+    fn make_file(&mut self, cpu: &mut CPU, fcb: RegT) -> RegT {
+        let dir = match self.fcb_dir(cpu, fcb) {
+            Some(dir) => dir,
+            None => return 0xFF,
+        };
+        let path = self.find_file(cpu, fcb)
+            .unwrap_or_else(|| dir.join(to_host_name(&fcb_name(cpu, fcb))));
+        match File::create(&path) {
+            Ok(_) => {
+                cpu.mem.w8(fcb + FCB_RC, 0);
+                0
+            }
+            Err(_) => 0xFF,
+        }
+    }
+
+    fn delete_file(&mut self, cpu: &mut CPU, fcb: RegT) -> RegT {
+        let files = self.match_files(cpu, fcb);
+        if files.is_empty() {
+            return 0xFF;
+        }
+        for (path, _) in files {
+            let _ = fs::remove_file(path);
+        }
+        0
+    }
+
+    fn rename_file(&mut self, cpu: &mut CPU, fcb: RegT) -> RegT {
+        match (self.find_file(cpu, fcb), self.fcb_dir(cpu, fcb)) {
+            (Some(path), Some(dir)) => {
+                let new_name = to_host_name(&fcb_name(cpu, fcb + 16));
+                if fs::rename(path, dir.join(new_name)).is_ok() { 0 } else { 0xFF }
+            }
+            _ => 0xFF,
+        }
+    }
+
+    /// write the next search result as directory entry into the DMA buffer
+    fn search_next(&mut self, cpu: &mut CPU) -> RegT {
+        if self.search_results.is_empty() {
+            return 0xFF;
+        }
+        let name = self.search_results.remove(0);
+        let mut entry = [0u8; 32];
+        entry[0] = self.user;
+        entry[1..12].copy_from_slice(&name);
+        cpu.mem.write(self.dma, &entry);
+        0
+    }
+
+    fn search_first(&mut self, cpu: &mut CPU, fcb: RegT) -> RegT {
+        self.search_results = self.match_files(cpu, fcb).into_iter().map(|(_, n)| n).collect();
+        self.search_next(cpu)
+    }
+
+    /// read a record into the DMA buffer, return 1 if beyond end of file
+    fn read_record(&self, cpu: &mut CPU, fcb: RegT, rec: usize) -> RegT {
+        let path = match self.find_file(cpu, fcb) {
+            Some(path) => path,
+            None => return 1,
+        };
+        let mut buf = [EOF; RECORD_SIZE];
+        let len = File::open(&path)
+            .and_then(|mut f| {
+                f.seek(SeekFrom::Start((rec * RECORD_SIZE) as u64))?;
+                let mut len = 0;
+                while len < RECORD_SIZE {
+                    match f.read(&mut buf[len..])? {
+                        0 => break,
+                        n => len += n,
+                    }
+                }
+                Ok(len)
+            })
+            .unwrap_or(0);
+        if len == 0 {
+            return 1;
+        }
+        for b in buf[len..].iter_mut() {
+            *b = EOF;
+        }
+        cpu.mem.write(self.dma, &buf);
+        0
+    }
+
+    /// write a record from the DMA buffer
+    fn write_record(&self, cpu: &mut CPU, fcb: RegT, rec: usize) -> RegT {
+        let path = match self.find_file(cpu, fcb) {
+            Some(path) => path,
+            None => return 0xFF,
+        };
+        let mut buf = [0u8; RECORD_SIZE];
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = cpu.mem.r8(self.dma + i as RegT) as u8;
+        }
+        let res = OpenOptions::new().write(true).open(&path).and_then(|mut f| {
+            f.seek(SeekFrom::Start((rec * RECORD_SIZE) as u64))?;
+            f.write_all(&buf)
+        });
+        if res.is_err() {
+            return 2;
+        }
+        self.update_rc(cpu, fcb, &path);
+        0
+    }
+
+    fn read_seq(&mut self, cpu: &mut CPU, fcb: RegT) -> RegT {
+        let rec = seq_record(cpu, fcb);
+        let result = self.read_record(cpu, fcb, rec);
+        if result == 0 {
+            set_seq_record(cpu, fcb, rec + 1);
+        }
+        result
+    }
+
+    fn write_seq(&mut self, cpu: &mut CPU, fcb: RegT) -> RegT {
+        let rec = seq_record(cpu, fcb);
+        let result = self.write_record(cpu, fcb, rec);
+        if result == 0 {
+            set_seq_record(cpu, fcb, rec + 1);
+        }
+        result
+    }
+
+    fn read_random(&mut self, cpu: &mut CPU, fcb: RegT) -> RegT {
+        let rec = random_record(cpu, fcb);
+        // the next sequential access starts at the random record
+        set_seq_record(cpu, fcb, rec);
+        self.read_record(cpu, fcb, rec)
+    }
+
+    fn write_random(&mut self, cpu: &mut CPU, fcb: RegT) -> RegT {
+        let rec = random_record(cpu, fcb);
+        set_seq_record(cpu, fcb, rec);
+        self.write_record(cpu, fcb, rec)
+    }
+
+    fn file_size(&mut self, cpu: &mut CPU, fcb: RegT) -> RegT {
+        match self.find_file(cpu, fcb) {
+            Some(path) => {
+                set_random_record(cpu, fcb, num_records(&path));
+                0
+            }
+            None => 0xFF,
+        }
+    }
+
+    /// BIOS READ and WRITE, transfer a sector between disk image and DMA buffer
+    fn rw_sector(&mut self, cpu: &mut CPU, write: bool) -> RegT {
+        if self.bios_track >= NUM_TRACKS || self.bios_sector >= SECTORS_PER_TRACK {
+            return 1;
+        }
+        let offset = (self.bios_track * SECTORS_PER_TRACK + self.bios_sector) * RECORD_SIZE;
+        let dma = self.bios_dma;
+        match self.drives[self.bios_drive] {
+            Drive::Image(ref mut data) => {
+                let sector = &mut data[offset..offset + RECORD_SIZE];
+                if write {
+                    for (i, b) in sector.iter_mut().enumerate() {
+                        *b = cpu.mem.r8(dma + i as RegT) as u8;
+                    }
+                } else {
+                    cpu.mem.write(dma, sector);
+                }
+                0
+            }
+            _ => 1,
+        }
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    // call a BDOS or BIOS function directly, return the A register
+    fn call(cpm: &CPM, addr: RegT, c: RegT, de: RegT) -> RegT {
+        {
+            let mut cpu = cpm.cpu.borrow_mut();
+            cpu.push(0x1000);
+            cpu.reg.set_pc(addr);
+            cpu.reg.set_c(c);
+            cpu.reg.set_de(de);
+        }
+        cpm.step();
+        cpm.cpu.borrow().reg.a()
+    }
+
+    fn set_fcb(cpm: &CPM, fcb: RegT, name: &str) {
+        let (drive, name) = parse_file_name(name);
+        let mut cpu = cpm.cpu.borrow_mut();
+        cpu.mem.write(fcb, &[0; 36]);
+        cpu.mem.w8(fcb, drive as RegT);
+        cpu.mem.write(fcb + 1, &name);
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rz80-cpm-{}-{}", name, ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn command_line() {
+        let cpm = CPM::new();
+        cpm.load_com(&[0xC9], "b:test.txt *.com");
+        let cpu = cpm.cpu.borrow();
+        assert_eq!(0xC3, cpu.mem.r8(0x0005));
+        assert_eq!(CPM_BDOS_ADDR, cpu.mem.r16(0x0006));
+        assert_eq!(CPM_BIOS_ADDR + 3, cpu.mem.r16(0x0001));
+        assert_eq!(CPM_TPA_ADDR, cpu.reg.pc());
+        assert_eq!(0x0000, cpu.mem.r16(cpu.reg.sp()));
+        assert_eq!(17, cpu.mem.r8(0x80));
+        assert_eq!(b'B', cpu.mem.r8(0x81 + 1) as u8);
+        assert_eq!(2, cpu.mem.r8(FCB1_ADDR));
+        let name: Vec<u8> = (0..11).map(|i| cpu.mem.r8(FCB1_ADDR + 1 + i) as u8).collect();
+        assert_eq!(b"TEST    TXT", &name[..]);
+        let name: Vec<u8> = (0..11).map(|i| cpu.mem.r8(FCB2_ADDR + 1 + i) as u8).collect();
+        assert_eq!(b"????????COM", &name[..]);
+        drop(cpu);
+
+        // RET from the program exits
+        assert_eq!(CPMStatus::Exited, cpm.run(1000));
+    }
+
+    #[test]
+    fn console() {
+        let cpm = CPM::new();
+        cpm.load_com(&[0x76], "");
+        call(&cpm, CPM_BDOS_ADDR, 2, b'A' as RegT);
+        cpm.cpu.borrow_mut().mem.write(0x2000, b"BC$");
+        call(&cpm, CPM_BDOS_ADDR, 9, 0x2000);
+        call(&cpm, CPM_BIOS_ADDR + 4 * 3, b'D' as RegT, 0);
+        assert_eq!(b"ABCD", &cpm.take_output()[..]);
+        assert_eq!(0x1000, cpm.cpu.borrow().reg.pc());
+        assert_eq!(0, call(&cpm, CPM_BDOS_ADDR, 11, 0));
+
+        // console input waits until input is available
+        {
+            let mut cpu = cpm.cpu.borrow_mut();
+            cpu.reg.set_c(1);
+            cpu.reg.set_pc(CPM_BDOS_ADDR);
+        }
+        assert_eq!(0, cpm.step());
+        assert_eq!(CPMStatus::WaitingForInput, cpm.status());
+        assert_eq!(CPM_BDOS_ADDR, cpm.cpu.borrow().reg.pc());
+        cpm.input(b"x");
+        assert_eq!(0xFF, call(&cpm, CPM_BDOS_ADDR, 11, 0));
+        assert_eq!(b'x' as RegT, call(&cpm, CPM_BDOS_ADDR, 1, 0));
+        assert_eq!(b"x", &cpm.take_output()[..]);
+        assert_eq!(0, call(&cpm, CPM_BDOS_ADDR, 6, 0xFF));
+        assert_eq!(0x22, call(&cpm, CPM_BDOS_ADDR, 12, 0));
+    }
+
+    #[test]
+    fn files() {
+        let dir = temp_dir("files");
+        fs::write(dir.join("hello.txt"), b"Hello World!").unwrap();
+        let cpm = CPM::new();
+        cpm.mount_dir(0, &dir);
+        cpm.load_com(&[0x76], "");
+        let fcb = 0x2000;
+
+        // read an existing file with a lowercase host name
+        set_fcb(&cpm, fcb, "HELLO.TXT");
+        assert_eq!(0, call(&cpm, CPM_BDOS_ADDR, 15, fcb));
+        assert_eq!(1, cpm.cpu.borrow().mem.r8(fcb + FCB_RC));
+        assert_eq!(0, call(&cpm, CPM_BDOS_ADDR, 20, fcb));
+        {
+            let cpu = cpm.cpu.borrow();
+            let rec: Vec<u8> = (0..14).map(|i| cpu.mem.r8(0x80 + i) as u8).collect();
+            assert_eq!(b"Hello World!\x1a\x1a", &rec[..]);
+        }
+        assert_eq!(1, call(&cpm, CPM_BDOS_ADDR, 20, fcb));
+        set_fcb(&cpm, fcb, "MISSING.TXT");
+        assert_eq!(0xFF, call(&cpm, CPM_BDOS_ADDR, 15, fcb));
+
+        // write a new file with 3 records, and read the second one back
+        set_fcb(&cpm, fcb, "NEW.DAT");
+        assert_eq!(0, call(&cpm, CPM_BDOS_ADDR, 22, fcb));
+        call(&cpm, CPM_BDOS_ADDR, 26, 0x3000);
+        for i in 0..3 {
+            cpm.cpu.borrow_mut().mem.write(0x3000, &[i as u8 + 1; 128]);
+            assert_eq!(0, call(&cpm, CPM_BDOS_ADDR, 21, fcb));
+        }
+        assert_eq!(0, call(&cpm, CPM_BDOS_ADDR, 16, fcb));
+        assert_eq!(384, fs::read(dir.join("NEW.DAT")).unwrap().len());
+        assert_eq!(0, call(&cpm, CPM_BDOS_ADDR, 35, fcb));
+        assert_eq!(3, random_record(&cpm.cpu.borrow(), fcb));
+        set_random_record(&mut cpm.cpu.borrow_mut(), fcb, 1);
+        assert_eq!(0, call(&cpm, CPM_BDOS_ADDR, 33, fcb));
+        assert_eq!(2, cpm.cpu.borrow().mem.r8(0x3000));
+
+        // search with wildcards, rename and delete
+        set_fcb(&cpm, fcb, "*.*");
+        assert_eq!(0, call(&cpm, CPM_BDOS_ADDR, 17, fcb));
+        assert_eq!(b'H', cpm.cpu.borrow().mem.r8(0x3001) as u8);
+        assert_eq!(0, call(&cpm, CPM_BDOS_ADDR, 18, fcb));
+        assert_eq!(b'N', cpm.cpu.borrow().mem.r8(0x3001) as u8);
+        assert_eq!(0xFF, call(&cpm, CPM_BDOS_ADDR, 18, fcb));
+        set_fcb(&cpm, fcb, "NEW.DAT");
+        set_fcb(&cpm, fcb + 16, "OLD.DAT");
+        assert_eq!(0, call(&cpm, CPM_BDOS_ADDR, 23, fcb));
+        assert!(dir.join("OLD.DAT").exists());
+        set_fcb(&cpm, fcb, "OLD.*");
+        assert_eq!(0, call(&cpm, CPM_BDOS_ADDR, 19, fcb));
+        assert!(!dir.join("OLD.DAT").exists());
+
+        // drive B: is not mounted
+        set_fcb(&cpm, fcb, "B:HELLO.TXT");
+        assert_eq!(0xFF, call(&cpm, CPM_BDOS_ADDR, 15, fcb));
+        assert_eq!(0xFF, call(&cpm, CPM_BDOS_ADDR, 14, 1));
+        assert_eq!(0, call(&cpm, CPM_BDOS_ADDR, 25, 0));
+        assert_eq!(0, call(&cpm, CPM_BDOS_ADDR, 14, 0));
+        assert_eq!(1, call(&cpm, CPM_BDOS_ADDR, 24, 0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn disk_image() {
+        let cpm = CPM::new();
+        let mut image = vec![0xE5; CPM_DISK_IMAGE_SIZE];
+        image[(2 * 26 + 3) * 128] = 0x42;
+        cpm.mount_image(1, &image);
+        cpm.load_com(&[0x76], "");
+        let bios = |func: RegT, bc: RegT| {
+            {
+                let mut cpu = cpm.cpu.borrow_mut();
+                cpu.reg.set_bc(bc);
+            }
+            call(&cpm, CPM_BIOS_ADDR + func * 3, bc & 0xFF, 0)
+        };
+
+        // drive A: has no disk image
+        bios(9, 0);
+        assert_eq!(0, cpm.cpu.borrow().reg.hl());
+        bios(9, 1);
+        let dph = cpm.cpu.borrow().reg.hl();
+        assert_eq!(DPB_ADDR, cpm.cpu.borrow().mem.r16(dph + 10));
+        assert_eq!(26, cpm.cpu.borrow().mem.r16(DPB_ADDR));
+        bios(10, 2);
+        bios(11, 3);
+        bios(12, 0x4000);
+        assert_eq!(0, bios(13, 0));
+        assert_eq!(0x42, cpm.cpu.borrow().mem.r8(0x4000));
+        cpm.cpu.borrow_mut().mem.w8(0x4000, 0x43);
+        assert_eq!(0, bios(14, 0));
+        assert_eq!(0x43, cpm.image(1).unwrap()[(2 * 26 + 3) * 128]);
+        bios(10, 77);
+        assert_eq!(1, bios(13, 0));
+
+        // warm boot exits
+        bios(1, 0);
+        assert_eq!(CPMStatus::Exited, cpm.status());
+        assert_eq!(CPM_BIOS_ADDR + 3, cpm.cpu.borrow().reg.pc());
+    }
+}
@@ -1,14 +1,18 @@
 use RegT;
 use memory::Memory;
 use registers::Registers;
-use bus::Bus;
+use bus::{Bus, BusCycle};
+use std::collections::{HashMap, HashSet};
+use trace::Tracer;
 
 /// Z80 CPU emulation
 ///
 /// The core of the CPU emulation is the **step()** method, this fetches
 /// the next instruction from the memory location pointed to by the PC
 /// register, executes the instruction, handles any pending interrupt
-/// request, and finally returns the number of cycles taken.
+/// request, and finally returns the number of cycles taken. The
+/// **run()** method executes instructions for a budget of cycles and
+/// skips over HALT in one go.
 ///
 /// An object implementing the Bus trait must be handed to the step()
 /// method which is called if the CPU needs to communicate with the
@@ -22,9 +26,27 @@ use bus::Bus;
 ///
 /// What's **not** implemented:
 ///
-/// - interrupt modes 0 and 1
-/// - non-maskable interrupts (including the RETN instruction)
-/// - extra memory wait states
+/// - interrupt mode 0 only accepts RST instructions on the data bus
+///   (other values are handled like RST 38h)
+///
+/// Extra wait states can be inserted into memory and I/O cycles,
+/// either statically per memory page (see **Memory::set_wait_states()**),
+/// or by the Bus trait's **cpu_wait()** method after enabling it with
+/// **set_wait_callback()**. The wait states are added to the cycle count
+/// returned by step().
+///
+/// Opcode fetches (M1 cycles, including prefix bytes and the NOPs in
+/// HALT state) can be observed with the Bus trait's **cpu_fetch()**
+/// method after enabling it with **set_fetch_callback()**, for instance
+/// to switch off a boot ROM after the first fetch from RAM. While the
+/// fetch callback is enabled, RETI doesn't call **Bus::irq_reti()**,
+/// the system is expected to decode it from the opcode fetches.
+///
+/// The HALT instruction puts the CPU into HALT state, where it executes
+/// NOPs (4 cycles each, incrementing the R register) with the PC pointing
+/// to the instruction after the HALT, until an interrupt is accepted, a
+/// non-maskable interrupt is requested or the CPU is reset. Changes of the
+/// HALT output line are reported through **Bus::cpu_halt()**.
 ///
 /// # Examples
 ///
@@ -71,7 +93,45 @@ pub struct CPU {
     pub invalid_op: bool,
     enable_interrupt: bool,
     irq_received: bool,
+    nmi_received: bool,
+    /// the HALT output line state last reported to the bus
+    halt_line: bool,
+    wait_callback: bool,
+    fetch_callback: bool,
     pub mem: Memory,
+    traps: HashMap<RegT, TrapFn>,
+    idle_loops: HashSet<RegT>,
+    tracer: Option<Tracer>,
+}
+
+/// a copy of the CPU state and memory, created with **CPU::snapshot()**
+#[derive(Clone)]
+pub struct CPUSnapshot {
+    reg: Registers,
+    halt: bool,
+    iff1: bool,
+    iff2: bool,
+    invalid_op: bool,
+    enable_interrupt: bool,
+    irq_received: bool,
+    nmi_received: bool,
+    mem: Box<Memory>,
+}
+
+type TrapFn = Box<FnMut(&mut CPU) -> TrapAction>;
+
+/// what the CPU should do after a trap handler has run
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TrapAction {
+    /// execute the instruction at the current PC
+    Continue,
+    /// return to the caller as if a RET instruction had been executed
+    Return,
+    /// don't execute the instruction and stay at the current PC (this
+    /// takes 4 cycles and increments R like a NOP in HALT state, but
+    /// doesn't fetch an opcode), the handler runs again on the next step,
+    /// for instance to wait for host input
+    Stay,
 }
 
 use registers::CF;
@@ -148,7 +208,14 @@ impl CPU {
             invalid_op: false,
             enable_interrupt: false,
             irq_received: false,
+            nmi_received: false,
+            halt_line: false,
+            wait_callback: false,
+            fetch_callback: false,
             mem: Memory::new(),
+            traps: HashMap::new(),
+            idle_loops: HashSet::new(),
+            tracer: None,
         }
     }
 
@@ -162,7 +229,14 @@ impl CPU {
             invalid_op: false,
             enable_interrupt: false,
             irq_received: false,
+            nmi_received: false,
+            halt_line: false,
+            wait_callback: false,
+            fetch_callback: false,
             mem: Memory::new_64k(),
+            traps: HashMap::new(),
+            idle_loops: HashSet::new(),
+            tracer: None,
         }
     }
 
@@ -174,19 +248,35 @@ impl CPU {
         self.iff2 = false;
         self.invalid_op = false;
         self.irq_received = false;
+        self.nmi_received = false;
         self.enable_interrupt = false;
     }
 
-    /// fetch the next instruction byte from memory
+    /// fetch the next instruction byte from memory (M1 cycle)
     #[inline(always)]
-    fn fetch_op(&mut self) -> RegT {
+    fn fetch_op(&mut self, bus: &Bus) -> RegT {
         self.reg.r = (self.reg.r & 0x80) | ((self.reg.r + 1) & 0x7F);
         let pc = self.reg.pc();
-        let op = self.mem.r8(pc);
+        let op = self.mem.fetch8(pc);
+        if self.fetch_callback {
+            bus.cpu_fetch(pc, op);
+        }
         self.reg.inc_pc(1);
         op
     }
 
+    /// execute a NOP in HALT state, the opcode fetch doesn't advance the PC
+    #[inline(always)]
+    fn halted_op(&mut self, bus: &Bus) -> i64 {
+        self.reg.r = (self.reg.r & 0x80) | ((self.reg.r + 1) & 0x7F);
+        let pc = self.reg.pc();
+        let op = self.mem.fetch8(pc);
+        if self.fetch_callback {
+            bus.cpu_fetch(pc, op);
+        }
+        4
+    }
+
     /// decode and execute one instruction, return number of cycles taken
     pub fn step(&mut self, bus: &Bus) -> i64 {
         self.invalid_op = false;
@@ -195,14 +285,348 @@ impl CPU {
             self.iff2 = true;
             self.enable_interrupt = false
         }
-        let mut cyc = self.do_op(bus, false);
-        if self.irq_received {
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer.log(self);
+            self.tracer = Some(tracer);
+        }
+        let waits = self.mem.begin_cycles();
+        let mut cyc = if self.halt {
+            self.halted_op(bus)
+        } else if self.traps.is_empty() {
+            self.do_op(bus, false)
+        } else {
+            self.do_trap_or_op(bus)
+        };
+        if self.nmi_received {
+            cyc += self.handle_nmi();
+        } else if self.irq_received {
             cyc += self.handle_irq(bus);
             self.irq_received = false;
         }
+        if waits {
+            self.mem.end_cycles();
+            cyc += self.wait_cycles(bus);
+        }
+        if self.halt != self.halt_line {
+            self.halt_line = self.halt;
+            bus.cpu_halt(self.halt);
+        }
+        if let Some(ref mut tracer) = self.tracer {
+            tracer.add_cycles(cyc);
+        }
         cyc
     }
 
+    /// the extra wait states of the bus cycles recorded during a step
+    fn wait_cycles(&self, bus: &Bus) -> i64 {
+        let mem = &self.mem;
+        let callback = self.wait_callback;
+        let mut cycles = 0;
+        mem.drain_cycles(|cycle, addr| {
+            cycles += mem.wait_states(cycle, addr);
+            if callback {
+                cycles += bus.cpu_wait(cycle, addr);
+            }
+        });
+        cycles
+    }
+
+    /// enable or disable calling Bus::cpu_wait() for each bus cycle
+    pub fn set_wait_callback(&mut self, enabled: bool) {
+        self.wait_callback = enabled;
+        self.mem.set_record_cycles(enabled);
+    }
+
+    /// enable or disable calling Bus::cpu_fetch() for each opcode fetch
+    pub fn set_fetch_callback(&mut self, enabled: bool) {
+        self.fetch_callback = enabled;
+    }
+
+    /// execute instructions until a number of cycles has been reached,
+    /// return the number of cycles executed beyond the budget
+    ///
+    /// Pending interrupt requests are handled like in step(). When the
+    /// CPU is in HALT state or in a registered idle loop (see is_idle()),
+    /// the remaining budget is skipped in one go (with the same R register
+    /// and cycle count as executing the instructions one by one). Devices
+    /// behind the bus are not updated while run() executes, so the budget
+    /// should end at the next device event (for instance a scheduled interrupt).
+    /// Skipped HALT cycles include the M1 wait states of the memory page,
+    /// but Bus::cpu_wait() and Bus::cpu_fetch() aren't called for them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rz80::{CPU, Bus};
+    ///
+    /// struct DummyBus;
+    /// impl Bus for DummyBus { };
+    ///
+    /// let mut cpu = CPU::new_64k();
+    /// // LD A,0x11 (7 cycles), HALT (4 cycles)
+    /// cpu.mem.write(0x0000, &[0x3E, 0x11, 0x76]);
+    ///
+    /// let overshoot = cpu.run(&DummyBus, 10);
+    /// assert_eq!(1, overshoot);
+    /// assert!(cpu.halt);
+    ///
+    /// // the CPU sits in HALT until an interrupt arrives
+    /// let overshoot = cpu.run(&DummyBus, 1000 - overshoot);
+    /// assert_eq!(1, overshoot);
+    /// assert_eq!(0x0003, cpu.reg.pc());
+    /// ```
+    pub fn run(&mut self, bus: &Bus, num_cycles: i64) -> i64 {
+        let mut cycles = 0;
+        while cycles < num_cycles {
+            if !self.is_idle() {
+                cycles += self.step(bus);
+            } else if self.halt {
+                cycles += self.skip_halt(num_cycles - cycles);
+            } else {
+                cycles += self.skip_idle_loop(bus, num_cycles - cycles);
+            }
+        }
+        cycles - num_cycles
+    }
+
+    /// return true if the CPU waits for an interrupt, either in HALT
+    /// state or in a registered idle loop, and can be fast-forwarded
+    ///
+    /// This is false while an interrupt request is pending, and while
+    /// traps are registered or a tracer is attached.
+    pub fn is_idle(&self) -> bool {
+        (self.halt || self.idle_loops.contains(&self.reg.pc())) && !self.irq_received &&
+        !self.nmi_received && !self.enable_interrupt && self.traps.is_empty() && self.tracer.is_none()
+    }
+
+    /// register the start address of an idle loop which waits for an interrupt
+    ///
+    /// When the CPU reaches the address in run(), it executes one iteration
+    /// of the loop, and if the CPU registers are unchanged afterwards,
+    /// skips the following iterations up to the end of the cycle budget.
+    /// This is only correct for loops which don't write memory or I/O
+    /// ports, and which only read values which are changed by interrupt
+    /// handlers (for instance 'JR $', or polling a frame counter).
+    pub fn add_idle_loop(&mut self, addr: RegT) {
+        self.idle_loops.insert(addr & 0xFFFF);
+    }
+
+    /// remove an idle loop address, return true if it was registered
+    pub fn remove_idle_loop(&mut self, addr: RegT) -> bool {
+        self.idle_loops.remove(&(addr & 0xFFFF))
+    }
+
+    /// remove all idle loop addresses
+    pub fn clear_idle_loops(&mut self) {
+        self.idle_loops.clear();
+    }
+
+    /// execute the HALT instruction until a number of cycles has been reached
+    fn skip_halt(&mut self, num_cycles: i64) -> i64 {
+        // each HALT is an opcode fetch of 4 cycles which increments R
+        let op_cycles = 4 + self.mem.wait_states(BusCycle::M1, self.reg.pc());
+        let num_ops = (num_cycles + op_cycles - 1) / op_cycles;
+        self.reg.r = (self.reg.r & 0x80) | (((self.reg.r as i64 + num_ops) & 0x7F) as RegT);
+        num_ops * op_cycles
+    }
+
+    /// execute one iteration of an idle loop, and skip the following
+    /// iterations which fit into a number of cycles
+    fn skip_idle_loop(&mut self, bus: &Bus, num_cycles: i64) -> i64 {
+        let start = self.reg_state();
+        let start_r = self.reg.r;
+        let mut cycles = 0;
+        loop {
+            cycles += self.step(bus);
+            if self.reg.pc() == start[11] || cycles >= num_cycles || self.halt ||
+               self.irq_received || self.nmi_received {
+                break;
+            }
+        }
+        if cycles < num_cycles && self.reg_state() == start && !self.halt &&
+           !self.irq_received && !self.nmi_received {
+            // the next iterations are identical, except for the R register
+            let num_iters = (num_cycles - cycles) / cycles;
+            let r_inc = (self.reg.r - start_r) as i64 & 0x7F;
+            self.reg.r = (self.reg.r & 0x80) |
+                         (((self.reg.r as i64 + num_iters * r_inc) & 0x7F) as RegT);
+            cycles * (num_iters + 1)
+        } else {
+            cycles
+        }
+    }
+
+    /// the registers (except R), interrupt flip-flops and interrupt mode
+    fn reg_state(&self) -> [RegT; 17] {
+        let r = &self.reg;
+        [r.af(), r.bc(), r.de(), r.hl(), r.af_(), r.bc_(), r.de_(), r.hl_(), r.ix(), r.iy(),
+         r.sp(), r.pc(), r.wz(), r.i, r.im, self.iff1 as RegT, self.iff2 as RegT]
+    }
+
+    /// attach an execution trace logger, replaces the current tracer
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// detach and return the execution trace logger
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    /// get the attached execution trace logger
+    pub fn tracer(&mut self) -> Option<&mut Tracer> {
+        self.tracer.as_mut()
+    }
+
+    /// return true if an interrupt request will be handled after the next instruction
+    pub fn irq_pending(&self) -> bool {
+        self.irq_received
+    }
+
+    /// return true if a non-maskable interrupt will be handled after the next instruction
+    pub fn nmi_pending(&self) -> bool {
+        self.nmi_received
+    }
+
+    /// create a copy of the CPU state and memory
+    ///
+    /// Traps and the tracer are not part of the snapshot.
+    pub fn snapshot(&self) -> CPUSnapshot {
+        CPUSnapshot {
+            reg: self.reg.clone(),
+            halt: self.halt,
+            iff1: self.iff1,
+            iff2: self.iff2,
+            invalid_op: self.invalid_op,
+            enable_interrupt: self.enable_interrupt,
+            irq_received: self.irq_received,
+            nmi_received: self.nmi_received,
+            mem: Box::new(self.mem.clone()),
+        }
+    }
+
+    /// restore the CPU state and memory from a snapshot
+    pub fn restore(&mut self, snapshot: &CPUSnapshot) {
+        self.reg = snapshot.reg.clone();
+        self.halt = snapshot.halt;
+        self.iff1 = snapshot.iff1;
+        self.iff2 = snapshot.iff2;
+        self.invalid_op = snapshot.invalid_op;
+        self.enable_interrupt = snapshot.enable_interrupt;
+        self.irq_received = snapshot.irq_received;
+        self.nmi_received = snapshot.nmi_received;
+        self.mem.clone_from(&snapshot.mem);
+        self.mem.set_record_cycles(self.wait_callback);
+    }
+
+    /// compute a 64-bit FNV-1a hash over the CPU state and the memory heap
+    ///
+    /// Two emulation runs which ended in the same state have the same hash,
+    /// this is used to verify that a replayed input recording is bit-identical.
+    pub fn state_hash(&self) -> u64 {
+        let r = &self.reg;
+        let words = [r.af(), r.bc(), r.de(), r.hl(), r.af_(), r.bc_(), r.de_(), r.hl_(),
+                     r.ix(), r.iy(), r.sp(), r.pc(), r.wz(), r.i, r.r, r.im];
+        let flags = [self.halt, self.iff1, self.iff2, self.enable_interrupt, self.irq_received,
+                     self.nmi_received];
+        let mut hash: u64 = 0xcbf29ce484222325;
+        {
+            let mut add = |b: u8| {
+                hash ^= b as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            };
+            for w in words.iter() {
+                add(*w as u8);
+                add((*w >> 8) as u8);
+            }
+            for f in flags.iter() {
+                add(*f as u8);
+            }
+            for b in self.mem.heap.iter() {
+                add(*b);
+            }
+        }
+        hash
+    }
+
+    /// register a host function which is called when PC reaches an address
+    ///
+    /// The trap handler runs before the instruction at the address is
+    /// fetched and has full access to the CPU and its memory. It decides
+    /// whether the instruction is executed afterwards, or whether the CPU
+    /// returns to the caller as if a RET had been executed, which is useful
+    /// to replace ROM or BIOS subroutines with host code. An existing trap
+    /// at the same address is replaced. A handler can't remove its own trap
+    /// while running, this must happen outside of step().
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rz80::{CPU, Bus, TrapAction};
+    ///
+    /// struct DummyBus;
+    /// impl Bus for DummyBus { };
+    ///
+    /// let mut cpu = CPU::new_64k();
+    /// let bus = DummyBus { };
+    ///
+    /// // replace the subroutine at 0x0010 with a host function which doubles A
+    /// cpu.add_trap(0x0010, |cpu| {
+    ///     let a = cpu.reg.a();
+    ///     cpu.reg.set_a(a * 2);
+    ///     TrapAction::Return
+    /// });
+    /// cpu.mem.write(0x0100, &[0x3E, 0x21, 0xCD, 0x10, 0x00]);  // LD A,0x21; CALL 0x0010
+    /// cpu.reg.set_pc(0x0100);
+    /// cpu.reg.set_sp(0x8000);
+    /// for _ in 0..3 {
+    ///     cpu.step(&bus);
+    /// }
+    /// assert_eq!(cpu.reg.a(), 0x42);
+    /// assert_eq!(cpu.reg.pc(), 0x0105);
+    /// ```
+    pub fn add_trap<F>(&mut self, addr: RegT, handler: F)
+        where F: FnMut(&mut CPU) -> TrapAction + 'static
+    {
+        self.traps.insert(addr & 0xFFFF, Box::new(handler));
+    }
+
+    /// remove the trap at an address, return true if a trap was registered
+    pub fn remove_trap(&mut self, addr: RegT) -> bool {
+        self.traps.remove(&(addr & 0xFFFF)).is_some()
+    }
+
+    /// remove all traps
+    pub fn clear_traps(&mut self) {
+        self.traps.clear();
+    }
+
+    /// return true if a trap is registered at an address
+    pub fn has_trap(&self, addr: RegT) -> bool {
+        self.traps.contains_key(&(addr & 0xFFFF))
+    }
+
+    /// run the trap handler at PC (if any), then execute the instruction
+    fn do_trap_or_op(&mut self, bus: &Bus) -> i64 {
+        let pc = self.reg.pc();
+        // take the handler out of the trap table while it runs, so that
+        // it can get a mutable reference to the CPU
+        if let Some(mut handler) = self.traps.remove(&pc) {
+            let action = handler(self);
+            // don't overwrite a trap the handler has installed at its own address
+            self.traps.entry(pc).or_insert(handler);
+            match action {
+                TrapAction::Continue => (),
+                TrapAction::Return => return self.ret(),
+                TrapAction::Stay => {
+                    self.reg.r = (self.reg.r & 0x80) | ((self.reg.r + 1) & 0x7F);
+                    return 4;
+                }
+            }
+        }
+        self.do_op(bus, false)
+    }
+
     /// load 8-bit unsigned immediate operand and increment PC
     #[inline(always)]
     fn imm8(&mut self) -> RegT {
@@ -288,7 +712,7 @@ impl CPU {
         } else {
             (0, 0)
         };
-        let op = self.fetch_op();
+        let op = self.fetch_op(bus);
 
         // split instruction byte into bit groups
         let x = op >> 6;
@@ -591,7 +1015,7 @@ impl CPU {
                         self.reg.set_pc(nn);
                         10
                     }
-                    1 => self.do_cb_op(ext),
+                    1 => self.do_cb_op(bus, ext),
                     2 => {
                         // OUT (n),A
                         let a = self.reg.a();
@@ -692,7 +1116,7 @@ impl CPU {
 
     /// fetch and execute ED prefix instruction
     fn do_ed_op(&mut self, bus: &Bus) -> i64 {
-        let op = self.fetch_op();
+        let op = self.fetch_op(bus);
 
         // split instruction byte into bit groups
         let x = op >> 6;
@@ -808,9 +1232,14 @@ impl CPU {
                 8
             }
             (1, 1, 5) => {
-                // RETI (RETN is not implemented)
+                // RETI
                 self.reti(bus)
             }
+            (1, _, 5) => {
+                // RETN
+                self.iff1 = self.iff2;
+                self.ret() + 4
+            }
             (1, _, 6) => {
                 match y {
                     0 | 1 | 4 | 5 => {
@@ -864,13 +1293,14 @@ impl CPU {
     }
 
     /// fetch and execute CB prefix instruction
-    fn do_cb_op(&mut self, ext: bool) -> i64 {
-        let d = if ext {
-            self.d()
+    fn do_cb_op(&mut self, bus: &Bus, ext: bool) -> i64 {
+        // in DD CB d op and FD CB d op, the opcode is read like an operand
+        let (d, op) = if ext {
+            let d = self.d();
+            (d, self.imm8())
         } else {
-            0
+            (0, self.fetch_op(bus))
         };
-        let op = self.fetch_op();
         let cyc = if ext {
             4
         } else {
@@ -979,40 +1409,71 @@ impl CPU {
         self.irq_received = true;
     }
 
+    /// request a non-maskable interrupt (will call the handler at 0x0066
+    /// after the next instruction, also if interrupts are disabled)
+    pub fn nmi(&mut self) {
+        self.nmi_received = true;
+    }
+
+    #[inline(always)]
+    fn handle_nmi(&mut self) -> i64 {
+        self.nmi_received = false;
+        self.halt = false;
+        self.iff1 = false;
+        self.rst(0x66);
+        11
+    }
+
     fn reti(&mut self, bus: &Bus) -> i64 {
         self.ret();
-        bus.irq_reti();
+        // with the fetch callback, the RETI is decoded from the opcode
+        // fetches instead (see Daisychain::fetch())
+        if !self.fetch_callback {
+            bus.irq_reti();
+        }
         15
     }
 
     #[inline(always)]
     fn handle_irq(&mut self, bus: &Bus) -> i64 {
-        // NOTE: only interrupt mode 2 is supported at the moment
-        assert_eq!(2, self.reg.im);
-
-        let mut cycles = 2;
-
-        // leave HALT state
-        if self.halt {
-            self.halt = false;
-            self.reg.inc_pc(1);
+        // while interrupts are disabled, the request isn't accepted
+        // and takes no extra cycles
+        if !self.iff1 {
+            return 0;
         }
+        let mut cycles = 2;
 
-        // handle the interrupt
-        if self.iff1 {
-            self.irq_received = false;
-            self.iff1 = false;
-            self.iff2 = false;
-            let vec = bus.irq_ack();
-            let addr = (self.reg.i << 8 | vec) & 0xFFFE;
-
-            // store return address on stack, and jump to interrupt handler
-            let sp = (self.reg.sp() - 2) & 0xFFFF;
-            self.mem.w16(sp, self.reg.pc());
-            self.reg.set_sp(sp);
-            let int_handler = self.mem.r16(addr);
-            self.reg.set_pc(int_handler);
-            cycles += 19;
+        // handle the interrupt, this also leaves HALT state
+        self.halt = false;
+        self.irq_received = false;
+        self.iff1 = false;
+        self.iff2 = false;
+        match self.reg.im {
+            0 => {
+                // NOTE: only RST instructions are supported on the data bus,
+                // any other value is handled like RST 38h (an idle bus reads 0xFF)
+                let op = bus.irq_ack();
+                let addr = if (op & 0xC7) == 0xC7 { op & 0x38 } else { 0x38 };
+                self.rst(addr);
+                cycles += 11;
+            }
+            1 => {
+                // the data bus is ignored, always call the handler at 0x0038
+                self.rst(0x38);
+                cycles += 11;
+            }
+            _ => {
+                let vec = bus.irq_ack();
+                let addr = (self.reg.i << 8 | vec) & 0xFFFE;
+
+                // store return address on stack, and jump to interrupt handler
+                let sp = (self.reg.sp() - 2) & 0xFFFF;
+                self.mem.w16(sp, self.reg.pc());
+                self.reg.set_sp(sp);
+                let int_handler = self.mem.r16(addr);
+                self.reg.set_pc(int_handler);
+                cycles += 19;
+            }
         }
         let pc = self.reg.pc();
         self.reg.set_wz(pc);
@@ -1020,9 +1481,11 @@ impl CPU {
     }
 
     /// execute a halt instruction
+    ///
+    /// The PC already points to the next instruction, which is where
+    /// execution continues after an interrupt.
     pub fn halt(&mut self) {
         self.halt = true;
-        self.reg.dec_pc(1);
     }
 
     #[inline(always)]
@@ -1600,11 +2063,13 @@ impl CPU {
 
     #[inline(always)]
     pub fn inp(&mut self, bus: &Bus, port: RegT) -> RegT {
+        self.mem.record_cycle(BusCycle::IoRead, port);
         bus.cpu_inp(port) & 0xFF
     }
 
     #[inline(always)]
     pub fn outp(&mut self, bus: &Bus, port: RegT, val: RegT) {
+        self.mem.record_cycle(BusCycle::IoWrite, port);
         bus.cpu_outp(port, val);
     }
 
@@ -1798,7 +2263,63 @@ mod tests {
         cpu.reg.set_pc(0x1234);
         cpu.halt();
         assert!(cpu.halt);
-        assert_eq!(0x1233, cpu.reg.pc());
+        assert_eq!(0x1234, cpu.reg.pc());
+    }
+
+    #[test]
+    fn halt_state() {
+        use std::cell::RefCell;
+        struct TestBus {
+            halt_line: RefCell<Vec<bool>>,
+        }
+        impl Bus for TestBus {
+            fn cpu_halt(&self, halt: bool) {
+                self.halt_line.borrow_mut().push(halt);
+            }
+        }
+        let bus = TestBus { halt_line: RefCell::new(Vec::new()) };
+        // DI, HALT, and an NMI handler which returns with RETN
+        let mut cpu = CPU::new_64k();
+        cpu.mem.write(0x0000, &[0xF3, 0x76, 0x00]);
+        cpu.mem.write(0x0066, &[0xED, 0x45]);
+        cpu.reg.set_sp(0x8000);
+        cpu.step(&bus);
+        assert_eq!(4, cpu.step(&bus));
+        assert!(cpu.halt);
+        assert_eq!(vec![true], *bus.halt_line.borrow());
+
+        // NOPs in HALT state increment R, but not PC
+        let r = cpu.reg.r;
+        for _ in 0..10 {
+            assert_eq!(4, cpu.step(&bus));
+        }
+        assert_eq!(0x0002, cpu.reg.pc());
+        assert_eq!(r + 10, cpu.reg.r);
+
+        // a maskable interrupt doesn't end HALT while interrupts are disabled
+        cpu.irq();
+        cpu.step(&bus);
+        assert!(cpu.halt);
+        assert_eq!(1, bus.halt_line.borrow().len());
+
+        // an NMI does, and returns behind the HALT instruction
+        cpu.nmi();
+        assert_eq!(15, cpu.step(&bus));
+        assert!(!cpu.halt);
+        assert_eq!(0x0066, cpu.reg.pc());
+        assert_eq!(0x0002, cpu.mem.r16(cpu.reg.sp()));
+        assert_eq!(vec![true, false], *bus.halt_line.borrow());
+        assert_eq!(14, cpu.step(&bus));
+        assert_eq!(0x0002, cpu.reg.pc());
+
+        // reset ends HALT, the bus is notified on the next step
+        cpu.reg.set_pc(0x0001);
+        cpu.step(&bus);
+        assert!(cpu.halt);
+        cpu.reset();
+        cpu.step(&bus);
+        assert_eq!(vec![true, false, true, false], *bus.halt_line.borrow());
+        assert_eq!(0x0001, cpu.reg.pc());
     }
 
     #[test]
@@ -2170,4 +2691,327 @@ mod tests {
         let bus = TestBus {};
         cpu.outp(&bus, 0x1234, 12);
     }
+
+    struct IrqBus;
+    impl Bus for IrqBus {
+        fn irq_ack(&self) -> RegT {
+            0xCF    // RST 08h in IM0, vector 0xCE in IM2
+        }
+    }
+
+    fn irq_cpu(im: RegT) -> CPU {
+        let mut cpu = CPU::new_64k();
+        cpu.reg.set_sp(0x8000);
+        cpu.reg.set_pc(0x1000);
+        cpu.reg.im = im;
+        cpu.reg.i = 0x20;
+        cpu.mem.w16(0x20CE, 0x3456);
+        cpu.iff1 = true;
+        cpu.iff2 = true;
+        cpu
+    }
+
+    #[test]
+    fn irq_im0() {
+        let mut cpu = irq_cpu(0);
+        cpu.irq();
+        assert_eq!(4 + 13, cpu.step(&IrqBus {}));
+        assert_eq!(0x0008, cpu.reg.pc());
+        assert_eq!(0x1001, cpu.mem.r16(cpu.reg.sp()));
+        assert!(!cpu.iff1 && !cpu.iff2);
+    }
+
+    #[test]
+    fn irq_im0_no_rst() {
+        // a CALL opcode on the data bus doesn't crash the CPU
+        struct CallBus;
+        impl Bus for CallBus {
+            fn irq_ack(&self) -> RegT {
+                0xCD
+            }
+        }
+        let mut cpu = irq_cpu(0);
+        cpu.irq();
+        cpu.step(&CallBus {});
+        assert_eq!(0x0038, cpu.reg.pc());
+        assert_eq!(0x1001, cpu.mem.r16(cpu.reg.sp()));
+    }
+
+    #[test]
+    fn irq_im1() {
+        let mut cpu = irq_cpu(1);
+        cpu.irq();
+        assert_eq!(4 + 13, cpu.step(&IrqBus {}));
+        assert_eq!(0x0038, cpu.reg.pc());
+        assert_eq!(0x1001, cpu.mem.r16(cpu.reg.sp()));
+        assert!(!cpu.iff1 && !cpu.iff2);
+
+        // interrupts are ignored while disabled, without extra
+        // cycles and without changing WZ
+        cpu.reg.set_wz(0x1234);
+        cpu.irq();
+        assert_eq!(4, cpu.step(&IrqBus {}));
+        assert_eq!(0x0039, cpu.reg.pc());
+        assert_eq!(0x1234, cpu.reg.wz());
+    }
+
+    #[test]
+    fn irq_im2() {
+        let mut cpu = irq_cpu(2);
+        cpu.irq();
+        assert_eq!(4 + 21, cpu.step(&IrqBus {}));
+        assert_eq!(0x3456, cpu.reg.pc());
+        assert_eq!(0x1001, cpu.mem.r16(cpu.reg.sp()));
+    }
+
+    #[test]
+    fn trap_continue() {
+        use std::rc::Rc;
+        use std::cell::Cell;
+        struct TestBus;
+        impl Bus for TestBus {}
+        let mut cpu = CPU::new_64k();
+        let hits = Rc::new(Cell::new(0));
+        let h = hits.clone();
+        cpu.add_trap(0x0001, move |cpu| {
+            h.set(h.get() + 1);
+            cpu.reg.set_b(0x55);
+            TrapAction::Continue
+        });
+        assert!(cpu.has_trap(0x0001));
+        // NOP; LD A,B; NOP
+        cpu.mem.write(0x0000, &[0x00, 0x78, 0x00]);
+        assert_eq!(4, cpu.step(&TestBus {}));
+        assert_eq!(4, cpu.step(&TestBus {}));
+        assert_eq!(0x55, cpu.reg.a());
+        assert_eq!(0x0002, cpu.reg.pc());
+        assert_eq!(1, hits.get());
+        // the trap stays registered
+        cpu.reg.set_pc(0x0001);
+        cpu.step(&TestBus {});
+        assert_eq!(2, hits.get());
+        assert!(cpu.remove_trap(0x0001));
+        assert!(!cpu.remove_trap(0x0001));
+        cpu.reg.set_pc(0x0001);
+        cpu.step(&TestBus {});
+        assert_eq!(2, hits.get());
+    }
+
+    #[test]
+    fn trap_return() {
+        struct TestBus;
+        impl Bus for TestBus {}
+        let mut cpu = CPU::new_64k();
+        cpu.add_trap(0x0038, |cpu| {
+            cpu.mem.w8(0x4000, 0x99);
+            TrapAction::Return
+        });
+        // CALL 0x0038; NOP, the subroutine itself would HALT
+        cpu.mem.write(0x0100, &[0xCD, 0x38, 0x00, 0x00]);
+        cpu.mem.w8(0x0038, 0x76);
+        cpu.reg.set_pc(0x0100);
+        cpu.reg.set_sp(0x8000);
+        assert_eq!(17, cpu.step(&TestBus {}));
+        assert_eq!(10, cpu.step(&TestBus {}));
+        assert_eq!(0x0103, cpu.reg.pc());
+        assert_eq!(0x8000, cpu.reg.sp());
+        assert_eq!(0x99, cpu.mem.r8(0x4000));
+        assert!(!cpu.halt);
+        cpu.clear_traps();
+        assert!(!cpu.has_trap(0x0038));
+    }
+
+    #[test]
+    fn trap_stay() {
+        use std::rc::Rc;
+        use std::cell::Cell;
+        struct TestBus;
+        impl Bus for TestBus {}
+        let mut cpu = CPU::new_64k();
+        let ready = Rc::new(Cell::new(false));
+        let trap_ready = ready.clone();
+        cpu.add_trap(0x0001, move |_| {
+            if trap_ready.get() { TrapAction::Continue } else { TrapAction::Stay }
+        });
+        // NOP; INC A
+        cpu.mem.write(0x0000, &[0x00, 0x3C]);
+        assert_eq!(4, cpu.step(&TestBus {}));
+        for _ in 0..3 {
+            assert_eq!(4, cpu.step(&TestBus {}));
+            assert_eq!(0x0001, cpu.reg.pc());
+        }
+        // each step in the trap increments R like a NOP
+        assert_eq!(4, cpu.reg.r);
+        ready.set(true);
+        assert_eq!(4, cpu.step(&TestBus {}));
+        assert_eq!(0x0002, cpu.reg.pc());
+        assert_eq!(1, cpu.reg.a());
+        assert_eq!(5, cpu.reg.r);
+    }
+
+    #[test]
+    fn run() {
+        struct TestBus;
+        impl Bus for TestBus {}
+        // IM 1, EI, HALT, JR -3, and an interrupt handler which counts in B
+        let prog = |cpu: &mut CPU| {
+            cpu.mem.write(0x0000, &[0xED, 0x56, 0xFB, 0x76, 0x18, 0xFD]);
+            cpu.mem.write(0x0038, &[0x04, 0xFB, 0xC9]);
+            cpu.reg.set_sp(0x8000);
+        };
+        let mut cpu = CPU::new_64k();
+        prog(&mut cpu);
+        let mut ref_cpu = CPU::new_64k();
+        prog(&mut ref_cpu);
+
+        // running into HALT gives the same state as single-stepping
+        let overshoot = cpu.run(&TestBus, 1001);
+        let mut cycles = 0;
+        while cycles < 1001 {
+            cycles += ref_cpu.step(&TestBus);
+        }
+        assert_eq!(cycles - 1001, overshoot);
+        assert!(cpu.halt);
+        assert_eq!(ref_cpu.reg.r, cpu.reg.r);
+        assert_eq!(ref_cpu.reg.pc(), cpu.reg.pc());
+
+        // an interrupt request wakes up the CPU from HALT
+        cpu.irq();
+        let overshoot = cpu.run(&TestBus, 100);
+        assert_eq!(1, cpu.reg.b());
+        assert!(cpu.halt);
+        assert_eq!(0x0004, cpu.reg.pc());
+        assert!(overshoot >= 0 && overshoot < 4);
+        assert_eq!(0, cpu.run(&TestBus, 0));
+    }
+
+    #[test]
+    fn wait_states() {
+        use std::cell::RefCell;
+        use bus::BusCycle::*;
+        struct TestBus {
+            cycles: RefCell<Vec<(BusCycle, RegT)>>,
+        }
+        impl Bus for TestBus {
+            fn cpu_wait(&self, cycle: BusCycle, addr: RegT) -> i64 {
+                self.cycles.borrow_mut().push((cycle, addr));
+                if cycle == IoWrite { 1 } else { 0 }
+            }
+        }
+        let bus = TestBus { cycles: RefCell::new(Vec::new()) };
+        // NOP, LD A,(0x8000), OUT (0x10),A
+        let mut cpu = CPU::new_64k();
+        cpu.mem.write(0x0000, &[0x00, 0x3A, 0x00, 0x80, 0xD3, 0x10]);
+        cpu.mem.w8(0x8000, 0x12);
+        cpu.mem.set_wait_states(0x0000, 0x0400, 1, 2, 0);
+        cpu.mem.set_wait_states(0x8000, 0x0400, 0, 5, 0);
+        assert_eq!(5, cpu.step(&bus));
+        assert_eq!(13 + 1 + 2 * 2 + 5, cpu.step(&bus));
+        assert!(bus.cycles.borrow().is_empty());
+
+        // the callback sees each bus cycle of the instruction
+        cpu.mem.clear_wait_states();
+        cpu.set_wait_callback(true);
+        assert_eq!(11 + 1, cpu.step(&bus));
+        assert_eq!(vec![(M1, 0x0004), (MemRead, 0x0005), (IoWrite, 0x1210)],
+                   *bus.cycles.borrow());
+        // host-side memory accesses between instructions are not bus cycles
+        bus.cycles.borrow_mut().clear();
+        cpu.mem.write(0x0006, &[0x00]);
+        assert_eq!(0x12, cpu.mem.r8(0x8000));
+        assert_eq!(4, cpu.step(&bus));
+        assert_eq!(vec![(M1, 0x0006)], *bus.cycles.borrow());
+        cpu.set_wait_callback(false);
+        assert!(!cpu.mem.is_recording_cycles());
+    }
+
+    #[test]
+    fn fetch_callback() {
+        use std::cell::RefCell;
+        struct TestBus {
+            fetches: RefCell<Vec<(RegT, RegT)>>,
+        }
+        impl Bus for TestBus {
+            fn cpu_fetch(&self, addr: RegT, op: RegT) {
+                self.fetches.borrow_mut().push((addr, op));
+            }
+        }
+        let bus = TestBus { fetches: RefCell::new(Vec::new()) };
+        // NOP, RLC (IX+1), NEG, HALT
+        let mut cpu = CPU::new_64k();
+        cpu.mem.write(0x0000, &[0x00, 0xDD, 0xCB, 0x01, 0x06, 0xED, 0x44, 0x76]);
+        cpu.step(&bus);
+        assert!(bus.fetches.borrow().is_empty());
+        cpu.set_fetch_callback(true);
+        for _ in 0..4 {
+            cpu.step(&bus);
+        }
+        assert_eq!(vec![(0x0001, 0xDD), (0x0002, 0xCB), (0x0005, 0xED), (0x0006, 0x44),
+                        (0x0007, 0x76), (0x0008, 0x00)],
+                   *bus.fetches.borrow());
+        // only M1 cycles increment the R register
+        assert_eq!(7, cpu.reg.r);
+    }
+
+    #[test]
+    fn fetch_callback_reti() {
+        use std::cell::Cell;
+        struct TestBus {
+            retis: Cell<usize>,
+        }
+        impl Bus for TestBus {
+            fn irq_reti(&self) {
+                self.retis.set(self.retis.get() + 1);
+            }
+        }
+        let bus = TestBus { retis: Cell::new(0) };
+        // RETI, RETI
+        let mut cpu = CPU::new_64k();
+        cpu.mem.write(0x0000, &[0xED, 0x4D]);
+        cpu.mem.write(0x8000, &[0x00, 0x00, 0x00, 0x00]);
+        cpu.reg.set_sp(0x8000);
+        cpu.step(&bus);
+        assert_eq!(1, bus.retis.get());
+        // with the fetch callback, irq_reti() isn't called
+        cpu.set_fetch_callback(true);
+        cpu.step(&bus);
+        assert_eq!(1, bus.retis.get());
+    }
+
+    #[test]
+    fn idle_loop() {
+        struct TestBus;
+        impl Bus for TestBus {}
+        // wait for an interrupt handler to set a flag at 0x4000:
+        // LD A,(0x4000); OR A; JR Z,-6
+        let prog = |cpu: &mut CPU| {
+            cpu.mem.write(0x0000, &[0xED, 0x56, 0xFB, 0x3A, 0x00, 0x40, 0xB7, 0x28, 0xFA, 0x76]);
+            cpu.mem.write(0x0038, &[0x3E, 0x01, 0x32, 0x00, 0x40, 0xFB, 0xC9]);
+            cpu.reg.set_sp(0x8000);
+        };
+        let mut cpu = CPU::new_64k();
+        prog(&mut cpu);
+        cpu.add_idle_loop(0x0003);
+        let mut ref_cpu = CPU::new_64k();
+        prog(&mut ref_cpu);
+
+        // skipping the loop gives the same state as single-stepping
+        let overshoot = cpu.run(&TestBus, 10000);
+        let mut cycles = 0;
+        while cycles < 10000 {
+            cycles += ref_cpu.step(&TestBus);
+        }
+        assert_eq!(cycles - 10000, overshoot);
+        assert_eq!(ref_cpu.reg_state(), cpu.reg_state());
+        assert_eq!(ref_cpu.reg.r, cpu.reg.r);
+        assert!(cpu.reg.pc() >= 0x0003 && cpu.reg.pc() < 0x0009);
+
+        // the interrupt handler ends the loop
+        cpu.irq();
+        assert!(!cpu.is_idle());
+        cpu.run(&TestBus, 200);
+        assert!(cpu.halt);
+        assert!(cpu.remove_idle_loop(0x0003));
+        assert!(!cpu.remove_idle_loop(0x0003));
+    }
 }
@@ -0,0 +1,556 @@
+use RegT;
+use bus::Bus;
+
+/// CRTC register: horizontal total (in characters, minus 1)
+pub const CRTC_H_TOTAL: usize = 0;
+/// CRTC register: horizontal displayed characters
+pub const CRTC_H_DISPLAYED: usize = 1;
+/// CRTC register: horizontal sync position
+pub const CRTC_H_SYNC_POS: usize = 2;
+/// CRTC register: HSYNC (bits 0..3) and VSYNC (bits 4..7) widths
+pub const CRTC_SYNC_WIDTHS: usize = 3;
+/// CRTC register: vertical total (in character rows, minus 1)
+pub const CRTC_V_TOTAL: usize = 4;
+/// CRTC register: vertical total adjust (in scanlines)
+pub const CRTC_V_TOTAL_ADJUST: usize = 5;
+/// CRTC register: vertical displayed character rows
+pub const CRTC_V_DISPLAYED: usize = 6;
+/// CRTC register: vertical sync position (in character rows)
+pub const CRTC_V_SYNC_POS: usize = 7;
+/// CRTC register: interlace and skew
+pub const CRTC_INTERLACE_MODE: usize = 8;
+/// CRTC register: maximum scanline address (scanlines per row, minus 1)
+pub const CRTC_MAX_SCANLINE_ADDR: usize = 9;
+/// CRTC register: cursor start scanline and blink mode
+pub const CRTC_CURSOR_START: usize = 10;
+/// CRTC register: cursor end scanline
+pub const CRTC_CURSOR_END: usize = 11;
+/// CRTC register: display start address high byte
+pub const CRTC_START_ADDR_HI: usize = 12;
+/// CRTC register: display start address low byte
+pub const CRTC_START_ADDR_LO: usize = 13;
+/// CRTC register: cursor address high byte
+pub const CRTC_CURSOR_HI: usize = 14;
+/// CRTC register: cursor address low byte
+pub const CRTC_CURSOR_LO: usize = 15;
+/// CRTC register: light pen address high byte
+pub const CRTC_LIGHTPEN_HI: usize = 16;
+/// CRTC register: light pen address low byte
+pub const CRTC_LIGHTPEN_LO: usize = 17;
+const NUM_REGS: usize = 18;
+
+/// the number of valid bits in each register
+static REG_MASK: [u8; NUM_REGS] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0x7F, 0x1F, 0x7F, 0x7F, 0x03,
+    0x1F, 0x7F, 0x1F, 0x3F, 0xFF, 0x3F, 0xFF, 0x3F, 0xFF,
+];
+
+/// Motorola MC6845 CRT controller emulation
+///
+/// The CRTC generates the video timing signals of a character-based
+/// video system. Each character clock it advances the horizontal
+/// and vertical counters, and outputs the HSYNC, VSYNC and display-enable
+/// signals, together with the 14-bit memory address (MA) and 5-bit
+/// row address (RA), which are used by the external video circuitry
+/// to fetch the video memory and character generator bytes.
+///
+/// The CRTC must be advanced by calling **update_timers()** with the
+/// number of CPU cycles executed. On each character clock the
+/// **crtc_tick()** Bus callback is invoked with the current MA, RA and
+/// display-enable outputs, which allows a frontend to build a
+/// raster-accurate video decoder. Changes to the sync
+/// outputs are reported through **crtc_hsync()** and **crtc_vsync()**.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{CRTC, Bus, RegT, CRTC_H_TOTAL};
+///
+/// struct DummyBus;
+/// impl Bus for DummyBus { };
+/// let bus = DummyBus { };
+///
+/// // a CRTC which is clocked once every 4 CPU cycles
+/// let mut crtc = CRTC::new(0, 4);
+///
+/// // select register 0 and write a value
+/// crtc.select(CRTC_H_TOTAL as RegT);
+/// crtc.write(63);
+/// assert_eq!(crtc.reg[CRTC_H_TOTAL], 63);
+///
+/// crtc.update_timers(&bus, 4);
+/// assert_eq!(crtc.h_ctr, 1);
+/// ```
+pub struct CRTC {
+    id: usize, // a CRTC ID for systems with multiple CRTCs
+    pub reg: [u8; NUM_REGS],
+    sel: usize, // currently selected register
+    cycles_per_char: i64, // number of CPU cycles per character clock
+    cycle_counter: i64,
+
+    /// horizontal character counter
+    pub h_ctr: RegT,
+    /// scanline counter inside a character row (the RA output)
+    pub row_ctr: RegT,
+    /// vertical character row counter
+    pub v_ctr: RegT,
+    /// vertical total adjust scanline counter
+    pub adj_ctr: RegT,
+    /// memory address at start of current character row
+    pub ma_row_start: RegT,
+    /// frame counter (used for cursor blinking)
+    pub frame_ctr: RegT,
+    /// true while in vertical total adjust period
+    pub in_adjust: bool,
+
+    hsync_ctr: RegT,
+    vsync_ctr: RegT,
+    /// current state of the HSYNC output
+    pub hsync: bool,
+    /// current state of the VSYNC output
+    pub vsync: bool,
+    h_de: bool,
+    v_de: bool,
+}
+
+impl CRTC {
+    /// initialize a new CRTC object
+    pub fn new(id: usize, cycles_per_char: i64) -> CRTC {
+        CRTC {
+            id: id,
+            reg: [0; NUM_REGS],
+            sel: 0,
+            cycles_per_char: cycles_per_char,
+            cycle_counter: 0,
+            h_ctr: 0,
+            row_ctr: 0,
+            v_ctr: 0,
+            adj_ctr: 0,
+            ma_row_start: 0,
+            frame_ctr: 0,
+            in_adjust: false,
+            hsync_ctr: 0,
+            vsync_ctr: 0,
+            hsync: false,
+            vsync: false,
+            h_de: true,
+            v_de: true,
+        }
+    }
+
+    /// reset the CRTC (register content is preserved)
+    pub fn reset(&mut self) {
+        self.sel = 0;
+        self.cycle_counter = 0;
+        self.h_ctr = 0;
+        self.row_ctr = 0;
+        self.v_ctr = 0;
+        self.adj_ctr = 0;
+        self.ma_row_start = self.start_addr();
+        self.frame_ctr = 0;
+        self.in_adjust = false;
+        self.hsync_ctr = 0;
+        self.vsync_ctr = 0;
+        self.hsync = false;
+        self.vsync = false;
+        self.h_de = true;
+        self.v_de = true;
+    }
+
+    /// select a register for the next read or write
+    pub fn select(&mut self, val: RegT) {
+        self.sel = (val & 0x1F) as usize;
+    }
+
+    /// write the currently selected register
+    pub fn write(&mut self, val: RegT) {
+        // the light pen registers are read-only
+        if self.sel < CRTC_LIGHTPEN_HI {
+            self.reg[self.sel] = (val as u8) & REG_MASK[self.sel];
+        }
+    }
+
+    /// read the currently selected register (only R14 to R17 are readable)
+    pub fn read(&self) -> RegT {
+        if self.sel >= CRTC_CURSOR_HI && self.sel < NUM_REGS {
+            self.reg[self.sel] as RegT
+        } else {
+            0
+        }
+    }
+
+    /// latch the current memory address into the light pen registers
+    pub fn trigger_lightpen(&mut self) {
+        let ma = self.ma();
+        self.reg[CRTC_LIGHTPEN_HI] = ((ma >> 8) & 0x3F) as u8;
+        self.reg[CRTC_LIGHTPEN_LO] = (ma & 0xFF) as u8;
+    }
+
+    /// current 14-bit memory address output (MA0..MA13)
+    pub fn ma(&self) -> RegT {
+        (self.ma_row_start + self.h_ctr) & 0x3FFF
+    }
+
+    /// current 5-bit row address output (RA0..RA4)
+    pub fn ra(&self) -> RegT {
+        self.row_ctr & 0x1F
+    }
+
+    /// current state of the display-enable output
+    pub fn display_enable(&self) -> bool {
+        self.h_de && self.v_de
+    }
+
+    /// true if the cursor output is currently active
+    pub fn cursor(&self) -> bool {
+        if !self.display_enable() {
+            return false;
+        }
+        let cursor_addr = ((self.reg[CRTC_CURSOR_HI] as RegT) << 8) |
+                          self.reg[CRTC_CURSOR_LO] as RegT;
+        if self.ma() != cursor_addr {
+            return false;
+        }
+        let start = (self.reg[CRTC_CURSOR_START] & 0x1F) as RegT;
+        let end = self.reg[CRTC_CURSOR_END] as RegT;
+        if self.row_ctr < start || self.row_ctr > end {
+            return false;
+        }
+        match (self.reg[CRTC_CURSOR_START] >> 5) & 3 {
+            0 => true,
+            1 => false,
+            2 => (self.frame_ctr & 0x10) != 0,
+            _ => (self.frame_ctr & 0x20) != 0,
+        }
+    }
+
+    /// advance the CRTC by a number of CPU cycles
+    pub fn update_timers(&mut self, bus: &Bus, cycles: i64) {
+        self.cycle_counter += cycles;
+        while self.cycle_counter >= self.cycles_per_char {
+            self.cycle_counter -= self.cycles_per_char;
+            self.tick(bus);
+        }
+    }
+
+    fn start_addr(&self) -> RegT {
+        ((self.reg[CRTC_START_ADDR_HI] as RegT) << 8) | self.reg[CRTC_START_ADDR_LO] as RegT
+    }
+
+    /// get HSYNC width in characters (0 is interpreted as 16)
+    fn hsync_width(&self) -> RegT {
+        match self.reg[CRTC_SYNC_WIDTHS] & 0x0F {
+            0 => 16,
+            w => w as RegT,
+        }
+    }
+
+    /// get VSYNC width in scanlines (0 is interpreted as 16)
+    fn vsync_width(&self) -> RegT {
+        match self.reg[CRTC_SYNC_WIDTHS] >> 4 {
+            0 => 16,
+            w => w as RegT,
+        }
+    }
+
+    fn set_hsync(&mut self, bus: &Bus, hsync: bool) {
+        if self.hsync != hsync {
+            self.hsync = hsync;
+            bus.crtc_hsync(self.id, hsync);
+        }
+    }
+
+    fn set_vsync(&mut self, bus: &Bus, vsync: bool) {
+        if self.vsync != vsync {
+            self.vsync = vsync;
+            bus.crtc_vsync(self.id, vsync);
+        }
+    }
+
+    /// advance the CRTC by one character clock
+    fn tick(&mut self, bus: &Bus) {
+        bus.crtc_tick(self.id, self);
+
+        // compare against the total before incrementing, so that
+        // R0 = 255 doesn't wrap the counter before the line ends
+        let line_end = self.h_ctr == self.reg[CRTC_H_TOTAL] as RegT;
+        if line_end {
+            self.h_ctr = 0;
+            self.h_de = true;
+        } else {
+            self.h_ctr = (self.h_ctr + 1) & 0xFF;
+        }
+        if self.h_ctr == self.reg[CRTC_H_DISPLAYED] as RegT {
+            self.h_de = false;
+        }
+        if self.hsync {
+            self.hsync_ctr += 1;
+            if self.hsync_ctr >= self.hsync_width() {
+                self.set_hsync(bus, false);
+            }
+        }
+        if self.h_ctr == self.reg[CRTC_H_SYNC_POS] as RegT {
+            self.hsync_ctr = 0;
+            self.set_hsync(bus, true);
+        }
+        if line_end {
+            self.scanline(bus);
+        }
+    }
+
+    /// called at the end of a scanline
+    fn scanline(&mut self, bus: &Bus) {
+        if self.vsync {
+            self.vsync_ctr += 1;
+            if self.vsync_ctr >= self.vsync_width() {
+                self.set_vsync(bus, false);
+            }
+        }
+        if self.in_adjust {
+            self.adj_ctr += 1;
+            if self.adj_ctr >= self.reg[CRTC_V_TOTAL_ADJUST] as RegT {
+                self.frame();
+            }
+        } else {
+            self.row_ctr += 1;
+            if self.row_ctr > self.reg[CRTC_MAX_SCANLINE_ADDR] as RegT {
+                self.row_ctr = 0;
+                self.ma_row_start += self.reg[CRTC_H_DISPLAYED] as RegT;
+                let frame_end = self.v_ctr == self.reg[CRTC_V_TOTAL] as RegT;
+                self.v_ctr = (self.v_ctr + 1) & 0x7F;
+                if frame_end {
+                    if self.reg[CRTC_V_TOTAL_ADJUST] > 0 {
+                        self.in_adjust = true;
+                        self.adj_ctr = 0;
+                    } else {
+                        self.frame();
+                    }
+                }
+                if self.v_ctr == self.reg[CRTC_V_DISPLAYED] as RegT {
+                    self.v_de = false;
+                }
+                if self.v_ctr == self.reg[CRTC_V_SYNC_POS] as RegT && !self.in_adjust {
+                    self.vsync_ctr = 0;
+                    self.set_vsync(bus, true);
+                }
+            }
+        }
+    }
+
+    /// called at the end of a frame
+    fn frame(&mut self) {
+        self.in_adjust = false;
+        self.adj_ctr = 0;
+        self.row_ctr = 0;
+        self.v_ctr = 0;
+        self.v_de = true;
+        self.ma_row_start = self.start_addr();
+        self.frame_ctr = (self.frame_ctr + 1) & 0xFF;
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use super::*;
+    use Bus;
+    use RegT;
+
+    struct TestState {
+        ticks: i32,
+        de_ticks: i32,
+        hsyncs: i32,
+        vsyncs: i32,
+        vsync_tick: i32,
+        last_ma: RegT,
+        last_ra: RegT,
+    }
+    struct TestBus {
+        state: RefCell<TestState>,
+    }
+    impl TestBus {
+        pub fn new() -> TestBus {
+            TestBus {
+                state: RefCell::new(TestState {
+                    ticks: 0,
+                    de_ticks: 0,
+                    hsyncs: 0,
+                    vsyncs: 0,
+                    vsync_tick: -1,
+                    last_ma: 0,
+                    last_ra: 0,
+                }),
+            }
+        }
+    }
+    impl Bus for TestBus {
+        fn crtc_hsync(&self, _: usize, hsync: bool) {
+            if hsync {
+                self.state.borrow_mut().hsyncs += 1;
+            }
+        }
+        fn crtc_vsync(&self, _: usize, vsync: bool) {
+            let mut state = self.state.borrow_mut();
+            if vsync {
+                state.vsyncs += 1;
+                if state.vsync_tick < 0 {
+                    state.vsync_tick = state.ticks;
+                }
+            }
+        }
+        fn crtc_tick(&self, _: usize, crtc: &CRTC) {
+            let mut state = self.state.borrow_mut();
+            state.ticks += 1;
+            if crtc.display_enable() {
+                state.de_ticks += 1;
+                state.last_ma = crtc.ma();
+                state.last_ra = crtc.ra();
+            }
+        }
+    }
+
+    fn write_regs(crtc: &mut CRTC, vals: &[RegT]) {
+        for (r, v) in vals.iter().enumerate() {
+            crtc.select(r as RegT);
+            crtc.write(*v);
+        }
+    }
+
+    // a small test display: 10 chars per line, 8 displayed, 4 scanlines per row,
+    // 6 rows per frame plus 2 adjust lines, 5 rows displayed
+    fn small_crtc() -> CRTC {
+        let mut crtc = CRTC::new(0, 1);
+        write_regs(&mut crtc, &[9, 8, 8, 0x21, 5, 2, 5, 5, 0, 3, 0, 0, 0x00, 0x40]);
+        crtc.reset();
+        crtc
+    }
+
+    #[test]
+    fn reset() {
+        let mut crtc = small_crtc();
+        crtc.h_ctr = 5;
+        crtc.v_ctr = 3;
+        crtc.hsync = true;
+        crtc.reset();
+        assert_eq!(0, crtc.h_ctr);
+        assert_eq!(0, crtc.v_ctr);
+        assert_eq!(0, crtc.ra());
+        assert_eq!(0x40, crtc.ma());
+        assert!(!crtc.hsync);
+        assert!(crtc.display_enable());
+        assert_eq!(9, crtc.reg[CRTC_H_TOTAL]);
+    }
+
+    #[test]
+    fn registers() {
+        let mut crtc = CRTC::new(0, 1);
+        crtc.select(CRTC_V_TOTAL as RegT);
+        crtc.write(0xFF);
+        assert_eq!(0x7F, crtc.reg[CRTC_V_TOTAL]);
+        // only the cursor and light pen registers are readable
+        assert_eq!(0, crtc.read());
+        crtc.select(CRTC_CURSOR_HI as RegT);
+        crtc.write(0xFF);
+        assert_eq!(0x3F, crtc.read());
+        // light pen registers are read-only
+        crtc.select(CRTC_LIGHTPEN_LO as RegT);
+        crtc.write(0x12);
+        assert_eq!(0, crtc.read());
+        crtc.h_ctr = 0x12;
+        crtc.trigger_lightpen();
+        assert_eq!(0x12, crtc.read());
+    }
+
+    #[test]
+    fn frame_timing() {
+        let bus = TestBus::new();
+        let mut crtc = small_crtc();
+        // one frame is 10 chars * (6 rows * 4 scanlines + 2 adjust lines)
+        let frame_ticks = 10 * (6 * 4 + 2);
+        crtc.update_timers(&bus, frame_ticks);
+        {
+            let state = bus.state.borrow();
+            assert_eq!(frame_ticks as i32, state.ticks);
+            assert_eq!(8 * 5 * 4, state.de_ticks);
+            assert_eq!(6 * 4 + 2, state.hsyncs);
+            assert_eq!(1, state.vsyncs);
+            // vsync starts at the first scanline of row 5
+            assert_eq!(10 * 5 * 4, state.vsync_tick);
+            // the last displayed character
+            assert_eq!(0x40 + 5 * 8 - 1, state.last_ma);
+            assert_eq!(3, state.last_ra);
+        }
+        assert_eq!(0, crtc.h_ctr);
+        assert_eq!(0, crtc.v_ctr);
+        assert_eq!(1, crtc.frame_ctr);
+        assert_eq!(0x40, crtc.ma());
+        // vsync is 2 scanlines wide
+        assert!(!crtc.vsync);
+        crtc.update_timers(&bus, 10 * 5 * 4);
+        assert!(crtc.vsync);
+        crtc.update_timers(&bus, 10);
+        assert!(crtc.vsync);
+        crtc.update_timers(&bus, 10);
+        assert!(!crtc.vsync);
+    }
+
+    #[test]
+    fn max_totals() {
+        let bus = TestBus::new();
+        // 256 chars per line, 2 rows of 1 scanline per frame
+        let mut crtc = CRTC::new(0, 1);
+        write_regs(&mut crtc, &[255, 8, 200, 0x11, 1, 0, 1, 1, 0, 0]);
+        crtc.reset();
+        crtc.update_timers(&bus, 255);
+        assert_eq!(255, crtc.h_ctr);
+        assert_eq!(0, crtc.v_ctr);
+        crtc.update_timers(&bus, 1);
+        assert_eq!(0, crtc.h_ctr);
+        assert_eq!(1, crtc.v_ctr);
+        crtc.update_timers(&bus, 256);
+        assert_eq!(0, crtc.v_ctr);
+        assert_eq!(1, crtc.frame_ctr);
+
+        // 2 chars per line, 128 rows of 1 scanline per frame
+        let mut crtc = CRTC::new(0, 1);
+        write_regs(&mut crtc, &[1, 1, 1, 0x11, 127, 0, 100, 110, 0, 0]);
+        crtc.reset();
+        crtc.update_timers(&bus, 2 * 127);
+        assert_eq!(127, crtc.v_ctr);
+        assert_eq!(0, crtc.frame_ctr);
+        crtc.update_timers(&bus, 2);
+        assert_eq!(0, crtc.v_ctr);
+        assert_eq!(1, crtc.frame_ctr);
+    }
+
+    #[test]
+    fn hsync_width() {
+        let bus = TestBus::new();
+        let mut crtc = small_crtc();
+        crtc.update_timers(&bus, 8);
+        assert!(crtc.hsync);
+        assert!(!crtc.display_enable());
+        crtc.update_timers(&bus, 1);
+        assert!(!crtc.hsync);
+        crtc.update_timers(&bus, 1);
+        assert!(crtc.display_enable());
+        assert_eq!(1, crtc.ra());
+    }
+
+    #[test]
+    fn cursor() {
+        let bus = TestBus::new();
+        let mut crtc = small_crtc();
+        write_regs(&mut crtc, &[9, 8, 8, 0x21, 5, 2, 5, 5, 0, 3, 2, 3, 0x00, 0x40, 0x00, 0x43]);
+        crtc.update_timers(&bus, 3);
+        assert!(!crtc.cursor());
+        crtc.update_timers(&bus, 20);
+        assert_eq!(2, crtc.ra());
+        assert!(crtc.cursor());
+        // cursor blink mode 'off'
+        crtc.select(CRTC_CURSOR_START as RegT);
+        crtc.write(0x22);
+        assert!(!crtc.cursor());
+    }
+}
@@ -156,6 +156,22 @@ impl CTC {
         }
     }
 
+    /// number of CPU cycles until the next running timer reaches zero
+    ///
+    /// Channels in counter mode only count external triggers and are
+    /// not considered. This is used to skip forward in time without
+    /// missing a timer interrupt.
+    pub fn cycles_to_zero(&self) -> Option<i64> {
+        self.chn
+            .iter()
+            .filter(|c| {
+                (c.control & (CTC_RESET | CTC_CONSTANT_FOLLOWS)) == 0 &&
+                (c.control & CTC_MODE_BIT) == CTC_MODE_TIMER && !c.waiting_for_trigger
+            })
+            .map(|c| c.down_counter as i64)
+            .min()
+    }
+
     /// get prescaler value (256 or 16) based on prescaler bit
     fn prescale(ctrl: u8) -> RegT {
         if (ctrl & CTC_PRESCALER_BIT) == CTC_PRESCALER_256 {
@@ -383,4 +399,29 @@ mod test {
     fn ctc_timer_with_irq() {
         ctc_timer_test(true);
     }
+
+    #[test]
+    fn cycles_to_zero() {
+        let mut ctc = CTC::new(0);
+        let bus = TestBus::new();
+        assert_eq!(None, ctc.cycles_to_zero());
+        // timer with prescaler 16 and time constant 0x20
+        let ctrl = CTC_CONTROL_WORD | CTC_MODE_TIMER | CTC_PRESCALER_16 | CTC_CONSTANT_FOLLOWS;
+        ctc.write(&bus, CTC_1, ctrl as RegT);
+        assert_eq!(None, ctc.cycles_to_zero());
+        ctc.write(&bus, CTC_1, 0x20);
+        assert_eq!(Some(0x200), ctc.cycles_to_zero());
+        ctc.update_timers(&bus, 0x1F0);
+        assert_eq!(Some(0x10), ctc.cycles_to_zero());
+        assert!(!bus.state.borrow().ctc_zero_called);
+        ctc.update_timers(&bus, 0x10);
+        assert!(bus.state.borrow().ctc_zero_called);
+        assert_eq!(Some(0x200), ctc.cycles_to_zero());
+
+        // counters are not timed
+        let ctrl = CTC_CONTROL_WORD | CTC_MODE_COUNTER | CTC_CONSTANT_FOLLOWS;
+        ctc.write(&bus, CTC_2, ctrl as RegT);
+        ctc.write(&bus, CTC_2, 0x04);
+        assert_eq!(Some(0x200), ctc.cycles_to_zero());
+    }
 }
@@ -8,8 +8,12 @@ const MAX_CONTROLLERS: usize = 16;
 /// a single interrupt controller
 #[derive(Clone,Copy)]
 pub struct Controller {
+    /// interrupt enable input (IEI), false while an upstream controller
+    /// requests an interrupt or is being serviced
     pub int_enabled: bool,
+    /// interrupt requested, but not yet acknowledged by the CPU
     pub int_requested: bool,
+    /// interrupt acknowledged, the interrupt service routine is running
     pub int_pending: bool,
     pub int_vec: u8,
 }
@@ -32,9 +36,28 @@ impl Controller {
 }
 
 /// interrupt controller daisychain
+///
+/// The controllers are chained by priority, controller 0 has the highest
+/// priority. A controller which requests an interrupt, or whose interrupt
+/// is being serviced, disables interrupts on all downstream controllers
+/// (through its IEO output, which is the IEI input of the next
+/// controller). Requests from disabled controllers are latched until
+/// the upstream controllers are done, and a higher-priority controller
+/// can interrupt the service routine of a lower-priority controller
+/// once it has enabled interrupts with EI.
+///
+/// The end of an interrupt service routine is either signalled by the
+/// system calling **irq_reti()** from Bus::irq_reti(), or detected by the
+/// daisychain itself like on real Z80 peripherals, which decode the RETI
+/// instruction from the opcode bytes on the data bus: forward the
+/// opcode fetches from Bus::cpu_fetch() to **fetch()** (after enabling
+/// them with CPU::set_fetch_callback(), which also stops the CPU from
+/// calling Bus::irq_reti(), so that the RETI isn't handled twice).
 pub struct Daisychain {
     pub num_ctrl: usize,
     pub ctrl: [Controller; MAX_CONTROLLERS],
+    /// true if the last fetched opcode byte was an ED prefix
+    ed_fetched: bool,
 }
 
 impl Daisychain {
@@ -43,6 +66,7 @@ impl Daisychain {
         Daisychain {
             num_ctrl: num_controllers,
             ctrl: [Controller::new(); MAX_CONTROLLERS],
+            ed_fetched: false,
         }
     }
 
@@ -51,58 +75,91 @@ impl Daisychain {
         for ctrl in self.ctrl.iter_mut() {
             ctrl.reset();
         }
+        self.ed_fetched = false;
+    }
+
+    /// observe an opcode byte fetched by the CPU (M1 cycle), called by bus
+    ///
+    /// An ED prefix followed by 4D (RETI) or 45 (RETN) ends the current
+    /// interrupt service routine like irq_reti(), when the second byte is
+    /// fetched.
+    pub fn fetch(&mut self, op: RegT) {
+        if self.ed_fetched && (op == 0x4D || op == 0x45) {
+            self.irq_reti();
+        }
+        self.ed_fetched = op == 0xED;
     }
 
     /// request an interrupt from an interrupt controller, called by bus
+    ///
+    /// The request is latched in the controller, and forwarded to the
+    /// CPU through Bus::irq_cpu() if the controller is enabled.
     pub fn irq(&mut self, bus: &Bus, ctrl_id: usize, vec: u8) {
-        if self.ctrl[ctrl_id].int_enabled {
-            {
-                let ctrl = &mut self.ctrl[ctrl_id];
-                assert!(!ctrl.int_pending);
-                ctrl.int_enabled = false;
-                ctrl.int_requested = true;
-                ctrl.int_vec = vec;
-            }
+        {
+            let ctrl = &mut self.ctrl[ctrl_id];
+            ctrl.int_requested = true;
+            ctrl.int_vec = vec;
+        }
+        self.update_chain();
+        if self.int_requested() {
             bus.irq_cpu();
-
-            // disable interrupt on downstream controllers
-            for i in ctrl_id + 1..self.num_ctrl {
-                self.ctrl[i].int_enabled = false;
-            }
         }
     }
 
     /// CPU acknowledges interrupt request, return the interrupt vector
     pub fn irq_ack(&mut self) -> RegT {
-        // find the interrupt controller which issued the request
-        // and return it's interrupt vector.
-        // downstream controller remain in interrupt-disabled
-        // state until the CPU sends the RETI
-        for ctrl in self.ctrl.iter_mut() {
-            if ctrl.int_requested {
+        // the enabled controller which requests an interrupt puts
+        // its interrupt vector on the data bus, its service routine
+        // is running until the CPU executes a RETI
+        let vec = match self.active_ctrl() {
+            Some(ctrl) => {
                 ctrl.int_requested = false;
                 ctrl.int_pending = true;
-                return ctrl.int_vec as RegT;
+                ctrl.int_vec as RegT
             }
-        }
-        panic!("irq_ack() called without any interrupt pending!")
+            None => panic!("irq_ack() called without any interrupt pending!"),
+        };
+        self.update_chain();
+        vec
     }
 
-    /// CPU executes a RETI, this enabled interrupts on downstream controllers
+    /// return true if an enabled controller is requesting an interrupt (CPU INT line is active)
+    pub fn int_requested(&self) -> bool {
+        self.ctrl[..self.num_ctrl]
+            .iter()
+            .any(|ctrl| ctrl.int_requested && ctrl.int_enabled && !ctrl.int_pending)
+    }
+
+    /// CPU executes a RETI, this ends the interrupt service of the
+    /// highest-priority controller which is being serviced
+    ///
+    /// This enables interrupts on the downstream controllers up to the
+    /// next controller which is being serviced, afterwards int_requested()
+    /// returns true if one of them has a latched interrupt request.
     pub fn irq_reti(&mut self) {
-        let mut is_downstream = false;
-        for ctrl in self.ctrl.iter_mut() {
-            ctrl.int_enabled = true;
-            if ctrl.int_pending {
-                if is_downstream {
-                    // interrupt-enable propagation stops at
-                    // first downstream device where an interrupt
-                    // is still pending
-                    break;
-                }
-                ctrl.int_pending = false;
-                is_downstream = true;
-            }
+        // during the RETI opcode fetch, requesting controllers don't
+        // block the IEI input of the controller which is being serviced
+        let num_ctrl = self.num_ctrl;
+        if let Some(ctrl) = self.ctrl[..num_ctrl].iter_mut().find(|ctrl| ctrl.int_pending) {
+            ctrl.int_pending = false;
+        }
+        self.update_chain();
+    }
+
+    /// the controller which answers an interrupt acknowledge
+    fn active_ctrl(&mut self) -> Option<&mut Controller> {
+        let num_ctrl = self.num_ctrl;
+        self.ctrl[..num_ctrl]
+            .iter_mut()
+            .find(|ctrl| ctrl.int_requested && ctrl.int_enabled && !ctrl.int_pending)
+    }
+
+    /// propagate the interrupt enable signal from IEO to IEI along the chain
+    fn update_chain(&mut self) {
+        let mut iei = true;
+        for ctrl in self.ctrl[..self.num_ctrl].iter_mut() {
+            ctrl.int_enabled = iei;
+            iei = iei && !ctrl.int_requested && !ctrl.int_pending;
         }
     }
 }
@@ -115,6 +172,9 @@ mod test {
     use RegT;
     use Bus;
     use CPU;
+    use {PIO, PIO_A, PIO_B};
+    use {CTC, CTC_0};
+    use ctc::{CTC_CONTROL_WORD, CTC_INTERRUPT_ENABLED, CTC_MODE_COUNTER, CTC_CONSTANT_FOLLOWS};
 
     #[test]
     fn reset() {
@@ -181,33 +241,42 @@ mod test {
             let mut state = self.state.borrow_mut();
             state.irq_cpu_called = true;
         }
+        fn cpu_fetch(&self, _: RegT, op: RegT) {
+            self.daisy.borrow_mut().fetch(op);
+        }
     }
 
     #[test]
     fn irq_ack() {
         let bus = TestBus::new();
         let mut daisy = bus.daisy.borrow_mut();
-        // test with interrupt disabled
-        daisy.ctrl[DEV0].int_enabled = false;
-        daisy.irq(&bus, DEV0, 0x10);
+        // test with interrupt disabled by an upstream controller in service
+        daisy.ctrl[DEV0].int_pending = true;
+        daisy.irq(&bus, DEV1, 0x10);
         {
-            let dev0 = &daisy.ctrl[DEV0];
+            let dev1 = &daisy.ctrl[DEV1];
             let state = bus.state.borrow();
-            assert!(!dev0.int_enabled);
-            assert!(!dev0.int_requested);
-            assert!(!dev0.int_pending);
-            assert_eq!(dev0.int_vec, 0x00);
+            assert!(!dev1.int_enabled);
+            assert!(dev1.int_requested);
+            assert!(!dev1.int_pending);
+            assert_eq!(dev1.int_vec, 0x10);
             assert!(!state.irq_received);
+            assert!(!state.irq_cpu_called);
+            assert!(!daisy.int_requested());
         }
+        // the latched request becomes active after the RETI
+        daisy.irq_reti();
+        assert!(daisy.int_requested());
+        daisy.irq_ack();
+        daisy.irq_reti();
         // test with interrupt enabled
-        daisy.ctrl[DEV0].int_enabled = true;
         daisy.irq(&bus, DEV0, 0x10);
         {
             let dev0 = &daisy.ctrl[DEV0];
             let dev1 = &daisy.ctrl[DEV1];
             let dev2 = &daisy.ctrl[DEV2];
             let state = bus.state.borrow();
-            assert!(!dev0.int_enabled);
+            assert!(dev0.int_enabled);
             assert!(dev0.int_requested);
             assert!(!dev0.int_pending);
             assert_eq!(dev0.int_vec, 0x10);
@@ -215,5 +284,218 @@ mod test {
             assert!(!dev1.int_enabled);
             assert!(!dev2.int_enabled);
         }
+        assert_eq!(0x10, daisy.irq_ack());
+        assert!(daisy.ctrl[DEV0].int_pending);
+        assert!(!daisy.ctrl[DEV1].int_enabled);
+    }
+
+    #[test]
+    fn nested_irqs() {
+        let bus = TestBus::new();
+        let mut daisy = bus.daisy.borrow_mut();
+        daisy.irq(&bus, DEV2, 0x30);
+        assert_eq!(0x30, daisy.irq_ack());
+
+        // a higher-priority controller preempts the service routine
+        daisy.irq(&bus, DEV0, 0x10);
+        assert!(daisy.int_requested());
+        assert_eq!(0x10, daisy.irq_ack());
+
+        // a controller between the two must wait
+        daisy.irq(&bus, DEV1, 0x20);
+        assert!(!daisy.int_requested());
+        assert!(daisy.ctrl[DEV1].int_requested);
+
+        // each RETI ends the innermost service routine
+        daisy.irq_reti();
+        assert!(!daisy.ctrl[DEV0].int_pending);
+        assert!(daisy.ctrl[DEV2].int_pending);
+        assert!(daisy.int_requested());
+        assert_eq!(0x20, daisy.irq_ack());
+        daisy.irq_reti();
+        assert!(!daisy.ctrl[DEV1].int_pending);
+        assert!(daisy.ctrl[DEV2].int_pending);
+        assert!(daisy.ctrl[DEV2].int_enabled);
+        daisy.irq_reti();
+        for ctrl in daisy.ctrl[..NUM_DEVS].iter() {
+            assert!(ctrl.int_enabled);
+            assert!(!ctrl.int_requested);
+            assert!(!ctrl.int_pending);
+        }
+
+        // a controller can't interrupt its own service routine
+        daisy.irq(&bus, DEV1, 0x20);
+        daisy.irq_ack();
+        daisy.irq(&bus, DEV1, 0x20);
+        assert!(!daisy.int_requested());
+        daisy.irq_reti();
+        assert!(daisy.int_requested());
+    }
+
+    #[test]
+    fn reti_snooping() {
+        let bus = TestBus::new();
+        {
+            let mut daisy = bus.daisy.borrow_mut();
+            daisy.irq(&bus, DEV1, 0x20);
+            assert_eq!(0x20, daisy.irq_ack());
+            assert!(daisy.ctrl[DEV1].int_pending);
+            assert!(!daisy.ctrl[DEV2].int_enabled);
+
+            // the RETI opcode bytes must be fetched in sequence
+            for &op in [0x4D, 0xED, 0x44, 0x00, 0x4D].iter() {
+                daisy.fetch(op);
+            }
+            assert!(daisy.ctrl[DEV1].int_pending);
+        }
+
+        // LD A,B; RETI executed by the CPU ends the interrupt service
+        let mut cpu = bus.cpu.borrow_mut();
+        cpu.mem.map(0, 0, 0, true, 0x10000);
+        cpu.mem.write(0x0000, &[0x78, 0xED, 0x4D]);
+        cpu.reg.set_sp(0x8000);
+        cpu.set_fetch_callback(true);
+        cpu.step(&bus);
+        cpu.step(&bus);
+        let daisy = bus.daisy.borrow();
+        assert!(!daisy.ctrl[DEV1].int_pending);
+        assert!(daisy.ctrl[DEV1].int_enabled);
+        assert!(daisy.ctrl[DEV2].int_enabled);
+    }
+
+    /// a system with a PIO (channels A and B) and a CTC in the daisychain
+    struct System {
+        cpu: RefCell<CPU>,
+        pio: RefCell<PIO>,
+        ctc: RefCell<CTC>,
+        daisy: RefCell<Daisychain>,
+    }
+
+    const SYS_PIO_A: usize = 0;
+    const SYS_PIO_B: usize = 1;
+    const SYS_CTC: usize = 2;
+
+    impl Bus for System {
+        fn irq_ack(&self) -> RegT {
+            self.daisy.borrow_mut().irq_ack()
+        }
+        fn cpu_fetch(&self, _: RegT, op: RegT) {
+            self.daisy.borrow_mut().fetch(op);
+        }
+        fn pio_irq(&self, _: usize, chn: usize, int_vector: RegT) {
+            self.daisy.borrow_mut().irq(self, SYS_PIO_A + chn, int_vector as u8);
+        }
+        fn ctc_irq(&self, _: usize, chn: usize, int_vector: RegT) {
+            self.daisy.borrow_mut().irq(self, SYS_CTC + chn, int_vector as u8);
+        }
+    }
+
+    impl System {
+        fn new() -> System {
+            let sys = System {
+                cpu: RefCell::new(CPU::new_64k()),
+                pio: RefCell::new(PIO::new(0)),
+                ctc: RefCell::new(CTC::new(0)),
+                daisy: RefCell::new(Daisychain::new(SYS_CTC + 4)),
+            };
+            {
+                let mut cpu = sys.cpu.borrow_mut();
+                cpu.set_fetch_callback(true);
+                // IM 2; LD A,0x01; LD I,A; LD HL,0x4000; LD SP,0x8000; EI; HALT; JR -3
+                cpu.mem.write(0x0000, &[0xED, 0x5E, 0x3E, 0x01, 0xED, 0x47, 0x21, 0x00,
+                                        0x40, 0x31, 0x00, 0x80, 0xFB, 0x76, 0x18, 0xFD]);
+                // interrupt vectors: PIO A 0x10, PIO B 0x12, CTC 0x20
+                cpu.mem.w16(0x0110, 0x0300);
+                cpu.mem.w16(0x0112, 0x0400);
+                cpu.mem.w16(0x0120, 0x0500);
+                // interrupt handlers which write tag bytes before and
+                // after a delay loop: EI; LD (HL),tag; INC HL; LD B,0;
+                // DJNZ $; LD (HL),tag+1; INC HL; RETI
+                for &(addr, tag) in [(0x0300, 0xA0), (0x0400, 0xB0), (0x0500, 0xC0)].iter() {
+                    cpu.mem.write(addr, &[0xFB, 0x36, tag, 0x23, 0x06, 0x00, 0x10, 0xFE,
+                                          0x36, tag + 1, 0x23, 0xED, 0x4D]);
+                }
+            }
+            {
+                // PIO channels in bit control mode, interrupt when bit 0 goes high
+                let mut pio = sys.pio.borrow_mut();
+                for &(chn, vec) in [(PIO_A, 0x10), (PIO_B, 0x12)].iter() {
+                    for &val in [vec, 0xCF, 0xFF, 0xB7, 0xFE].iter() {
+                        pio.write_control(chn, val);
+                    }
+                }
+            }
+            {
+                // CTC channel 0 counts triggers and interrupts on each one
+                let mut ctc = sys.ctc.borrow_mut();
+                let ctrl = CTC_CONTROL_WORD | CTC_INTERRUPT_ENABLED | CTC_MODE_COUNTER |
+                           CTC_CONSTANT_FOLLOWS;
+                ctc.write(&sys, CTC_0, 0x20);
+                ctc.write(&sys, CTC_0, ctrl as RegT);
+                ctc.write(&sys, CTC_0, 1);
+            }
+            sys
+        }
+
+        /// execute instructions until the tag log has a number of entries
+        fn run_until(&self, num_tags: usize) {
+            for _ in 0..100000 {
+                if self.tags().len() >= num_tags {
+                    return;
+                }
+                self.cpu.borrow_mut().step(self);
+                if self.daisy.borrow().int_requested() {
+                    self.cpu.borrow_mut().irq();
+                }
+            }
+            panic!("tags not written: {:?}", self.tags());
+        }
+
+        fn tags(&self) -> Vec<u8> {
+            let cpu = self.cpu.borrow();
+            (0x4000..).map(|addr| cpu.mem.r8(addr) as u8).take_while(|t| *t != 0).collect()
+        }
+
+        fn trigger_pio(&self, chn: usize) {
+            let mut pio = self.pio.borrow_mut();
+            pio.write(self, chn, 0x00);
+            pio.write(self, chn, 0x01);
+        }
+
+        fn trigger_ctc(&self) {
+            self.ctc.borrow_mut().trigger(self, CTC_0);
+        }
+    }
+
+    #[test]
+    fn nested_irqs_pio_ctc() {
+        let sys = System::new();
+        for _ in 0..10 {
+            sys.cpu.borrow_mut().step(&sys);
+        }
+        assert!(sys.cpu.borrow().halt);
+
+        // each interrupt preempts the service routine of a lower-priority one
+        sys.trigger_ctc();
+        sys.run_until(1);
+        sys.trigger_pio(PIO_B);
+        sys.run_until(2);
+        sys.trigger_pio(PIO_A);
+        sys.run_until(6);
+        assert_eq!(vec![0xC0, 0xB0, 0xA0, 0xA1, 0xB1, 0xC1], sys.tags());
+
+        // lower-priority interrupts wait for the service routine to end,
+        // and are then serviced in the order of their priority
+        sys.trigger_pio(PIO_A);
+        sys.run_until(7);
+        sys.trigger_ctc();
+        sys.trigger_pio(PIO_B);
+        sys.run_until(12);
+        assert_eq!(vec![0xA0, 0xA1, 0xB0, 0xB1, 0xC0, 0xC1], &sys.tags()[6..]);
+        for _ in 0..10 {
+            sys.cpu.borrow_mut().step(&sys);
+        }
+        let daisy = sys.daisy.borrow();
+        assert!(daisy.ctrl[..daisy.num_ctrl].iter().all(|c| c.int_enabled && !c.int_pending));
     }
 }
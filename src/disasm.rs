@@ -0,0 +1,355 @@
+use RegT;
+use memory::Memory;
+
+static R: [&'static str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+static RP: [&'static str; 4] = ["BC", "DE", "HL", "SP"];
+static RP2: [&'static str; 4] = ["BC", "DE", "HL", "AF"];
+static CC: [&'static str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+static ALU: [&'static str; 8] = ["ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ",
+                                 "CP "];
+static ROT: [&'static str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SLL", "SRL"];
+static X0Z7: [&'static str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+static IM: [&'static str; 8] = ["0", "0", "1", "2", "0", "0", "1", "2"];
+static ED_X1Z7: [&'static str; 8] = ["LD I,A", "LD R,A", "LD A,I", "LD A,R", "RRD", "RLD",
+                                     "NOP", "NOP"];
+static BLOCK: [[&'static str; 4]; 4] = [["LDI", "CPI", "INI", "OUTI"],
+                                        ["LDD", "CPD", "IND", "OUTD"],
+                                        ["LDIR", "CPIR", "INIR", "OTIR"],
+                                        ["LDDR", "CPDR", "INDR", "OTDR"]];
+
+/// reads instruction bytes and builds operand strings for one instruction
+struct Decoder<'a> {
+    mem: &'a Memory,
+    addr: RegT,
+    len: RegT,
+    // "HL", "IX" or "IY"
+    hl: &'static str,
+    // displacement for (IX+d) / (IY+d), read once per instruction
+    d: Option<RegT>,
+}
+
+impl<'a> Decoder<'a> {
+    fn fetch(&mut self) -> RegT {
+        let val = self.mem.r8((self.addr + self.len) & 0xFFFF);
+        self.len += 1;
+        val
+    }
+
+    fn n(&mut self) -> String {
+        format!("${:02X}", self.fetch())
+    }
+
+    fn nn(&mut self) -> String {
+        let lo = self.fetch();
+        let hi = self.fetch();
+        format!("${:04X}", hi << 8 | lo)
+    }
+
+    fn jr_target(&mut self) -> String {
+        let d = self.fetch() as u8 as i8 as RegT;
+        format!("${:04X}", (self.addr + self.len + d) & 0xFFFF)
+    }
+
+    fn indexed(&self) -> bool {
+        self.hl != "HL"
+    }
+
+    /// the (HL), (IX+d) or (IY+d) operand
+    fn ind_hl(&mut self) -> String {
+        if self.indexed() {
+            let d = match self.d {
+                Some(d) => d,
+                None => {
+                    let d = self.fetch();
+                    self.d = Some(d);
+                    d
+                }
+            };
+            let d = d as u8 as i8;
+            if d < 0 {
+                format!("({}-${:02X})", self.hl, -(d as i32))
+            } else {
+                format!("({}+${:02X})", self.hl, d)
+            }
+        } else {
+            String::from("(HL)")
+        }
+    }
+
+    /// 8-bit register operand, H/L map to IXH/IXL unless (IX+d) is used
+    fn r(&mut self, i: usize, allow_ixh: bool) -> String {
+        match i {
+            4 | 5 if self.indexed() && allow_ixh => format!("{}{}", self.hl, R[i]),
+            6 => self.ind_hl(),
+            _ => String::from(R[i]),
+        }
+    }
+
+    fn rp(&self, i: usize) -> &'static str {
+        if i == 2 { self.hl } else { RP[i] }
+    }
+
+    fn rp2(&self, i: usize) -> &'static str {
+        if i == 2 { self.hl } else { RP2[i] }
+    }
+
+    fn op(&mut self) -> String {
+        let op = self.fetch() as usize;
+        let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+        let (p, q) = (y >> 1, y & 1);
+        match x {
+            0 => {
+                match z {
+                    0 => {
+                        match y {
+                            0 => String::from("NOP"),
+                            1 => String::from("EX AF,AF'"),
+                            2 => format!("DJNZ {}", self.jr_target()),
+                            3 => format!("JR {}", self.jr_target()),
+                            _ => format!("JR {},{}", CC[y - 4], self.jr_target()),
+                        }
+                    }
+                    1 => {
+                        if q == 0 {
+                            format!("LD {},{}", self.rp(p), self.nn())
+                        } else {
+                            format!("ADD {},{}", self.hl, self.rp(p))
+                        }
+                    }
+                    2 => {
+                        match (q, p) {
+                            (0, 0) => String::from("LD (BC),A"),
+                            (0, 1) => String::from("LD (DE),A"),
+                            (0, 2) => format!("LD ({}),{}", self.nn(), self.hl),
+                            (0, _) => format!("LD ({}),A", self.nn()),
+                            (_, 0) => String::from("LD A,(BC)"),
+                            (_, 1) => String::from("LD A,(DE)"),
+                            (_, 2) => {
+                                let hl = self.hl;
+                                format!("LD {},({})", hl, self.nn())
+                            }
+                            (_, _) => format!("LD A,({})", self.nn()),
+                        }
+                    }
+                    3 => format!("{} {}", if q == 0 { "INC" } else { "DEC" }, self.rp(p)),
+                    4 => format!("INC {}", self.r(y, true)),
+                    5 => format!("DEC {}", self.r(y, true)),
+                    6 => {
+                        let dst = self.r(y, true);
+                        format!("LD {},{}", dst, self.n())
+                    }
+                    _ => String::from(X0Z7[y]),
+                }
+            }
+            1 => {
+                if y == 6 && z == 6 {
+                    String::from("HALT")
+                } else {
+                    // with (IX+d) as one operand, the other operand is H or L
+                    let allow_ixh = y != 6 && z != 6;
+                    let dst = self.r(y, allow_ixh);
+                    let src = self.r(z, allow_ixh);
+                    format!("LD {},{}", dst, src)
+                }
+            }
+            2 => format!("{}{}", ALU[y], self.r(z, true)),
+            _ => {
+                match z {
+                    0 => format!("RET {}", CC[y]),
+                    1 => {
+                        match (q, p) {
+                            (0, _) => format!("POP {}", self.rp2(p)),
+                            (_, 0) => String::from("RET"),
+                            (_, 1) => String::from("EXX"),
+                            (_, 2) => format!("JP ({})", self.hl),
+                            (_, _) => format!("LD SP,{}", self.hl),
+                        }
+                    }
+                    2 => format!("JP {},{}", CC[y], self.nn()),
+                    3 => {
+                        match y {
+                            0 => format!("JP {}", self.nn()),
+                            1 => self.cb_op(),
+                            2 => format!("OUT ({}),A", self.n()),
+                            3 => format!("IN A,({})", self.n()),
+                            4 => format!("EX (SP),{}", self.hl),
+                            5 => String::from("EX DE,HL"),
+                            6 => String::from("DI"),
+                            _ => String::from("EI"),
+                        }
+                    }
+                    4 => format!("CALL {},{}", CC[y], self.nn()),
+                    5 => {
+                        match (q, p) {
+                            (0, _) => format!("PUSH {}", self.rp2(p)),
+                            (_, 0) => format!("CALL {}", self.nn()),
+                            (_, 1) => self.prefix_op("IX"),
+                            (_, 2) => self.ed_op(),
+                            (_, _) => self.prefix_op("IY"),
+                        }
+                    }
+                    6 => format!("{}{}", ALU[y], self.n()),
+                    _ => format!("RST ${:02X}", y * 8),
+                }
+            }
+        }
+    }
+
+    fn prefix_op(&mut self, reg: &'static str) -> String {
+        // a DD or FD prefix followed by another prefix acts as a NOP
+        let next = self.mem.r8((self.addr + self.len) & 0xFFFF);
+        if self.indexed() || next == 0xDD || next == 0xED || next == 0xFD {
+            return String::from("NOP");
+        }
+        self.hl = reg;
+        self.op()
+    }
+
+    fn cb_op(&mut self) -> String {
+        // for DD CB and FD CB the displacement comes before the opcode
+        let operand = if self.indexed() { Some(self.ind_hl()) } else { None };
+        let op = self.fetch() as usize;
+        let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+        let (opnd, copy) = match operand {
+            // undocumented: the result is also copied into a register
+            Some(opnd) => (opnd, if z != 6 { Some(R[z]) } else { None }),
+            None => (String::from(R[z]), None),
+        };
+        let mut res = match x {
+            0 => format!("{} {}", ROT[y], opnd),
+            1 => format!("BIT {},{}", y, opnd),
+            2 => format!("RES {},{}", y, opnd),
+            _ => format!("SET {},{}", y, opnd),
+        };
+        if let Some(r) = copy {
+            if x != 1 {
+                res = format!("{},{}", res, r);
+            }
+        }
+        res
+    }
+
+    fn ed_op(&mut self) -> String {
+        // the ED prefix cancels a preceding DD or FD prefix
+        self.hl = "HL";
+        let op = self.fetch() as usize;
+        let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+        let (p, q) = (y >> 1, y & 1);
+        match x {
+            1 => {
+                match z {
+                    0 => {
+                        if y == 6 {
+                            String::from("IN (C)")
+                        } else {
+                            format!("IN {},(C)", R[y])
+                        }
+                    }
+                    1 => {
+                        if y == 6 {
+                            String::from("OUT (C),0")
+                        } else {
+                            format!("OUT (C),{}", R[y])
+                        }
+                    }
+                    2 => format!("{} HL,{}", if q == 0 { "SBC" } else { "ADC" }, RP[p]),
+                    3 => {
+                        if q == 0 {
+                            format!("LD ({}),{}", self.nn(), RP[p])
+                        } else {
+                            format!("LD {},({})", RP[p], self.nn())
+                        }
+                    }
+                    4 => String::from("NEG"),
+                    5 => String::from(if y == 1 { "RETI" } else { "RETN" }),
+                    6 => format!("IM {}", IM[y]),
+                    _ => String::from(ED_X1Z7[y]),
+                }
+            }
+            2 if z <= 3 && y >= 4 => String::from(BLOCK[y - 4][z]),
+            _ => String::from("NOP"),
+        }
+    }
+}
+
+/// disassemble the instruction at an address
+///
+/// Returns the instruction in Zilog mnemonics (with hexadecimal
+/// numbers and absolute jump targets) and the length of the
+/// instruction in bytes.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{Memory, disassemble};
+///
+/// let mut mem = Memory::new_64k();
+/// mem.write(0x0100, &[0xDD, 0x36, 0x05, 0x42, 0x18, 0xFE]);
+/// assert_eq!(disassemble(&mem, 0x0100), (String::from("LD (IX+$05),$42"), 4));
+/// assert_eq!(disassemble(&mem, 0x0104), (String::from("JR $0104"), 2));
+/// ```
+pub fn disassemble(mem: &Memory, addr: RegT) -> (String, usize) {
+    let mut dec = Decoder {
+        mem: mem,
+        addr: addr & 0xFFFF,
+        len: 0,
+        hl: "HL",
+        d: None,
+    };
+    let text = dec.op();
+    (text, dec.len as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dis(bytes: &[u8]) -> (String, usize) {
+        let mut mem = Memory::new_64k();
+        mem.write(0x1000, bytes);
+        let (text, len) = disassemble(&mem, 0x1000);
+        (text, len)
+    }
+
+    #[test]
+    fn main_ops() {
+        assert_eq!(dis(&[0x00]), (String::from("NOP"), 1));
+        assert_eq!(dis(&[0x08]), (String::from("EX AF,AF'"), 1));
+        assert_eq!(dis(&[0x10, 0xFE]), (String::from("DJNZ $1000"), 2));
+        assert_eq!(dis(&[0x38, 0x10]), (String::from("JR C,$1012"), 2));
+        assert_eq!(dis(&[0x21, 0x34, 0x12]), (String::from("LD HL,$1234"), 3));
+        assert_eq!(dis(&[0x22, 0x34, 0x12]), (String::from("LD ($1234),HL"), 3));
+        assert_eq!(dis(&[0x3A, 0x34, 0x12]), (String::from("LD A,($1234)"), 3));
+        assert_eq!(dis(&[0x36, 0x42]), (String::from("LD (HL),$42"), 2));
+        assert_eq!(dis(&[0x76]), (String::from("HALT"), 1));
+        assert_eq!(dis(&[0x7E]), (String::from("LD A,(HL)"), 1));
+        assert_eq!(dis(&[0x9E]), (String::from("SBC A,(HL)"), 1));
+        assert_eq!(dis(&[0xB8]), (String::from("CP B"), 1));
+        assert_eq!(dis(&[0xC2, 0x00, 0x80]), (String::from("JP NZ,$8000"), 3));
+        assert_eq!(dis(&[0xCD, 0x05, 0x00]), (String::from("CALL $0005"), 3));
+        assert_eq!(dis(&[0xD3, 0xFE]), (String::from("OUT ($FE),A"), 2));
+        assert_eq!(dis(&[0xF5]), (String::from("PUSH AF"), 1));
+        assert_eq!(dis(&[0xFF]), (String::from("RST $38"), 1));
+    }
+
+    #[test]
+    fn prefixed_ops() {
+        assert_eq!(dis(&[0xCB, 0x06]), (String::from("RLC (HL)"), 2));
+        assert_eq!(dis(&[0xCB, 0x7F]), (String::from("BIT 7,A"), 2));
+        assert_eq!(dis(&[0xED, 0xB0]), (String::from("LDIR"), 2));
+        assert_eq!(dis(&[0xED, 0x4D]), (String::from("RETI"), 2));
+        assert_eq!(dis(&[0xED, 0x5E]), (String::from("IM 2"), 2));
+        assert_eq!(dis(&[0xED, 0x73, 0x00, 0xC0]), (String::from("LD ($C000),SP"), 4));
+        assert_eq!(dis(&[0xED, 0x70]), (String::from("IN (C)"), 2));
+        assert_eq!(dis(&[0xED, 0x00]), (String::from("NOP"), 2));
+        assert_eq!(dis(&[0xDD, 0x21, 0x00, 0x40]), (String::from("LD IX,$4000"), 4));
+        assert_eq!(dis(&[0xFD, 0x7E, 0xFB]), (String::from("LD A,(IY-$05)"), 3));
+        assert_eq!(dis(&[0xDD, 0x66, 0x01]), (String::from("LD H,(IX+$01)"), 3));
+        assert_eq!(dis(&[0xDD, 0x65]), (String::from("LD IXH,IXL"), 2));
+        assert_eq!(dis(&[0xFD, 0xE9]), (String::from("JP (IY)"), 2));
+        assert_eq!(dis(&[0xDD, 0xEB]), (String::from("EX DE,HL"), 2));
+        assert_eq!(dis(&[0xDD, 0xCB, 0x02, 0x46]), (String::from("BIT 0,(IX+$02)"), 4));
+        assert_eq!(dis(&[0xFD, 0xCB, 0xFF, 0x00]), (String::from("RLC (IY-$01),B"), 4));
+        assert_eq!(dis(&[0xDD, 0xDD, 0x00]), (String::from("NOP"), 1));
+    }
+}
@@ -0,0 +1,384 @@
+use std::fmt;
+
+/// a host input event which is fed into a machine emulation
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum InputEvent {
+    /// press a key (ASCII code)
+    KeyDown(u8),
+    /// release a key (ASCII code)
+    KeyUp(u8),
+    /// set the joystick state (machine-specific bit mask)
+    Joystick(u8),
+    /// start the tape player
+    TapePlay,
+    /// stop the tape player
+    TapeStop,
+    /// rewind the tape to the start
+    TapeRewind,
+}
+
+/// a complete machine emulation which can be driven by recorded input
+///
+/// Input events which a machine doesn't support are ignored.
+pub trait Machine {
+    /// CPU frequency in kHz
+    fn freq_khz(&self) -> i64;
+    /// number of CPU cycles executed since power-on
+    fn cycles(&self) -> u64;
+    /// run the emulation for at least a number of CPU cycles
+    fn step_cycles(&self, num_cycles: i64);
+    /// feed an input event into the emulation
+    fn input(&self, event: InputEvent);
+    /// hash over the CPU registers and memory (see CPU::state_hash()),
+    /// device state like timers, PIOs or the keyboard isn't included
+    fn cpu_state_hash(&self) -> u64;
+}
+
+impl InputEvent {
+    fn parse(name: &str, arg: Option<&str>) -> Result<InputEvent, String> {
+        let val = || {
+            arg.ok_or(format!("missing value for '{}'", name))
+                .and_then(|a| u8::from_str_radix(a, 16).map_err(|e| format!("{}: {}", a, e)))
+        };
+        match name {
+            "key_down" => Ok(InputEvent::KeyDown(val()?)),
+            "key_up" => Ok(InputEvent::KeyUp(val()?)),
+            "joystick" => Ok(InputEvent::Joystick(val()?)),
+            "tape_play" => Ok(InputEvent::TapePlay),
+            "tape_stop" => Ok(InputEvent::TapeStop),
+            "tape_rewind" => Ok(InputEvent::TapeRewind),
+            _ => Err(format!("unknown input event '{}'", name)),
+        }
+    }
+}
+
+impl fmt::Display for InputEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InputEvent::KeyDown(key) => write!(f, "key_down {:02X}", key),
+            InputEvent::KeyUp(key) => write!(f, "key_up {:02X}", key),
+            InputEvent::Joystick(bits) => write!(f, "joystick {:02X}", bits),
+            InputEvent::TapePlay => write!(f, "tape_play"),
+            InputEvent::TapeStop => write!(f, "tape_stop"),
+            InputEvent::TapeRewind => write!(f, "tape_rewind"),
+        }
+    }
+}
+
+/// a recorded input session: input events with their CPU cycle timestamps,
+/// and the cycle count and CPU state hash at the end of the recording
+///
+/// The text format has one event per line, prefixed with the decimal
+/// cycle count, the last line is the end of the recording with the
+/// CPU state hash, lines starting with '#' are comments:
+///
+/// ```text
+/// # rz80 input recording
+/// 1250000 key_down 61
+/// 1400000 key_up 61
+/// 2000000 end 9C0F3A51D2E8B764
+/// ```
+#[derive(Clone, PartialEq, Debug)]
+pub struct InputLog {
+    pub events: Vec<(u64, InputEvent)>,
+    pub end_cycles: u64,
+    pub end_hash: u64,
+}
+
+impl InputLog {
+    /// convert the recording into the text format
+    pub fn to_text(&self) -> String {
+        let mut text = String::from("# rz80 input recording\n");
+        for &(cycles, event) in &self.events {
+            text.push_str(&format!("{} {}\n", cycles, event));
+        }
+        text.push_str(&format!("{} end {:016X}\n", self.end_cycles, self.end_hash));
+        text
+    }
+
+    /// parse a recording from the text format
+    pub fn from_text(text: &str) -> Result<InputLog, String> {
+        let mut events = Vec::new();
+        let mut end = None;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |msg: String| format!("line {}: {}", i + 1, msg);
+            if end.is_some() {
+                return Err(err("events after end of recording".to_string()));
+            }
+            let mut tokens = line.split_whitespace();
+            let cycles: u64 = tokens.next()
+                .unwrap()
+                .parse()
+                .map_err(|_| err(format!("invalid cycle count in '{}'", line)))?;
+            if events.last().map_or(false, |&(c, _)| cycles < c) {
+                return Err(err("cycle count goes backward".to_string()));
+            }
+            let name = tokens.next().ok_or(err("missing input event".to_string()))?;
+            let arg = tokens.next();
+            if name == "end" {
+                let hash = arg.ok_or(err("missing state hash".to_string()))?;
+                let hash = u64::from_str_radix(hash, 16).map_err(|e| err(e.to_string()))?;
+                end = Some((cycles, hash));
+            } else {
+                events.push((cycles, InputEvent::parse(name, arg).map_err(&err)?));
+            }
+        }
+        match end {
+            Some((end_cycles, end_hash)) => {
+                Ok(InputLog {
+                    events: events,
+                    end_cycles: end_cycles,
+                    end_hash: end_hash,
+                })
+            }
+            None => Err("missing end of recording".to_string()),
+        }
+    }
+}
+
+/// records host input events with the CPU cycle count at which they
+/// are fed into the emulation
+///
+/// The emulation must have been powered on before recording starts, and
+/// a replay must start from the same state (including any loaded tapes
+/// and program files).
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{Z1013, Z1013Model, Z1013Monitor, InputRecorder, InputPlayer, InputEvent};
+///
+/// static MON: &'static [u8] = include_bytes!("../examples/dumps/z1013_mon_a2.bin");
+/// static FONT: &'static [u8] = include_bytes!("../examples/dumps/z1013_font.bin");
+///
+/// let z1013 = Z1013::new(Z1013Model::Z1013_64, Z1013Monitor::VA2, MON, FONT);
+/// z1013.poweron();
+/// let mut recorder = InputRecorder::new();
+/// z1013.step(500000);
+/// recorder.input(&z1013, InputEvent::KeyDown(b'H'));
+/// z1013.step(50000);
+/// recorder.input(&z1013, InputEvent::KeyUp(b'H'));
+/// z1013.step(300000);
+/// let log = recorder.finish(&z1013);
+///
+/// // replay the recording on a fresh machine, in different step sizes
+/// let replay = Z1013::new(Z1013Model::Z1013_64, Z1013Monitor::VA2, MON, FONT);
+/// replay.poweron();
+/// let mut player = InputPlayer::new(log);
+/// while !player.is_finished(&replay) {
+///     player.step(&replay, 16667);
+/// }
+/// assert!(player.verify(&replay).is_ok());
+/// assert_eq!(z1013.screen_text(), replay.screen_text());
+/// ```
+pub struct InputRecorder {
+    events: Vec<(u64, InputEvent)>,
+}
+
+impl InputRecorder {
+    /// create a new, empty recorder
+    pub fn new() -> InputRecorder {
+        InputRecorder { events: Vec::new() }
+    }
+
+    /// feed an input event into the machine, and record it
+    pub fn input(&mut self, machine: &Machine, event: InputEvent) {
+        self.events.push((machine.cycles(), event));
+        machine.input(event);
+    }
+
+    /// end the recording, the current cycle count and CPU state hash are stored
+    pub fn finish(self, machine: &Machine) -> InputLog {
+        InputLog {
+            events: self.events,
+            end_cycles: machine.cycles(),
+            end_hash: machine.cpu_state_hash(),
+        }
+    }
+}
+
+impl Default for InputRecorder {
+    fn default() -> InputRecorder {
+        InputRecorder::new()
+    }
+}
+
+/// replays a recorded input session
+///
+/// Each event is fed into the emulation at exactly the recorded
+/// CPU cycle count, independent from the host's frame timing, so
+/// that the replay is bit-identical to the recorded session.
+pub struct InputPlayer {
+    log: InputLog,
+    next: usize,
+}
+
+impl InputPlayer {
+    /// create a new player for a recording
+    pub fn new(log: InputLog) -> InputPlayer {
+        InputPlayer { log: log, next: 0 }
+    }
+
+    /// run the emulation for a number of microseconds, and feed in recorded events
+    pub fn step(&mut self, machine: &Machine, micro_seconds: i64) {
+        let num_cycles = (machine.freq_khz() * micro_seconds) / 1000;
+        self.step_cycles(machine, num_cycles);
+    }
+
+    /// run the emulation for a number of CPU cycles, and feed in recorded events
+    ///
+    /// The emulation stops at the end of the recording, so that the final
+    /// state can be verified.
+    pub fn step_cycles(&mut self, machine: &Machine, num_cycles: i64) {
+        let end = ::std::cmp::min(machine.cycles() + num_cycles.max(0) as u64,
+                                  self.log.end_cycles);
+        while self.next < self.log.events.len() && self.log.events[self.next].0 <= end {
+            let (cycles, event) = self.log.events[self.next];
+            if cycles > machine.cycles() {
+                machine.step_cycles((cycles - machine.cycles()) as i64);
+            }
+            machine.input(event);
+            self.next += 1;
+        }
+        if end > machine.cycles() {
+            machine.step_cycles((end - machine.cycles()) as i64);
+        }
+    }
+
+    /// return true when the end of the recording has been reached
+    pub fn is_finished(&self, machine: &Machine) -> bool {
+        machine.cycles() >= self.log.end_cycles
+    }
+
+    /// check that the end of the recording has been reached in the recorded state
+    pub fn verify(&self, machine: &Machine) -> Result<(), String> {
+        if machine.cycles() != self.log.end_cycles {
+            Err(format!("replay ended at cycle {}, recording at cycle {}",
+                        machine.cycles(),
+                        self.log.end_cycles))
+        } else if machine.cpu_state_hash() != self.log.end_hash {
+            Err(format!("CPU state hash {:016X} doesn't match recorded hash {:016X}",
+                        machine.cpu_state_hash(),
+                        self.log.end_hash))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// replay a complete recording and verify the final state
+pub fn replay(machine: &Machine, log: &InputLog) -> Result<(), String> {
+    let mut player = InputPlayer::new(log.clone());
+    let num_cycles = log.end_cycles.saturating_sub(machine.cycles());
+    player.step_cycles(machine, num_cycles as i64);
+    player.verify(machine)
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    // a fake machine which runs 7 cycles per instruction, and hashes
+    // the cycle counts at which input arrived
+    struct TestMachine {
+        cycles: RefCell<u64>,
+        inputs: RefCell<Vec<(u64, InputEvent)>>,
+    }
+
+    impl TestMachine {
+        fn new() -> TestMachine {
+            TestMachine {
+                cycles: RefCell::new(0),
+                inputs: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl Machine for TestMachine {
+        fn freq_khz(&self) -> i64 {
+            1000
+        }
+        fn cycles(&self) -> u64 {
+            *self.cycles.borrow()
+        }
+        fn step_cycles(&self, num_cycles: i64) {
+            let mut cur = 0;
+            while cur < num_cycles {
+                cur += 7;
+            }
+            *self.cycles.borrow_mut() += cur as u64;
+        }
+        fn input(&self, event: InputEvent) {
+            let cycles = self.cycles();
+            self.inputs.borrow_mut().push((cycles, event));
+        }
+        fn cpu_state_hash(&self) -> u64 {
+            self.inputs.borrow().iter().fold(self.cycles(), |h, &(c, _)| h.wrapping_mul(31) ^ c)
+        }
+    }
+
+    #[test]
+    fn text_format() {
+        let log = InputLog {
+            events: vec![(7, InputEvent::KeyDown(b'A')),
+                         (70, InputEvent::KeyUp(b'A')),
+                         (70, InputEvent::Joystick(0x11)),
+                         (140, InputEvent::TapePlay),
+                         (147, InputEvent::TapeStop),
+                         (154, InputEvent::TapeRewind)],
+            end_cycles: 700,
+            end_hash: 0x0123456789ABCDEF,
+        };
+        let text = log.to_text();
+        assert!(text.contains("\n70 key_up 41\n70 joystick 11\n"));
+        assert!(text.ends_with("700 end 0123456789ABCDEF\n"));
+        assert_eq!(Ok(log), InputLog::from_text(&text));
+
+        assert!(InputLog::from_text("7 key_down 41\n").is_err());
+        assert!(InputLog::from_text("7 key_down\n14 end 0").is_err());
+        assert!(InputLog::from_text("7 keydown 41\n14 end 0").is_err());
+        assert_eq!(Err("line 2: cycle count goes backward".to_string()),
+                   InputLog::from_text("14 key_down 41\n7 key_up 41\n21 end 0"));
+        assert!(InputLog::from_text("14 end 0\n21 key_up 41").is_err());
+    }
+
+    #[test]
+    fn record_replay() {
+        let m = TestMachine::new();
+        let mut recorder = InputRecorder::new();
+        m.step_cycles(100);
+        recorder.input(&m, InputEvent::KeyDown(b'A'));
+        recorder.input(&m, InputEvent::KeyDown(b'B'));
+        m.step_cycles(33);
+        recorder.input(&m, InputEvent::KeyUp(b'A'));
+        m.step_cycles(1000);
+        let log = recorder.finish(&m);
+        assert_eq!(105, log.events[0].0);
+        assert_eq!(140, log.events[2].0);
+        assert_eq!(1141, log.end_cycles);
+
+        // replay in small and large steps
+        for &step in &[1, 10, 2000] {
+            let r = TestMachine::new();
+            let mut player = InputPlayer::new(log.clone());
+            while !player.is_finished(&r) {
+                player.step_cycles(&r, step);
+            }
+            assert_eq!(1141, r.cycles());
+            assert_eq!(*m.inputs.borrow(), *r.inputs.borrow());
+            assert_eq!(Ok(()), player.verify(&r));
+        }
+        assert_eq!(Ok(()), replay(&TestMachine::new(), &log));
+
+        // a replay which starts from a different state diverges
+        let r = TestMachine::new();
+        r.input(InputEvent::Joystick(1));
+        assert!(replay(&r, &log).is_err());
+    }
+}
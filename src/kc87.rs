@@ -0,0 +1,710 @@
+use std::cell::RefCell;
+use RegT;
+use bus::Bus;
+use cpu::CPU;
+use input::{Machine, InputEvent};
+use scheduler::Scheduler;
+use pio::{PIO, PIO_A, PIO_B};
+use ctc::{CTC, CTC_0, CTC_1, CTC_2, CTC_3};
+use daisychain::Daisychain;
+use keyboard::Keyboard;
+use textdisplay::TextDisplay;
+use beeper::Beeper;
+
+/// KC87 CPU frequency in kHz
+pub const KC87_FREQ_KHZ: i64 = 2458;
+/// width of the decoded KC87 display in pixels (including border)
+pub const KC87_DISPLAY_WIDTH: usize = 40 * 8 + 2 * BORDER;
+/// height of the decoded KC87 display in pixels (including border)
+pub const KC87_DISPLAY_HEIGHT: usize = 24 * 8 + 2 * BORDER;
+
+const BORDER: usize = 8;
+const VIDEO_ADDR: RegT = 0xEC00;
+const COLOR_ADDR: RegT = 0xE800;
+
+// the blink flip-flop toggles at about 1.56 Hz
+// (a half-period of 320 ms, kHz times ms gives the number of cycles)
+const BLINK_CYCLES: i64 = KC87_FREQ_KHZ * 320;
+// the keyboard hold counter is advanced every 20ms
+const KBD_SCAN_CYCLES: i64 = KC87_FREQ_KHZ * 20;
+const SAMPLE_RATE: i64 = 44100;
+
+// interrupt daisychain priorities: PIO1 -> PIO2 -> CTC
+const DAISY_PIO1: usize = 0;
+const DAISY_PIO2: usize = 2;
+const DAISY_CTC: usize = 4;
+const DAISY_NUM: usize = 8;
+
+// PIO1-A output bits
+const PIO1_A_BORDER_SHIFT: u8 = 3;
+const PIO1_A_SOUND_ENABLE: u8 = 1 << 7;
+
+/// the 8 foreground/background colors
+static PALETTE: [u32; 8] = [
+    0xFF000000,     // black
+    0xFFFF0000,     // red
+    0xFF00FF00,     // green
+    0xFFFFFF00,     // yellow
+    0xFF0000FF,     // blue
+    0xFFFF00FF,     // purple
+    0xFF00FFFF,     // cyan
+    0xFFFFFFFF,     // white
+];
+
+// keyboard matrix layout, 8 columns by 8 lines, unshifted and shifted
+static KEYS_UNSHIFTED: &'static str = concat!("01234567",
+                                              "89:;,=.?",
+                                              "@ABCDEFG",
+                                              "HIJKLMNO",
+                                              "PQRSTUVW",
+                                              "XYZ   ^ ");
+static KEYS_SHIFTED: &'static str = concat!("_!\"#$%&'",
+                                            "()*+<->/",
+                                            " abcdefg",
+                                            "hijklmno",
+                                            "pqrstuvw",
+                                            "xyz   ~ ");
+
+struct State {
+    pio1_a: u8,
+    kbd_columns: u8,
+    kbd_lines: u8,
+    ctc3_triggers: u32,
+    blink: bool,
+    // cycle counts up to which the CTC and beeper have been updated
+    ctc_cycles: u64,
+    beeper_cycles: u64,
+}
+
+// timed events
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Event {
+    Blink,
+    KeyboardScan,
+    // the next zero-crossing of a CTC timer channel
+    CtcZero,
+    // the keyboard matrix lines or columns have been written through PIO2
+    KeyboardUpdate,
+}
+
+// create a scheduler with the initial timer events
+fn new_scheduler() -> Scheduler<Event> {
+    let mut scheduler = Scheduler::new();
+    scheduler.schedule(BLINK_CYCLES as u64, Event::Blink);
+    scheduler.schedule(KBD_SCAN_CYCLES as u64, Event::KeyboardScan);
+    scheduler
+}
+
+/// KC87 (aka Z9001) home computer emulation
+///
+/// The KC87 is an East German home computer with a 2.458 MHz Z80 CPU,
+/// a CTC, two PIOs, a 40x24 character color display and a keyboard
+/// matrix connected to the second PIO. All interrupts are
+/// routed through a daisychain (PIO1 -> PIO2 -> CTC), which detects
+/// RETI from the CPU's opcode fetches. The KC87 struct
+/// owns all chips and implements the Bus trait which wires them
+/// together, so that a KC87 can be driven by host code through a
+/// handful of methods.
+///
+/// The ROM images (operating system, BASIC and font) must be provided
+/// by the caller.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::KC87;
+///
+/// static OS: &'static [u8] = include_bytes!("../examples/dumps/kc87_os_2.bin");
+/// static BASIC: &'static [u8] = include_bytes!("../examples/dumps/z9001_basic.bin");
+/// static FONT: &'static [u8] = include_bytes!("../examples/dumps/kc87_font_2.bin");
+///
+/// let kc87 = KC87::new(OS, BASIC, FONT);
+/// kc87.poweron();
+///
+/// // run for 3 seconds, the OS prompt appears on screen
+/// kc87.step(3000000);
+/// assert!(kc87.screen_text().contains("OS"));
+/// ```
+pub struct KC87 {
+    pub cpu: RefCell<CPU>,
+    pub ctc: RefCell<CTC>,
+    pub pio1: RefCell<PIO>,
+    pub pio2: RefCell<PIO>,
+    pub daisy: RefCell<Daisychain>,
+    pub kbd: RefCell<Keyboard>,
+    pub beeper: RefCell<Beeper>,
+    scheduler: RefCell<Scheduler<Event>>,
+    state: RefCell<State>,
+    os: Vec<u8>,
+    basic: Vec<u8>,
+    font: Vec<u8>,
+}
+
+impl KC87 {
+    /// initialize a new KC87 with OS, BASIC and font ROM images
+    pub fn new(os: &[u8], basic: &[u8], font: &[u8]) -> KC87 {
+        let mut kbd = Keyboard::new(8, 8);
+        kbd.min_hold = 2;
+        // shift key is column 0, line 7
+        kbd.add_modifier(1, 0, 7);
+        kbd.add_key_table(0, KEYS_UNSHIFTED.as_bytes());
+        kbd.add_key_table(1, KEYS_SHIFTED.as_bytes());
+        for &(key, col, line) in &[(0x03, 6, 6),    // stop (Esc)
+                                   (0x08, 0, 6),    // cursor left
+                                   (0x09, 1, 6),    // cursor right
+                                   (0x0A, 2, 6),    // cursor down
+                                   (0x0B, 3, 6),    // cursor up
+                                   (0x0D, 5, 6),    // enter
+                                   (0x13, 4, 5),    // pause
+                                   (0x14, 1, 7),    // color
+                                   (0x19, 3, 5),    // home
+                                   (0x1A, 5, 5),    // insert
+                                   (0x1B, 4, 6),    // esc
+                                   (0x1C, 4, 7),    // list
+                                   (0x1D, 5, 7),    // run
+                                   (0x20, 7, 6)] {  // space
+            kbd.add_key(key, 0, col, line);
+        }
+        KC87 {
+            cpu: RefCell::new({
+                // the daisychain detects RETI from the opcode fetches
+                let mut cpu = CPU::new();
+                cpu.set_fetch_callback(true);
+                cpu
+            }),
+            ctc: RefCell::new(CTC::new(0)),
+            pio1: RefCell::new(PIO::new(0)),
+            pio2: RefCell::new(PIO::new(1)),
+            daisy: RefCell::new(Daisychain::new(DAISY_NUM)),
+            kbd: RefCell::new(kbd),
+            beeper: RefCell::new(Beeper::new(KC87_FREQ_KHZ, SAMPLE_RATE)),
+            scheduler: RefCell::new(new_scheduler()),
+            state: RefCell::new(State {
+                pio1_a: 0,
+                kbd_columns: 0xFF,
+                kbd_lines: 0xFF,
+                ctc3_triggers: 0,
+                blink: false,
+                ctc_cycles: 0,
+                beeper_cycles: 0,
+            }),
+            os: os.to_vec(),
+            basic: basic.to_vec(),
+            font: font.to_vec(),
+        }
+    }
+
+    /// power-on the KC87, initializes the memory map and starts at the OS entry
+    pub fn poweron(&self) {
+        let mut cpu = self.cpu.borrow_mut();
+        cpu.mem.unmap_all();
+
+        // 48 KByte RAM, and 2 KByte video RAM (1 KByte colors, 1 KByte ASCII)
+        cpu.mem.map(0, 0x00000, 0x0000, true, 0xC000);
+        cpu.mem.map(0, 0x0E800, 0xE800, true, 0x0800);
+
+        // BASIC and OS ROMs on a lower priority layer behind the video RAM
+        cpu.mem.map_bytes(1, 0x10000, 0xC000, false, &self.basic);
+        cpu.mem.map_bytes(1, 0x12000, 0xE000, false, &self.os);
+        drop(cpu);
+        *self.scheduler.borrow_mut() = new_scheduler();
+        {
+            let mut state = self.state.borrow_mut();
+            state.ctc_cycles = 0;
+            state.beeper_cycles = 0;
+        }
+        self.reset();
+    }
+
+    /// reset the KC87 (same as pressing the reset button)
+    pub fn reset(&self) {
+        let mut cpu = self.cpu.borrow_mut();
+        cpu.reset();
+        cpu.reg.set_pc(0xF000);
+        self.ctc.borrow_mut().reset();
+        self.pio1.borrow_mut().reset();
+        self.pio2.borrow_mut().reset();
+        self.daisy.borrow_mut().reset();
+        self.kbd.borrow_mut().release_all();
+        let mut state = self.state.borrow_mut();
+        state.pio1_a = 0;
+        state.kbd_columns = 0xFF;
+        state.kbd_lines = 0xFF;
+        state.ctc3_triggers = 0;
+        // all CTC channels are stopped after reset
+        self.scheduler.borrow_mut().cancel(Event::CtcZero);
+    }
+
+    /// run the emulation for a number of microseconds
+    pub fn step(&self, micro_seconds: i64) {
+        self.step_cycles((KC87_FREQ_KHZ * micro_seconds) / 1000);
+    }
+
+    /// run the emulation for at least a number of CPU cycles
+    ///
+    /// The emulation stops at the first instruction boundary after
+    /// the number of cycles has been reached. The CTC and beeper are
+    /// only updated at the scheduled CTC zero-crossings and when the
+    /// CPU accesses the CTC, and the keyboard matrix is only written
+    /// to PIO2 when it changes.
+    pub fn step_cycles(&self, num_cycles: i64) {
+        let mut cur_cycles = 0;
+        while cur_cycles < num_cycles {
+            let idle = self.cpu.borrow().is_idle() && !self.daisy.borrow().int_requested();
+            let cycles = if idle {
+                // fast-forward a waiting CPU to the next event
+                let mut budget = num_cycles - cur_cycles;
+                if let Some(next) = self.scheduler.borrow().cycles_to_next_event() {
+                    budget = budget.min(next as i64);
+                }
+                budget + self.cpu.borrow_mut().run(self, budget)
+            } else {
+                self.cpu.borrow_mut().step(self)
+            };
+            self.scheduler.borrow_mut().advance(cycles);
+            self.dispatch_events();
+            if self.daisy.borrow().int_requested() {
+                // the INT line is level-triggered, keep it active until
+                // the CPU has accepted the interrupt
+                self.cpu.borrow_mut().irq();
+            }
+            cur_cycles += cycles;
+        }
+        // generate the audio samples up to the end of the time slice
+        self.update_beeper(self.cycles());
+    }
+
+    /// number of CPU cycles executed since power-on
+    pub fn cycles(&self) -> u64 {
+        self.scheduler.borrow().cycles()
+    }
+
+    /// dispatch the timed events which are due
+    fn dispatch_events(&self) {
+        loop {
+            let event = self.scheduler.borrow_mut().pop_due();
+            match event {
+                Some((at, Event::Blink)) => {
+                    let mut state = self.state.borrow_mut();
+                    state.blink = !state.blink;
+                    self.scheduler.borrow_mut().schedule(at + BLINK_CYCLES as u64, Event::Blink);
+                }
+                Some((at, Event::KeyboardScan)) => {
+                    self.kbd.borrow_mut().update();
+                    self.update_keyboard();
+                    self.scheduler
+                        .borrow_mut()
+                        .schedule(at + KBD_SCAN_CYCLES as u64, Event::KeyboardScan);
+                }
+                Some((at, Event::CtcZero)) => self.update_ctc(at),
+                Some((_, Event::KeyboardUpdate)) => self.update_keyboard(),
+                None => break,
+            }
+        }
+    }
+
+    /// advance the CTC up to a cycle count, and schedule its next zero-crossing
+    fn update_ctc(&self, now: u64) {
+        let cycles = {
+            let mut state = self.state.borrow_mut();
+            let cycles = now.saturating_sub(state.ctc_cycles) as i64;
+            state.ctc_cycles = state.ctc_cycles.max(now);
+            cycles
+        };
+        let mut ctc = self.ctc.borrow_mut();
+        ctc.update_timers(self, cycles);
+        // CTC2 output is connected to CTC3 trigger input
+        let triggers = ::std::mem::replace(&mut self.state.borrow_mut().ctc3_triggers, 0);
+        for _ in 0..triggers {
+            ctc.trigger(self, CTC_3);
+        }
+        self.schedule_ctc_zero(&ctc);
+    }
+
+    /// (re-)schedule the next zero-crossing of the CTC timers
+    fn schedule_ctc_zero(&self, ctc: &CTC) {
+        let mut scheduler = self.scheduler.borrow_mut();
+        scheduler.cancel(Event::CtcZero);
+        if let Some(cycles) = ctc.cycles_to_zero() {
+            let at = self.state.borrow().ctc_cycles + cycles as u64;
+            scheduler.schedule(at, Event::CtcZero);
+        }
+    }
+
+    /// write a CTC channel, the CTC is first brought up to the current cycle count
+    fn ctc_write(&self, chn: usize, val: RegT) {
+        self.update_ctc(self.cycles());
+        let mut ctc = self.ctc.borrow_mut();
+        ctc.write(self, chn, val);
+        self.schedule_ctc_zero(&ctc);
+    }
+
+    /// read a CTC channel, the CTC is first brought up to the current cycle count
+    fn ctc_read(&self, chn: usize) -> RegT {
+        self.update_ctc(self.cycles());
+        self.ctc.borrow().read(chn)
+    }
+
+    /// generate the beeper's audio samples up to a cycle count
+    fn update_beeper(&self, now: u64) {
+        let cycles = {
+            let mut state = self.state.borrow_mut();
+            let cycles = now.saturating_sub(state.beeper_cycles) as i64;
+            state.beeper_cycles = state.beeper_cycles.max(now);
+            cycles
+        };
+        self.beeper.borrow_mut().update(cycles);
+    }
+
+    /// write the current keyboard matrix state into PIO2
+    fn update_keyboard(&self) {
+        let (columns, lines) = {
+            let state = self.state.borrow();
+            (self.kbd_columns(state.kbd_lines), self.kbd_lines(state.kbd_columns))
+        };
+        let mut pio2 = self.pio2.borrow_mut();
+        pio2.write(self, PIO_A, columns);
+        pio2.write(self, PIO_B, lines);
+    }
+
+    /// write the keyboard matrix into PIO2 after the current instruction,
+    /// used when the PIO2 outputs or control registers have changed
+    fn schedule_keyboard_update(&self) {
+        let mut scheduler = self.scheduler.borrow_mut();
+        scheduler.cancel(Event::KeyboardUpdate);
+        scheduler.schedule_in(0, Event::KeyboardUpdate);
+    }
+
+    /// keyboard matrix columns for the active lines (all active-low)
+    fn kbd_columns(&self, line_bits: u8) -> RegT {
+        let columns = self.kbd.borrow().scan_lines(!line_bits as u32);
+        (!columns & 0xFF) as RegT
+    }
+
+    /// keyboard matrix lines for the active columns (all active-low)
+    fn kbd_lines(&self, column_bits: u8) -> RegT {
+        let lines = self.kbd.borrow().scan_columns(!column_bits as u32);
+        (!lines & 0xFF) as RegT
+    }
+
+    /// press a key (ASCII code)
+    pub fn key_down(&self, ascii: u8) {
+        self.kbd.borrow_mut().key_down(ascii as usize);
+        self.update_keyboard();
+    }
+
+    /// release a key (ASCII code)
+    pub fn key_up(&self, ascii: u8) {
+        self.kbd.borrow_mut().key_up(ascii as usize);
+        self.update_keyboard();
+    }
+
+    /// get the current border color as RGBA8
+    pub fn border_color(&self) -> u32 {
+        PALETTE[((self.state.borrow().pio1_a >> PIO1_A_BORDER_SHIFT) & 7) as usize]
+    }
+
+    /// get the generated audio samples (44.1 kHz mono), and clear the sample buffer
+    pub fn audio_samples(&self) -> Vec<f32> {
+        self.beeper.borrow_mut().take_samples()
+    }
+
+    /// decode the video and color RAM into a linear RGBA8 framebuffer
+    /// of KC87_DISPLAY_WIDTH * KC87_DISPLAY_HEIGHT pixels
+    pub fn decode_framebuffer(&self, fb: &mut [u32]) {
+        let cpu = self.cpu.borrow();
+        let mut disp = TextDisplay::new(VIDEO_ADDR, 40, 24, &self.font);
+        disp.color_addr = Some(COLOR_ADDR);
+        disp.palette = &PALETTE;
+        disp.blink = self.state.borrow().blink;
+        let width = disp.width();
+        let mut pixels = vec![0u32; width * disp.height()];
+        disp.decode(&cpu.mem, &mut pixels);
+
+        let border = self.border_color();
+        for (y, line) in fb.chunks_mut(KC87_DISPLAY_WIDTH).enumerate().take(KC87_DISPLAY_HEIGHT) {
+            if !(BORDER..KC87_DISPLAY_HEIGHT - BORDER).contains(&y) {
+                for pixel in line.iter_mut() {
+                    *pixel = border;
+                }
+            } else {
+                let src = &pixels[(y - BORDER) * width..(y - BORDER + 1) * width];
+                line[..BORDER].copy_from_slice(&[border; BORDER]);
+                line[BORDER..BORDER + width].copy_from_slice(src);
+                line[BORDER + width..].copy_from_slice(&[border; BORDER]);
+            }
+        }
+    }
+
+    /// return the content of the video RAM as text, one line per character row
+    pub fn screen_text(&self) -> String {
+        let cpu = self.cpu.borrow();
+        let mut text = String::new();
+        for y in 0..24 {
+            let line: String = (0..40)
+                .map(|x| {
+                    let c = cpu.mem.r8(VIDEO_ADDR + y * 40 + x) as u8;
+                    if (0x20..0x7F).contains(&c) { c as char } else { ' ' }
+                })
+                .collect();
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text
+    }
+}
+
+impl Bus for KC87 {
+    fn cpu_outp(&self, port: RegT, val: RegT) {
+        match port & 0xFF {
+            0x80 | 0x84 => self.ctc_write(CTC_0, val),
+            0x81 | 0x85 => self.ctc_write(CTC_1, val),
+            0x82 | 0x86 => self.ctc_write(CTC_2, val),
+            0x83 | 0x87 => self.ctc_write(CTC_3, val),
+            0x88 | 0x8C => self.pio1.borrow_mut().write_data(self, PIO_A, val),
+            0x89 | 0x8D => self.pio1.borrow_mut().write_data(self, PIO_B, val),
+            0x8A | 0x8E => self.pio1.borrow_mut().write_control(PIO_A, val),
+            0x8B | 0x8F => self.pio1.borrow_mut().write_control(PIO_B, val),
+            0x90 | 0x94 => self.pio2.borrow_mut().write_data(self, PIO_A, val),
+            0x91 | 0x95 => self.pio2.borrow_mut().write_data(self, PIO_B, val),
+            0x92 | 0x96 => {
+                self.pio2.borrow_mut().write_control(PIO_A, val);
+                self.schedule_keyboard_update();
+            }
+            0x93 | 0x97 => {
+                self.pio2.borrow_mut().write_control(PIO_B, val);
+                self.schedule_keyboard_update();
+            }
+            _ => (),
+        }
+    }
+
+    fn cpu_inp(&self, port: RegT) -> RegT {
+        match port & 0xFF {
+            0x80 | 0x84 => self.ctc_read(CTC_0),
+            0x81 | 0x85 => self.ctc_read(CTC_1),
+            0x82 | 0x86 => self.ctc_read(CTC_2),
+            0x83 | 0x87 => self.ctc_read(CTC_3),
+            0x88 | 0x8C => self.pio1.borrow_mut().read_data(self, PIO_A),
+            0x89 | 0x8D => self.pio1.borrow_mut().read_data(self, PIO_B),
+            0x8A | 0x8E | 0x8B | 0x8F => self.pio1.borrow().read_control(),
+            0x90 | 0x94 => self.pio2.borrow_mut().read_data(self, PIO_A),
+            0x91 | 0x95 => self.pio2.borrow_mut().read_data(self, PIO_B),
+            0x92 | 0x96 | 0x93 | 0x97 => self.pio2.borrow().read_control(),
+            _ => 0xFF,
+        }
+    }
+
+    fn irq_ack(&self) -> RegT {
+        self.daisy.borrow_mut().irq_ack()
+    }
+
+    fn cpu_fetch(&self, _: RegT, op: RegT) {
+        self.daisy.borrow_mut().fetch(op);
+    }
+
+    fn pio_outp(&self, pio: usize, chn: usize, data: RegT) {
+        let mut state = self.state.borrow_mut();
+        match (pio, chn) {
+            (0, PIO_A) => state.pio1_a = data as u8,
+            (1, PIO_A) => state.kbd_columns = data as u8,
+            (1, PIO_B) => state.kbd_lines = data as u8,
+            _ => (),
+        }
+        if pio == 1 {
+            self.schedule_keyboard_update();
+        }
+    }
+
+    fn pio_inp(&self, pio: usize, chn: usize) -> RegT {
+        let state = self.state.borrow();
+        match (pio, chn) {
+            (1, PIO_A) => self.kbd_columns(state.kbd_lines),
+            (1, PIO_B) => self.kbd_lines(state.kbd_columns),
+            _ => 0xFF,
+        }
+    }
+
+    fn pio_irq(&self, pio: usize, chn: usize, int_vector: RegT) {
+        let ctrl_id = if pio == 0 { DAISY_PIO1 } else { DAISY_PIO2 } + chn;
+        self.daisy.borrow_mut().irq(self, ctrl_id, int_vector as u8);
+    }
+
+    fn ctc_zero(&self, chn: usize, _: &CTC) {
+        match chn {
+            // CTC0 output drives the beeper, if enabled through PIO1-A
+            CTC_0 if (self.state.borrow().pio1_a & PIO1_A_SOUND_ENABLE) != 0 => {
+                let now = self.state.borrow().ctc_cycles;
+                self.update_beeper(now);
+                self.beeper.borrow_mut().toggle();
+            }
+            CTC_2 => self.state.borrow_mut().ctc3_triggers += 1,
+            _ => (),
+        }
+    }
+
+    fn ctc_irq(&self, _: usize, chn: usize, int_vector: RegT) {
+        self.daisy.borrow_mut().irq(self, DAISY_CTC + chn, int_vector as u8);
+    }
+}
+
+impl Machine for KC87 {
+    fn freq_khz(&self) -> i64 {
+        KC87_FREQ_KHZ
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles()
+    }
+
+    fn step_cycles(&self, num_cycles: i64) {
+        self.step_cycles(num_cycles);
+    }
+
+    fn input(&self, event: InputEvent) {
+        match event {
+            InputEvent::KeyDown(key) => self.key_down(key),
+            InputEvent::KeyUp(key) => self.key_up(key),
+            _ => (),
+        }
+    }
+
+    fn cpu_state_hash(&self) -> u64 {
+        self.cpu.borrow().state_hash()
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cpu::TrapAction;
+
+    static OS: &'static [u8] = include_bytes!("../examples/dumps/kc87_os_2.bin");
+    static BASIC: &'static [u8] = include_bytes!("../examples/dumps/z9001_basic.bin");
+    static FONT: &'static [u8] = include_bytes!("../examples/dumps/kc87_font_2.bin");
+
+    fn type_text(kc87: &KC87, text: &[u8]) {
+        for ch in text {
+            kc87.key_down(*ch);
+            kc87.step(100000);
+            kc87.key_up(*ch);
+            kc87.step(if *ch == 0x0D { 3000000 } else { 100000 });
+        }
+    }
+
+    #[test]
+    fn boot() {
+        let kc87 = KC87::new(OS, BASIC, FONT);
+        kc87.poweron();
+        kc87.step(3000000);
+        let text = kc87.screen_text();
+        assert!(text.contains("robotron  Z 9001"));
+        assert!(text.contains("OS\n>"));
+        // the OS runs with interrupts enabled in IM2
+        assert_eq!(2, kc87.cpu.borrow().reg.im);
+        assert!(kc87.cpu.borrow().iff1);
+
+        let mut fb = vec![0u32; KC87_DISPLAY_WIDTH * KC87_DISPLAY_HEIGHT];
+        kc87.decode_framebuffer(&mut fb);
+        assert_eq!(kc87.border_color(), fb[0]);
+        assert_eq!(kc87.border_color(), fb[fb.len() - 1]);
+    }
+
+    #[test]
+    fn blink_period() {
+        let kc87 = KC87::new(OS, BASIC, FONT);
+        kc87.poweron();
+        let mut toggles = Vec::new();
+        let mut blink = kc87.state.borrow().blink;
+        while toggles.len() < 3 {
+            kc87.step_cycles(1000);
+            if kc87.state.borrow().blink != blink {
+                blink = !blink;
+                toggles.push(kc87.cycles() as i64);
+            }
+        }
+        // the blink flip-flop toggles every 320 ms
+        assert_eq!(786560, BLINK_CYCLES);
+        for t in toggles.windows(2) {
+            assert!((t[1] - t[0] - BLINK_CYCLES).abs() < 1100);
+        }
+    }
+
+    #[test]
+    fn ctc_zero_event() {
+        let kc87 = KC87::new(OS, BASIC, FONT);
+        kc87.poweron();
+        // no CTC channel is running after reset
+        assert!(!kc87.scheduler.borrow_mut().cancel(Event::CtcZero));
+        // CTC1: timer mode, prescaler 16, time constant 10
+        kc87.cpu_outp(0x81, 0x07);
+        kc87.cpu_outp(0x81, 10);
+        assert_eq!(Some(160), kc87.scheduler.borrow().cycles_to_next_event());
+        // the zero-crossings are rescheduled when the event is dispatched
+        kc87.scheduler.borrow_mut().advance(170);
+        kc87.dispatch_events();
+        assert_eq!(Some(150), kc87.scheduler.borrow().cycles_to_next_event());
+        // reading the channel brings the CTC up to date
+        kc87.scheduler.borrow_mut().advance(80);
+        assert_eq!(70 / 16, kc87.cpu_inp(0x81));
+    }
+
+    #[test]
+    fn di_int_requested() {
+        // a latched interrupt request doesn't slow down code which
+        // runs with disabled interrupts: DI; INC HL; JR -3
+        let kc87 = KC87::new(OS, BASIC, FONT);
+        kc87.poweron();
+        kc87.step(3000000);
+        {
+            let mut cpu = kc87.cpu.borrow_mut();
+            cpu.mem.write(0x1000, &[0xF3, 0x23, 0x18, 0xFD]);
+            cpu.reg.set_pc(0x1000);
+            cpu.reg.set_hl(0);
+        }
+        kc87.daisy.borrow_mut().irq(&kc87, 0, 0x00);
+        assert!(kc87.daisy.borrow().int_requested());
+        let start = kc87.cycles();
+        kc87.step_cycles(10000);
+        let cycles = (kc87.cycles() - start) as i64 - 4;
+        let cpu = kc87.cpu.borrow();
+        let hl = cpu.reg.hl() as i64;
+        assert!(kc87.daisy.borrow().int_requested());
+        assert!(cycles == hl * 18 || cycles == hl * 18 - 12);
+        assert_eq!(0x1001, cpu.reg.wz());
+    }
+
+    #[test]
+    fn basic() {
+        let kc87 = KC87::new(OS, BASIC, FONT);
+        kc87.poweron();
+        kc87.step(3000000);
+        type_text(&kc87, b"BASIC\r\rPRINT \"Hello\",12*3+1\r");
+        assert!(kc87.screen_text().contains("Hello         37"));
+        // beeper is silent until BEEP is executed
+        assert!(kc87.audio_samples().windows(2).all(|w| w[0] == w[1]));
+        type_text(&kc87, b"BEEP\r");
+        assert!(kc87.audio_samples().windows(2).any(|w| w[0] != w[1]));
+    }
+
+    #[test]
+    fn fast_forward() {
+        // a trap disables fast-forwarding, both runs must end up in the same state
+        let run = |fast_forward: bool| {
+            let kc87 = KC87::new(OS, BASIC, FONT);
+            if fast_forward {
+                // the OS waits for keyboard input in a loop at 0xF924
+                kc87.cpu.borrow_mut().add_idle_loop(0xF924);
+            } else {
+                kc87.cpu.borrow_mut().add_trap(0xFFFF, |_| TrapAction::Continue);
+            }
+            kc87.poweron();
+            kc87.step(3000000);
+            type_text(&kc87, b"BASIC\r\rBEEP\r");
+            let hash = kc87.cpu.borrow().state_hash();
+            (kc87.cycles(), hash, kc87.audio_samples())
+        };
+        assert!(run(true) == run(false));
+    }
+}
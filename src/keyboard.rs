@@ -0,0 +1,333 @@
+/// maximum number of keyboard matrix columns
+pub const KBD_MAX_COLUMNS: usize = 16;
+/// maximum number of keyboard matrix lines
+pub const KBD_MAX_LINES: usize = 16;
+/// maximum number of key codes
+pub const KBD_MAX_KEYS: usize = 256;
+const MAX_LAYERS: usize = 4;
+const MAX_PRESSED: usize = 8;
+
+#[derive(Clone, Copy)]
+struct KeyPos {
+    pub col: usize,
+    pub line: usize,
+    pub layer: usize,
+    pub mapped: bool,
+}
+
+impl KeyPos {
+    pub fn new() -> KeyPos {
+        KeyPos {
+            col: 0,
+            line: 0,
+            layer: 0,
+            mapped: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct PressedKey {
+    pub key: usize,
+    pub pressed_at: u32, // scan counter when key was pressed
+    pub released: bool, // key has been released, but is still held
+}
+
+/// generic keyboard matrix
+///
+/// The Keyboard object maps key codes (usually ASCII codes) to
+/// positions in a keyboard matrix of up to 16 columns and 16 lines.
+/// The emulated system 'lights up' one or more columns (or lines)
+/// and reads back which lines (or columns) are connected through
+/// pressed keys.
+///
+/// ## Shift Layers
+///
+/// A key can be mapped to a shift layer, layer 0 is the unshifted
+/// layer, the other layers are associated with a modifier key
+/// (such as Shift or Ctrl) in the matrix. Pressing a key on a
+/// shift layer also closes the switch of the layer's modifier key.
+///
+/// ## Holding Keys
+///
+/// The operating system of an emulated computer usually scans the
+/// keyboard matrix periodically, and a key which is pressed and
+/// released between 2 scans would be lost. The **min_hold** member
+/// defines the minimum number of scan cycles a key stays pressed
+/// in the matrix, counted from the key_down() call: a key which is
+/// released earlier stays pressed until min_hold scan cycles have
+/// passed since it was pressed, a key which has been held longer is
+/// released immediately. The emulated system
+/// advances the scan counter by calling **update()**, for instance
+/// when the OS starts a new scan, or once per frame.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::Keyboard;
+///
+/// // an 8x8 keyboard matrix, with shift key at column 7, line 6
+/// let mut kbd = Keyboard::new(8, 8);
+/// kbd.add_modifier(1, 7, 6);
+/// kbd.add_key(b'a' as usize, 0, 0, 2);
+/// kbd.add_key(b'A' as usize, 1, 0, 2);
+///
+/// // light up column 0 and read back the lines
+/// kbd.key_down(b'a' as usize);
+/// assert_eq!(kbd.scan_columns(1 << 0), 1 << 2);
+/// assert_eq!(kbd.scan_columns(1 << 7), 0);
+/// kbd.key_up(b'a' as usize);
+///
+/// // the shifted key also closes the shift key switch
+/// kbd.key_down(b'A' as usize);
+/// assert_eq!(kbd.scan_columns(1 << 7), 1 << 6);
+/// assert_eq!(kbd.scan_lines(1 << 6), 1 << 7);
+/// ```
+pub struct Keyboard {
+    pub num_columns: usize,
+    pub num_lines: usize,
+    /// minimum number of scan cycles a key stays pressed, counted from key_down()
+    pub min_hold: u32,
+    keys: [KeyPos; KBD_MAX_KEYS],
+    modifiers: [Option<(usize, usize)>; MAX_LAYERS],
+    pressed: [PressedKey; MAX_PRESSED],
+    num_pressed: usize,
+    scan_count: u32,
+}
+
+impl Keyboard {
+    /// initialize a new keyboard matrix with the given number of columns and lines
+    pub fn new(num_columns: usize, num_lines: usize) -> Keyboard {
+        assert!(num_columns <= KBD_MAX_COLUMNS);
+        assert!(num_lines <= KBD_MAX_LINES);
+        Keyboard {
+            num_columns: num_columns,
+            num_lines: num_lines,
+            min_hold: 0,
+            keys: [KeyPos::new(); KBD_MAX_KEYS],
+            modifiers: [None; MAX_LAYERS],
+            pressed: [PressedKey {
+                key: 0,
+                pressed_at: 0,
+                released: false,
+            }; MAX_PRESSED],
+            num_pressed: 0,
+            scan_count: 0,
+        }
+    }
+
+    /// associate a shift layer (1..3) with a modifier key in the matrix
+    pub fn add_modifier(&mut self, layer: usize, col: usize, line: usize) {
+        assert!(layer > 0 && layer < MAX_LAYERS);
+        assert!(col < self.num_columns && line < self.num_lines);
+        self.modifiers[layer] = Some((col, line));
+    }
+
+    /// map a key code to a matrix position on a shift layer
+    pub fn add_key(&mut self, key: usize, layer: usize, col: usize, line: usize) {
+        assert!(layer < MAX_LAYERS);
+        assert!(col < self.num_columns && line < self.num_lines);
+        self.keys[key & (KBD_MAX_KEYS - 1)] = KeyPos {
+            col: col,
+            line: line,
+            layer: layer,
+            mapped: true,
+        };
+    }
+
+    /// map a whole table of key codes on a shift layer
+    ///
+    /// The table is organized by lines, each line has num_columns entries,
+    /// space characters are ignored (use add_key() to map the space key).
+    pub fn add_key_table(&mut self, layer: usize, table: &[u8]) {
+        for (i, key) in table.iter().enumerate() {
+            if *key != b' ' {
+                let line = i / self.num_columns;
+                let col = i % self.num_columns;
+                self.add_key(*key as usize, layer, col, line);
+            }
+        }
+    }
+
+    /// press a key
+    pub fn key_down(&mut self, key: usize) {
+        let key = key & (KBD_MAX_KEYS - 1);
+        if !self.keys[key].mapped {
+            return;
+        }
+        for p in self.pressed[..self.num_pressed].iter_mut() {
+            if p.key == key {
+                p.pressed_at = self.scan_count;
+                p.released = false;
+                return;
+            }
+        }
+        if self.num_pressed < MAX_PRESSED {
+            self.pressed[self.num_pressed] = PressedKey {
+                key: key,
+                pressed_at: self.scan_count,
+                released: false,
+            };
+            self.num_pressed += 1;
+        }
+    }
+
+    /// release a key, the key stays pressed until at least min_hold
+    /// scan cycles have passed since it was pressed
+    pub fn key_up(&mut self, key: usize) {
+        let key = key & (KBD_MAX_KEYS - 1);
+        for p in self.pressed[..self.num_pressed].iter_mut() {
+            if p.key == key {
+                p.released = true;
+            }
+        }
+        self.remove_released();
+    }
+
+    /// immediately release all keys
+    pub fn release_all(&mut self) {
+        self.num_pressed = 0;
+    }
+
+    /// return true if a key is currently pressed in the matrix
+    pub fn is_pressed(&self, key: usize) -> bool {
+        let key = key & (KBD_MAX_KEYS - 1);
+        self.pressed[..self.num_pressed].iter().any(|p| p.key == key)
+    }
+
+    /// advance the scan counter, this is called once per keyboard scan cycle
+    pub fn update(&mut self) {
+        self.scan_count = self.scan_count.wrapping_add(1);
+        self.remove_released();
+    }
+
+    /// remove released keys which have been held long enough
+    fn remove_released(&mut self) {
+        let mut i = 0;
+        while i < self.num_pressed {
+            let p = self.pressed[i];
+            if p.released && self.scan_count.wrapping_sub(p.pressed_at) >= self.min_hold {
+                self.num_pressed -= 1;
+                self.pressed[i] = self.pressed[self.num_pressed];
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// iterate over the matrix positions of all closed switches
+    fn for_each_closed<F: FnMut(usize, usize)>(&self, mut f: F) {
+        for p in self.pressed[..self.num_pressed].iter() {
+            let pos = self.keys[p.key];
+            f(pos.col, pos.line);
+            if let Some((col, line)) = self.modifiers[pos.layer] {
+                f(col, line);
+            }
+        }
+    }
+
+    /// light up columns (bit mask) and return the active lines (bit mask)
+    pub fn scan_columns(&self, column_mask: u32) -> u32 {
+        let mut lines = 0;
+        self.for_each_closed(|col, line| if (column_mask & (1 << col)) != 0 {
+            lines |= 1 << line;
+        });
+        lines
+    }
+
+    /// light up lines (bit mask) and return the active columns (bit mask)
+    pub fn scan_lines(&self, line_mask: u32) -> u32 {
+        let mut columns = 0;
+        self.for_each_closed(|col, line| if (line_mask & (1 << line)) != 0 {
+            columns |= 1 << col;
+        });
+        columns
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn kbd_8x8() -> Keyboard {
+        let mut kbd = Keyboard::new(8, 8);
+        kbd.add_modifier(1, 7, 6);
+        kbd.add_modifier(2, 6, 5);
+        kbd.add_key_table(0, b"13579-  QETUO@  ");
+        kbd.add_key_table(1, b"!#%')=  qetuo`  ");
+        kbd.add_key(0x20, 0, 6, 4);
+        kbd.add_key(0x03, 2, 1, 3);
+        kbd
+    }
+
+    #[test]
+    fn key_table() {
+        let mut kbd = kbd_8x8();
+        kbd.key_down(b'1' as usize);
+        assert_eq!(1 << 0, kbd.scan_columns(1 << 0));
+        kbd.release_all();
+        kbd.key_down(b'-' as usize);
+        assert_eq!(1 << 0, kbd.scan_columns(1 << 5));
+        kbd.release_all();
+        kbd.key_down(b'Q' as usize);
+        assert_eq!(1 << 1, kbd.scan_columns(1 << 0));
+        assert_eq!(0, kbd.scan_columns(1 << 7));
+        kbd.release_all();
+        kbd.key_down(b'q' as usize);
+        assert_eq!(1 << 1, kbd.scan_columns(1 << 0));
+        assert_eq!(1 << 6, kbd.scan_columns(1 << 7));
+        kbd.release_all();
+        // unmapped keys are ignored
+        kbd.key_down(b'Z' as usize);
+        assert!(!kbd.is_pressed(b'Z' as usize));
+        assert_eq!(0, kbd.scan_columns(0xFF));
+    }
+
+    #[test]
+    fn multiple_keys() {
+        let mut kbd = kbd_8x8();
+        kbd.key_down(0x20);
+        kbd.key_down(0x03);
+        kbd.key_down(b'3' as usize);
+        assert_eq!((1 << 4) | (1 << 5), kbd.scan_columns(1 << 6));
+        assert_eq!((1 << 3) | (1 << 0), kbd.scan_columns(1 << 1));
+        assert_eq!((1 << 4) | (1 << 5) | (1 << 3) | (1 << 0), kbd.scan_columns(0xFF));
+        assert_eq!((1 << 1) | (1 << 6), kbd.scan_lines(1 << 0) | kbd.scan_lines(1 << 4));
+        kbd.key_up(0x03);
+        assert_eq!(1 << 4, kbd.scan_columns(1 << 6));
+        assert_eq!(1 << 0, kbd.scan_columns(1 << 1));
+        assert!(kbd.is_pressed(0x20));
+        assert!(!kbd.is_pressed(0x03));
+    }
+
+    #[test]
+    fn min_hold() {
+        let mut kbd = kbd_8x8();
+        kbd.min_hold = 2;
+        kbd.key_down(b'5' as usize);
+        kbd.key_up(b'5' as usize);
+        assert!(kbd.is_pressed(b'5' as usize));
+        kbd.update();
+        assert_eq!(1 << 0, kbd.scan_columns(1 << 2));
+        kbd.update();
+        assert_eq!(0, kbd.scan_columns(1 << 2));
+
+        // min_hold counts from the key press, not from the release
+        kbd.key_down(b'5' as usize);
+        kbd.update();
+        kbd.key_up(b'5' as usize);
+        assert!(kbd.is_pressed(b'5' as usize));
+        kbd.update();
+        assert!(!kbd.is_pressed(b'5' as usize));
+
+        // a key that's held longer than min_hold is released immediately
+        kbd.key_down(b'5' as usize);
+        for _ in 0..4 {
+            kbd.update();
+        }
+        assert!(kbd.is_pressed(b'5' as usize));
+        kbd.key_up(b'5' as usize);
+        assert!(!kbd.is_pressed(b'5' as usize));
+    }
+}
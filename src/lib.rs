@@ -4,8 +4,9 @@
 //! # Overview
 //!
 //! The rz80 library provides chip emulators for the Z80 **CPU**, **PIO** (parallel in/out), **CTC**
-//! (counter/timer channels) and a **Bus** trait which defines how the chips are wired together
-//! in a specific emulated system.
+//! (counter/timer channels), the TMS9918A **VDP** (video display processor), the MC6845 **CRTC**
+//! (video timing), the AY-3-8910 **PSG** (programmable sound generator) and a **Bus** trait
+//! which defines how the chips are wired together in a specific emulated system.
 //!
 //! Writing a home computer emulator usually involves the following steps
 //!
@@ -16,18 +17,40 @@
 //! - write a **System::poweron()** function which initializes the embedded chips and state objects,
 //!   initializes the memory map and sets the CPU PC register to the ROM dump start address
 //! - write a **video-decoder** function which generates a linear RGBA8 framebuffer each frame
+//!   (for simple character-based displays, the **TextDisplay** helper does this)
 //! - implement the **Bus trait** on the System struct, this usually involves:
-//!     - the keyboard emulation
+//!     - the keyboard emulation (the **Keyboard** helper implements a generic keyboard matrix)
 //!     - memory bank switching
 //!     - forward interrupt requests between the various hardware components
 //!     - sound generation
+//!     - cassette tape input and output (the **Tape** and **TapeRecorder** helpers play back
+//!       and record tape signals, and convert them from and to WAV and program files)
 //! - implement the **main loop** which creates a window, forwards keyboard input,
-//!   and steps the chips emulators forward
+//!   and steps the chips emulators forward (the **Scheduler** keeps the absolute
+//!   cycle count and dispatches timed device events, for instance the end of a video
+//!   frame, so that devices don't need to be polled after each instruction)
 //!
 //! Very simple 8-bit home computer systems (similar to the ZX81) don't require any additional
 //! code, more complex home computers will require additional custom chips emulations that
 //! are not part of the rz80 library.
 //!
+//! For debugging, **disassemble()** decodes single instructions and a **Tracer**
+//! attached to the CPU logs each executed instruction with the complete CPU state. The
+//! **TraceChecker** steps the CPU alongside a reference trace and stops at the first difference.
+//! **Rewind** records the execution history, so that the CPU can step backward in time.
+//!
+//! The library also contains complete machine emulations which can be driven by host
+//! code without writing any system glue code:
+//!
+//! - **KC87**: the East German KC87 (aka Z9001) home computer
+//! - **Z1013**: the East German Z1013 home computer kit (all models)
+//! - **ZXSpectrum**: the Sinclair ZX Spectrum 48K and 128
+//! - **CPM**: a CP/M 2.2 runtime which runs .COM programs with an emulated BDOS and BIOS
+//!
+//! The machines implement the **Machine** trait, an **InputRecorder** logs host input
+//! (keys, joystick, tape) with the CPU cycle count, and an **InputPlayer** replays such
+//! a recording bit-identically, independent from the host frame timing.
+//!
 //! Check out the two included example emulators:
 //!
 //! ```bash
@@ -46,11 +69,58 @@ mod cpu;
 mod pio;
 mod ctc;
 mod daisychain;
+mod vdp;
+mod crtc;
+mod keyboard;
+mod textdisplay;
+mod tape;
+mod beeper;
+mod psg;
+mod kc87;
+mod z1013;
+mod zx;
+mod cpm;
+mod disasm;
+mod trace;
+mod tracecheck;
+mod rewind;
+mod input;
+mod scheduler;
 
 pub use registers::{Registers, CF, NF, VF, PF, XF, HF, YF, ZF, SF};
 pub use memory::Memory;
-pub use cpu::CPU;
-pub use bus::Bus;
+pub use cpu::{CPU, CPUSnapshot, TrapAction};
+pub use disasm::disassemble;
+pub use trace::{Tracer, TraceFormat};
+pub use tracecheck::{TraceChecker, TraceMismatch};
+pub use rewind::Rewind;
+pub use scheduler::Scheduler;
+pub use input::{Machine, InputEvent, InputLog, InputRecorder, InputPlayer, replay};
+pub use bus::{Bus, BusCycle};
 pub use pio::{PIO, PIO_A, PIO_B};
 pub use ctc::{CTC, CTC_0, CTC_1, CTC_2, CTC_3};
 pub use daisychain::Daisychain;
+pub use vdp::{VDP, ScreenMode, VDP_DISPLAY_WIDTH, VDP_DISPLAY_HEIGHT,
+              VDP_CYCLES_PER_FRAME_NTSC, VDP_CYCLES_PER_FRAME_PAL, VDP_STATUS_INT,
+              VDP_STATUS_5S, VDP_STATUS_C, VDP_STATUS_5S_MASK};
+pub use crtc::{CRTC, CRTC_H_TOTAL, CRTC_H_DISPLAYED, CRTC_H_SYNC_POS, CRTC_SYNC_WIDTHS,
+               CRTC_V_TOTAL, CRTC_V_TOTAL_ADJUST, CRTC_V_DISPLAYED, CRTC_V_SYNC_POS,
+               CRTC_INTERLACE_MODE, CRTC_MAX_SCANLINE_ADDR, CRTC_CURSOR_START, CRTC_CURSOR_END,
+               CRTC_START_ADDR_HI, CRTC_START_ADDR_LO, CRTC_CURSOR_HI, CRTC_CURSOR_LO,
+               CRTC_LIGHTPEN_HI, CRTC_LIGHTPEN_LO};
+pub use keyboard::{Keyboard, KBD_MAX_COLUMNS, KBD_MAX_LINES, KBD_MAX_KEYS};
+pub use textdisplay::{TextDisplay, MONO_PALETTE, decode_attr_blink};
+pub use tape::{Tape, TapeRecorder, ProgramFile, KC_TAP_SIGNATURE, wav_to_pulses, pulses_to_wav,
+               z1013_encode, z1013_decode, kc_encode, kc_decode};
+pub use beeper::Beeper;
+pub use psg::{PSG, PSG_PERIOD_A_FINE, PSG_PERIOD_A_COARSE, PSG_PERIOD_B_FINE, PSG_PERIOD_B_COARSE,
+              PSG_PERIOD_C_FINE, PSG_PERIOD_C_COARSE, PSG_PERIOD_NOISE, PSG_ENABLE, PSG_AMP_A,
+              PSG_AMP_B, PSG_AMP_C, PSG_ENV_PERIOD_FINE, PSG_ENV_PERIOD_COARSE, PSG_ENV_SHAPE,
+              PSG_IO_PORT_A, PSG_IO_PORT_B};
+pub use kc87::{KC87, KC87_FREQ_KHZ, KC87_DISPLAY_WIDTH, KC87_DISPLAY_HEIGHT};
+pub use z1013::{Z1013, Z1013Snapshot, Z1013Model, Z1013Monitor, Z1013_DISPLAY_WIDTH,
+                Z1013_DISPLAY_HEIGHT};
+pub use zx::{ZXSpectrum, ZXModel, ZX_DISPLAY_WIDTH, ZX_DISPLAY_HEIGHT, ZX_JOY_RIGHT, ZX_JOY_LEFT,
+             ZX_JOY_DOWN, ZX_JOY_UP, ZX_JOY_FIRE};
+pub use cpm::{CPM, CPMStatus, CPM_TPA_ADDR, CPM_BDOS_ADDR, CPM_BIOS_ADDR, CPM_NUM_DRIVES,
+              CPM_DISK_IMAGE_SIZE};
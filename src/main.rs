@@ -0,0 +1,658 @@
+//
+// rz80 command-line runner
+//
+// Loads a ROM or program into a 64 KByte Z80 system without any
+// other hardware, runs it headless and prints the CPU registers
+// when it stops. Console I/O is either mapped to I/O ports, or to
+// the CP/M BDOS when running .COM programs.
+//
+
+extern crate rz80;
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Stdin, Write};
+use std::path::Path;
+use std::process;
+use rz80::{RegT, Bus, CPU, CPM, CPMStatus, Rewind, Tracer, TraceFormat, TraceChecker, TraceMismatch};
+
+static USAGE: &'static str = "\
+usage: rz80 [options] FILE [ARGS...]
+
+Loads FILE (raw binary, Intel HEX or CP/M .COM) and runs it headless.
+The file format is selected by the file extension (.hex/.ihx, .com,
+everything else is raw binary). ARGS are passed to CP/M programs as
+command line.
+
+options:
+  --format FMT      file format: raw, hex or com
+  --load ADDR       load address for raw files (default: 0x0000)
+  --start ADDR      start address (default: load address)
+  --sp ADDR         initial stack pointer (default: 0x0000)
+  --rom             map the loaded file as read-only memory
+  --cpm             run with the CP/M BDOS (default for .com files)
+  --out-port PORT   console output port (bytes are written to stdout)
+  --in-port PORT    console input port (bytes are read from stdin)
+  --status-port PORT  console status port (0xFF if input is available)
+  --max-cycles N    stop after N cycles
+  --break ADDR      stop when ADDR is about to be executed (repeatable)
+  --quiet           don't print the registers on exit
+  --trace FILE      log each executed instruction to FILE ('-' for stderr)
+  --trace-format FMT  trace line format: full (default), mame or doctor
+  --trace-range START:END  only trace instructions in an address range (repeatable)
+  --trace-from ADDR start tracing when ADDR is reached
+  --trace-to ADDR   stop tracing when ADDR is reached
+  --trace-limit N   stop tracing after N lines
+  --check-trace FILE  compare each instruction against a reference trace and
+                    stop at the first difference
+  --trace-context N number of reference lines shown before a difference (default: 5)
+  --last-write ADDR when stopped, step back through the execution history to the
+                    last instruction which changed the byte at ADDR (not for CP/M)
+
+Numbers are decimal, or hexadecimal with a 0x prefix or h suffix.
+The exit code is 0 if the program stopped on HALT, a breakpoint,
+returned to CP/M or reached the end of the reference trace, 2 if the
+cycle limit was reached, and 1 on errors and trace differences.";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Format {
+    Raw,
+    Hex,
+    Com,
+}
+
+#[derive(Debug)]
+struct Options {
+    path: String,
+    args: Vec<String>,
+    format: Option<Format>,
+    load_addr: RegT,
+    start_addr: Option<RegT>,
+    sp: RegT,
+    rom: bool,
+    cpm: bool,
+    out_port: Option<RegT>,
+    in_port: Option<RegT>,
+    status_port: Option<RegT>,
+    max_cycles: Option<i64>,
+    breakpoints: Vec<RegT>,
+    quiet: bool,
+    trace: Option<String>,
+    trace_format: TraceFormat,
+    trace_ranges: Vec<(RegT, RegT)>,
+    trace_from: Option<RegT>,
+    trace_to: Option<RegT>,
+    trace_limit: Option<usize>,
+    check_trace: Option<String>,
+    trace_context: usize,
+    last_write: Option<RegT>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum StopReason {
+    Halt,
+    Breakpoint,
+    Exit,
+    CycleLimit,
+    TraceEnd,
+}
+
+// execution history for --last-write, a snapshot every 10000 instructions
+// and 100 snapshots (each holds a copy of the 64 KByte memory heap), so that the
+// last million instructions can be searched
+const REWIND_INTERVAL: u64 = 10000;
+const REWIND_SNAPSHOTS: usize = 100;
+
+/// parse a decimal or hexadecimal number ('0x1234' or '1234h')
+fn parse_number(s: &str) -> Result<i64, String> {
+    let lower = s.to_lowercase();
+    let res = if lower.starts_with("0x") {
+        i64::from_str_radix(&lower[2..], 16)
+    } else if lower.ends_with('h') {
+        i64::from_str_radix(&lower[..lower.len() - 1], 16)
+    } else {
+        lower.parse::<i64>()
+    };
+    res.map_err(|_| format!("invalid number '{}'", s))
+}
+
+/// parse a 16-bit address or 8-bit port number
+fn parse_addr(s: &str, max: i64) -> Result<RegT, String> {
+    let val = parse_number(s)?;
+    if val < 0 || val > max {
+        return Err(format!("value '{}' out of range", s));
+    }
+    Ok(val as RegT)
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut opts = Options {
+        path: String::new(),
+        args: Vec::new(),
+        format: None,
+        load_addr: 0,
+        start_addr: None,
+        sp: 0,
+        rom: false,
+        cpm: false,
+        out_port: None,
+        in_port: None,
+        status_port: None,
+        max_cycles: None,
+        breakpoints: Vec::new(),
+        quiet: false,
+        trace: None,
+        trace_format: TraceFormat::Full,
+        trace_ranges: Vec::new(),
+        trace_from: None,
+        trace_to: None,
+        trace_limit: None,
+        check_trace: None,
+        trace_context: 5,
+        last_write: None,
+    };
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if !opts.path.is_empty() {
+            opts.args.push(arg.clone());
+            continue;
+        }
+        let mut value = || iter.next().ok_or(format!("missing value for '{}'", arg));
+        match arg.as_str() {
+            "--format" => {
+                opts.format = Some(match value()?.as_str() {
+                    "raw" => Format::Raw,
+                    "hex" => Format::Hex,
+                    "com" => Format::Com,
+                    fmt => return Err(format!("unknown format '{}'", fmt)),
+                })
+            }
+            "--load" => opts.load_addr = parse_addr(value()?, 0xFFFF)?,
+            "--start" => opts.start_addr = Some(parse_addr(value()?, 0xFFFF)?),
+            "--sp" => opts.sp = parse_addr(value()?, 0xFFFF)?,
+            "--rom" => opts.rom = true,
+            "--cpm" => opts.cpm = true,
+            "--out-port" => opts.out_port = Some(parse_addr(value()?, 0xFF)?),
+            "--in-port" => opts.in_port = Some(parse_addr(value()?, 0xFF)?),
+            "--status-port" => opts.status_port = Some(parse_addr(value()?, 0xFF)?),
+            "--max-cycles" => opts.max_cycles = Some(parse_number(value()?)?),
+            "--break" => opts.breakpoints.push(parse_addr(value()?, 0xFFFF)?),
+            "--quiet" => opts.quiet = true,
+            "--trace" => opts.trace = Some(value()?.clone()),
+            "--trace-format" => {
+                opts.trace_format = match value()?.as_str() {
+                    "full" => TraceFormat::Full,
+                    "mame" => TraceFormat::Mame,
+                    "doctor" => TraceFormat::Doctor,
+                    fmt => return Err(format!("unknown trace format '{}'", fmt)),
+                }
+            }
+            "--trace-range" => {
+                let range = value()?;
+                let mut parts = range.splitn(2, ':');
+                let start = parse_addr(parts.next().unwrap_or(""), 0xFFFF)?;
+                let end = match parts.next() {
+                    Some(end) => parse_addr(end, 0xFFFF)?,
+                    None => return Err(format!("invalid range '{}'", range)),
+                };
+                opts.trace_ranges.push((start, end));
+            }
+            "--trace-from" => opts.trace_from = Some(parse_addr(value()?, 0xFFFF)?),
+            "--trace-to" => opts.trace_to = Some(parse_addr(value()?, 0xFFFF)?),
+            "--trace-limit" => opts.trace_limit = Some(parse_number(value()?)? as usize),
+            "--check-trace" => opts.check_trace = Some(value()?.clone()),
+            "--trace-context" => opts.trace_context = parse_number(value()?)? as usize,
+            "--last-write" => opts.last_write = Some(parse_addr(value()?, 0xFFFF)?),
+            "--help" | "-h" => return Err(String::new()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option '{}'", arg)),
+            _ => opts.path = arg.clone(),
+        }
+    }
+    if opts.path.is_empty() {
+        return Err("no file given".to_string());
+    }
+    Ok(opts)
+}
+
+/// parse Intel HEX data into (address, bytes) chunks
+fn parse_hex(text: &str) -> Result<Vec<(RegT, Vec<u8>)>, String> {
+    let mut chunks = Vec::new();
+    for (num, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = || format!("invalid HEX record in line {}", num + 1);
+        if !line.starts_with(':') || line.len() < 11 || (line.len() & 1) == 0 {
+            return Err(err());
+        }
+        let bytes = (1..line.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&line[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| err())?;
+        let len = bytes[0] as usize;
+        if bytes.len() != len + 5 || bytes.iter().fold(0u8, |s, b| s.wrapping_add(*b)) != 0 {
+            return Err(err());
+        }
+        let addr = ((bytes[1] as RegT) << 8) | bytes[2] as RegT;
+        match bytes[3] {
+            0x00 => chunks.push((addr, bytes[4..4 + len].to_vec())),
+            0x01 => break,
+            // segment and linear address records don't matter in a 64 KByte address space
+            0x02..=0x05 => (),
+            _ => return Err(err()),
+        }
+    }
+    Ok(chunks)
+}
+
+/// the system bus, maps console I/O to ports
+struct System {
+    opts: Options,
+    stdin: RefCell<BufReader<Stdin>>,
+}
+
+impl System {
+    /// peek at the next stdin byte (blocks until input is available or EOF)
+    fn input_available(&self) -> bool {
+        self.stdin.borrow_mut().fill_buf().map(|buf| !buf.is_empty()).unwrap_or(false)
+    }
+}
+
+impl Bus for System {
+    fn cpu_outp(&self, port: RegT, val: RegT) {
+        if Some(port & 0xFF) == self.opts.out_port {
+            let mut stdout = io::stdout();
+            let _ = stdout.write_all(&[val as u8]);
+            let _ = stdout.flush();
+        }
+    }
+
+    fn cpu_inp(&self, port: RegT) -> RegT {
+        let port = port & 0xFF;
+        if Some(port) == self.opts.in_port {
+            let mut buf = [0u8; 1];
+            match self.stdin.borrow_mut().read(&mut buf) {
+                Ok(1) => buf[0] as RegT,
+                _ => 0x1A,
+            }
+        } else if Some(port) == self.opts.status_port {
+            if self.input_available() { 0xFF } else { 0x00 }
+        } else {
+            0xFF
+        }
+    }
+}
+
+fn print_registers(cpu: &CPU, cycles: i64, reason: StopReason) {
+    let r = &cpu.reg;
+    eprintln!("stopped: {:?} after {} cycles", reason, cycles);
+    eprintln!("PC={:04X} SP={:04X} AF={:04X} BC={:04X} DE={:04X} HL={:04X} IX={:04X} IY={:04X}",
+              r.pc(), r.sp(), r.af(), r.bc(), r.de(), r.hl(), r.ix(), r.iy());
+    eprintln!("AF'={:04X} BC'={:04X} DE'={:04X} HL'={:04X} WZ={:04X} I={:02X} R={:02X} IM={} \
+               IFF1={} IFF2={}",
+              r.af_(), r.bc_(), r.de_(), r.hl_(), r.wz(), r.i, r.r, r.im,
+              cpu.iff1 as u8, cpu.iff2 as u8);
+}
+
+/// create the execution trace logger from the --trace options
+fn make_tracer(opts: &Options) -> Result<Option<Tracer>, String> {
+    let path = match opts.trace {
+        Some(ref path) => path,
+        None => return Ok(None),
+    };
+    let mut tracer = if path == "-" {
+        Tracer::new(io::stderr(), opts.trace_format)
+    } else {
+        let file = File::create(path)
+            .map_err(|err| format!("unable to create trace file '{}': {}", path, err))?;
+        Tracer::new(BufWriter::new(file), opts.trace_format)
+    };
+    for &(start, end) in &opts.trace_ranges {
+        tracer.add_range(start, end);
+    }
+    if let Some(addr) = opts.trace_from {
+        tracer.set_trigger(addr);
+    }
+    if let Some(addr) = opts.trace_to {
+        tracer.set_stop(addr);
+    }
+    if let Some(limit) = opts.trace_limit {
+        tracer.set_limit(limit);
+    }
+    Ok(Some(tracer))
+}
+
+/// detach the tracer from the CPU and flush the trace output
+fn finish_trace(cpu: &mut CPU) -> Result<(), String> {
+    if let Some(mut tracer) = cpu.take_tracer() {
+        let res = match tracer.take_error() {
+            Some(err) => Err(err),
+            None => tracer.flush(),
+        };
+        res.map_err(|err| format!("unable to write trace: {}", err))?;
+    }
+    Ok(())
+}
+
+/// create the reference trace checker from the --check-trace option
+fn make_checker(opts: &Options) -> Result<Option<TraceChecker>, String> {
+    match opts.check_trace {
+        Some(ref path) => {
+            let file = File::open(path)
+                .map_err(|err| format!("unable to open reference trace '{}': {}", path, err))?;
+            Ok(Some(TraceChecker::new(BufReader::new(file), opts.trace_context)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// execute one instruction, checked against the reference trace if there is one,
+/// returns None at the end of the reference trace or on a difference
+fn checked_step<F: FnMut() -> i64>(cpu: &RefCell<CPU>,
+                                   checker: &mut Option<TraceChecker>,
+                                   mismatch: &mut Option<TraceMismatch>,
+                                   mut step: F)
+                                   -> Option<i64> {
+    let checker = match *checker {
+        Some(ref mut checker) => checker,
+        None => return Some(step()),
+    };
+    let more = checker.check(&cpu.borrow());
+    let res = more.and_then(|more| {
+        if more {
+            let cycles = step();
+            checker.check_after(&cpu.borrow(), cycles).map(|_| Some(cycles))
+        } else {
+            Ok(None)
+        }
+    });
+    match res {
+        Ok(cycles) => cycles,
+        Err(err) => {
+            *mismatch = Some(err);
+            None
+        }
+    }
+}
+
+/// turn the result of a checked run into a stop reason or error
+fn check_result(reason: StopReason,
+                checker: &Option<TraceChecker>,
+                mismatch: Option<TraceMismatch>)
+                -> Result<StopReason, String> {
+    if let Some(mismatch) = mismatch {
+        return Err(mismatch.report().trim_end().to_string());
+    }
+    if checker.is_some() && reason == StopReason::Exit {
+        Ok(StopReason::TraceEnd)
+    } else {
+        Ok(reason)
+    }
+}
+
+fn load_file(path: &str) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut data))
+        .map_err(|err| format!("unable to load '{}': {}", path, err))?;
+    Ok(data)
+}
+
+/// run until HALT, a breakpoint or the cycle limit, step() returns None when the program exited
+fn run<F: FnMut() -> Option<i64>>(cpu: &RefCell<CPU>, opts: &Options, mut step: F) -> (i64, StopReason) {
+    let mut cycles = 0;
+    loop {
+        if opts.max_cycles.map_or(false, |max| cycles >= max) {
+            return (cycles, StopReason::CycleLimit);
+        }
+        match step() {
+            Some(c) => cycles += c,
+            None => return (cycles, StopReason::Exit),
+        }
+        let cpu = cpu.borrow();
+        if cpu.halt {
+            return (cycles, StopReason::Halt);
+        }
+        if opts.breakpoints.contains(&cpu.reg.pc()) {
+            return (cycles, StopReason::Breakpoint);
+        }
+    }
+}
+
+fn run_main(opts: Options) -> Result<StopReason, String> {
+    let data = load_file(&opts.path)?;
+    let ext = Path::new(&opts.path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default();
+    let format = opts.format.unwrap_or(match ext.as_str() {
+        "hex" | "ihx" => Format::Hex,
+        "com" => Format::Com,
+        _ => Format::Raw,
+    });
+
+    let tracer = make_tracer(&opts)?;
+    let mut checker = make_checker(&opts)?;
+    let mut mismatch = None;
+    if opts.cpm || format == Format::Com {
+        if opts.last_write.is_some() {
+            return Err("--last-write is not supported for CP/M programs".to_string());
+        }
+        // run as CP/M program, with the current directory as drive A:
+        let cpm = CPM::new();
+        cpm.mount_dir(0, Path::new("."));
+        cpm.set_echo(true);
+        cpm.load_com(&data, &opts.args.join(" "));
+        if let Some(tracer) = tracer {
+            cpm.cpu.borrow_mut().set_tracer(tracer);
+        }
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+        let (cycles, reason) = run(&cpm.cpu, &opts, || {
+            loop {
+                match cpm.status() {
+                    CPMStatus::Running => {
+                        return checked_step(&cpm.cpu, &mut checker, &mut mismatch, || cpm.step())
+                    }
+                    CPMStatus::Exited => return None,
+                    CPMStatus::WaitingForInput => {
+                        match lines.next() {
+                            Some(Ok(line)) => {
+                                cpm.input(line.as_bytes());
+                                cpm.input(b"\r");
+                            }
+                            _ => return None,
+                        }
+                    }
+                }
+            }
+        });
+        finish_trace(&mut cpm.cpu.borrow_mut())?;
+        let reason = check_result(reason, &checker, mismatch)?;
+        if !opts.quiet {
+            print_registers(&cpm.cpu.borrow(), cycles, reason);
+        }
+        return Ok(reason);
+    }
+
+    let cpu = RefCell::new(CPU::new_64k());
+    let chunks = if format == Format::Hex {
+        let text = String::from_utf8(data).map_err(|_| "HEX file is not text".to_string())?;
+        parse_hex(&text)?
+    } else {
+        vec![(opts.load_addr, data)]
+    };
+    {
+        let mut cpu = cpu.borrow_mut();
+        for &(addr, ref bytes) in &chunks {
+            if addr as usize + bytes.len() > 0x10000 {
+                return Err("program doesn't fit into memory".to_string());
+            }
+            cpu.mem.write(addr, bytes);
+            if opts.rom {
+                // the 64 KByte RAM is mapped 1:1 to the heap, remap the pages read-only
+                let start = addr as usize & !0x3FF;
+                let end = (addr as usize + bytes.len() + 0x3FF) & !0x3FF;
+                cpu.mem.map(0, start, start, false, end - start);
+            }
+        }
+        let start = opts.start_addr.unwrap_or(chunks.first().map_or(0, |c| c.0));
+        cpu.reg.set_pc(start);
+        cpu.reg.set_sp(opts.sp);
+        if let Some(tracer) = tracer {
+            cpu.set_tracer(tracer);
+        }
+    }
+    let system = System {
+        stdin: RefCell::new(BufReader::new(io::stdin())),
+        opts: opts,
+    };
+    // the console ports are the only devices, so the CPU and memory
+    // snapshots of the execution history cover the whole system
+    let mut rewind = system.opts.last_write.map(|_| Rewind::new(REWIND_INTERVAL, REWIND_SNAPSHOTS));
+    let (cycles, reason) = run(&cpu, &system.opts, || {
+        checked_step(&cpu, &mut checker, &mut mismatch, || match rewind {
+            Some(ref mut rewind) => rewind.step(&mut cpu.borrow_mut(), &system),
+            None => cpu.borrow_mut().step(&system),
+        })
+    });
+    finish_trace(&mut cpu.borrow_mut())?;
+    let reason = check_result(reason, &checker, mismatch)?;
+    if let (Some(addr), Some(ref mut rewind)) = (system.opts.last_write, rewind) {
+        eprintln!("{}", last_write(rewind, &mut cpu.borrow_mut(), addr));
+    }
+    if !system.opts.quiet {
+        print_registers(&cpu.borrow(), cycles, reason);
+    }
+    Ok(reason)
+}
+
+/// find the last instruction in the execution history which changed
+/// the byte at an address, and return to the most recent position
+fn last_write(rewind: &mut Rewind, cpu: &mut CPU, addr: RegT) -> String {
+    let head = rewind.head();
+    rewind.add_watchpoint(addr);
+    let res = match rewind.reverse_continue(cpu) {
+        Some(pos) => {
+            let val = cpu.mem.r8(addr);
+            rewind.step_back(cpu);
+            format!("last write to {:04X}: {:02X} by instruction {} at PC={:04X}",
+                    addr, val, pos, cpu.reg.pc())
+        }
+        None => format!("last write to {:04X}: not in the last {} instructions",
+                        addr, head - rewind.oldest()),
+    };
+    rewind.clear_breakpoints();
+    rewind.seek(cpu, head);
+    res
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let opts = match parse_args(&args) {
+        Ok(opts) => opts,
+        Err(err) => {
+            if !err.is_empty() {
+                eprintln!("rz80: {}", err);
+            }
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+    };
+    match run_main(opts) {
+        Ok(StopReason::CycleLimit) => process::exit(2),
+        Ok(_) => (),
+        Err(err) => {
+            eprintln!("rz80: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn options() {
+        let opts = parse_args(&args("--load 0x100 --break 1234h --break 10 --out-port 1 \
+                                     --max-cycles 1000 test.bin a b")).unwrap();
+        assert_eq!("test.bin", opts.path);
+        assert_eq!(vec!["a", "b"], opts.args);
+        assert_eq!(0x100, opts.load_addr);
+        assert_eq!(vec![0x1234, 10], opts.breakpoints);
+        assert_eq!(Some(1), opts.out_port);
+        assert_eq!(Some(1000), opts.max_cycles);
+        assert!(parse_args(&args("--load")).is_err());
+        assert!(parse_args(&args("--out-port 0x100 test.bin")).is_err());
+        assert!(parse_args(&args("--load 0x100")).is_err());
+
+        let opts = parse_args(&args("--trace - --trace-format mame --trace-range 0x100:0x1FF \
+                                     --trace-from 0x100 --trace-limit 10 test.bin")).unwrap();
+        assert_eq!(Some("-".to_string()), opts.trace);
+        assert_eq!(TraceFormat::Mame, opts.trace_format);
+        assert_eq!(vec![(0x100, 0x1FF)], opts.trace_ranges);
+        assert_eq!(Some(0x100), opts.trace_from);
+        assert_eq!(Some(10), opts.trace_limit);
+        assert!(parse_args(&args("--trace-range 0x100 test.bin")).is_err());
+        assert!(parse_args(&args("--trace-format xyz test.bin")).is_err());
+
+        let opts = parse_args(&args("--last-write 0x4000 test.bin")).unwrap();
+        assert_eq!(Some(0x4000), opts.last_write);
+    }
+
+    #[test]
+    fn hex() {
+        let text = ":0300300002337A1E\n:02003300AABB66\n:00000001FF\n";
+        let chunks = parse_hex(text).unwrap();
+        assert_eq!(vec![(0x30, vec![0x02, 0x33, 0x7A]), (0x33, vec![0xAA, 0xBB])], chunks);
+        assert!(parse_hex(":0300300002337A1F\n").is_err());
+        assert!(parse_hex("0300300002337A1E\n").is_err());
+    }
+
+    #[test]
+    fn breakpoints() {
+        let opts = parse_args(&args("--break 0x0003 test.bin")).unwrap();
+        let cpu = RefCell::new(CPU::new_64k());
+        cpu.borrow_mut().mem.write(0, &[0x00, 0x00, 0x00, 0x00, 0x76]);
+        let system = System {
+            stdin: RefCell::new(BufReader::new(io::stdin())),
+            opts: opts,
+        };
+        let step = || Some(cpu.borrow_mut().step(&system));
+        assert_eq!((12, StopReason::Breakpoint), run(&cpu, &system.opts, step));
+        let step = || Some(cpu.borrow_mut().step(&system));
+        assert_eq!((8, StopReason::Halt), run(&cpu, &system.opts, step));
+    }
+
+    #[test]
+    fn last_writes() {
+        let mut cpu = CPU::new_64k();
+        // LD A,5; LD (4000h),A; INC A; LD (4001h),A; HALT
+        cpu.mem.write(0, &[0x3E, 0x05, 0x32, 0x00, 0x40, 0x3C, 0x32, 0x01, 0x40, 0x76]);
+        let opts = parse_args(&args("test.bin")).unwrap();
+        let system = System {
+            stdin: RefCell::new(BufReader::new(io::stdin())),
+            opts: opts,
+        };
+        let mut rewind = Rewind::new(2, 16);
+        while !cpu.halt {
+            rewind.step(&mut cpu, &system);
+        }
+        assert_eq!("last write to 4000: 05 by instruction 2 at PC=0002",
+                   last_write(&mut rewind, &mut cpu, 0x4000));
+        assert_eq!("last write to 4002: not in the last 5 instructions",
+                   last_write(&mut rewind, &mut cpu, 0x4002));
+        // back at the most recent position
+        assert_eq!(5, rewind.position());
+        assert!(cpu.halt);
+    }
+}
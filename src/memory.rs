@@ -1,9 +1,12 @@
 use std::mem;
+use std::cell::RefCell;
 use RegT;
+use bus::BusCycle;
 
 const PAGE_SHIFT: usize = 10;   // 1 kByte page size = (1<<10)
 const PAGE_SIZE: usize = (1 << PAGE_SHIFT);
 const PAGE_MASK: usize = PAGE_SIZE - 1;
+// default heap size of Memory::new(), see Memory::with_heap_size()
 const HEAP_SIZE: usize = 128 * PAGE_SIZE;
 const NUM_PAGES: usize = (1 << 16) / PAGE_SIZE;
 const NUM_LAYERS: usize = 4;
@@ -75,9 +78,22 @@ impl Page {
 /// ## The Heap
 ///
 /// The Memory class will never keep references to external memory, instead it
-/// comes with it's own few hundred KBytes of embedded memory which is used
-/// as 'heap'. A single memory page maps 1 KByte of memory from the Z80
-/// address range to 1 KByte of memory somewhere on the embedded heap.
+/// comes with it's own embedded memory which is used as 'heap'. A single
+/// memory page maps 1 KByte of memory from the Z80 address range to 1 KByte
+/// of memory somewhere on the embedded heap. The heap size is fixed when the
+/// Memory object is created: 128 KBytes for **new()**, 64 KBytes for
+/// **new_64k()**, or any multiple of 1 KByte with **with_heap_size()**.
+/// Each Memory object (for instance each snapshot in a rewind history)
+/// holds a complete copy of its heap.
+///
+/// ```
+/// use rz80::Memory;
+/// // 8 RAM banks of 16 KBytes
+/// let mut mem = Memory::with_heap_size(8 * 0x4000);
+/// mem.map(0, 7 * 0x4000, 0xC000, true, 0x4000);
+/// mem.w8(0xC000, 0x12);
+/// assert_eq!(mem.heap[7 * 0x4000], 0x12);
+/// ```
 ///
 /// ## Mapping Memory
 ///
@@ -183,31 +199,75 @@ impl Page {
 /// assert_eq!(mem.r8(0x0100), 1);
 /// assert_eq!(mem.r8(0x0101), 2);
 /// assert_eq!(mem.r8(0x0102), 3);
+/// ```
+///
+/// ## Wait States
+///
+/// Slow memory can be given extra wait states per page, separately for
+/// opcode fetches (M1), reads and writes. While wait states are set (or
+/// bus cycle recording is switched on), the memory records the CPU bus
+/// cycles of each instruction, and the CPU adds the wait states to the
+/// instruction's cycle count. Only the accesses inside CPU::step() are
+/// recorded, reading memory from host code (for instance to decode the
+/// video memory) doesn't add bus cycles:
 ///
 /// ```
+/// use rz80::{Memory, BusCycle};
+/// let mut mem = Memory::new_64k();
 ///
+/// // one extra wait state for all accesses to the upper 32 KBytes
+/// mem.set_wait_states(0x8000, 0x8000, 1, 1, 1);
+/// assert_eq!(mem.wait_states(BusCycle::MemRead, 0x9000), 1);
+/// assert_eq!(mem.wait_states(BusCycle::MemRead, 0x1000), 0);
+///
+/// ```
+///
+#[derive(Clone)]
 pub struct Memory {
     /// currently CPU-visible pages
     pages: [Page; NUM_PAGES],
     /// currently mapped layers
     layers: [[Page; NUM_PAGES]; NUM_LAYERS],
     /// 'host' memory
-    pub heap: [u8; HEAP_SIZE],
+    pub heap: Box<[u8]>,
+    /// extra wait states of CPU-visible pages for M1, read and write cycles
+    wait_states: [[u8; 3]; NUM_PAGES],
+    /// true if any wait states are set
+    has_wait_states: bool,
+    /// true if bus cycles are recorded without wait states
+    record_all: bool,
+    /// true while the CPU executes an instruction with bus cycle recording
+    recording: bool,
+    /// bus cycles recorded since the last begin_cycles()
+    cycles: RefCell<Vec<(BusCycle, RegT)>>,
 }
 
 impl Memory {
-    /// return new, unmapped memory object
+    /// return new, unmapped memory object with a 128 KByte heap
     pub fn new() -> Memory {
+        Memory::with_heap_size(HEAP_SIZE)
+    }
+
+    /// return new, unmapped memory object with a heap size in bytes
+    /// (a multiple of 1 KByte)
+    pub fn with_heap_size(heap_size: usize) -> Memory {
+        assert_eq!((heap_size & PAGE_MASK), 0);
         Memory {
             pages: [Page::new(); NUM_PAGES],
             layers: [[Page::new(); NUM_PAGES]; NUM_LAYERS],
-            heap: [0; HEAP_SIZE],
+            heap: vec![0; heap_size].into_boxed_slice(),
+            wait_states: [[0; 3]; NUM_PAGES],
+            has_wait_states: false,
+            record_all: false,
+            recording: false,
+            cycles: RefCell::new(Vec::new()),
         }
     }
 
-    /// return new memory object with 64 kByte mapped, writable memory (for testing)
+    /// return new memory object with 64 kByte mapped, writable memory (for testing),
+    /// the heap is 64 KBytes
     pub fn new_64k() -> Memory {
-        let mut mem = Memory::new();
+        let mut mem = Memory::with_heap_size(1 << 16);
         mem.map(0, 0, 0, true, (1 << 16));
         mem
     }
@@ -298,9 +358,94 @@ impl Memory {
         }
     }
 
+    /// set extra wait states for M1, read and write cycles of a CPU address range
+    pub fn set_wait_states(&mut self, addr: usize, size: usize, m1: u8, read: u8, write: u8) {
+        assert_eq!((size & PAGE_MASK), 0);
+        assert_eq!((addr & PAGE_MASK), 0);
+        let num = size >> PAGE_SHIFT;
+        for i in 0..num {
+            let page_index = ((addr + i * PAGE_SIZE) & 0xFFFF) >> PAGE_SHIFT;
+            self.wait_states[page_index] = [m1, read, write];
+        }
+        self.has_wait_states = self.wait_states.iter().any(|w| *w != [0; 3]);
+    }
+
+    /// remove all wait states
+    pub fn clear_wait_states(&mut self) {
+        self.wait_states = [[0; 3]; NUM_PAGES];
+        self.has_wait_states = false;
+    }
+
+    /// the extra wait states of a bus cycle (always 0 for I/O cycles)
+    #[inline(always)]
+    pub fn wait_states(&self, cycle: BusCycle, addr: RegT) -> i64 {
+        let page = &self.wait_states[(addr & 0xFFFF) as usize >> PAGE_SHIFT];
+        match cycle {
+            BusCycle::M1 => page[0] as i64,
+            BusCycle::MemRead => page[1] as i64,
+            BusCycle::MemWrite => page[2] as i64,
+            _ => 0,
+        }
+    }
+
+    /// switch recording of bus cycles on or off (it is also on while wait states are set)
+    pub fn set_record_cycles(&mut self, enabled: bool) {
+        self.record_all = enabled;
+    }
+
+    /// return true if bus cycles are recorded
+    #[inline(always)]
+    pub fn is_recording_cycles(&self) -> bool {
+        self.record_all || self.has_wait_states
+    }
+
+    /// record a bus cycle, this is called by the CPU for I/O cycles
+    #[inline(always)]
+    pub fn record_cycle(&self, cycle: BusCycle, addr: RegT) {
+        if self.recording {
+            self.cycles.borrow_mut().push((cycle, addr & 0xFFFF));
+        }
+    }
+
+    /// start recording the bus cycles of an instruction, this is called
+    /// by the CPU, returns false if bus cycles aren't recorded
+    pub fn begin_cycles(&mut self) -> bool {
+        self.recording = self.is_recording_cycles();
+        if self.recording {
+            self.cycles.borrow_mut().clear();
+        }
+        self.recording
+    }
+
+    /// stop recording bus cycles until the next begin_cycles()
+    pub fn end_cycles(&mut self) {
+        self.recording = false;
+    }
+
+    /// call a function for each recorded bus cycle in order, and remove them
+    pub fn drain_cycles<F: FnMut(BusCycle, RegT)>(&self, mut f: F) {
+        for (cycle, addr) in self.cycles.borrow_mut().drain(..) {
+            f(cycle, addr);
+        }
+    }
+
+    /// read an opcode byte from 16-bit address (M1 cycle)
+    #[inline(always)]
+    pub fn fetch8(&self, addr: RegT) -> RegT {
+        self.record_cycle(BusCycle::M1, addr);
+        self.read8(addr)
+    }
+
     /// read unsigned byte from 16-bit address
     #[inline(always)]
     pub fn r8(&self, addr: RegT) -> RegT {
+        self.record_cycle(BusCycle::MemRead, addr);
+        self.read8(addr)
+    }
+
+    /// read unsigned byte without recording a bus cycle
+    #[inline(always)]
+    fn read8(&self, addr: RegT) -> RegT {
         let uaddr = (addr & 0xFFFF) as usize;
         let page = &self.pages[uaddr >> PAGE_SHIFT];
         if page.mapped {
@@ -314,6 +459,7 @@ impl Memory {
     /// read signed byte from 16-bit address
     #[inline(always)]
     pub fn rs8(&self, addr: RegT) -> RegT {
+        self.record_cycle(BusCycle::MemRead, addr);
         let uaddr = (addr & 0xFFFF) as usize;
         let page = &self.pages[uaddr >> PAGE_SHIFT];
         if page.mapped {
@@ -327,6 +473,7 @@ impl Memory {
     /// write unsigned byte to 16-bit address
     #[inline(always)]
     pub fn w8(&mut self, addr: RegT, val: RegT) {
+        self.record_cycle(BusCycle::MemWrite, addr);
         let uaddr = (addr & 0xFFFF) as usize;
         let page = &self.pages[uaddr >> PAGE_SHIFT];
         if page.mapped && page.writable {
@@ -396,6 +543,39 @@ mod tests {
         assert_eq!(mem.r8(0x0000), 0x22);
     }
 
+    #[test]
+    fn heap_size() {
+        assert_eq!(HEAP_SIZE, Memory::new().heap.len());
+        assert_eq!(0x10000, Memory::new_64k().heap.len());
+        let mut mem = Memory::with_heap_size(160 * 1024);
+        assert_eq!(160 * 1024, mem.heap.len());
+        // the last 16 KBytes of the heap can be mapped
+        mem.map(0, 160 * 1024 - 0x4000, 0xC000, true, 0x4000);
+        mem.w8(0xFFFF, 0x33);
+        assert_eq!(0x33, mem.heap[160 * 1024 - 1]);
+        // snapshots only copy the heap of their own size
+        assert_eq!(160 * 1024, mem.clone().heap.len());
+    }
+
+    #[test]
+    fn record_cycles() {
+        let mut mem = Memory::new_64k();
+        mem.set_wait_states(0x0000, 0x0400, 1, 1, 1);
+        // accesses outside of a CPU instruction are not recorded
+        mem.w8(0x0010, 0x12);
+        assert_eq!(0x12, mem.r8(0x0010));
+        assert!(mem.begin_cycles());
+        assert_eq!(0x12, mem.fetch8(0x0010));
+        mem.w8(0x0011, 0x34);
+        mem.end_cycles();
+        assert_eq!(0x34, mem.r8(0x0011));
+        let mut cycles = Vec::new();
+        mem.drain_cycles(|cycle, addr| cycles.push((cycle, addr)));
+        assert_eq!(vec![(BusCycle::M1, 0x0010), (BusCycle::MemWrite, 0x0011)], cycles);
+        mem.clear_wait_states();
+        assert!(!mem.begin_cycles());
+    }
+
     #[test]
     fn mem_map() {
         let mut mem = Memory::new();
@@ -45,6 +45,7 @@ struct Channel {
 }
 
 /// Z80 PIO emulation
+#[derive(Clone)]
 pub struct PIO {
     id: usize, // id of PIO (needed for systems with multiple ids)
     chn: [Channel; NUM_CHANNELS],
@@ -209,7 +210,8 @@ impl PIO {
 
     /// write data from peripheral device into PIO
     pub fn write(&mut self, bus: &Bus, chn: usize, data: RegT) {
-        let mut c = self.chn[chn];
+        let id = self.id;
+        let c = &mut self.chn[chn];
         if c.mode == Mode::Bitcontrol {
             c.input = data as u8;
             let mask = !c.int_mask;
@@ -221,7 +223,7 @@ impl PIO {
                          ((ictrl == 0x60) && (val == mask));
 
             if !c.bctrl_match && bmatch && (0 != (c.int_control & INTCTRL_ENABLE_INT)) {
-                bus.pio_irq(self.id, chn, c.int_vector as RegT);
+                bus.pio_irq(id, chn, c.int_vector as RegT);
             }
             c.bctrl_match = bmatch;
         }
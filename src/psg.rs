@@ -0,0 +1,412 @@
+/// PSG register: channel A tone period, fine
+pub const PSG_PERIOD_A_FINE: usize = 0;
+/// PSG register: channel A tone period, coarse (4 bits)
+pub const PSG_PERIOD_A_COARSE: usize = 1;
+/// PSG register: channel B tone period, fine
+pub const PSG_PERIOD_B_FINE: usize = 2;
+/// PSG register: channel B tone period, coarse (4 bits)
+pub const PSG_PERIOD_B_COARSE: usize = 3;
+/// PSG register: channel C tone period, fine
+pub const PSG_PERIOD_C_FINE: usize = 4;
+/// PSG register: channel C tone period, coarse (4 bits)
+pub const PSG_PERIOD_C_COARSE: usize = 5;
+/// PSG register: noise period (5 bits)
+pub const PSG_PERIOD_NOISE: usize = 6;
+/// PSG register: mixer (tone and noise disable bits, I/O port direction)
+pub const PSG_ENABLE: usize = 7;
+/// PSG register: channel A amplitude (bit 4: use envelope)
+pub const PSG_AMP_A: usize = 8;
+/// PSG register: channel B amplitude (bit 4: use envelope)
+pub const PSG_AMP_B: usize = 9;
+/// PSG register: channel C amplitude (bit 4: use envelope)
+pub const PSG_AMP_C: usize = 10;
+/// PSG register: envelope period, fine
+pub const PSG_ENV_PERIOD_FINE: usize = 11;
+/// PSG register: envelope period, coarse
+pub const PSG_ENV_PERIOD_COARSE: usize = 12;
+/// PSG register: envelope shape (4 bits)
+pub const PSG_ENV_SHAPE: usize = 13;
+/// PSG register: I/O port A
+pub const PSG_IO_PORT_A: usize = 14;
+/// PSG register: I/O port B
+pub const PSG_IO_PORT_B: usize = 15;
+
+const NUM_REGS: usize = 16;
+const NUM_CHANNELS: usize = 3;
+
+// valid bits of each register
+static REG_MASK: [u8; NUM_REGS] = [0xFF, 0x0F, 0xFF, 0x0F, 0xFF, 0x0F, 0x1F, 0xFF,
+                                   0x1F, 0x1F, 0x1F, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF];
+
+// envelope shape bits
+const ENV_HOLD: u8 = 1 << 0;
+const ENV_ALTERNATE: u8 = 1 << 1;
+const ENV_ATTACK: u8 = 1 << 2;
+const ENV_CONTINUE: u8 = 1 << 3;
+
+// amplitude register bits
+const AMP_ENVELOPE: u8 = 1 << 4;
+
+// logarithmic output levels for the 16 amplitude steps
+static VOLUMES: [f32; 16] = [0.0, 0.0137, 0.0205, 0.0291, 0.0423, 0.0618, 0.0847, 0.1369,
+                             0.1691, 0.2647, 0.3527, 0.4499, 0.5704, 0.6873, 0.8482, 1.0];
+
+#[derive(Clone, Copy)]
+struct Tone {
+    counter: u32,
+    output: bool,
+}
+
+/// AY-3-8910 programmable sound generator emulation
+///
+/// The PSG (also known as AY-3-8912, which only has one I/O port,
+/// and YM2149) has 3 square wave tone channels, a noise generator
+/// and a single envelope generator. The CPU selects a register
+/// with **select()** and accesses it with **write()** and **read()**,
+/// how this maps to I/O ports is defined by the emulated system.
+///
+/// The PSG must be advanced by calling **update()** with the number
+/// of executed CPU cycles, it internally converts the CPU clock to
+/// the PSG clock. The mixed output of all channels is sampled at the
+/// host audio sample rate, and the samples must be drained
+/// regularly with **take_samples()**.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{PSG, PSG_PERIOD_A_FINE, PSG_ENABLE, PSG_AMP_A};
+///
+/// // 3.5 MHz CPU clock, 1.75 MHz PSG clock, 44.1 kHz sample rate
+/// let mut psg = PSG::new(3500, 1750, 44100);
+///
+/// // a 441 Hz tone (1750 kHz / (16 * 248)) on channel A at full volume
+/// psg.select(PSG_PERIOD_A_FINE);
+/// psg.write(248);
+/// psg.select(PSG_ENABLE);
+/// psg.write(0b111110);
+/// psg.select(PSG_AMP_A);
+/// psg.write(15);
+/// assert_eq!(psg.read(), 15);
+///
+/// // run for 10ms and count the square wave edges
+/// psg.update(35000);
+/// let samples = psg.take_samples();
+/// let edges = samples.windows(2).filter(|w| w[0] != w[1]).count();
+/// assert!(edges >= 8 && edges <= 9);
+/// ```
+pub struct PSG {
+    /// the 16 PSG registers
+    pub reg: [u8; NUM_REGS],
+    /// output volume (0.0 to 1.0)
+    pub volume: f32,
+    addr: usize, // currently selected register
+    cpu_freq_khz: i64,
+    psg_freq_khz: i64,
+    tick_counter: i64, // converts CPU cycles to PSG clock ticks (8 PSG clock cycles)
+    tone: [Tone; NUM_CHANNELS],
+    noise_counter: u32,
+    noise_prescaler: bool,
+    noise_rng: u32,
+    env_counter: u32,
+    env_step: u8,
+    env_attack: bool,
+    env_holding: bool,
+    env_volume: u8,
+    sample_rate: i64,
+    sample_counter: i64, // counts sample_rate per CPU cycle, a sample is due at the CPU frequency in Hz
+    samples: Vec<f32>,
+}
+
+impl PSG {
+    /// initialize a new PSG with CPU frequency, PSG frequency and host sample rate
+    pub fn new(cpu_freq_khz: i64, psg_freq_khz: i64, sample_rate: i64) -> PSG {
+        assert!(cpu_freq_khz > 0 && psg_freq_khz > 0 && sample_rate > 0);
+        PSG {
+            reg: [0; NUM_REGS],
+            volume: 0.5,
+            addr: 0,
+            cpu_freq_khz: cpu_freq_khz,
+            psg_freq_khz: psg_freq_khz,
+            tick_counter: 0,
+            tone: [Tone {
+                counter: 0,
+                output: false,
+            }; NUM_CHANNELS],
+            noise_counter: 0,
+            noise_prescaler: false,
+            noise_rng: 1,
+            env_counter: 0,
+            env_step: 0,
+            env_attack: false,
+            env_holding: true,
+            env_volume: 0,
+            sample_rate: sample_rate,
+            sample_counter: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    /// reset the PSG, this clears all registers
+    pub fn reset(&mut self) {
+        self.reg = [0; NUM_REGS];
+        self.addr = 0;
+        self.tick_counter = 0;
+        for tone in self.tone.iter_mut() {
+            tone.counter = 0;
+            tone.output = false;
+        }
+        self.noise_counter = 0;
+        self.noise_prescaler = false;
+        self.noise_rng = 1;
+        self.env_counter = 0;
+        self.env_step = 0;
+        self.env_attack = false;
+        self.env_holding = true;
+        self.env_volume = 0;
+        self.sample_counter = 0;
+    }
+
+    /// select the register for the next read() or write()
+    pub fn select(&mut self, addr: usize) {
+        self.addr = addr & (NUM_REGS - 1);
+    }
+
+    /// read the currently selected register
+    pub fn read(&self) -> u8 {
+        self.reg[self.addr]
+    }
+
+    /// write the currently selected register
+    pub fn write(&mut self, val: u8) {
+        self.reg[self.addr] = val & REG_MASK[self.addr];
+        if self.addr == PSG_ENV_SHAPE {
+            // writing the envelope shape restarts the envelope
+            let shape = self.reg[PSG_ENV_SHAPE];
+            self.env_counter = 0;
+            self.env_step = 0;
+            self.env_attack = (shape & ENV_ATTACK) != 0;
+            self.env_holding = false;
+            self.env_volume = if self.env_attack { 0 } else { 15 };
+        }
+    }
+
+    /// advance the PSG by a number of CPU cycles, generating samples
+    pub fn update(&mut self, cycles: i64) {
+        let freq_hz = self.cpu_freq_khz * 1000;
+        let mut cycles = cycles;
+        while cycles > 0 {
+            // number of CPU cycles until the next sample is due
+            let due = (freq_hz - self.sample_counter + self.sample_rate - 1) / self.sample_rate;
+            let step = if cycles < due { cycles } else { due };
+            cycles -= step;
+            self.tick_counter += step * self.psg_freq_khz;
+            while self.tick_counter >= 8 * self.cpu_freq_khz {
+                self.tick_counter -= 8 * self.cpu_freq_khz;
+                self.tick();
+            }
+            self.sample_counter += step * self.sample_rate;
+            while self.sample_counter >= freq_hz {
+                self.sample_counter -= freq_hz;
+                let sample = self.sample();
+                self.samples.push(sample);
+            }
+        }
+    }
+
+    /// return the generated samples and clear the sample buffer
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        ::std::mem::take(&mut self.samples)
+    }
+
+    /// advance the generators by 8 PSG clock cycles
+    fn tick(&mut self) {
+        // tone generators toggle their output every 'period' ticks
+        for (chn, tone) in self.tone.iter_mut().enumerate() {
+            let period = (self.reg[2 * chn] as u32) | ((self.reg[2 * chn + 1] as u32) << 8);
+            tone.counter += 1;
+            if tone.counter >= period {
+                tone.counter = 0;
+                tone.output = !tone.output;
+            }
+        }
+
+        // the noise generator runs at half the tone generator rate
+        self.noise_prescaler = !self.noise_prescaler;
+        if self.noise_prescaler {
+            self.noise_counter += 1;
+            if self.noise_counter >= self.reg[PSG_PERIOD_NOISE] as u32 {
+                self.noise_counter = 0;
+                // 17-bit LFSR
+                let bit = (self.noise_rng ^ (self.noise_rng >> 3)) & 1;
+                self.noise_rng = (self.noise_rng >> 1) | (bit << 16);
+            }
+        }
+
+        // the envelope has 16 steps, one step every 2*period ticks
+        let env_period = (self.reg[PSG_ENV_PERIOD_FINE] as u32) |
+                         ((self.reg[PSG_ENV_PERIOD_COARSE] as u32) << 8);
+        self.env_counter += 1;
+        if self.env_counter >= 2 * env_period {
+            self.env_counter = 0;
+            self.env_tick();
+        }
+    }
+
+    /// advance the envelope by one step
+    fn env_tick(&mut self) {
+        if self.env_holding {
+            return;
+        }
+        let shape = self.reg[PSG_ENV_SHAPE];
+        self.env_step += 1;
+        if self.env_step > 15 {
+            if (shape & ENV_CONTINUE) == 0 {
+                self.env_holding = true;
+                self.env_volume = 0;
+                return;
+            }
+            if (shape & ENV_ALTERNATE) != 0 {
+                self.env_attack = !self.env_attack;
+            }
+            if (shape & ENV_HOLD) != 0 {
+                self.env_holding = true;
+                self.env_volume = if self.env_attack { 15 } else { 0 };
+                return;
+            }
+            self.env_step = 0;
+        }
+        self.env_volume = if self.env_attack {
+            self.env_step
+        } else {
+            15 - self.env_step
+        };
+    }
+
+    /// mix the 3 channels into an output sample
+    fn sample(&self) -> f32 {
+        let enable = self.reg[PSG_ENABLE];
+        let noise = (self.noise_rng & 1) != 0;
+        let mut out = 0.0;
+        for chn in 0..NUM_CHANNELS {
+            let tone_off = (enable & (1 << chn)) != 0;
+            let noise_off = (enable & (1 << (chn + 3))) != 0;
+            if (self.tone[chn].output || tone_off) && (noise || noise_off) {
+                let amp = self.reg[PSG_AMP_A + chn];
+                let level = if (amp & AMP_ENVELOPE) != 0 {
+                    self.env_volume
+                } else {
+                    amp & 0x0F
+                };
+                out += VOLUMES[level as usize];
+            }
+        }
+        out * self.volume / NUM_CHANNELS as f32
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(psg: &mut PSG, addr: usize, val: u8) {
+        psg.select(addr);
+        psg.write(val);
+    }
+
+    #[test]
+    fn registers() {
+        let mut psg = PSG::new(2000, 1000, 10000);
+        for addr in 0..16 {
+            write(&mut psg, addr, 0xFF);
+        }
+        assert_eq!(psg.reg, REG_MASK);
+        psg.select(PSG_PERIOD_A_COARSE);
+        assert_eq!(psg.read(), 0x0F);
+        psg.reset();
+        assert_eq!(psg.reg, [0; 16]);
+    }
+
+    #[test]
+    fn mixer() {
+        // 1 PSG tick per 8 CPU cycles, 1 sample per 8 CPU cycles
+        let mut psg = PSG::new(1000, 1000, 125000);
+        psg.volume = 1.0;
+        write(&mut psg, PSG_PERIOD_B_FINE, 2);
+        write(&mut psg, PSG_AMP_B, 15);
+
+        // all channels disabled, the output is constant
+        write(&mut psg, PSG_ENABLE, 0xFF);
+        psg.update(8 * 16);
+        let samples = psg.take_samples();
+        assert_eq!(samples.len(), 16);
+        assert!(samples.iter().all(|s| *s == 1.0 / 3.0));
+
+        // channel B tone enabled, a square wave with period 4 samples
+        write(&mut psg, PSG_ENABLE, !(1 << 1));
+        psg.update(8 * 16);
+        let samples = psg.take_samples();
+        assert_eq!(samples.len(), 16);
+        for i in 0..12 {
+            assert_eq!(samples[i], samples[i + 4]);
+            assert!(samples[i] != samples[i + 2]);
+        }
+
+        // channel B noise only
+        write(&mut psg, PSG_ENABLE, !(1 << 4));
+        write(&mut psg, PSG_PERIOD_NOISE, 1);
+        psg.update(8 * 256);
+        let samples = psg.take_samples();
+        assert!(samples.iter().any(|s| *s == 0.0));
+        assert!(samples.iter().any(|s| *s != 0.0));
+    }
+
+    #[test]
+    fn envelope() {
+        let mut psg = PSG::new(1000, 1000, 125000);
+        write(&mut psg, PSG_ENV_PERIOD_FINE, 1);
+
+        // decay, then hold at 0
+        write(&mut psg, PSG_ENV_SHAPE, 0b0000);
+        assert_eq!(psg.env_volume, 15);
+        let mut levels = Vec::new();
+        for _ in 0..20 {
+            psg.update(16);
+            levels.push(psg.env_volume);
+        }
+        assert_eq!(levels[..15], [14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0]);
+        assert!(levels[15..].iter().all(|l| *l == 0));
+
+        // attack, then hold at 15
+        write(&mut psg, PSG_ENV_SHAPE, 0b1101);
+        psg.update(16 * 20);
+        assert_eq!(psg.env_volume, 15);
+
+        // triangle
+        write(&mut psg, PSG_ENV_SHAPE, 0b1110);
+        psg.update(16 * 15);
+        assert_eq!(psg.env_volume, 15);
+        psg.update(16);
+        assert_eq!(psg.env_volume, 15);
+        psg.update(16);
+        assert_eq!(psg.env_volume, 14);
+
+        // sawtooth
+        write(&mut psg, PSG_ENV_SHAPE, 0b1000);
+        psg.update(16 * 15);
+        assert_eq!(psg.env_volume, 0);
+        psg.update(16);
+        assert_eq!(psg.env_volume, 15);
+    }
+
+    #[test]
+    fn fractional_sample_rate() {
+        // ZX Spectrum 48K clock: 79.4 CPU cycles per sample, one second
+        // must generate exactly 44100 samples
+        let mut psg = PSG::new(3500, 1750, 44100);
+        for _ in 0..50 {
+            psg.update(70000);
+        }
+        assert_eq!(44100, psg.take_samples().len());
+    }
+}
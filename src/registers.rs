@@ -103,6 +103,7 @@ pub const WZ_: usize = 24;
 /// cpu.reg.set_hl(hl);
 /// assert_eq!(cpu.reg.hl(), 0xFFFF);
 /// ```
+#[derive(Clone)]
 pub struct Registers {
     reg: [u8; NUM_REGS],
     r_pc: u16,
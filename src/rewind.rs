@@ -0,0 +1,648 @@
+use std::cell::Cell;
+use std::collections::VecDeque;
+use RegT;
+use bus::{Bus, BusCycle};
+use cpu::{CPU, CPUSnapshot};
+
+/// forwards CPU bus accesses and records the input values
+struct RecordBus<'a> {
+    bus: &'a Bus,
+    inputs: Cell<Vec<RegT>>,
+}
+
+impl<'a> RecordBus<'a> {
+    fn record(&self, val: RegT) -> RegT {
+        let mut inputs = self.inputs.take();
+        inputs.push(val);
+        self.inputs.set(inputs);
+        val
+    }
+}
+
+impl<'a> Bus for RecordBus<'a> {
+    fn cpu_inp(&self, port: RegT) -> RegT {
+        self.record(self.bus.cpu_inp(port))
+    }
+    fn cpu_outp(&self, port: RegT, val: RegT) {
+        self.bus.cpu_outp(port, val);
+    }
+    fn irq_ack(&self) -> RegT {
+        self.record(self.bus.irq_ack())
+    }
+    fn irq_reti(&self) {
+        self.bus.irq_reti();
+    }
+    fn cpu_halt(&self, halt: bool) {
+        self.bus.cpu_halt(halt);
+    }
+    fn cpu_fetch(&self, addr: RegT, op: RegT) {
+        self.bus.cpu_fetch(addr, op);
+    }
+    fn cpu_wait(&self, cycle: BusCycle, addr: RegT) -> i64 {
+        self.record(self.bus.cpu_wait(cycle, addr) as RegT) as i64
+    }
+}
+
+/// replays recorded input values, outputs are dropped
+struct ReplayBus<'a> {
+    inputs: &'a VecDeque<(u64, RegT)>,
+    cursor: Cell<usize>,
+}
+
+impl<'a> ReplayBus<'a> {
+    fn next(&self) -> RegT {
+        let cursor = self.cursor.get();
+        self.cursor.set(cursor + 1);
+        self.inputs.get(cursor).map_or(0xFF, |i| i.1)
+    }
+}
+
+impl<'a> Bus for ReplayBus<'a> {
+    fn cpu_inp(&self, _: RegT) -> RegT {
+        self.next()
+    }
+    fn irq_ack(&self) -> RegT {
+        self.next()
+    }
+    fn cpu_wait(&self, _: BusCycle, _: RegT) -> i64 {
+        self.next() as i64
+    }
+}
+
+/// execution history for stepping backward in time
+///
+/// Instructions executed through **Rewind::step()** are recorded: every
+/// few instructions a snapshot of the CPU and memory is stored in a
+/// ring buffer, and all values which the CPU reads from the Bus
+/// (port input, interrupt vectors and wait states) as well as interrupt
+/// and NMI requests are logged with the instruction position. To go back
+/// in time, the nearest older snapshot is restored and the recorded
+/// instructions are replayed up to the requested position, which is
+/// deterministic because the CPU only sees the recorded inputs.
+///
+/// Trap handlers (see CPU::add_trap()) run host code and aren't called
+/// again during replay, instead the state after a trapped instruction is
+/// stored as an extra snapshot and restored in place of the instruction.
+/// These snapshots count against the capacity, so the history of programs
+/// which hit traps often reaches back fewer instructions.
+///
+/// Only the CPU and its memory are rewound, devices behind the Bus keep
+/// their state and don't see the replayed port output. Stepping forward
+/// after going back replays the recorded history until the most recent
+/// instruction is reached again. This makes the history complete for
+/// systems without device state (like the command line runner's
+/// --last-write option). To rewind a complete machine by video frames,
+/// store machine snapshots instead (see Z1013::snapshot()).
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{CPU, Bus, Rewind};
+///
+/// struct DummyBus;
+/// impl Bus for DummyBus { };
+///
+/// let mut cpu = CPU::new_64k();
+/// let bus = DummyBus { };
+/// // a loop which increments the byte at 0x4000
+/// cpu.mem.write(0x0000, &[0x21, 0x00, 0x40, 0x34, 0x18, 0xFD]);
+///
+/// // snapshot every 16 instructions, keep 64 snapshots
+/// let mut rewind = Rewind::new(16, 64);
+/// for _ in 0..100 {
+///     rewind.step(&mut cpu, &bus);
+/// }
+/// assert_eq!(cpu.mem.r8(0x4000), 50);
+///
+/// // find the instruction which wrote the byte before its current value
+/// rewind.add_watchpoint(0x4000);
+/// assert_eq!(rewind.reverse_continue(&mut cpu), Some(98));
+/// assert_eq!(cpu.mem.r8(0x4000), 49);
+/// rewind.step_back(&mut cpu);
+/// assert_eq!(cpu.mem.r8(0x4000), 48);
+/// assert_eq!(cpu.reg.pc(), 0x0003);
+/// ```
+pub struct Rewind {
+    interval: u64,
+    capacity: usize,
+    snapshots: VecDeque<(u64, i64, CPUSnapshot)>,
+    inputs: VecDeque<(u64, RegT)>,
+    irqs: VecDeque<u64>,
+    nmis: VecDeque<u64>,
+    traps: VecDeque<u64>,
+    pos: u64,
+    head: u64,
+    cycles: i64,
+    cursor: usize,
+    breakpoints: Vec<RegT>,
+    watchpoints: Vec<RegT>,
+}
+
+impl Rewind {
+    /// create a new history with a snapshot interval (in instructions) and maximum number of snapshots
+    pub fn new(interval: u64, capacity: usize) -> Rewind {
+        assert!(interval > 0 && capacity > 0);
+        Rewind {
+            interval: interval,
+            capacity: capacity,
+            snapshots: VecDeque::new(),
+            inputs: VecDeque::new(),
+            irqs: VecDeque::new(),
+            nmis: VecDeque::new(),
+            traps: VecDeque::new(),
+            pos: 0,
+            head: 0,
+            cycles: 0,
+            cursor: 0,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+        }
+    }
+
+    /// discard the recorded history
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.inputs.clear();
+        self.irqs.clear();
+        self.nmis.clear();
+        self.traps.clear();
+        self.pos = 0;
+        self.head = 0;
+        self.cycles = 0;
+        self.cursor = 0;
+    }
+
+    /// the current position (number of executed instructions)
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// the position of the most recent executed instruction
+    pub fn head(&self) -> u64 {
+        self.head
+    }
+
+    /// the oldest position which can be reached
+    pub fn oldest(&self) -> u64 {
+        self.snapshots.front().map_or(self.pos, |s| s.0)
+    }
+
+    /// the number of cycles executed up to the current position
+    pub fn cycles(&self) -> i64 {
+        self.cycles
+    }
+
+    /// stop reverse_continue() when PC reaches an address
+    pub fn add_breakpoint(&mut self, addr: RegT) {
+        self.breakpoints.push(addr & 0xFFFF);
+    }
+
+    /// stop reverse_continue() after an instruction changed the byte at an address
+    pub fn add_watchpoint(&mut self, addr: RegT) {
+        self.watchpoints.push(addr & 0xFFFF);
+    }
+
+    /// remove all breakpoints and watchpoints
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+    }
+
+    /// execute one instruction and record it, or replay it if not at the head of the history
+    pub fn step(&mut self, cpu: &mut CPU, bus: &Bus) -> i64 {
+        if self.pos < self.head {
+            return self.replay(cpu);
+        }
+        if self.pos % self.interval == 0 {
+            self.push_snapshot(cpu);
+        }
+        if cpu.irq_pending() {
+            self.irqs.push_back(self.pos);
+        }
+        if cpu.nmi_pending() {
+            self.nmis.push_back(self.pos);
+        }
+        let trapped = !cpu.halt && cpu.has_trap(cpu.reg.pc());
+        let rec = RecordBus {
+            bus: bus,
+            inputs: Cell::new(Vec::new()),
+        };
+        let cycles = cpu.step(&rec);
+        for val in rec.inputs.take() {
+            self.inputs.push_back((self.pos, val));
+        }
+        self.pos += 1;
+        self.head = self.pos;
+        self.cycles += cycles;
+        self.cursor = self.inputs.len();
+        if trapped {
+            self.traps.push_back(self.pos - 1);
+            self.push_snapshot(cpu);
+        }
+        cycles
+    }
+
+    /// store a snapshot at the current position (unless there already is one)
+    fn push_snapshot(&mut self, cpu: &CPU) {
+        if self.snapshots.back().map_or(true, |s| s.0 < self.pos) {
+            self.snapshots.push_back((self.pos, self.cycles, cpu.snapshot()));
+            if self.snapshots.len() > self.capacity {
+                self.snapshots.pop_front();
+                self.discard_before(self.snapshots[0].0);
+            }
+        }
+    }
+
+    /// discard recorded inputs before a position
+    fn discard_before(&mut self, pos: u64) {
+        while self.inputs.front().map_or(false, |i| i.0 < pos) {
+            self.inputs.pop_front();
+            self.cursor -= 1;
+        }
+        for positions in [&mut self.irqs, &mut self.nmis, &mut self.traps].iter_mut() {
+            while positions.front().map_or(false, |p| *p < pos) {
+                positions.pop_front();
+            }
+        }
+    }
+
+    /// replay the next recorded instruction
+    fn replay(&mut self, cpu: &mut CPU) -> i64 {
+        if self.traps.binary_search(&self.pos).is_ok() {
+            // don't call the trap handler again, restore the state after the instruction
+            let start = self.cycles;
+            let index = self.snapshot_before(self.pos + 1).unwrap();
+            self.restore(cpu, index);
+            return self.cycles - start;
+        }
+        if self.irqs.binary_search(&self.pos).is_ok() {
+            cpu.irq();
+        }
+        if self.nmis.binary_search(&self.pos).is_ok() {
+            cpu.nmi();
+        }
+        let cycles = {
+            let bus = ReplayBus {
+                inputs: &self.inputs,
+                cursor: Cell::new(self.cursor),
+            };
+            let cycles = cpu.step(&bus);
+            self.cursor = bus.cursor.get();
+            cycles
+        };
+        self.pos += 1;
+        self.cycles += cycles;
+        cycles
+    }
+
+    /// restore the snapshot with an index
+    fn restore(&mut self, cpu: &mut CPU, index: usize) {
+        let (pos, cycles, ref snapshot) = self.snapshots[index];
+        cpu.restore(snapshot);
+        self.pos = pos;
+        self.cycles = cycles;
+        self.cursor = self.inputs.iter().position(|i| i.0 >= pos).unwrap_or(self.inputs.len());
+    }
+
+    /// the index of the newest snapshot at or before a position
+    fn snapshot_before(&self, pos: u64) -> Option<usize> {
+        self.snapshots.iter().rposition(|s| s.0 <= pos)
+    }
+
+    /// go to a position in the recorded history, return false if it isn't recorded
+    pub fn seek(&mut self, cpu: &mut CPU, pos: u64) -> bool {
+        if pos > self.head {
+            return false;
+        }
+        if pos < self.pos {
+            match self.snapshot_before(pos) {
+                Some(index) => self.restore(cpu, index),
+                None => return false,
+            }
+        }
+        while self.pos < pos {
+            self.replay(cpu);
+        }
+        true
+    }
+
+    /// go back one instruction, return false at the start of the history
+    pub fn step_back(&mut self, cpu: &mut CPU) -> bool {
+        self.pos > 0 && self.seek(cpu, self.pos - 1)
+    }
+
+    /// go back a number of cycles (for instance a number of video frames)
+    ///
+    /// The new position is the first instruction boundary at or after the
+    /// requested cycle count, returns false if it isn't recorded.
+    pub fn rewind_cycles(&mut self, cpu: &mut CPU, cycles: i64) -> bool {
+        let target = self.cycles - cycles;
+        let index = match self.snapshots.iter().rposition(|s| s.1 <= target) {
+            Some(index) => index,
+            None => return false,
+        };
+        self.restore(cpu, index);
+        while self.cycles < target && self.pos < self.head {
+            self.replay(cpu);
+        }
+        true
+    }
+
+    /// go back to the previous breakpoint or watchpoint hit
+    ///
+    /// For breakpoints, the new position is before the instruction at the
+    /// breakpoint address, for watchpoints after the instruction which
+    /// changed the watched byte. Returns the new position, or None (and
+    /// stays at the current position) if there's no hit in the history.
+    pub fn reverse_continue(&mut self, cpu: &mut CPU) -> Option<u64> {
+        let start = self.pos;
+        let mut index = self.snapshot_before(start)?;
+        loop {
+            // replay the range between 2 snapshots and remember the last hit
+            self.restore(cpu, index);
+            let end = self.snapshots.get(index + 1).map_or(start, |s| s.0.min(start));
+            let mut hit = None;
+            while self.pos < end {
+                if self.breakpoints.contains(&cpu.reg.pc()) {
+                    hit = Some(self.pos);
+                }
+                let before: Vec<RegT> = self.watchpoints.iter().map(|a| cpu.mem.r8(*a)).collect();
+                self.replay(cpu);
+                let changed = self.watchpoints.iter().zip(before.iter()).any(|(a, b)| {
+                    cpu.mem.r8(*a) != *b
+                });
+                if changed && self.pos < start {
+                    hit = Some(self.pos);
+                }
+            }
+            if let Some(pos) = hit {
+                self.seek(cpu, pos);
+                return Some(pos);
+            }
+            if index == 0 {
+                self.seek(cpu, start);
+                return None;
+            }
+            index -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    // a bus with an input port which counts up, and an output log
+    struct TestBus {
+        counter: Cell<RegT>,
+        out: RefCell<Vec<RegT>>,
+    }
+
+    impl Bus for TestBus {
+        fn cpu_inp(&self, _: RegT) -> RegT {
+            let val = self.counter.get();
+            self.counter.set(val + 3);
+            val & 0xFF
+        }
+        fn cpu_outp(&self, _: RegT, val: RegT) {
+            self.out.borrow_mut().push(val);
+        }
+        fn irq_ack(&self) -> RegT {
+            0xFF
+        }
+    }
+
+    fn test_bus() -> TestBus {
+        TestBus {
+            counter: Cell::new(0),
+            out: RefCell::new(Vec::new()),
+        }
+    }
+
+    // IN A,(0); ADD A,B; LD B,A; LD (HL),A; INC HL; OUT (1),A; JR $0000
+    static PROG: [u8; 11] = [0xDB, 0x00, 0x80, 0x47, 0x77, 0x23, 0xD3, 0x01, 0x18, 0xF6, 0x00];
+
+    fn state(cpu: &CPU) -> (RegT, RegT, RegT, RegT, bool, Vec<u8>) {
+        (cpu.reg.pc(), cpu.reg.af(), cpu.reg.bc(), cpu.reg.hl(), cpu.iff1,
+         (0x4000..0x4100).map(|a| cpu.mem.r8(a) as u8).collect())
+    }
+
+    fn cpu() -> CPU {
+        let mut cpu = CPU::new_64k();
+        cpu.mem.write(0x0000, &PROG);
+        cpu.reg.set_hl(0x4000);
+        cpu
+    }
+
+    #[test]
+    fn step_back() {
+        let mut cpu = cpu();
+        let bus = test_bus();
+        let mut rewind = Rewind::new(10, 100);
+        let mut states = Vec::new();
+        let mut cycles = Vec::new();
+        for _ in 0..200 {
+            states.push(state(&cpu));
+            cycles.push(rewind.cycles());
+            rewind.step(&mut cpu, &bus);
+        }
+        assert_eq!(200, rewind.position());
+        let num_out = bus.out.borrow().len();
+        for pos in (150..200).rev() {
+            assert!(rewind.step_back(&mut cpu));
+            assert_eq!(pos as u64, rewind.position());
+            assert_eq!(states[pos], state(&cpu));
+            assert_eq!(cycles[pos], rewind.cycles());
+        }
+        assert!(rewind.seek(&mut cpu, 3));
+        assert_eq!(states[3], state(&cpu));
+
+        // stepping forward replays the history without touching the bus
+        for _ in 3..100 {
+            rewind.step(&mut cpu, &bus);
+        }
+        assert_eq!(states[100], state(&cpu));
+        assert_eq!(num_out, bus.out.borrow().len());
+        assert!(!rewind.seek(&mut cpu, 201));
+
+        // back at the head, steps are executed on the bus again
+        assert!(rewind.seek(&mut cpu, 200));
+        rewind.step(&mut cpu, &bus);
+        assert_eq!(201, rewind.head());
+    }
+
+    #[test]
+    fn wait_states() {
+        // a bus which inserts a varying number of wait states
+        struct WaitBus {
+            waits: Cell<i64>,
+        }
+        impl Bus for WaitBus {
+            fn cpu_wait(&self, _: BusCycle, _: RegT) -> i64 {
+                let waits = self.waits.get();
+                self.waits.set((waits + 1) % 3);
+                waits
+            }
+        }
+        let mut cpu = cpu();
+        cpu.set_wait_callback(true);
+        let bus = WaitBus { waits: Cell::new(0) };
+        let mut rewind = Rewind::new(10, 100);
+        let mut cycles = Vec::new();
+        for _ in 0..50 {
+            cycles.push(rewind.cycles());
+            rewind.step(&mut cpu, &bus);
+        }
+        cycles.push(rewind.cycles());
+        // the replayed instructions see the recorded wait states
+        assert!(rewind.seek(&mut cpu, 5));
+        for pos in 5..50 {
+            assert_eq!(cycles[pos], rewind.cycles());
+            rewind.step(&mut cpu, &bus);
+        }
+        assert_eq!(cycles[50], rewind.cycles());
+    }
+
+    #[test]
+    fn capacity() {
+        let mut cpu = cpu();
+        let bus = test_bus();
+        let mut rewind = Rewind::new(10, 5);
+        for _ in 0..100 {
+            rewind.step(&mut cpu, &bus);
+        }
+        assert_eq!(50, rewind.oldest());
+        assert!(!rewind.seek(&mut cpu, 49));
+        assert!(rewind.seek(&mut cpu, 50));
+        assert!(rewind.seek(&mut cpu, 100));
+        assert!(rewind.rewind_cycles(&mut cpu, 0));
+        assert_eq!(100, rewind.position());
+        let cycles = rewind.cycles();
+        assert!(!rewind.rewind_cycles(&mut cpu, cycles));
+        assert_eq!(100, rewind.position());
+    }
+
+    #[test]
+    fn reverse_continue() {
+        let mut cpu = cpu();
+        let bus = test_bus();
+        let mut rewind = Rewind::new(16, 100);
+        for _ in 0..300 {
+            rewind.step(&mut cpu, &bus);
+        }
+        // 0x4010 was written by the 'LD (HL),A' in the 17th loop
+        rewind.add_watchpoint(0x4010);
+        assert_eq!(Some(16 * 7 + 4), rewind.reverse_continue(&mut cpu));
+        assert_eq!(0x0005, cpu.reg.pc());
+        assert_eq!(None, rewind.reverse_continue(&mut cpu));
+        assert_eq!(16 * 7 + 4, rewind.position());
+
+        // the OUT instruction
+        rewind.clear_breakpoints();
+        rewind.add_breakpoint(0x0006);
+        assert_eq!(Some(15 * 7 + 5), rewind.reverse_continue(&mut cpu));
+        assert_eq!(0x0006, cpu.reg.pc());
+        assert_eq!(Some(14 * 7 + 5), rewind.reverse_continue(&mut cpu));
+
+        // rewind by cycles
+        rewind.seek(&mut cpu, 300);
+        let cycles = rewind.cycles();
+        assert!(rewind.rewind_cycles(&mut cpu, 1000));
+        assert!(rewind.cycles() >= cycles - 1000 && rewind.cycles() < cycles - 1000 + 12);
+    }
+
+    #[test]
+    fn interrupts() {
+        // EI; JR $0001 with an IM 1 handler which increments the byte at 0x4000
+        let mut cpu = CPU::new_64k();
+        cpu.mem.write(0x0000, &[0xFB, 0x18, 0xFE]);
+        cpu.mem.write(0x0038, &[0xE5, 0x21, 0x00, 0x40, 0x34, 0xE1, 0xFB, 0xC9]);
+        cpu.reg.set_sp(0x8000);
+        cpu.reg.im = 1;
+        let bus = test_bus();
+        let mut rewind = Rewind::new(8, 100);
+        let mut states = Vec::new();
+        for i in 0..200 {
+            if i % 13 == 0 {
+                cpu.irq();
+            }
+            states.push(state(&cpu));
+            rewind.step(&mut cpu, &bus);
+        }
+        assert!(cpu.mem.r8(0x4000) > 10);
+        for pos in (0..200).rev() {
+            rewind.step_back(&mut cpu);
+            assert_eq!(states[pos], state(&cpu));
+        }
+    }
+
+    #[test]
+    fn nmis() {
+        // JR $0000 with an NMI handler which increments the byte at 0x4000
+        let mut cpu = CPU::new_64k();
+        cpu.mem.write(0x0000, &[0x18, 0xFE]);
+        cpu.mem.write(0x0066, &[0xE5, 0x21, 0x00, 0x40, 0x34, 0xE1, 0xED, 0x45]);
+        cpu.reg.set_sp(0x8000);
+        let bus = test_bus();
+        let mut rewind = Rewind::new(8, 100);
+        let mut states = Vec::new();
+        for i in 0..200 {
+            if i % 11 == 0 {
+                cpu.nmi();
+            }
+            states.push(state(&cpu));
+            rewind.step(&mut cpu, &bus);
+        }
+        assert!(cpu.mem.r8(0x4000) > 10);
+        for pos in (0..200).rev() {
+            rewind.step_back(&mut cpu);
+            assert_eq!(states[pos], state(&cpu));
+        }
+        // replaying forward sees the same NMIs
+        for _ in 0..200 {
+            rewind.step(&mut cpu, &bus);
+        }
+        assert_eq!(states[199].5, state(&cpu).5);
+    }
+
+    #[test]
+    fn traps() {
+        use std::rc::Rc;
+        use cpu::TrapAction;
+        // a trap on the OUT instruction which counts its calls and
+        // writes the count to memory, like a host function
+        let mut cpu = cpu();
+        let calls = Rc::new(Cell::new(0));
+        let trap_calls = calls.clone();
+        cpu.add_trap(0x0006, move |cpu| {
+            trap_calls.set(trap_calls.get() + 1);
+            cpu.mem.w8(0x5000, trap_calls.get());
+            cpu.reg.set_b(trap_calls.get());
+            TrapAction::Continue
+        });
+        let bus = test_bus();
+        let mut rewind = Rewind::new(10, 100);
+        let mut states = Vec::new();
+        for _ in 0..100 {
+            states.push((state(&cpu), cpu.mem.r8(0x5000)));
+            rewind.step(&mut cpu, &bus);
+        }
+        states.push((state(&cpu), cpu.mem.r8(0x5000)));
+        let num_calls = calls.get();
+        assert!(num_calls > 10);
+        // stepping back and replaying doesn't call the trap handler again
+        for pos in (0..100).rev() {
+            assert!(rewind.step_back(&mut cpu));
+            assert_eq!(states[pos], (state(&cpu), cpu.mem.r8(0x5000)));
+        }
+        for pos in 1..101 {
+            rewind.step(&mut cpu, &bus);
+            assert_eq!(states[pos], (state(&cpu), cpu.mem.r8(0x5000)));
+        }
+        assert_eq!(num_calls, calls.get());
+        rewind.add_watchpoint(0x5000);
+        assert!(rewind.reverse_continue(&mut cpu).is_some());
+        assert_eq!(num_calls, calls.get());
+    }
+}
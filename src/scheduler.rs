@@ -0,0 +1,174 @@
+/// a cycle-based event scheduler
+///
+/// The scheduler keeps an absolute 64-bit CPU cycle counter which the
+/// system advances after each instruction, and a queue of device events
+/// at future cycles (for instance the start of a video frame or the expiry
+/// of a timer). Instead of polling each device after every instruction,
+/// the system runs the CPU up to the next event, and dispatches the
+/// events which are due. The event type is usually a system-specific enum.
+///
+/// Events which are scheduled for the same cycle are dispatched in the
+/// order they were scheduled.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::Scheduler;
+///
+/// #[derive(Clone, Copy, PartialEq, Debug)]
+/// enum Event { VSync, TimerExpired }
+///
+/// let mut sched = Scheduler::new();
+/// sched.schedule_in(100, Event::VSync);
+/// sched.schedule_in(30, Event::TimerExpired);
+/// assert_eq!(Some(30), sched.cycles_to_next_event());
+///
+/// sched.advance(50);
+/// assert_eq!(Some((30, Event::TimerExpired)), sched.pop_due());
+/// assert_eq!(None, sched.pop_due());
+/// sched.advance(50);
+/// assert_eq!(Some((100, Event::VSync)), sched.pop_due());
+/// ```
+#[derive(Clone)]
+pub struct Scheduler<E> {
+    cycles: u64,
+    /// pending events, sorted by descending cycle count (next event last)
+    events: Vec<(u64, E)>,
+}
+
+impl<E: Copy + PartialEq> Scheduler<E> {
+    /// create a new scheduler with the cycle counter at 0
+    pub fn new() -> Scheduler<E> {
+        Scheduler {
+            cycles: 0,
+            events: Vec::new(),
+        }
+    }
+
+    /// reset the cycle counter to 0 and remove all pending events
+    pub fn reset(&mut self) {
+        self.cycles = 0;
+        self.events.clear();
+    }
+
+    /// the current absolute cycle count
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// advance the cycle counter by a number of executed cycles
+    pub fn advance(&mut self, cycles: i64) {
+        self.cycles += cycles as u64;
+    }
+
+    /// schedule an event at an absolute cycle count
+    pub fn schedule(&mut self, at: u64, event: E) {
+        // insert behind all events at the same or a later cycle count
+        let pos = self.events.iter().rposition(|&(c, _)| c > at).map_or(0, |p| p + 1);
+        self.events.insert(pos, (at, event));
+    }
+
+    /// schedule an event a number of cycles after the current cycle count
+    pub fn schedule_in(&mut self, delay: i64, event: E) {
+        let at = self.cycles + delay as u64;
+        self.schedule(at, event);
+    }
+
+    /// remove all pending instances of an event, return true if any was removed
+    pub fn cancel(&mut self, event: E) -> bool {
+        let num = self.events.len();
+        self.events.retain(|&(_, e)| e != event);
+        num != self.events.len()
+    }
+
+    /// the cycle count of the next pending event
+    pub fn next_event(&self) -> Option<u64> {
+        self.events.last().map(|&(c, _)| c)
+    }
+
+    /// the number of cycles until the next pending event (0 if it is due)
+    pub fn cycles_to_next_event(&self) -> Option<u64> {
+        self.next_event().map(|c| c.saturating_sub(self.cycles))
+    }
+
+    /// remove and return the next event which is due at the current
+    /// cycle count, with the cycle count it was scheduled for
+    pub fn pop_due(&mut self) -> Option<(u64, E)> {
+        if self.next_event().map_or(false, |c| c <= self.cycles) {
+            self.events.pop()
+        } else {
+            None
+        }
+    }
+}
+
+impl<E: Copy + PartialEq> Default for Scheduler<E> {
+    fn default() -> Scheduler<E> {
+        Scheduler::new()
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    enum Event {
+        A,
+        B,
+        C,
+    }
+
+    #[test]
+    fn order() {
+        let mut sched = Scheduler::new();
+        assert_eq!(None, sched.next_event());
+        sched.schedule(20, Event::A);
+        sched.schedule(10, Event::B);
+        sched.schedule(20, Event::C);
+        sched.schedule(10, Event::A);
+        assert_eq!(Some(10), sched.next_event());
+        assert_eq!(None, sched.pop_due());
+        sched.advance(25);
+        assert_eq!(Some(0), sched.cycles_to_next_event());
+        assert_eq!(Some((10, Event::B)), sched.pop_due());
+        assert_eq!(Some((10, Event::A)), sched.pop_due());
+        assert_eq!(Some((20, Event::A)), sched.pop_due());
+        assert_eq!(Some((20, Event::C)), sched.pop_due());
+        assert_eq!(None, sched.pop_due());
+        assert_eq!(25, sched.cycles());
+
+        sched.schedule_in(10, Event::A);
+        assert_eq!(Some(35), sched.next_event());
+        sched.reset();
+        assert_eq!(0, sched.cycles());
+        assert_eq!(None, sched.next_event());
+    }
+
+    #[test]
+    fn cancel() {
+        let mut sched = Scheduler::new();
+        sched.schedule(30, Event::A);
+        sched.schedule(10, Event::B);
+        sched.schedule(20, Event::A);
+        assert!(sched.cancel(Event::A));
+        assert!(!sched.cancel(Event::C));
+        assert_eq!(Some(10), sched.next_event());
+        sched.advance(100);
+        assert_eq!(Some((10, Event::B)), sched.pop_due());
+        assert_eq!(None, sched.pop_due());
+    }
+
+    #[test]
+    fn large_cycle_counts() {
+        // the counter doesn't wrap after 2^32 cycles
+        let mut sched = Scheduler::new();
+        for _ in 0..3 {
+            sched.advance(0x7FFF_FFFF);
+        }
+        sched.schedule_in(1, Event::C);
+        assert_eq!(Some(3 * 0x7FFF_FFFF + 1), sched.next_event());
+        assert_eq!(Some(1), sched.cycles_to_next_event());
+    }
+}
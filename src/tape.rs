@@ -0,0 +1,780 @@
+use RegT;
+use bus::Bus;
+
+// Z1013 tape timing in microseconds (length of a half-wave)
+const Z1013_LEADER_US: i64 = 770;
+const Z1013_SYNC_US: i64 = 385;
+const Z1013_ZERO_US: i64 = 190;
+const Z1013_ONE_US: i64 = 380;
+const Z1013_FIRST_LEADER: usize = 1800;
+const Z1013_LEADER: usize = 14;
+const Z1013_BLOCK_SIZE: usize = 32;
+
+// KC tape timing in microseconds (length of a half-wave)
+const KC_ZERO_US: i64 = 208;
+const KC_ONE_US: i64 = 416;
+const KC_SEP_US: i64 = 833;
+const KC_FIRST_LEADER: usize = 8000;
+const KC_LEADER: usize = 160;
+const KC_BLOCK_SIZE: usize = 128;
+
+/// signature at the start of a KC .TAP file
+pub const KC_TAP_SIGNATURE: &'static [u8] = b"\xC3KC-TAPE by AF. ";
+
+/// convert microseconds into CPU cycles
+fn us_to_cycles(us: i64, freq_khz: i64) -> i64 {
+    (us * freq_khz) / 1000
+}
+
+/// tape player
+///
+/// The Tape object plays back a list of pulses, each pulse is the
+/// duration of a half-wave in CPU cycles, the output level toggles
+/// after each pulse. A pulse list can be created directly from a WAV
+/// file, or from a program file with one of the tape format encoders.
+///
+/// The player must be advanced by calling **update()** with the number
+/// of CPU cycles executed. Each level change of the output is reported
+/// through the **tape_edge()** Bus callback, which would usually trigger
+/// a CTC channel or write a PIO input bit. Alternatively the current
+/// level can be polled with **level()**.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{Tape, Bus};
+///
+/// struct DummyBus;
+/// impl Bus for DummyBus { };
+/// let bus = DummyBus { };
+///
+/// let mut tape = Tape::new(vec![100, 200]);
+/// tape.play();
+/// assert!(!tape.level());
+/// tape.update(&bus, 100);
+/// assert!(tape.level());
+/// tape.update(&bus, 200);
+/// assert!(!tape.level());
+/// assert!(tape.is_finished());
+/// ```
+pub struct Tape {
+    pub pulses: Vec<i64>,
+    pos: usize,
+    counter: i64,
+    level: bool,
+    playing: bool,
+}
+
+impl Tape {
+    /// initialize a new tape from a list of half-wave durations in CPU cycles
+    pub fn new(pulses: Vec<i64>) -> Tape {
+        let counter = if pulses.is_empty() { 0 } else { pulses[0] };
+        Tape {
+            pulses: pulses,
+            pos: 0,
+            counter: counter,
+            level: false,
+            playing: false,
+        }
+    }
+
+    /// initialize a new tape from the content of a WAV file
+    pub fn from_wav(wav: &[u8], freq_khz: i64) -> Result<Tape, String> {
+        Ok(Tape::new(wav_to_pulses(wav, freq_khz)?))
+    }
+
+    /// start playback
+    pub fn play(&mut self) {
+        self.playing = true;
+    }
+
+    /// stop playback
+    pub fn stop(&mut self) {
+        self.playing = false;
+    }
+
+    /// stop playback and rewind to start of tape
+    pub fn rewind(&mut self) {
+        self.playing = false;
+        self.pos = 0;
+        self.counter = if self.pulses.is_empty() { 0 } else { self.pulses[0] };
+        self.level = false;
+    }
+
+    /// return true if the end of the tape has been reached
+    pub fn is_finished(&self) -> bool {
+        self.pos >= self.pulses.len()
+    }
+
+    /// current output level
+    pub fn level(&self) -> bool {
+        self.level
+    }
+
+    /// number of CPU cycles until the next level change, or None
+    /// if the tape is stopped or finished
+    pub fn cycles_to_edge(&self) -> Option<i64> {
+        if self.playing && !self.is_finished() {
+            Some(self.counter.max(0))
+        } else {
+            None
+        }
+    }
+
+    /// advance the tape by a number of CPU cycles
+    pub fn update(&mut self, bus: &Bus, cycles: i64) {
+        if !self.playing {
+            return;
+        }
+        self.counter -= cycles;
+        while self.counter <= 0 && self.pos < self.pulses.len() {
+            self.level = !self.level;
+            bus.tape_edge(self.level);
+            self.pos += 1;
+            if self.pos < self.pulses.len() {
+                self.counter += self.pulses[self.pos];
+            }
+        }
+    }
+}
+
+/// tape recorder
+///
+/// The TapeRecorder records the level changes of a tape output bit
+/// into a list of pulses (durations of half-waves in CPU cycles),
+/// which can be written as WAV file, or converted back into a
+/// program file with one of the tape format decoders.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::TapeRecorder;
+///
+/// let mut rec = TapeRecorder::new();
+/// rec.write(true);
+/// rec.update(100);
+/// rec.write(false);
+/// rec.update(200);
+/// rec.write(true);
+/// assert_eq!(rec.pulses, [100, 200]);
+/// ```
+pub struct TapeRecorder {
+    pub pulses: Vec<i64>,
+    counter: i64,
+    level: bool,
+    started: bool,
+}
+
+impl TapeRecorder {
+    /// initialize a new, empty tape recorder
+    pub fn new() -> TapeRecorder {
+        TapeRecorder {
+            pulses: Vec::new(),
+            counter: 0,
+            level: false,
+            started: false,
+        }
+    }
+
+    /// advance the recorder time by a number of CPU cycles
+    pub fn update(&mut self, cycles: i64) {
+        self.counter += cycles;
+    }
+
+    /// write the current level of the tape output bit
+    pub fn write(&mut self, level: bool) {
+        if level != self.level {
+            self.level = level;
+            if self.started {
+                self.pulses.push(self.counter);
+            }
+            self.started = true;
+            self.counter = 0;
+        }
+    }
+
+    /// convert the recorded pulses into a WAV file
+    pub fn to_wav(&self, freq_khz: i64, sample_rate: u32) -> Vec<u8> {
+        pulses_to_wav(&self.pulses, freq_khz, sample_rate)
+    }
+}
+
+impl Default for TapeRecorder {
+    fn default() -> TapeRecorder {
+        TapeRecorder::new()
+    }
+}
+
+/// convert a WAV file into a list of half-wave durations in CPU cycles
+pub fn wav_to_pulses(wav: &[u8], freq_khz: i64) -> Result<Vec<i64>, String> {
+    fn u16_at(d: &[u8], i: usize) -> usize {
+        d[i] as usize | (d[i + 1] as usize) << 8
+    }
+    fn u32_at(d: &[u8], i: usize) -> usize {
+        u16_at(d, i) | u16_at(d, i + 2) << 16
+    }
+    if wav.len() < 12 || &wav[0..4] != b"RIFF" || &wav[8..12] != b"WAVE" {
+        return Err("not a WAV file".to_string());
+    }
+    let mut pos = 12;
+    let mut format: Option<(usize, usize, usize)> = None;
+    while pos + 8 <= wav.len() {
+        let id = &wav[pos..pos + 4];
+        let size = u32_at(wav, pos + 4);
+        let chunk = &wav[pos + 8..(pos + 8 + size).min(wav.len())];
+        if id == b"fmt " {
+            if chunk.len() < 16 || u16_at(chunk, 0) != 1 {
+                return Err("WAV file must be uncompressed PCM".to_string());
+            }
+            let channels = u16_at(chunk, 2);
+            let sample_rate = u32_at(chunk, 4);
+            let bits = u16_at(chunk, 14);
+            if bits != 8 && bits != 16 {
+                return Err("WAV file must have 8 or 16 bits per sample".to_string());
+            }
+            if channels == 0 || sample_rate == 0 {
+                return Err("WAV file has no channels or no sample rate".to_string());
+            }
+            format = Some((channels, sample_rate, bits));
+        } else if id == b"data" {
+            let (channels, sample_rate, bits) = match format {
+                Some(f) => f,
+                None => return Err("WAV data chunk before fmt chunk".to_string()),
+            };
+            let frame_size = channels * bits / 8;
+            let mut pulses = Vec::new();
+            let mut level = false;
+            let mut num_samples: i64 = 0;
+            for frame in chunk.chunks(frame_size) {
+                if frame.len() < frame_size {
+                    break;
+                }
+                // only look at the first channel
+                let sample = if bits == 8 {
+                    frame[0] as i32 - 0x80
+                } else {
+                    (u16_at(frame, 0) as i16 as i32) >> 8
+                };
+                // level detection with a little hysteresis
+                let new_level = if level { sample > -8 } else { sample > 8 };
+                if new_level != level {
+                    if num_samples > 0 {
+                        pulses.push((num_samples * freq_khz * 1000) / sample_rate as i64);
+                    }
+                    level = new_level;
+                    num_samples = 0;
+                }
+                num_samples += 1;
+            }
+            return Ok(pulses);
+        }
+        pos += 8 + size + (size & 1);
+    }
+    Err("WAV file has no data chunk".to_string())
+}
+
+/// convert a list of half-wave durations in CPU cycles into an 8-bit mono WAV file
+pub fn pulses_to_wav(pulses: &[i64], freq_khz: i64, sample_rate: u32) -> Vec<u8> {
+    let mut samples = Vec::new();
+    let mut level = false;
+    let mut t: i64 = 0;
+    let mut sample_pos: i64 = 0;
+    for pulse in pulses {
+        t += *pulse;
+        let end = (t * sample_rate as i64) / (freq_khz * 1000);
+        level = !level;
+        while sample_pos < end {
+            samples.push(if level { 0xC0 } else { 0x40 });
+            sample_pos += 1;
+        }
+    }
+    let mut wav = Vec::with_capacity(44 + samples.len());
+    let put32 = |v: &mut Vec<u8>, x: u32| v.extend_from_slice(&[x as u8, (x >> 8) as u8, (x >> 16) as u8, (x >> 24) as u8]);
+    wav.extend_from_slice(b"RIFF");
+    put32(&mut wav, 36 + samples.len() as u32);
+    wav.extend_from_slice(b"WAVEfmt ");
+    put32(&mut wav, 16);
+    wav.extend_from_slice(&[1, 0, 1, 0]);   // PCM, 1 channel
+    put32(&mut wav, sample_rate);
+    put32(&mut wav, sample_rate);
+    wav.extend_from_slice(&[1, 0, 8, 0]);   // block align 1, 8 bits per sample
+    wav.extend_from_slice(b"data");
+    put32(&mut wav, samples.len() as u32);
+    wav.extend_from_slice(&samples);
+    wav
+}
+
+/// a program file, loaded into memory at a specific address
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProgramFile {
+    /// file name (without extension)
+    pub name: String,
+    /// file type or extension (e.g. 'C' for Z1013 files or "COM" for KC files)
+    pub ext: String,
+    /// load address
+    pub load_addr: RegT,
+    /// execution start address, if the program is auto-started
+    pub exec_addr: Option<RegT>,
+    /// the program data
+    pub data: Vec<u8>,
+}
+
+impl ProgramFile {
+    /// last address of the program data (inclusive)
+    pub fn end_addr(&self) -> RegT {
+        (self.load_addr + self.data.len() as RegT - 1) & 0xFFFF
+    }
+
+    /// parse a Z1013 headersave file (.z80), with a 32-byte header
+    pub fn from_z80(bytes: &[u8]) -> Result<ProgramFile, String> {
+        if bytes.len() < 32 || bytes[13..16] != [0xD3, 0xD3, 0xD3] {
+            return Err("not a Z1013 headersave file".to_string());
+        }
+        let load = bytes[0] as RegT | (bytes[1] as RegT) << 8;
+        let end = bytes[2] as RegT | (bytes[3] as RegT) << 8;
+        let exec = bytes[4] as RegT | (bytes[5] as RegT) << 8;
+        let len = ((end - load + 1) as usize).min(bytes.len() - 32);
+        Ok(ProgramFile {
+            name: String::from_utf8_lossy(&bytes[16..32]).trim_end().to_string(),
+            ext: (bytes[12] as char).to_string(),
+            load_addr: load,
+            exec_addr: if exec != 0 { Some(exec) } else { None },
+            data: bytes[32..32 + len].to_vec(),
+        })
+    }
+
+    /// write a Z1013 headersave file (.z80)
+    pub fn to_z80(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(32 + self.data.len());
+        let exec = self.exec_addr.unwrap_or(0);
+        let end = self.end_addr();
+        bytes.extend_from_slice(&[self.load_addr as u8, (self.load_addr >> 8) as u8,
+                                  end as u8, (end >> 8) as u8,
+                                  exec as u8, (exec >> 8) as u8]);
+        bytes.extend_from_slice(&[0; 6]);
+        bytes.push(self.ext.bytes().next().unwrap_or(b'C'));
+        bytes.extend_from_slice(&[0xD3, 0xD3, 0xD3]);
+        bytes.extend(padded(&self.name, 16, b' '));
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    /// parse a KC .KCC file (128-byte header block followed by data)
+    pub fn from_kcc(bytes: &[u8]) -> Result<ProgramFile, String> {
+        if bytes.len() < KC_BLOCK_SIZE {
+            return Err("KCC file too short".to_string());
+        }
+        let num_addr = bytes[16];
+        if num_addr < 2 {
+            return Err("not a KCC program file".to_string());
+        }
+        let load = bytes[17] as RegT | (bytes[18] as RegT) << 8;
+        // the end address in the KCC header is exclusive
+        let end = bytes[19] as RegT | (bytes[20] as RegT) << 8;
+        let exec = bytes[21] as RegT | (bytes[22] as RegT) << 8;
+        let len = (((end - load) & 0xFFFF) as usize).min(bytes.len() - KC_BLOCK_SIZE);
+        Ok(ProgramFile {
+            name: String::from_utf8_lossy(&bytes[0..8]).trim_end().to_string(),
+            ext: String::from_utf8_lossy(&bytes[8..11]).trim_end().to_string(),
+            load_addr: load,
+            exec_addr: if num_addr > 2 { Some(exec) } else { None },
+            data: bytes[KC_BLOCK_SIZE..KC_BLOCK_SIZE + len].to_vec(),
+        })
+    }
+
+    /// write a KC .KCC file
+    pub fn to_kcc(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(KC_BLOCK_SIZE + self.data.len());
+        bytes.extend(padded(&self.name, 8, b' '));
+        bytes.extend(padded(&self.ext, 3, b' '));
+        bytes.extend_from_slice(&[0; 5]);
+        let end = (self.end_addr() + 1) & 0xFFFF;
+        let exec = self.exec_addr.unwrap_or(0);
+        bytes.push(if self.exec_addr.is_some() { 3 } else { 2 });
+        bytes.extend_from_slice(&[self.load_addr as u8, (self.load_addr >> 8) as u8,
+                                  end as u8, (end >> 8) as u8,
+                                  exec as u8, (exec >> 8) as u8]);
+        bytes.resize(KC_BLOCK_SIZE, 0);
+        bytes.extend_from_slice(&self.data);
+        // pad data to a multiple of the block size
+        let len = (bytes.len() + KC_BLOCK_SIZE - 1) / KC_BLOCK_SIZE * KC_BLOCK_SIZE;
+        bytes.resize(len, 0);
+        bytes
+    }
+
+    /// parse a KC .TAP file (signature, followed by 129-byte blocks)
+    pub fn from_kc_tap(bytes: &[u8]) -> Result<ProgramFile, String> {
+        if bytes.len() < KC_TAP_SIGNATURE.len() || &bytes[..KC_TAP_SIGNATURE.len()] != KC_TAP_SIGNATURE {
+            return Err("not a KC TAP file".to_string());
+        }
+        let mut kcc = Vec::new();
+        for block in bytes[KC_TAP_SIGNATURE.len()..].chunks(KC_BLOCK_SIZE + 1) {
+            if block.len() > 1 {
+                kcc.extend_from_slice(&block[1..]);
+            }
+        }
+        ProgramFile::from_kcc(&kcc)
+    }
+
+    /// write a KC .TAP file, blocks are numbered from 1, the last block is 0xFF
+    pub fn to_kc_tap(&self) -> Vec<u8> {
+        let mut bytes = KC_TAP_SIGNATURE.to_vec();
+        let kcc = self.to_kcc();
+        let num_blocks = kcc.len() / KC_BLOCK_SIZE;
+        for (i, block) in kcc.chunks(KC_BLOCK_SIZE).enumerate() {
+            bytes.push(if i + 1 == num_blocks { 0xFF } else { (i + 1) as u8 });
+            bytes.extend_from_slice(block);
+        }
+        bytes
+    }
+}
+
+/// return a string as bytes, truncated or padded to a fixed length
+fn padded(s: &str, len: usize, pad: u8) -> Vec<u8> {
+    let mut bytes: Vec<u8> = s.bytes().take(len).collect();
+    bytes.resize(len, pad);
+    bytes
+}
+
+/// push a full wave (2 half-waves of the same duration)
+fn push_wave(pulses: &mut Vec<i64>, half_wave: i64) {
+    pulses.push(half_wave);
+    pulses.push(half_wave);
+}
+
+/// encode data into Z1013 monitor tape format
+///
+/// The data is written in blocks of 32 bytes. Each block starts with a
+/// leader tone and a sync wave, followed by 18 words of 16 bits: a
+/// header word (the block's load address), 16 data words and the
+/// 16-bit sum of all words as checksum. A '0' bit is a full wave
+/// of 2.6 kHz, a '1' bit a single half-wave of 1.3 kHz.
+pub fn z1013_encode(addr: RegT, data: &[u8], freq_khz: i64) -> Vec<i64> {
+    let mut pulses = Vec::new();
+    let zero = us_to_cycles(Z1013_ZERO_US, freq_khz);
+    let one = us_to_cycles(Z1013_ONE_US, freq_khz);
+    for (i, block) in data.chunks(Z1013_BLOCK_SIZE).enumerate() {
+        let leader = if i == 0 { Z1013_FIRST_LEADER } else { Z1013_LEADER };
+        for _ in 0..leader {
+            pulses.push(us_to_cycles(Z1013_LEADER_US, freq_khz));
+        }
+        push_wave(&mut pulses, us_to_cycles(Z1013_SYNC_US, freq_khz));
+        let mut words = vec![(addr as usize + i * Z1013_BLOCK_SIZE) & 0xFFFF];
+        for j in 0..Z1013_BLOCK_SIZE / 2 {
+            let l = *block.get(j * 2).unwrap_or(&0) as usize;
+            let h = *block.get(j * 2 + 1).unwrap_or(&0) as usize;
+            words.push(h << 8 | l);
+        }
+        let sum = words.iter().fold(0, |sum, w| (sum + w) & 0xFFFF);
+        words.push(sum);
+        for w in words {
+            for bit in 0..16 {
+                if (w & (1 << bit)) != 0 {
+                    pulses.push(one);
+                } else {
+                    push_wave(&mut pulses, zero);
+                }
+            }
+        }
+    }
+    // a final edge to terminate the last bit
+    pulses.push(us_to_cycles(Z1013_LEADER_US, freq_khz));
+    pulses
+}
+
+/// decode Z1013 monitor tape format, returns the load address of the first
+/// block and the data
+pub fn z1013_decode(pulses: &[i64], freq_khz: i64) -> Result<(RegT, Vec<u8>), String> {
+    let leader_min = us_to_cycles((Z1013_LEADER_US + Z1013_ONE_US) / 2, freq_khz);
+    let bit_limit = us_to_cycles((Z1013_ZERO_US + Z1013_ONE_US) / 2, freq_khz);
+    let mut data = Vec::new();
+    let mut addr: Option<RegT> = None;
+    let mut pos = 0;
+    loop {
+        // skip leader tone, then the 2 sync half-waves
+        while pos < pulses.len() && pulses[pos] < leader_min {
+            pos += 1;
+        }
+        while pos < pulses.len() && pulses[pos] >= leader_min {
+            pos += 1;
+        }
+        pos += 2;
+        if pos >= pulses.len() {
+            break;
+        }
+        let mut words = [0usize; 2 + Z1013_BLOCK_SIZE / 2];
+        for w in words.iter_mut() {
+            for bit in 0..16 {
+                if pos >= pulses.len() {
+                    return Err("unexpected end of tape".to_string());
+                }
+                if pulses[pos] >= bit_limit {
+                    *w |= 1 << bit;
+                    pos += 1;
+                } else {
+                    pos += 2;
+                }
+            }
+        }
+        let sum = words[..words.len() - 1].iter().fold(0, |sum, w| (sum + w) & 0xFFFF);
+        if sum != words[words.len() - 1] {
+            return Err(format!("checksum error in block {}", data.len() / Z1013_BLOCK_SIZE));
+        }
+        if addr.is_none() {
+            addr = Some(words[0] as RegT);
+        }
+        for w in words[1..words.len() - 1].iter() {
+            data.push(*w as u8);
+            data.push((*w >> 8) as u8);
+        }
+    }
+    match addr {
+        Some(addr) => Ok((addr, data)),
+        None => Err("no data found on tape".to_string()),
+    }
+}
+
+/// encode a program file into KC tape format
+///
+/// The program file is written in KCC layout (a 128-byte header block,
+/// followed by data blocks) into blocks of 128 bytes. Each block starts
+/// with a leader tone of '1' bits and a separator wave, followed by the
+/// block number, 128 data bytes and an 8-bit checksum (the sum of the data
+/// bytes). Each byte is written LSB first and followed by a separator wave.
+/// A '0' bit is a full wave of 2.4 kHz, a '1' bit a full wave of 1.2 kHz,
+/// and a separator a full wave of 600 Hz.
+pub fn kc_encode(prog: &ProgramFile, freq_khz: i64) -> Vec<i64> {
+    let zero = us_to_cycles(KC_ZERO_US, freq_khz);
+    let one = us_to_cycles(KC_ONE_US, freq_khz);
+    let sep = us_to_cycles(KC_SEP_US, freq_khz);
+    let push_byte = |pulses: &mut Vec<i64>, b: u8| {
+        for bit in 0..8 {
+            push_wave(pulses, if (b & (1 << bit)) != 0 { one } else { zero });
+        }
+        push_wave(pulses, sep);
+    };
+    let mut pulses = Vec::new();
+    let tap = prog.to_kc_tap();
+    for (i, block) in tap[KC_TAP_SIGNATURE.len()..].chunks(KC_BLOCK_SIZE + 1).enumerate() {
+        let leader = if i == 0 { KC_FIRST_LEADER } else { KC_LEADER };
+        for _ in 0..leader {
+            push_wave(&mut pulses, one);
+        }
+        push_wave(&mut pulses, sep);
+        push_byte(&mut pulses, block[0]);
+        let mut sum: u8 = 0;
+        for b in block[1..].iter() {
+            push_byte(&mut pulses, *b);
+            sum = sum.wrapping_add(*b);
+        }
+        push_byte(&mut pulses, sum);
+    }
+    pulses
+}
+
+/// decode KC tape format into a program file
+pub fn kc_decode(pulses: &[i64], freq_khz: i64) -> Result<ProgramFile, String> {
+    let bit_limit = us_to_cycles((KC_ZERO_US + KC_ONE_US) / 2, freq_khz);
+    let sep_limit = us_to_cycles((KC_ONE_US + KC_SEP_US) / 2, freq_khz);
+    let mut tap = KC_TAP_SIGNATURE.to_vec();
+    let mut pos = 0;
+    // read a byte and its trailing separator
+    let read_byte = |pos: &mut usize| -> Result<u8, String> {
+        let mut b = 0;
+        for bit in 0..8 {
+            if *pos + 1 >= pulses.len() {
+                return Err("unexpected end of tape".to_string());
+            }
+            if pulses[*pos] >= sep_limit {
+                return Err("unexpected separator".to_string());
+            }
+            if pulses[*pos] >= bit_limit {
+                b |= 1 << bit;
+            }
+            *pos += 2;
+        }
+        *pos += 2;
+        Ok(b)
+    };
+    loop {
+        // skip leader tone until the separator
+        while pos < pulses.len() && pulses[pos] < sep_limit {
+            pos += 1;
+        }
+        pos += 2;
+        if pos >= pulses.len() {
+            break;
+        }
+        let block_nr = read_byte(&mut pos)?;
+        let mut block = Vec::with_capacity(KC_BLOCK_SIZE);
+        let mut sum: u8 = 0;
+        for _ in 0..KC_BLOCK_SIZE {
+            let b = read_byte(&mut pos)?;
+            sum = sum.wrapping_add(b);
+            block.push(b);
+        }
+        if read_byte(&mut pos)? != sum {
+            return Err(format!("checksum error in block {}", block_nr));
+        }
+        tap.push(block_nr);
+        tap.extend(block);
+        if block_nr == 0xFF {
+            break;
+        }
+    }
+    ProgramFile::from_kc_tap(&tap)
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use super::*;
+    use Bus;
+
+    struct TestBus {
+        edges: RefCell<Vec<bool>>,
+    }
+    impl Bus for TestBus {
+        fn tape_edge(&self, level: bool) {
+            self.edges.borrow_mut().push(level);
+        }
+    }
+
+    fn test_prog() -> ProgramFile {
+        ProgramFile {
+            name: "HELLO".to_string(),
+            ext: "COM".to_string(),
+            load_addr: 0x0300,
+            exec_addr: Some(0x0310),
+            data: (0..300).map(|i| (i * 7) as u8).collect(),
+        }
+    }
+
+    #[test]
+    fn play() {
+        let bus = TestBus { edges: RefCell::new(Vec::new()) };
+        let mut tape = Tape::new(vec![10, 20, 30]);
+        tape.update(&bus, 100);
+        assert!(bus.edges.borrow().is_empty());
+        assert_eq!(None, tape.cycles_to_edge());
+        tape.play();
+        assert_eq!(Some(10), tape.cycles_to_edge());
+        tape.update(&bus, 9);
+        assert!(bus.edges.borrow().is_empty());
+        assert_eq!(Some(1), tape.cycles_to_edge());
+        tape.update(&bus, 1);
+        assert_eq!(*bus.edges.borrow(), [true]);
+        assert_eq!(Some(20), tape.cycles_to_edge());
+        tape.update(&bus, 50);
+        assert_eq!(*bus.edges.borrow(), [true, false, true]);
+        assert!(tape.is_finished());
+        assert_eq!(None, tape.cycles_to_edge());
+        tape.rewind();
+        assert!(!tape.is_finished());
+        assert!(!tape.level());
+    }
+
+    #[test]
+    fn record() {
+        let mut rec = TapeRecorder::new();
+        // time before the first edge is ignored
+        rec.update(1000);
+        rec.write(true);
+        rec.update(10);
+        rec.write(true);
+        rec.update(10);
+        rec.write(false);
+        rec.update(5);
+        rec.write(true);
+        assert_eq!(rec.pulses, [20, 5]);
+    }
+
+    #[test]
+    fn wav_roundtrip() {
+        let pulses = [1000, 2000, 1000, 4000, 3000];
+        let wav = pulses_to_wav(&pulses, 2000, 44100);
+        assert_eq!(b"RIFF", &wav[0..4]);
+        let decoded = wav_to_pulses(&wav, 2000).unwrap();
+        // the last pulse doesn't end with an edge
+        assert_eq!(pulses.len() - 1, decoded.len());
+        for (p, d) in pulses.iter().zip(decoded.iter()) {
+            assert!((p - d).abs() < 50);
+        }
+        assert!(wav_to_pulses(b"RIFF0000WAVX", 2000).is_err());
+    }
+
+    #[test]
+    fn wav_malformed_header() {
+        let wav = pulses_to_wav(&[1000, 2000, 1000], 2000, 44100);
+        let patched = |offset: usize, val: &[u8]| {
+            let mut w = wav.clone();
+            w[offset..offset + val.len()].copy_from_slice(val);
+            wav_to_pulses(&w, 2000)
+        };
+        // 0 channels, sample rate 0, 4 bits per sample
+        assert!(patched(22, &[0, 0]).is_err());
+        assert!(patched(24, &[0, 0, 0, 0]).is_err());
+        assert!(patched(34, &[4, 0]).is_err());
+        // truncated fmt chunk
+        assert!(wav_to_pulses(&wav[..30], 2000).is_err());
+    }
+
+    #[test]
+    fn z80_file() {
+        let prog = test_prog();
+        let bytes = prog.to_z80();
+        assert_eq!(32 + 300, bytes.len());
+        assert_eq!(&[0x00, 0x03, 0x2B, 0x04, 0x10, 0x03], &bytes[0..6]);
+        let mut copy = ProgramFile::from_z80(&bytes).unwrap();
+        assert_eq!("C", copy.ext);
+        copy.ext = prog.ext.clone();
+        assert_eq!(prog, copy);
+    }
+
+    #[test]
+    fn kc_files() {
+        let prog = test_prog();
+        let kcc = prog.to_kcc();
+        assert_eq!(128 * 4, kcc.len());
+        assert_eq!(b"HELLO   COM", &kcc[0..11]);
+        assert_eq!(prog, ProgramFile::from_kcc(&kcc).unwrap());
+        let tap = prog.to_kc_tap();
+        assert_eq!(16 + 129 * 4, tap.len());
+        assert_eq!(1, tap[16]);
+        assert_eq!(0xFF, tap[16 + 129 * 3]);
+        assert_eq!(prog, ProgramFile::from_kc_tap(&tap).unwrap());
+    }
+
+    #[test]
+    fn z1013_tape() {
+        let prog = test_prog();
+        let pulses = z1013_encode(prog.load_addr, &prog.data, 2000);
+        let (addr, data) = z1013_decode(&pulses, 2000).unwrap();
+        assert_eq!(0x0300, addr);
+        // data is padded to full blocks
+        assert_eq!(320, data.len());
+        assert_eq!(&prog.data[..], &data[..300]);
+
+        // through a WAV file
+        let wav = pulses_to_wav(&pulses, 2000, 44100);
+        let (_, data) = z1013_decode(&wav_to_pulses(&wav, 2000).unwrap(), 2000).unwrap();
+        assert_eq!(&prog.data[..], &data[..300]);
+
+        // a broken bit results in a checksum error
+        let mut broken = pulses.clone();
+        let pos = broken.len() - 100;
+        broken[pos] = broken[pos] * 2;
+        broken.insert(pos, 0);
+        assert!(z1013_decode(&broken, 2000).is_err());
+    }
+
+    #[test]
+    fn kc_tape() {
+        let prog = test_prog();
+        let pulses = kc_encode(&prog, 2458);
+        assert_eq!(prog, kc_decode(&pulses, 2458).unwrap());
+        let wav = pulses_to_wav(&pulses, 2458, 22050);
+        assert_eq!(prog, kc_decode(&wav_to_pulses(&wav, 2458).unwrap(), 2458).unwrap());
+    }
+}
@@ -0,0 +1,196 @@
+use RegT;
+use memory::Memory;
+
+/// default monochrome palette (black background, white foreground)
+pub static MONO_PALETTE: [u32; 2] = [0xFF000000, 0xFFFFFFFF];
+
+/// decode a color attribute byte with foreground color in bits 4..6,
+/// background color in bits 0..2 and a blink flag in bit 7, blinking
+/// characters swap foreground and background colors while the blink
+/// state is active
+pub fn decode_attr_blink(attr: u8, blink: bool) -> (usize, usize) {
+    let fg = ((attr >> 4) & 7) as usize;
+    let bg = (attr & 7) as usize;
+    if (attr & 0x80) != 0 && blink {
+        (bg, fg)
+    } else {
+        (fg, bg)
+    }
+}
+
+/// character-based video display decoder
+///
+/// Many simple home computers have a character-based video display
+/// where each byte in video RAM is a character code which is expanded
+/// to pixels through a font ROM, optionally with a second color
+/// attribute byte for each character. The TextDisplay decodes such a
+/// display into a linear RGBA8 framebuffer.
+///
+/// The font ROM stores **cell_height** bytes per character, one byte
+/// per pixel row with the leftmost pixel in the most significant bit.
+///
+/// # Examples
+///
+/// Decode a 32x32 character display at address 0xEC00 (similar to
+/// the Z1013):
+///
+/// ```
+/// use rz80::{Memory, TextDisplay};
+///
+/// let mut mem = Memory::new_64k();
+/// let font = [0xFFu8; 256 * 8];
+/// mem.w8(0xEC00, 0x41);
+///
+/// let disp = TextDisplay::new(0xEC00, 32, 32, &font);
+/// let mut fb = vec![0u32; disp.width() * disp.height()];
+/// disp.decode(&mem, &mut fb);
+/// assert_eq!(fb[0], 0xFFFFFFFF);
+/// ```
+///
+/// With color attribute RAM at 0xE800 and an 8-color palette (similar
+/// to the KC87):
+///
+/// ```
+/// use rz80::{Memory, TextDisplay};
+///
+/// let mut mem = Memory::new_64k();
+/// let font = [0x0Fu8; 256 * 8];
+/// let palette = [0xFF000000, 0xFFFF0000, 0xFF00FF00, 0xFFFFFF00,
+///                0xFF0000FF, 0xFFFF00FF, 0xFF00FFFF, 0xFFFFFFFF];
+/// // red foreground on blue background
+/// mem.w8(0xE800, 0x14);
+///
+/// let mut disp = TextDisplay::new(0xEC00, 40, 24, &font);
+/// disp.color_addr = Some(0xE800);
+/// disp.palette = &palette;
+/// let mut fb = vec![0u32; disp.width() * disp.height()];
+/// disp.decode(&mem, &mut fb);
+/// assert_eq!(fb[0], 0xFF0000FF);
+/// assert_eq!(fb[7], 0xFFFF0000);
+/// ```
+pub struct TextDisplay<'a> {
+    /// start address of video RAM (one byte per character)
+    pub video_addr: RegT,
+    /// start address of color attribute RAM, or None for monochrome displays
+    pub color_addr: Option<RegT>,
+    /// number of character columns
+    pub columns: usize,
+    /// number of character rows
+    pub rows: usize,
+    /// width of a character cell in pixels (max 8)
+    pub cell_width: usize,
+    /// height of a character cell in pixels
+    pub cell_height: usize,
+    /// font ROM pixel data
+    pub font: &'a [u8],
+    /// RGBA8 color palette
+    pub palette: &'a [u32],
+    /// function which decodes a color attribute into foreground/background palette indices
+    pub decode_attr: fn(u8, bool) -> (usize, usize),
+    /// current blink state
+    pub blink: bool,
+}
+
+impl<'a> TextDisplay<'a> {
+    /// initialize a new monochrome text display with 8x8 character cells
+    pub fn new(video_addr: RegT, columns: usize, rows: usize, font: &'a [u8]) -> TextDisplay<'a> {
+        TextDisplay {
+            video_addr: video_addr,
+            color_addr: None,
+            columns: columns,
+            rows: rows,
+            cell_width: 8,
+            cell_height: 8,
+            font: font,
+            palette: &MONO_PALETTE,
+            decode_attr: decode_attr_blink,
+            blink: false,
+        }
+    }
+
+    /// width of the decoded framebuffer in pixels
+    pub fn width(&self) -> usize {
+        self.columns * self.cell_width
+    }
+
+    /// height of the decoded framebuffer in pixels
+    pub fn height(&self) -> usize {
+        self.rows * self.cell_height
+    }
+
+    /// decode the video RAM into a linear RGBA8 framebuffer, panics
+    /// if cell_width is greater than 8
+    pub fn decode(&self, mem: &Memory, fb: &mut [u32]) {
+        assert!(self.cell_width <= 8);
+        let mut fb_iter = fb.iter_mut();
+        for y in 0..self.rows {
+            for py in 0..self.cell_height {
+                for x in 0..self.columns {
+                    let offset = (y * self.columns + x) as RegT;
+                    let chr = mem.r8(self.video_addr + offset) as usize;
+                    let bits = self.font[(chr * self.cell_height + py) % self.font.len()];
+                    let (fg, bg) = match self.color_addr {
+                        Some(addr) => {
+                            let attr = mem.r8(addr + offset) as u8;
+                            let (fg, bg) = (self.decode_attr)(attr, self.blink);
+                            (self.palette[fg], self.palette[bg])
+                        }
+                        None => (self.palette[1], self.palette[0]),
+                    };
+                    for px in 0..self.cell_width {
+                        let pixel = if (bits & (0x80 >> px)) != 0 { fg } else { bg };
+                        *fb_iter.next().unwrap() = pixel;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use super::*;
+    use Memory;
+
+    #[test]
+    fn decode_attr() {
+        assert_eq!((7, 1), decode_attr_blink(0x71, false));
+        assert_eq!((7, 1), decode_attr_blink(0x71, true));
+        assert_eq!((7, 1), decode_attr_blink(0xF1, false));
+        assert_eq!((1, 7), decode_attr_blink(0xF1, true));
+    }
+
+    #[test]
+    fn decode_cells() {
+        let mut mem = Memory::new_64k();
+        // 2 characters, 6x4 pixel cells
+        let font = [0x80, 0x40, 0x20, 0x10, 0xFC, 0x00, 0xFC, 0x00];
+        mem.w8(0x1000, 0x00);
+        mem.w8(0x1001, 0x01);
+        mem.w8(0x2000, 0x12);
+        mem.w8(0x2001, 0xA3);
+        let palette = [0, 1, 2, 3, 4, 5, 6, 7];
+        let mut disp = TextDisplay::new(0x1000, 2, 1, &font);
+        disp.cell_width = 6;
+        disp.cell_height = 4;
+        assert_eq!(12, disp.width());
+        assert_eq!(4, disp.height());
+        let mut fb = vec![0u32; 12 * 4];
+        disp.decode(&mem, &mut fb);
+        assert_eq!(MONO_PALETTE[1], fb[0]);
+        assert_eq!(MONO_PALETTE[0], fb[1]);
+        assert_eq!(MONO_PALETTE[1], fb[12 + 1]);
+        assert_eq!(MONO_PALETTE[1], fb[6 + 5]);
+        assert_eq!(MONO_PALETTE[0], fb[12 + 6]);
+
+        disp.color_addr = Some(0x2000);
+        disp.palette = &palette;
+        disp.decode(&mem, &mut fb);
+        assert_eq!(&[1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2], &fb[..12]);
+        assert_eq!(&[2, 2, 2, 1, 2, 2, 3, 3, 3, 3, 3, 3], &fb[36..48]);
+        disp.blink = true;
+        disp.decode(&mem, &mut fb);
+        assert_eq!(&[1, 2, 2, 2, 2, 2, 3, 3, 3, 3, 3, 3], &fb[..12]);
+    }
+}
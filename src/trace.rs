@@ -0,0 +1,311 @@
+use std::io::{self, Write};
+use RegT;
+use cpu::CPU;
+use disasm::disassemble;
+
+/// the line format of execution traces
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TraceFormat {
+    /// cycle counter, PC, opcode bytes, disassembly and all registers
+    Full,
+    /// MAME debugger 'trace' command style: PC and disassembly only
+    Mame,
+    /// 'doctor' style register dump with the 4 bytes at PC, no disassembly
+    Doctor,
+}
+
+/// execution trace logger
+///
+/// A Tracer is attached to a CPU with **CPU::set_tracer()**, after that
+/// the CPU writes one line per executed instruction to the tracer's
+/// writer, with the CPU state before the instruction is executed. The
+/// output can be limited to address ranges, started and stopped when
+/// PC reaches a trigger address, and capped to a maximum number of lines.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{CPU, Bus, Tracer, TraceFormat};
+///
+/// struct DummyBus;
+/// impl Bus for DummyBus { };
+///
+/// let mut cpu = CPU::new_64k();
+/// let bus = DummyBus { };
+/// cpu.mem.write(0x0100, &[0x3E, 0x11, 0x06, 0x22, 0x80]);
+/// cpu.reg.set_pc(0x0100);
+///
+/// // trace only from the ADD instruction on, the lines are discarded
+/// // here (pass a file or stdout instead to keep them)
+/// let mut tracer = Tracer::new(std::io::sink(), TraceFormat::Mame);
+/// tracer.set_trigger(0x0104);
+/// cpu.set_tracer(tracer);
+/// for _ in 0..3 {
+///     cpu.step(&bus);
+/// }
+/// let tracer = cpu.take_tracer().unwrap();
+/// assert_eq!(tracer.cycles(), 18);
+/// assert_eq!(tracer.lines(), 1);
+/// ```
+pub struct Tracer {
+    writer: Box<Write>,
+    format: TraceFormat,
+    cycles: i64,
+    lines: usize,
+    ranges: Vec<(RegT, RegT)>,
+    trigger: Option<RegT>,
+    stop: Option<RegT>,
+    limit: Option<usize>,
+    active: bool,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    /// create a new tracer writing to a writer
+    pub fn new<W: Write + 'static>(writer: W, format: TraceFormat) -> Tracer {
+        Tracer {
+            writer: Box::new(writer),
+            format: format,
+            cycles: 0,
+            lines: 0,
+            ranges: Vec::new(),
+            trigger: None,
+            stop: None,
+            limit: None,
+            active: true,
+            error: None,
+        }
+    }
+
+    /// only log instructions in an address range (inclusive), can be called multiple times
+    pub fn add_range(&mut self, start: RegT, end: RegT) {
+        self.ranges.push((start & 0xFFFF, end & 0xFFFF));
+    }
+
+    /// start logging when PC reaches an address
+    pub fn set_trigger(&mut self, addr: RegT) {
+        self.trigger = Some(addr & 0xFFFF);
+        self.active = false;
+    }
+
+    /// stop logging when PC reaches an address (the instruction isn't logged)
+    pub fn set_stop(&mut self, addr: RegT) {
+        self.stop = Some(addr & 0xFFFF);
+    }
+
+    /// stop logging after a number of lines
+    pub fn set_limit(&mut self, max_lines: usize) {
+        self.limit = Some(max_lines);
+    }
+
+    /// the number of cycles executed since the tracer was attached
+    pub fn cycles(&self) -> i64 {
+        self.cycles
+    }
+
+    /// the number of lines written so far
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+
+    /// return true while instructions are logged
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// return the first write error, logging stops after an error
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    /// flush the writer
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// called by the CPU before an instruction is executed
+    pub fn log(&mut self, cpu: &CPU) {
+        let pc = cpu.reg.pc();
+        if self.trigger == Some(pc) {
+            self.active = true;
+        }
+        if self.stop == Some(pc) {
+            self.active = false;
+        }
+        if !self.active || self.error.is_some() {
+            return;
+        }
+        if !self.ranges.is_empty() && !self.ranges.iter().any(|&(s, e)| pc >= s && pc <= e) {
+            return;
+        }
+        if let Some(limit) = self.limit {
+            if self.lines >= limit {
+                self.active = false;
+                return;
+            }
+        }
+        let line = self.format_line(cpu);
+        match writeln!(self.writer, "{}", line) {
+            Ok(_) => self.lines += 1,
+            Err(err) => self.error = Some(err),
+        }
+    }
+
+    /// called by the CPU after an instruction was executed
+    pub fn add_cycles(&mut self, cycles: i64) {
+        self.cycles += cycles;
+    }
+
+    fn format_line(&self, cpu: &CPU) -> String {
+        let r = &cpu.reg;
+        let pc = r.pc();
+        match self.format {
+            TraceFormat::Full => format_full(cpu, self.cycles),
+            TraceFormat::Mame => {
+                let (text, _) = disassemble(&cpu.mem, pc);
+                format!("{:04X}: {}", pc, text)
+            }
+            TraceFormat::Doctor => {
+                format!("A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} \
+                         L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+                        r.a(),
+                        r.f(),
+                        r.b(),
+                        r.c(),
+                        r.d(),
+                        r.e(),
+                        r.h(),
+                        r.l(),
+                        r.sp(),
+                        pc,
+                        cpu.mem.r8(pc),
+                        cpu.mem.r8((pc + 1) & 0xFFFF),
+                        cpu.mem.r8((pc + 2) & 0xFFFF),
+                        cpu.mem.r8((pc + 3) & 0xFFFF))
+            }
+        }
+    }
+}
+
+/// format the CPU state in the 'full' trace format
+pub fn format_full(cpu: &CPU, cycles: i64) -> String {
+    let r = &cpu.reg;
+    let pc = r.pc();
+    let (text, len) = disassemble(&cpu.mem, pc);
+    let bytes: Vec<String> = (0..len as RegT)
+        .map(|i| format!("{:02X}", cpu.mem.r8((pc + i) & 0xFFFF)))
+        .collect();
+    format!("{:>10} {:04X}  {:<11} {:<18} AF={:04X} BC={:04X} DE={:04X} HL={:04X} IX={:04X} \
+             IY={:04X} SP={:04X} AF'={:04X} BC'={:04X} DE'={:04X} HL'={:04X} I={:02X} R={:02X} \
+             WZ={:04X} IM={} IFF={}{}",
+            cycles,
+            pc,
+            bytes.join(" "),
+            text,
+            r.af(),
+            r.bc(),
+            r.de(),
+            r.hl(),
+            r.ix(),
+            r.iy(),
+            r.sp(),
+            r.af_(),
+            r.bc_(),
+            r.de_(),
+            r.hl_(),
+            r.i,
+            r.r,
+            r.wz(),
+            r.im,
+            cpu.iff1 as u8,
+            cpu.iff2 as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use Bus;
+
+    struct TestBus;
+    impl Bus for TestBus {}
+
+    // a writer which can still be read after the tracer took ownership
+    #[derive(Clone)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    impl SharedBuf {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.borrow().clone()).unwrap().lines().map(String::from).collect()
+        }
+    }
+
+    fn run(tracer: Tracer, num_ops: usize) -> CPU {
+        let mut cpu = CPU::new_64k();
+        // LD A,$11; LD B,$22; ADD A,B; JR $0000
+        cpu.mem.write(0x0000, &[0x3E, 0x11, 0x06, 0x22, 0x80, 0x18, 0xF9]);
+        cpu.set_tracer(tracer);
+        for _ in 0..num_ops {
+            cpu.step(&TestBus {});
+        }
+        cpu
+    }
+
+    #[test]
+    fn formats() {
+        let buf = SharedBuf(Rc::new(RefCell::new(Vec::new())));
+        run(Tracer::new(buf.clone(), TraceFormat::Full), 3);
+        let lines = buf.lines();
+        assert_eq!(3, lines.len());
+        assert!(lines[0].starts_with("         0 0000  3E 11       LD A,$11           AF=0000"));
+        assert!(lines[2].starts_with("        14 0004  80          ADD A,B            AF=1100 \
+                                      BC=2200"));
+        assert!(lines[2].ends_with("I=00 R=02 WZ=0000 IM=0 IFF=00"));
+
+        let buf = SharedBuf(Rc::new(RefCell::new(Vec::new())));
+        run(Tracer::new(buf.clone(), TraceFormat::Mame), 4);
+        assert_eq!(buf.lines(), vec!["0000: LD A,$11", "0002: LD B,$22", "0004: ADD A,B",
+                                     "0005: JR $0000"]);
+
+        let buf = SharedBuf(Rc::new(RefCell::new(Vec::new())));
+        run(Tracer::new(buf.clone(), TraceFormat::Doctor), 1);
+        assert_eq!(buf.lines(), vec!["A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:0000 \
+                                      PC:0000 PCMEM:3E,11,06,22"]);
+    }
+
+    #[test]
+    fn filters() {
+        // address range
+        let buf = SharedBuf(Rc::new(RefCell::new(Vec::new())));
+        let mut tracer = Tracer::new(buf.clone(), TraceFormat::Mame);
+        tracer.add_range(0x0004, 0x0004);
+        let mut cpu = run(tracer, 8);
+        assert_eq!(buf.lines(), vec!["0004: ADD A,B", "0004: ADD A,B"]);
+        assert_eq!(2 * (7 + 7 + 4 + 12), cpu.take_tracer().unwrap().cycles());
+
+        // trigger, stop and line limit
+        let buf = SharedBuf(Rc::new(RefCell::new(Vec::new())));
+        let mut tracer = Tracer::new(buf.clone(), TraceFormat::Mame);
+        tracer.set_trigger(0x0004);
+        tracer.set_stop(0x0002);
+        run(tracer, 12);
+        assert_eq!(buf.lines(), vec!["0004: ADD A,B", "0005: JR $0000", "0000: LD A,$11",
+                                     "0004: ADD A,B", "0005: JR $0000", "0000: LD A,$11",
+                                     "0004: ADD A,B", "0005: JR $0000"]);
+
+        let buf = SharedBuf(Rc::new(RefCell::new(Vec::new())));
+        let mut tracer = Tracer::new(buf.clone(), TraceFormat::Mame);
+        tracer.set_limit(2);
+        let mut cpu = run(tracer, 8);
+        assert_eq!(2, buf.lines().len());
+        assert!(!cpu.take_tracer().unwrap().is_active());
+    }
+}
@@ -0,0 +1,444 @@
+use std::collections::VecDeque;
+use std::io::BufRead;
+use RegT;
+use bus::Bus;
+use cpu::CPU;
+use registers::{CF, NF, PF, HF, XF, YF, ZF, SF};
+use trace::format_full;
+
+static FLAG_NAMES: [(RegT, &'static str); 8] = [(SF, "SF"), (ZF, "ZF"), (YF, "YF"), (HF, "HF"),
+                                                (XF, "XF"), (PF, "PF"), (NF, "NF"), (CF, "CF")];
+
+/// one executed instruction in the reference trace
+struct Record {
+    line: usize,
+    text: String,
+    cycles: Option<i64>,
+    regs: Vec<(String, RegT)>,
+    writes: Vec<(RegT, RegT)>,
+}
+
+/// the first difference between rz80 and a reference trace
+#[derive(Debug)]
+pub struct TraceMismatch {
+    /// line number in the reference trace (starting at 1)
+    pub line: usize,
+    /// the reference trace lines before the mismatch
+    pub context: Vec<String>,
+    /// the reference trace line of the mismatching instruction
+    pub expected: String,
+    /// the rz80 CPU state in the 'full' trace format
+    pub actual: String,
+    /// a description of each difference
+    pub errors: Vec<String>,
+}
+
+impl TraceMismatch {
+    /// format the mismatch as multi-line report
+    pub fn report(&self) -> String {
+        let mut res = format!("trace mismatch in line {}:\n", self.line);
+        for line in &self.context {
+            res += &format!("  {}\n", line);
+        }
+        res += &format!("> {}\n", self.expected);
+        res += &format!("  rz80: {}\n", self.actual);
+        for err in &self.errors {
+            res += &format!("  {}\n", err);
+        }
+        res
+    }
+}
+
+/// compares rz80 against a recorded reference trace
+///
+/// The TraceChecker reads a reference trace line by line and compares
+/// the CPU state before each instruction with the registers in the
+/// trace line, and after the instruction the memory writes. Stepping
+/// stops at the first difference with a report which includes the
+/// preceding trace lines and the differing flag bits.
+///
+/// Registers are read from KEY=VALUE or KEY:VALUE pairs with
+/// hexadecimal values, which covers the 'full' and 'doctor' formats
+/// of the **Tracer** and similar formats of other emulators. Known
+/// keys are A, F, B, C, D, E, H, L, AF, BC, DE, HL, IX, IY, SP, PC,
+/// AF', BC', DE', HL', I, R, WZ (or MEMPTR), IM, IFF1, IFF2 and IFF
+/// (two binary digits), other keys are ignored. A MAME style 'PC:'
+/// at the start of a line is also accepted, and a decimal number at
+/// the start of a line is checked against the executed cycles (and may
+/// be followed by the PC without a key, as in the 'full' format). Memory
+/// writes are MW:ADDR=VALUE pairs, either in the line of the instruction
+/// or in separate lines after it.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{CPU, Bus, TraceChecker};
+///
+/// struct DummyBus;
+/// impl Bus for DummyBus { };
+///
+/// let mut cpu = CPU::new_64k();
+/// let bus = DummyBus { };
+/// cpu.mem.write(0x0000, &[0x3E, 0x11, 0x32, 0x00, 0x40, 0x3C]);
+///
+/// // the reference trace expects A to be 0x12 after storing it
+/// let reference = "PC=0000 A=00\n\
+///                  PC=0002 A=11\n\
+///                  MW:4000=11\n\
+///                  PC=0005 A=12\n";
+/// let mut checker = TraceChecker::new(reference.as_bytes(), 2);
+/// assert_eq!(checker.step(&mut cpu, &bus).unwrap(), Some(7));
+/// assert_eq!(checker.step(&mut cpu, &bus).unwrap(), Some(13));
+/// let mismatch = checker.step(&mut cpu, &bus).unwrap_err();
+/// assert_eq!(mismatch.line, 4);
+/// assert_eq!(mismatch.errors, vec!["A: expected 12, found 11"]);
+/// ```
+pub struct TraceChecker {
+    reader: Box<BufRead>,
+    line: usize,
+    next: Option<Record>,
+    current: Option<Record>,
+    context: VecDeque<String>,
+    num_context: usize,
+    cycles: i64,
+    base_cycles: Option<i64>,
+    checked: usize,
+}
+
+/// get a register value from the CPU by trace key
+fn reg_value(cpu: &CPU, key: &str) -> Option<RegT> {
+    let r = &cpu.reg;
+    Some(match key {
+        "A" => r.a(),
+        "F" => r.f(),
+        "B" => r.b(),
+        "C" => r.c(),
+        "D" => r.d(),
+        "E" => r.e(),
+        "H" => r.h(),
+        "L" => r.l(),
+        "AF" => r.af(),
+        "BC" => r.bc(),
+        "DE" => r.de(),
+        "HL" => r.hl(),
+        "IX" => r.ix(),
+        "IY" => r.iy(),
+        "SP" => r.sp(),
+        "PC" => r.pc(),
+        "AF'" => r.af_(),
+        "BC'" => r.bc_(),
+        "DE'" => r.de_(),
+        "HL'" => r.hl_(),
+        "I" => r.i,
+        "R" => r.r,
+        "WZ" | "MEMPTR" => r.wz(),
+        "IM" => r.im,
+        "IFF1" => cpu.iff1 as RegT,
+        "IFF2" => cpu.iff2 as RegT,
+        "IFF" => (cpu.iff1 as RegT) << 1 | cpu.iff2 as RegT,
+        _ => return None,
+    })
+}
+
+/// describe the differing flag bits
+fn flag_diff(expected: RegT, found: RegT) -> String {
+    let diff = (expected ^ found) & 0xFF;
+    let names: Vec<&str> = FLAG_NAMES.iter()
+        .filter(|&&(mask, _)| diff & mask != 0)
+        .map(|&(_, name)| name)
+        .collect();
+    names.join(" ")
+}
+
+/// parse one reference trace line, return None if it isn't an instruction or memory write
+fn parse_line(line: usize, text: &str) -> Option<Record> {
+    let mut rec = Record {
+        line: line,
+        text: text.to_string(),
+        cycles: None,
+        regs: Vec::new(),
+        writes: Vec::new(),
+    };
+    for (i, token) in text.split_whitespace().enumerate() {
+        if i == 0 {
+            if let Ok(cycles) = token.parse::<i64>() {
+                rec.cycles = Some(cycles);
+                continue;
+            }
+            // MAME style 'PC: instruction'
+            if token.len() == 5 && token.ends_with(':') {
+                if let Ok(pc) = RegT::from_str_radix(&token[..4], 16) {
+                    rec.regs.push(("PC".to_string(), pc));
+                    continue;
+                }
+            }
+        }
+        // 'full' format: cycles followed by PC
+        if i == 1 && rec.cycles.is_some() && token.len() == 4 {
+            if let Ok(pc) = RegT::from_str_radix(token, 16) {
+                rec.regs.push(("PC".to_string(), pc));
+                continue;
+            }
+        }
+        let sep = match token.find(['=', ':']) {
+            Some(sep) => sep,
+            None => continue,
+        };
+        let key = token[..sep].to_uppercase();
+        let val = &token[sep + 1..];
+        if key == "MW" {
+            let mut parts = val.splitn(2, '=');
+            let addr = parts.next().and_then(|a| RegT::from_str_radix(a, 16).ok());
+            let data = parts.next().and_then(|d| RegT::from_str_radix(d, 16).ok());
+            if let (Some(addr), Some(data)) = (addr, data) {
+                rec.writes.push((addr & 0xFFFF, data & 0xFF));
+            }
+            continue;
+        }
+        let radix = if key == "IFF" { 2 } else { 16 };
+        if let Ok(v) = RegT::from_str_radix(val, radix) {
+            rec.regs.push((key, v));
+        }
+    }
+    if rec.regs.is_empty() && rec.writes.is_empty() {
+        None
+    } else {
+        Some(rec)
+    }
+}
+
+impl TraceChecker {
+    /// create a new checker reading a reference trace, with a number of context lines
+    pub fn new<R: BufRead + 'static>(reader: R, context_lines: usize) -> TraceChecker {
+        TraceChecker {
+            reader: Box::new(reader),
+            line: 0,
+            next: None,
+            current: None,
+            context: VecDeque::new(),
+            num_context: context_lines,
+            cycles: 0,
+            base_cycles: None,
+            checked: 0,
+        }
+    }
+
+    /// the number of instructions checked so far
+    pub fn checked(&self) -> usize {
+        self.checked
+    }
+
+    fn mismatch(&self, cpu: &CPU, rec: &Record, errors: Vec<String>) -> TraceMismatch {
+        TraceMismatch {
+            line: rec.line,
+            context: self.context.iter().cloned().collect(),
+            expected: rec.text.clone(),
+            actual: format_full(cpu, self.cycles),
+            errors: errors,
+        }
+    }
+
+    /// read the next trace line which contains registers or memory writes
+    fn read_line(&mut self) -> Result<Option<Record>, String> {
+        loop {
+            let mut text = String::new();
+            match self.reader.read_line(&mut text) {
+                Ok(0) => return Ok(None),
+                Ok(_) => self.line += 1,
+                Err(err) => return Err(format!("unable to read line {}: {}", self.line + 1, err)),
+            }
+            let text = text.trim_end();
+            if text.is_empty() || text.starts_with('#') {
+                continue;
+            }
+            if let Some(rec) = parse_line(self.line, text) {
+                return Ok(Some(rec));
+            }
+        }
+    }
+
+    /// read the next instruction record, including the memory writes in the following lines
+    fn read_record(&mut self) -> Result<Option<Record>, String> {
+        let mut rec = match self.next.take() {
+            Some(rec) => rec,
+            None => {
+                loop {
+                    match self.read_line()? {
+                        Some(rec) => {
+                            if rec.regs.iter().any(|r| r.0 == "PC") {
+                                break rec;
+                            }
+                        }
+                        None => return Ok(None),
+                    }
+                }
+            }
+        };
+        while let Some(next) = self.read_line()? {
+            if next.regs.iter().any(|r| r.0 == "PC") {
+                self.next = Some(next);
+                break;
+            }
+            rec.writes.extend(next.writes);
+        }
+        Ok(Some(rec))
+    }
+
+    /// compare the CPU state before the next instruction, return false at the end of the trace
+    pub fn check(&mut self, cpu: &CPU) -> Result<bool, TraceMismatch> {
+        let rec = match self.read_record() {
+            Ok(Some(rec)) => rec,
+            Ok(None) => return Ok(false),
+            Err(err) => {
+                return Err(TraceMismatch {
+                    line: self.line,
+                    context: Vec::new(),
+                    expected: String::new(),
+                    actual: format_full(cpu, self.cycles),
+                    errors: vec![err],
+                })
+            }
+        };
+        let mut errors = Vec::new();
+        if let Some(cycles) = rec.cycles {
+            let base = *self.base_cycles.get_or_insert(cycles - self.cycles);
+            if cycles - base != self.cycles {
+                errors.push(format!("cycles: expected {}, found {}", cycles - base, self.cycles));
+            }
+        }
+        for &(ref key, expected) in &rec.regs {
+            if let Some(found) = reg_value(cpu, key) {
+                if found != expected {
+                    let width = if found > 0xFF || expected > 0xFF { 4 } else { 2 };
+                    let mut err = format!("{}: expected {:0w$X}, found {:0w$X}",
+                                          key, expected, found, w = width);
+                    if key == "F" || key == "AF" {
+                        err += &format!(" (flags differ: {})", flag_diff(expected, found));
+                    }
+                    errors.push(err);
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(self.mismatch(cpu, &rec, errors));
+        }
+        self.current = Some(rec);
+        Ok(true)
+    }
+
+    /// check the memory writes after the instruction was executed
+    pub fn check_after(&mut self, cpu: &CPU, cycles: i64) -> Result<(), TraceMismatch> {
+        self.cycles += cycles;
+        if let Some(rec) = self.current.take() {
+            let errors: Vec<String> = rec.writes.iter()
+                .filter(|&&(addr, val)| cpu.mem.r8(addr) != val)
+                .map(|&(addr, val)| {
+                    format!("memory write {:04X}: expected {:02X}, found {:02X}",
+                            addr, val, cpu.mem.r8(addr))
+                })
+                .collect();
+            if !errors.is_empty() {
+                return Err(self.mismatch(cpu, &rec, errors));
+            }
+            self.checked += 1;
+            if self.num_context > 0 {
+                if self.context.len() == self.num_context {
+                    self.context.pop_front();
+                }
+                self.context.push_back(rec.text);
+            }
+        }
+        Ok(())
+    }
+
+    /// check and execute one instruction, return None at the end of the trace
+    pub fn step(&mut self, cpu: &mut CPU, bus: &Bus) -> Result<Option<i64>, TraceMismatch> {
+        if !self.check(cpu)? {
+            return Ok(None);
+        }
+        let cycles = cpu.step(bus);
+        self.check_after(cpu, cycles)?;
+        Ok(Some(cycles))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use trace::{Tracer, TraceFormat};
+
+    struct TestBus;
+    impl Bus for TestBus {}
+
+    #[derive(Clone)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // LD A,$0F; INC A; LD ($4000),A; LD HL,$1234; JR $0000
+    static PROG: [u8; 11] = [0x3E, 0x0F, 0x3C, 0x32, 0x00, 0x40, 0x21, 0x34, 0x12, 0x18, 0xF5];
+
+    fn cpu() -> CPU {
+        let mut cpu = CPU::new_64k();
+        cpu.mem.write(0x0000, &PROG);
+        cpu
+    }
+
+    fn record(format: TraceFormat, num_ops: usize) -> String {
+        let buf = SharedBuf(Rc::new(RefCell::new(Vec::new())));
+        let mut cpu = cpu();
+        cpu.set_tracer(Tracer::new(buf.clone(), format));
+        for _ in 0..num_ops {
+            cpu.step(&TestBus {});
+        }
+        let text = String::from_utf8(buf.0.borrow().clone()).unwrap();
+        text
+    }
+
+    fn check(reference: String) -> Result<usize, TraceMismatch> {
+        let mut cpu = cpu();
+        let mut checker = TraceChecker::new(io::Cursor::new(reference.into_bytes()), 2);
+        while checker.step(&mut cpu, &TestBus {})?.is_some() {}
+        Ok(checker.checked())
+    }
+
+    #[test]
+    fn matching_traces() {
+        assert_eq!(12, check(record(TraceFormat::Full, 12)).unwrap());
+        assert_eq!(12, check(record(TraceFormat::Doctor, 12)).unwrap());
+        assert_eq!(12, check(record(TraceFormat::Mame, 12)).unwrap());
+    }
+
+    #[test]
+    fn register_mismatch() {
+        let reference = record(TraceFormat::Full, 6).replace("AF=1010", "AF=1000");
+        let mismatch = check(reference).unwrap_err();
+        assert_eq!(3, mismatch.line);
+        assert_eq!(2, mismatch.context.len());
+        assert!(mismatch.context[1].contains("INC A"));
+        assert_eq!(vec!["AF: expected 1000, found 1010 (flags differ: HF)"], mismatch.errors);
+        assert!(mismatch.actual.contains("AF=1010"));
+        assert!(mismatch.report().starts_with("trace mismatch in line 3:\n"));
+    }
+
+    #[test]
+    fn cycle_and_memory_mismatch() {
+        let reference = record(TraceFormat::Full, 4).replace("        24 0006", "        23 0006");
+        let mismatch = check(reference).unwrap_err();
+        assert_eq!(vec!["cycles: expected 23, found 24"], mismatch.errors);
+
+        let reference = "PC=0000\nPC=0002\nPC=0003\nMW:4000=11\nPC=0006\n".to_string();
+        let mismatch = check(reference).unwrap_err();
+        assert_eq!(3, mismatch.line);
+        assert_eq!(vec!["memory write 4000: expected 11, found 10"], mismatch.errors);
+    }
+}
@@ -0,0 +1,630 @@
+use RegT;
+use bus::Bus;
+
+/// width of the VDP display area in pixels
+pub const VDP_DISPLAY_WIDTH: usize = 256;
+/// height of the VDP display area in pixels
+pub const VDP_DISPLAY_HEIGHT: usize = 192;
+/// CPU cycles per frame for a 3.58 MHz CPU and a 60 Hz (NTSC) VDP
+pub const VDP_CYCLES_PER_FRAME_NTSC: i64 = 228 * 262;
+/// CPU cycles per frame for a 3.58 MHz CPU and a 50 Hz (PAL) VDP
+pub const VDP_CYCLES_PER_FRAME_PAL: i64 = 228 * 313;
+
+const VRAM_SIZE: usize = 1 << 14;
+const VRAM_MASK: usize = VRAM_SIZE - 1;
+const NUM_REGS: usize = 8;
+const NUM_SPRITES: usize = 32;
+const MAX_SPRITES_PER_LINE: usize = 4;
+const SPRITE_TERMINATOR: u8 = 0xD0;
+
+/// status register: frame interrupt flag
+pub const VDP_STATUS_INT: u8 = 1 << 7;
+/// status register: 5th sprite flag
+pub const VDP_STATUS_5S: u8 = 1 << 6;
+/// status register: sprite coincidence flag
+pub const VDP_STATUS_C: u8 = 1 << 5;
+/// status register: 5th sprite number mask
+pub const VDP_STATUS_5S_MASK: u8 = 0x1F;
+
+// register 0 bits
+const REG0_M3: u8 = 1 << 1;
+// register 1 bits
+const REG1_BLANK: u8 = 1 << 6;
+const REG1_IE: u8 = 1 << 5;
+const REG1_M1: u8 = 1 << 4;
+const REG1_M2: u8 = 1 << 3;
+const REG1_SIZE: u8 = 1 << 1;
+const REG1_MAG: u8 = 1 << 0;
+
+/// VDP screen modes
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ScreenMode {
+    Graphics1,
+    Graphics2,
+    Multicolor,
+    Text,
+}
+
+/// the 16 TMS9918A colors, color 0 is 'transparent'
+static PALETTE: [u32; 16] = [
+    0xFF000000, 0xFF000000, 0xFF21C842, 0xFF5EDC78,
+    0xFF5455ED, 0xFF7D76FC, 0xFFD4524D, 0xFF42EBF5,
+    0xFFFC5554, 0xFFFF7978, 0xFFD4C154, 0xFFE6CE80,
+    0xFF21B03B, 0xFFC95BBA, 0xFFCCCCCC, 0xFFFFFFFF,
+];
+
+/// TMS9918A video display processor emulation
+///
+/// The VDP owns its own 16 KByte of video RAM which is only
+/// accessible by the CPU through the data and control ports.
+/// The VDP must be advanced by calling **update_timers()** with
+/// the number of CPU cycles executed, at the end of each frame
+/// the interrupt flag in the status register is set, and if
+/// interrupts are enabled in register 1, the **vdp_int()** Bus
+/// callback is invoked. Reading the status register clears the
+/// interrupt flag again.
+///
+/// The current content of the video RAM can be rendered into a
+/// 256x192 RGBA8 framebuffer with **decode_framebuffer()**.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{VDP, Bus, VDP_CYCLES_PER_FRAME_NTSC};
+///
+/// struct DummyBus;
+/// impl Bus for DummyBus { };
+/// let bus = DummyBus { };
+///
+/// let mut vdp = VDP::new(0, VDP_CYCLES_PER_FRAME_NTSC);
+///
+/// // set VRAM write address to 0x0100 and write 2 bytes
+/// vdp.write_control(&bus, 0x00);
+/// vdp.write_control(&bus, 0x41);
+/// vdp.write_data(0x12);
+/// vdp.write_data(0x34);
+///
+/// // set VRAM read address to 0x0100 and read the bytes back
+/// vdp.write_control(&bus, 0x00);
+/// vdp.write_control(&bus, 0x01);
+/// assert_eq!(vdp.read_data(), 0x12);
+/// assert_eq!(vdp.read_data(), 0x34);
+/// ```
+pub struct VDP {
+    id: usize, // a VDP ID for systems with multiple VDPs
+    pub vram: [u8; VRAM_SIZE],
+    pub reg: [u8; NUM_REGS],
+    pub status: u8,
+    addr: usize, // current VRAM address
+    latch: u8, // first byte of a control port write sequence
+    latched: bool, // true if first control byte has been written
+    read_buffer: u8, // read-ahead buffer for VRAM reads
+    int_active: bool, // current state of the INT output line
+    cycles_per_frame: i64,
+    frame_counter: i64,
+}
+
+impl VDP {
+    /// initialize a new VDP object
+    pub fn new(id: usize, cycles_per_frame: i64) -> VDP {
+        VDP {
+            id: id,
+            vram: [0; VRAM_SIZE],
+            reg: [0; NUM_REGS],
+            status: 0,
+            addr: 0,
+            latch: 0,
+            latched: false,
+            read_buffer: 0,
+            int_active: false,
+            cycles_per_frame: cycles_per_frame,
+            frame_counter: cycles_per_frame,
+        }
+    }
+
+    /// reset the VDP (VRAM content is preserved)
+    pub fn reset(&mut self) {
+        self.reg = [0; NUM_REGS];
+        self.status = 0;
+        self.addr = 0;
+        self.latch = 0;
+        self.latched = false;
+        self.read_buffer = 0;
+        self.int_active = false;
+        self.frame_counter = self.cycles_per_frame;
+    }
+
+    /// write to the control port (register write or VRAM address setup)
+    pub fn write_control(&mut self, bus: &Bus, val: RegT) {
+        let val = val as u8;
+        if !self.latched {
+            self.latch = val;
+            self.latched = true;
+            self.addr = (self.addr & 0x3F00) | val as usize;
+        } else {
+            self.latched = false;
+            if (val & 0x80) != 0 {
+                // register write
+                let r = (val & 7) as usize;
+                self.reg[r] = self.latch;
+                if r == 1 {
+                    self.update_int(bus);
+                }
+            } else {
+                // VRAM address setup, bit 6 cleared means read setup
+                self.addr = ((val as usize & 0x3F) << 8) | self.latch as usize;
+                if (val & 0x40) == 0 {
+                    self.read_buffer = self.vram[self.addr];
+                    self.addr = (self.addr + 1) & VRAM_MASK;
+                }
+            }
+        }
+    }
+
+    /// read the status register, this clears the interrupt and sprite flags
+    pub fn read_status(&mut self, bus: &Bus) -> RegT {
+        let val = self.status;
+        self.status &= VDP_STATUS_5S_MASK;
+        self.latched = false;
+        self.update_int(bus);
+        val as RegT
+    }
+
+    /// write a byte to VRAM through the data port
+    pub fn write_data(&mut self, val: RegT) {
+        self.latched = false;
+        self.vram[self.addr] = val as u8;
+        self.read_buffer = val as u8;
+        self.addr = (self.addr + 1) & VRAM_MASK;
+    }
+
+    /// read a byte from VRAM through the data port
+    pub fn read_data(&mut self) -> RegT {
+        self.latched = false;
+        let val = self.read_buffer;
+        self.read_buffer = self.vram[self.addr];
+        self.addr = (self.addr + 1) & VRAM_MASK;
+        val as RegT
+    }
+
+    /// advance the frame timer, sets the interrupt flag at the end of a frame
+    pub fn update_timers(&mut self, bus: &Bus, cycles: i64) {
+        self.frame_counter -= cycles;
+        while self.frame_counter <= 0 {
+            self.frame_counter += self.cycles_per_frame;
+            self.end_frame(bus);
+        }
+    }
+
+    /// get the current screen mode from the mode bits in registers 0 and 1
+    pub fn screen_mode(&self) -> ScreenMode {
+        if (self.reg[1] & REG1_M1) != 0 {
+            ScreenMode::Text
+        } else if (self.reg[1] & REG1_M2) != 0 {
+            ScreenMode::Multicolor
+        } else if (self.reg[0] & REG0_M3) != 0 {
+            ScreenMode::Graphics2
+        } else {
+            ScreenMode::Graphics1
+        }
+    }
+
+    /// return true if the INT output line is currently active
+    pub fn int_active(&self) -> bool {
+        self.int_active
+    }
+
+    /// set the interrupt flag and update sprite status flags at end of frame
+    fn end_frame(&mut self, bus: &Bus) {
+        if self.screen_mode() != ScreenMode::Text {
+            let mut line_buf = [0u8; VDP_DISPLAY_WIDTH];
+            for y in 0..VDP_DISPLAY_HEIGHT {
+                let flags = self.sprite_line(y, &mut line_buf);
+                if (flags & VDP_STATUS_5S) != 0 && (self.status & VDP_STATUS_5S) == 0 {
+                    self.status = (self.status & !VDP_STATUS_5S_MASK) | flags;
+                }
+                self.status |= flags & VDP_STATUS_C;
+            }
+        }
+        self.status |= VDP_STATUS_INT;
+        self.update_int(bus);
+    }
+
+    /// update INT output line and notify bus if changed
+    fn update_int(&mut self, bus: &Bus) {
+        let active = (self.status & VDP_STATUS_INT) != 0 && (self.reg[1] & REG1_IE) != 0;
+        if active != self.int_active {
+            self.int_active = active;
+            bus.vdp_int(self.id, active);
+        }
+    }
+
+    fn name_table(&self) -> usize {
+        (self.reg[2] as usize & 0x0F) << 10
+    }
+
+    fn color_table(&self) -> usize {
+        (self.reg[3] as usize) << 6
+    }
+
+    fn pattern_table(&self) -> usize {
+        (self.reg[4] as usize & 0x07) << 11
+    }
+
+    fn sprite_attr_table(&self) -> usize {
+        (self.reg[5] as usize & 0x7F) << 7
+    }
+
+    fn sprite_pattern_table(&self) -> usize {
+        (self.reg[6] as usize & 0x07) << 11
+    }
+
+    /// render the sprites of one scanline into a line buffer of color indices,
+    /// returns the 5th-sprite and coincidence status flags for the line
+    fn sprite_line(&self, y: usize, line_buf: &mut [u8; VDP_DISPLAY_WIDTH]) -> u8 {
+        let size = if (self.reg[1] & REG1_SIZE) != 0 { 16 } else { 8 };
+        let mag = if (self.reg[1] & REG1_MAG) != 0 { 2 } else { 1 };
+        let attr_base = self.sprite_attr_table();
+        let pat_base = self.sprite_pattern_table();
+        let mut drawn = [false; VDP_DISPLAY_WIDTH];
+        let mut num_visible = 0;
+        let mut flags = 0;
+        for i in 0..NUM_SPRITES {
+            let attr = attr_base + i * 4;
+            let sy = self.vram[attr];
+            if sy == SPRITE_TERMINATOR {
+                break;
+            }
+            // Y coordinates are off by one, and values beyond 0xE0 wrap to the top
+            let mut top = sy as i32 + 1;
+            if top > 0xE0 {
+                top -= 0x100;
+            }
+            let row = y as i32 - top;
+            if row < 0 || row >= size * mag {
+                continue;
+            }
+            if num_visible == MAX_SPRITES_PER_LINE {
+                flags |= VDP_STATUS_5S | i as u8;
+                break;
+            }
+            num_visible += 1;
+            let color = self.vram[attr + 3];
+            let mut x = self.vram[attr + 1] as i32;
+            if (color & 0x80) != 0 {
+                // early clock bit
+                x -= 32;
+            }
+            let mut name = self.vram[attr + 2] as usize;
+            if size == 16 {
+                name &= 0xFC;
+            }
+            let row = (row / mag) as usize;
+            for col in 0..(size * mag) {
+                let px = x + col;
+                if px < 0 || px >= VDP_DISPLAY_WIDTH as i32 {
+                    continue;
+                }
+                let c = (col / mag) as usize;
+                let offset = (c >> 3) * 16 + row;
+                let bits = self.vram[(pat_base + name * 8 + offset) & VRAM_MASK];
+                if (bits & (0x80 >> (c & 7))) != 0 {
+                    let px = px as usize;
+                    if drawn[px] {
+                        flags |= VDP_STATUS_C;
+                    } else {
+                        drawn[px] = true;
+                        if (color & 0x0F) != 0 {
+                            line_buf[px] = color & 0x0F;
+                        }
+                    }
+                }
+            }
+        }
+        flags
+    }
+
+    /// render one scanline of the background into a line buffer of color indices
+    fn background_line(&self, y: usize, line_buf: &mut [u8; VDP_DISPLAY_WIDTH]) {
+        let row = y >> 3;
+        let py = y & 7;
+        let name_base = self.name_table();
+        match self.screen_mode() {
+            ScreenMode::Graphics1 => {
+                let pat_base = self.pattern_table();
+                let col_base = self.color_table();
+                for x in 0..32 {
+                    let name = self.vram[name_base + row * 32 + x] as usize;
+                    let bits = self.vram[pat_base + name * 8 + py];
+                    let color = self.vram[col_base + (name >> 3)];
+                    VDP::expand_pattern(bits, color, &mut line_buf[x * 8..x * 8 + 8]);
+                }
+            }
+            ScreenMode::Graphics2 => {
+                let pat_base = (self.reg[4] as usize & 0x04) << 11;
+                let pat_mask = ((self.reg[4] as usize & 0x03) << 11) | 0x7FF;
+                let col_base = (self.reg[3] as usize & 0x80) << 6;
+                let col_mask = ((self.reg[3] as usize & 0x7F) << 6) | 0x3F;
+                for x in 0..32 {
+                    let name = self.vram[name_base + row * 32 + x] as usize;
+                    let offset = ((row >> 3) * 256 + name) * 8 + py;
+                    let bits = self.vram[pat_base | (offset & pat_mask)];
+                    let color = self.vram[col_base | (offset & col_mask)];
+                    VDP::expand_pattern(bits, color, &mut line_buf[x * 8..x * 8 + 8]);
+                }
+            }
+            ScreenMode::Multicolor => {
+                let pat_base = self.pattern_table();
+                for x in 0..32 {
+                    let name = self.vram[name_base + row * 32 + x] as usize;
+                    let color = self.vram[pat_base + name * 8 + (row & 3) * 2 + (py >> 2)];
+                    for px in 0..4 {
+                        line_buf[x * 8 + px] = color >> 4;
+                        line_buf[x * 8 + 4 + px] = color & 0x0F;
+                    }
+                }
+            }
+            ScreenMode::Text => {
+                let pat_base = self.pattern_table();
+                let color = self.reg[7];
+                for b in line_buf.iter_mut() {
+                    *b = 0;
+                }
+                for x in 0..40 {
+                    let name = self.vram[name_base + row * 40 + x] as usize;
+                    let bits = self.vram[pat_base + name * 8 + py];
+                    let start = 8 + x * 6;
+                    VDP::expand_pattern(bits, color, &mut line_buf[start..start + 6]);
+                }
+            }
+        }
+    }
+
+    /// expand 8 (or less) pattern bits into color indices
+    #[inline(always)]
+    fn expand_pattern(bits: u8, color: u8, dst: &mut [u8]) {
+        let fg = color >> 4;
+        let bg = color & 0x0F;
+        for (px, d) in dst.iter_mut().enumerate() {
+            *d = if (bits & (0x80 >> px)) != 0 { fg } else { bg };
+        }
+    }
+
+    /// decode the current VRAM content into a 256x192 linear RGBA8 framebuffer
+    pub fn decode_framebuffer(&self, fb: &mut [u32]) {
+        let backdrop = PALETTE[(self.reg[7] & 0x0F) as usize];
+        if (self.reg[1] & REG1_BLANK) == 0 {
+            for p in fb.iter_mut().take(VDP_DISPLAY_WIDTH * VDP_DISPLAY_HEIGHT) {
+                *p = backdrop;
+            }
+            return;
+        }
+        let text_mode = self.screen_mode() == ScreenMode::Text;
+        let mut bg_line = [0u8; VDP_DISPLAY_WIDTH];
+        let mut spr_line = [0u8; VDP_DISPLAY_WIDTH];
+        for (y, fb_line) in fb.chunks_mut(VDP_DISPLAY_WIDTH).take(VDP_DISPLAY_HEIGHT).enumerate() {
+            self.background_line(y, &mut bg_line);
+            for b in spr_line.iter_mut() {
+                *b = 0;
+            }
+            if !text_mode {
+                self.sprite_line(y, &mut spr_line);
+            }
+            for x in 0..VDP_DISPLAY_WIDTH {
+                let c = if spr_line[x] != 0 { spr_line[x] } else { bg_line[x] };
+                fb_line[x] = if c == 0 { backdrop } else { PALETTE[c as usize] };
+            }
+        }
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use super::*;
+    use Bus;
+    use RegT;
+
+    struct TestBus {
+        int_active: RefCell<bool>,
+        int_counter: RefCell<i32>,
+    }
+    impl TestBus {
+        pub fn new() -> TestBus {
+            TestBus {
+                int_active: RefCell::new(false),
+                int_counter: RefCell::new(0),
+            }
+        }
+    }
+    impl Bus for TestBus {
+        fn vdp_int(&self, _: usize, active: bool) {
+            *self.int_active.borrow_mut() = active;
+            if active {
+                *self.int_counter.borrow_mut() += 1;
+            }
+        }
+    }
+
+    fn write_reg(vdp: &mut VDP, bus: &Bus, r: RegT, val: RegT) {
+        vdp.write_control(bus, val);
+        vdp.write_control(bus, 0x80 | r);
+    }
+
+    fn write_vram(vdp: &mut VDP, bus: &Bus, addr: RegT, data: &[u8]) {
+        vdp.write_control(bus, addr & 0xFF);
+        vdp.write_control(bus, 0x40 | (addr >> 8));
+        for b in data {
+            vdp.write_data(*b as RegT);
+        }
+    }
+
+    #[test]
+    fn reset() {
+        let bus = TestBus::new();
+        let mut vdp = VDP::new(0, VDP_CYCLES_PER_FRAME_NTSC);
+        write_reg(&mut vdp, &bus, 1, 0xE0);
+        write_vram(&mut vdp, &bus, 0x1000, &[0x11]);
+        vdp.status = VDP_STATUS_INT | VDP_STATUS_C;
+        vdp.reset();
+        assert_eq!([0; NUM_REGS], vdp.reg);
+        assert_eq!(0, vdp.status);
+        assert_eq!(0, vdp.addr);
+        assert!(!vdp.latched);
+        assert!(!vdp.int_active());
+        assert_eq!(0x11, vdp.vram[0x1000]);
+    }
+
+    #[test]
+    fn write_registers() {
+        let bus = TestBus::new();
+        let mut vdp = VDP::new(0, VDP_CYCLES_PER_FRAME_NTSC);
+        write_reg(&mut vdp, &bus, 0, 0x02);
+        write_reg(&mut vdp, &bus, 2, 0x06);
+        write_reg(&mut vdp, &bus, 7, 0xF4);
+        // register number is taken from the lower 3 bits only
+        write_reg(&mut vdp, &bus, 0x0D, 0x36);
+        assert_eq!(0x02, vdp.reg[0]);
+        assert_eq!(0x06, vdp.reg[2]);
+        assert_eq!(0x36, vdp.reg[5]);
+        assert_eq!(0xF4, vdp.reg[7]);
+        assert_eq!(ScreenMode::Graphics2, vdp.screen_mode());
+        assert_eq!(0x1800, vdp.name_table());
+        assert_eq!(0x1B00, vdp.sprite_attr_table());
+    }
+
+    #[test]
+    fn vram_access() {
+        let bus = TestBus::new();
+        let mut vdp = VDP::new(0, VDP_CYCLES_PER_FRAME_NTSC);
+        write_vram(&mut vdp, &bus, 0x3FFE, &[0x11, 0x22, 0x33]);
+        assert_eq!(0x11, vdp.vram[0x3FFE]);
+        assert_eq!(0x22, vdp.vram[0x3FFF]);
+        // address wraps around at 16 KByte
+        assert_eq!(0x33, vdp.vram[0x0000]);
+
+        // a read setup pre-fetches the first byte
+        vdp.write_control(&bus, 0xFF);
+        vdp.write_control(&bus, 0x3F);
+        vdp.vram[0x3FFF] = 0x44;
+        assert_eq!(0x22, vdp.read_data());
+        assert_eq!(0x33, vdp.read_data());
+
+        // reading the status register resets the control byte latch
+        vdp.write_control(&bus, 0x12);
+        vdp.read_status(&bus);
+        write_reg(&mut vdp, &bus, 3, 0x80);
+        assert_eq!(0x80, vdp.reg[3]);
+    }
+
+    #[test]
+    fn frame_interrupt() {
+        let bus = TestBus::new();
+        let mut vdp = VDP::new(0, 1000);
+        vdp.update_timers(&bus, 999);
+        assert_eq!(0, vdp.status & VDP_STATUS_INT);
+        vdp.update_timers(&bus, 1);
+        assert_eq!(VDP_STATUS_INT, vdp.status & VDP_STATUS_INT);
+        // interrupts are disabled, so the INT line must not be active
+        assert!(!*bus.int_active.borrow());
+        assert_eq!(VDP_STATUS_INT as RegT, vdp.read_status(&bus) & 0x80);
+        assert_eq!(0, vdp.status & VDP_STATUS_INT);
+
+        // enable interrupts
+        write_reg(&mut vdp, &bus, 1, (REG1_BLANK | REG1_IE) as RegT);
+        vdp.update_timers(&bus, 1000);
+        assert!(*bus.int_active.borrow());
+        assert_eq!(1, *bus.int_counter.borrow());
+        vdp.read_status(&bus);
+        assert!(!*bus.int_active.borrow());
+
+        // enabling interrupts with the flag already set raises INT
+        vdp.update_timers(&bus, 1000);
+        write_reg(&mut vdp, &bus, 1, REG1_BLANK as RegT);
+        assert!(!*bus.int_active.borrow());
+        write_reg(&mut vdp, &bus, 1, (REG1_BLANK | REG1_IE) as RegT);
+        assert!(*bus.int_active.borrow());
+        assert_eq!(3, *bus.int_counter.borrow());
+    }
+
+    fn sprite_setup(vdp: &mut VDP, bus: &Bus) {
+        write_reg(vdp, bus, 1, (REG1_BLANK) as RegT);
+        write_reg(vdp, bus, 5, 0x36);   // sprite attributes at 0x1B00
+        write_reg(vdp, bus, 6, 0x07);   // sprite patterns at 0x3800
+        write_vram(vdp, bus, 0x3800, &[0xFF; 8]);
+    }
+
+    #[test]
+    fn sprite_5th_flag() {
+        let bus = TestBus::new();
+        let mut vdp = VDP::new(0, 1000);
+        sprite_setup(&mut vdp, &bus);
+        // 6 sprites side by side on the same line, sprite 6 terminates the list
+        let mut attrs = Vec::new();
+        for i in 0..6 {
+            attrs.extend_from_slice(&[0x0F, i * 16, 0, 0x01]);
+        }
+        attrs.push(SPRITE_TERMINATOR);
+        write_vram(&mut vdp, &bus, 0x1B00, &attrs);
+        vdp.update_timers(&bus, 1000);
+        assert_eq!(VDP_STATUS_5S, vdp.status & VDP_STATUS_5S);
+        assert_eq!(4, vdp.status & VDP_STATUS_5S_MASK);
+        assert_eq!(0, vdp.status & VDP_STATUS_C);
+        vdp.read_status(&bus);
+        assert_eq!(0, vdp.status & VDP_STATUS_5S);
+    }
+
+    #[test]
+    fn sprite_collision() {
+        let bus = TestBus::new();
+        let mut vdp = VDP::new(0, 1000);
+        sprite_setup(&mut vdp, &bus);
+        // 2 overlapping transparent sprites still collide
+        write_vram(&mut vdp, &bus, 0x1B00, &[0x20, 0x20, 0, 0, 0x24, 0x24, 0, 0, SPRITE_TERMINATOR]);
+        vdp.update_timers(&bus, 1000);
+        assert_eq!(VDP_STATUS_C, vdp.status & VDP_STATUS_C);
+        assert_eq!(0, vdp.status & VDP_STATUS_5S);
+        vdp.read_status(&bus);
+
+        // move second sprite away
+        write_vram(&mut vdp, &bus, 0x1B05, &[0x40]);
+        vdp.update_timers(&bus, 1000);
+        assert_eq!(0, vdp.status & VDP_STATUS_C);
+    }
+
+    #[test]
+    fn decode_graphics1() {
+        let bus = TestBus::new();
+        let mut vdp = VDP::new(0, VDP_CYCLES_PER_FRAME_NTSC);
+        write_reg(&mut vdp, &bus, 1, REG1_BLANK as RegT);
+        write_reg(&mut vdp, &bus, 2, 0x06);     // name table at 0x1800
+        write_reg(&mut vdp, &bus, 3, 0x80);     // color table at 0x2000
+        write_reg(&mut vdp, &bus, 4, 0x00);     // pattern table at 0x0000
+        write_reg(&mut vdp, &bus, 5, 0x36);     // sprite attributes at 0x1B00
+        write_reg(&mut vdp, &bus, 7, 0x04);     // dark blue backdrop
+        write_vram(&mut vdp, &bus, 0x1B00, &[SPRITE_TERMINATOR]);
+        // pattern 8 is a vertical bar in white on transparent
+        write_vram(&mut vdp, &bus, 0x0040, &[0xF0; 8]);
+        write_vram(&mut vdp, &bus, 0x2001, &[0xF0]);
+        write_vram(&mut vdp, &bus, 0x1800, &[8]);
+        let mut fb = vec![0u32; VDP_DISPLAY_WIDTH * VDP_DISPLAY_HEIGHT];
+        vdp.decode_framebuffer(&mut fb);
+        assert_eq!(PALETTE[15], fb[0]);
+        assert_eq!(PALETTE[15], fb[7 * VDP_DISPLAY_WIDTH + 3]);
+        assert_eq!(PALETTE[4], fb[4]);
+        assert_eq!(PALETTE[4], fb[8 * VDP_DISPLAY_WIDTH]);
+
+        // a sprite on top of the background
+        write_reg(&mut vdp, &bus, 6, 0x07);
+        write_vram(&mut vdp, &bus, 0x3800, &[0xFF; 8]);
+        write_vram(&mut vdp, &bus, 0x1B00, &[0xFF, 0x02, 0x00, 0x06, SPRITE_TERMINATOR]);
+        vdp.decode_framebuffer(&mut fb);
+        assert_eq!(PALETTE[15], fb[1]);
+        assert_eq!(PALETTE[6], fb[2]);
+        assert_eq!(PALETTE[6], fb[9]);
+        assert_eq!(PALETTE[4], fb[10]);
+        // rendering must not modify the status register
+        assert_eq!(0, vdp.status);
+    }
+}
@@ -0,0 +1,599 @@
+use std::cell::RefCell;
+use RegT;
+use bus::Bus;
+use cpu::{CPU, CPUSnapshot};
+use input::{Machine, InputEvent};
+use pio::{PIO, PIO_A, PIO_B};
+use keyboard::Keyboard;
+use textdisplay::TextDisplay;
+use tape::{Tape, TapeRecorder, ProgramFile};
+use scheduler::Scheduler;
+
+/// width of the decoded Z1013 display in pixels
+pub const Z1013_DISPLAY_WIDTH: usize = 32 * 8;
+/// height of the decoded Z1013 display in pixels
+pub const Z1013_DISPLAY_HEIGHT: usize = 32 * 8;
+
+const VIDEO_ADDR: RegT = 0xEC00;
+const ROM_ADDR: RegT = 0xF000;
+
+// PIO-B bits
+const PIO_B_KBD_HIGH_LINES: RegT = 1 << 4;
+const PIO_B_TAPE_IN: RegT = 1 << 6;
+const PIO_B_TAPE_OUT: RegT = 1 << 7;
+
+/// Z1013 models (RAM size and CPU clock)
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Z1013Model {
+    /// 1 MHz, 16 KByte RAM
+    Z1013_01,
+    /// 2 MHz, 1 KByte RAM
+    Z1013_12,
+    /// 2 MHz, 16 KByte RAM
+    Z1013_16,
+    /// 2 MHz, 64 KByte RAM
+    Z1013_64,
+}
+
+/// Z1013 monitor ROM versions
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Z1013Monitor {
+    /// monitor 2.02, for the original 8x4 keyboard matrix
+    V202,
+    /// monitor A.2, for the 8x8 keyboard matrix
+    VA2,
+}
+
+// 8x8 keyboard matrix, unshifted and shifted, line by line
+static KEYS_8X8_UNSHIFTED: &'static str = concat!("13579-  ",
+                                                  "QETUO@  ",
+                                                  "ADGJL*  ",
+                                                  "YCBM.^  ",
+                                                  "24680[  ",
+                                                  "WRZIP]  ",
+                                                  "SFHK+\\  ",
+                                                  "XVN,/_  ");
+static KEYS_8X8_SHIFTED: &'static str = concat!("!#%')=  ",
+                                                "qetuo`  ",
+                                                "adgjl:  ",
+                                                "ycbm>~  ",
+                                                "\"$&( {  ",
+                                                "wrzip}  ",
+                                                "sfhk;|  ",
+                                                "xvn<?   ");
+
+// 8x4 keyboard matrix, with 3 shift layers (S1, S2, S3), line by line
+static KEYS_8X4: [&'static str; 4] = [concat!("@ABCDEFG", "HIJKLMNO", "PQRSTUVW"),
+                                      concat!("XYZ[\\]^-", "01234567", "89:;<=>?"),
+                                      concat!("   {|}~ ", " !\"#$%&'", "()*+,-./"),
+                                      concat!("`abcdefg", "hijklmno", "pqrstuvw")];
+
+// scheduled events
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Event {
+    // the next level change of the tape player
+    TapeEdge,
+}
+
+#[derive(Clone)]
+struct State {
+    kbd_column: usize,
+    kbd_high_lines: bool,
+    // cycle count up to which the tape player and recorder have been updated
+    tape_cycles: u64,
+}
+
+/// snapshot of the Z1013 machine state, see Z1013::snapshot()
+pub struct Z1013Snapshot {
+    cpu: CPUSnapshot,
+    pio: PIO,
+    state: State,
+    scheduler: Scheduler<Event>,
+}
+
+/// Z1013 home computer emulation
+///
+/// The Z1013 is a very simple East German home computer kit with
+/// a Z80 CPU, a PIO, RAM, a 2 KByte monitor ROM, a 32x32 character
+/// monochrome display, a keyboard matrix and a cassette tape interface.
+/// The Z1013 struct owns all chips and implements the Bus trait which
+/// wires them together.
+///
+/// The model defines the RAM size and CPU clock, the monitor version
+/// defines the keyboard matrix layout (the original 8x4 matrix for monitor
+/// 2.02, or the 8x8 matrix for monitor A.2). The monitor and font ROM
+/// images must be provided by the caller.
+///
+/// The cassette tape input and output are connected to the **tape** player
+/// and **recorder** members.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{Z1013, Z1013Model, Z1013Monitor};
+///
+/// static MON: &'static [u8] = include_bytes!("../examples/dumps/z1013_mon_a2.bin");
+/// static FONT: &'static [u8] = include_bytes!("../examples/dumps/z1013_font.bin");
+///
+/// let z1013 = Z1013::new(Z1013Model::Z1013_64, Z1013Monitor::VA2, MON, FONT);
+/// z1013.poweron();
+/// z1013.step(1000000);
+/// assert!(z1013.screen_text().contains("robotron Z 1013/A.2"));
+/// ```
+pub struct Z1013 {
+    pub model: Z1013Model,
+    pub monitor: Z1013Monitor,
+    pub cpu: RefCell<CPU>,
+    pub pio: RefCell<PIO>,
+    pub kbd: RefCell<Keyboard>,
+    pub tape: RefCell<Tape>,
+    pub recorder: RefCell<TapeRecorder>,
+    state: RefCell<State>,
+    scheduler: RefCell<Scheduler<Event>>,
+    os: Vec<u8>,
+    font: Vec<u8>,
+}
+
+impl Z1013 {
+    /// initialize a new Z1013 with model, monitor version, and monitor and font ROM images
+    pub fn new(model: Z1013Model, monitor: Z1013Monitor, os: &[u8], font: &[u8]) -> Z1013 {
+        Z1013 {
+            model: model,
+            monitor: monitor,
+            cpu: RefCell::new(CPU::new()),
+            pio: RefCell::new(PIO::new(0)),
+            kbd: RefCell::new(Z1013::keyboard(monitor)),
+            tape: RefCell::new(Tape::new(Vec::new())),
+            recorder: RefCell::new(TapeRecorder::new()),
+            state: RefCell::new(State {
+                kbd_column: 0,
+                kbd_high_lines: false,
+                tape_cycles: 0,
+            }),
+            scheduler: RefCell::new(Scheduler::new()),
+            os: os.to_vec(),
+            font: font.to_vec(),
+        }
+    }
+
+    /// setup the keyboard matrix for a monitor version
+    fn keyboard(monitor: Z1013Monitor) -> Keyboard {
+        match monitor {
+            Z1013Monitor::VA2 => {
+                let mut kbd = Keyboard::new(8, 8);
+                // shift key is column 7, line 6, ctrl key is column 6, line 5
+                kbd.add_modifier(1, 7, 6);
+                kbd.add_modifier(2, 6, 5);
+                kbd.add_key_table(0, KEYS_8X8_UNSHIFTED.as_bytes());
+                kbd.add_key_table(1, KEYS_8X8_SHIFTED.as_bytes());
+                for &(key, col, line) in &[(0x20, 6, 4),    // space
+                                           (0x08, 6, 2),    // cursor left
+                                           (0x09, 6, 3),    // cursor right
+                                           (0x0A, 6, 7),    // cursor down
+                                           (0x0B, 6, 6),    // cursor up
+                                           (0x0D, 6, 1)] {  // enter
+                    kbd.add_key(key, 0, col, line);
+                }
+                // Ctrl+C (== STOP/BREAK)
+                kbd.add_key(0x03, 2, 1, 3);
+                kbd.min_hold = 1;
+                kbd
+            }
+            Z1013Monitor::V202 => {
+                let mut kbd = Keyboard::new(8, 4);
+                // shift keys S1..S3 are in line 3, columns 0..2
+                for layer in 1..4 {
+                    kbd.add_modifier(layer, layer - 1, 3);
+                }
+                for (layer, table) in KEYS_8X4.iter().enumerate() {
+                    kbd.add_key_table(layer, table.as_bytes());
+                }
+                for &(key, col, line) in &[(0x08, 4, 3),    // cursor left
+                                           (0x20, 5, 3),    // space
+                                           (0x09, 6, 3),    // cursor right
+                                           (0x0D, 7, 3)] {  // enter
+                    kbd.add_key(key, 0, col, line);
+                }
+                // S3+C (== STOP/BREAK)
+                kbd.add_key(0x03, 3, 3, 0);
+                kbd.min_hold = 1;
+                kbd
+            }
+        }
+    }
+
+    /// CPU frequency in kHz of the Z1013 model
+    pub fn freq_khz(&self) -> i64 {
+        match self.model {
+            Z1013Model::Z1013_01 => 1000,
+            _ => 2000,
+        }
+    }
+
+    /// power-on the Z1013, initializes the memory map and starts at the monitor entry
+    pub fn poweron(&self) {
+        let mut cpu = self.cpu.borrow_mut();
+        cpu.mem.unmap_all();
+
+        // RAM on layer 1, this is overlapped by video RAM and ROM on the 64 KByte model
+        let ram_size = match self.model {
+            Z1013Model::Z1013_12 => 0x0400,
+            Z1013Model::Z1013_01 | Z1013Model::Z1013_16 => 0x4000,
+            Z1013Model::Z1013_64 => 0x10000,
+        };
+        cpu.mem.map(1, 0x00000, 0x0000, true, ram_size);
+
+        // 1 KByte video RAM and 2 KByte monitor ROM on layer 0
+        cpu.mem.map(0, 0x10000, VIDEO_ADDR as usize, true, 0x0400);
+        cpu.mem.map_bytes(0, 0x10400, ROM_ADDR as usize, false, &self.os);
+        drop(cpu);
+        self.state.borrow_mut().tape_cycles = 0;
+        self.scheduler.borrow_mut().reset();
+        self.reset();
+    }
+
+    /// reset the Z1013 (same as pressing the reset button)
+    pub fn reset(&self) {
+        let mut cpu = self.cpu.borrow_mut();
+        cpu.reset();
+        cpu.reg.set_pc(ROM_ADDR);
+        self.pio.borrow_mut().reset();
+        self.kbd.borrow_mut().release_all();
+        let mut state = self.state.borrow_mut();
+        state.kbd_column = 0;
+        state.kbd_high_lines = false;
+    }
+
+    /// run the emulation for a number of microseconds
+    pub fn step(&self, micro_seconds: i64) {
+        self.step_cycles((self.freq_khz() * micro_seconds) / 1000);
+    }
+
+    /// run the emulation for at least a number of CPU cycles
+    ///
+    /// The emulation stops at the first instruction boundary after
+    /// the number of cycles has been reached. The tape player is only
+    /// updated at its scheduled level changes, and the tape recorder
+    /// when the tape output bit is written.
+    pub fn step_cycles(&self, num_cycles: i64) {
+        // the tape may have been started, stopped or replaced since the last time slice
+        self.update_tape();
+        let mut cur_cycles = 0;
+        while cur_cycles < num_cycles {
+            let idle = self.cpu.borrow().is_idle();
+            let cycles = if idle {
+                // the Z1013 has no interrupt sources, skip to the next event
+                // or the end of the time slice
+                let mut budget = num_cycles - cur_cycles;
+                if let Some(next) = self.scheduler.borrow().cycles_to_next_event() {
+                    budget = budget.min(next as i64);
+                }
+                budget + self.cpu.borrow_mut().run(self, budget)
+            } else {
+                self.cpu.borrow_mut().step(self)
+            };
+            self.scheduler.borrow_mut().advance(cycles);
+            self.dispatch_events();
+            cur_cycles += cycles;
+        }
+        self.update_tape();
+    }
+
+    /// number of CPU cycles executed since power-on
+    pub fn cycles(&self) -> u64 {
+        self.scheduler.borrow().cycles()
+    }
+
+    // dispatch all scheduled events which are due
+    fn dispatch_events(&self) {
+        loop {
+            let event = self.scheduler.borrow_mut().pop_due();
+            match event {
+                Some((_, Event::TapeEdge)) => self.update_tape(),
+                None => break,
+            }
+        }
+    }
+
+    // bring the tape player and recorder up to the current cycle count,
+    // and schedule the next level change of the tape player
+    fn update_tape(&self) {
+        let now = self.cycles();
+        let cycles = {
+            let mut state = self.state.borrow_mut();
+            let cycles = (now - state.tape_cycles) as i64;
+            state.tape_cycles = now;
+            cycles
+        };
+        self.tape.borrow_mut().update(self, cycles);
+        self.recorder.borrow_mut().update(cycles);
+        let mut scheduler = self.scheduler.borrow_mut();
+        scheduler.cancel(Event::TapeEdge);
+        if let Some(edge) = self.tape.borrow().cycles_to_edge() {
+            scheduler.schedule_in(edge, Event::TapeEdge);
+        }
+    }
+
+    /// take a snapshot of the machine state
+    ///
+    /// The snapshot contains the CPU with its memory, the PIO and the
+    /// scheduled events. The keyboard, tape player and tape recorder are
+    /// host-side input and output and not part of the snapshot. Taking a
+    /// snapshot every frame allows rewinding the machine (the Z1013 example
+    /// does this with the F3 key).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rz80::{Z1013, Z1013Model, Z1013Monitor};
+    ///
+    /// static MON: &'static [u8] = include_bytes!("../examples/dumps/z1013_mon_a2.bin");
+    /// static FONT: &'static [u8] = include_bytes!("../examples/dumps/z1013_font.bin");
+    ///
+    /// let z1013 = Z1013::new(Z1013Model::Z1013_64, Z1013Monitor::VA2, MON, FONT);
+    /// z1013.poweron();
+    /// z1013.step(1000000);
+    /// let snapshot = z1013.snapshot();
+    /// let text = z1013.screen_text();
+    ///
+    /// // type a command and run it
+    /// let run = |z1013: &Z1013| {
+    ///     for &key in b"D 0\r" {
+    ///         z1013.key_down(key);
+    ///         z1013.step(50000);
+    ///         z1013.key_up(key);
+    ///         z1013.step(50000);
+    ///     }
+    ///     (z1013.screen_text(), z1013.cycles(), z1013.cpu.borrow().reg.pc())
+    /// };
+    /// let first = run(&z1013);
+    /// assert!(first.0 != text);
+    ///
+    /// // go back and do the same again
+    /// z1013.restore(&snapshot);
+    /// assert_eq!(text, z1013.screen_text());
+    /// assert_eq!(first, run(&z1013));
+    /// ```
+    pub fn snapshot(&self) -> Z1013Snapshot {
+        Z1013Snapshot {
+            cpu: self.cpu.borrow().snapshot(),
+            pio: self.pio.borrow().clone(),
+            state: self.state.borrow().clone(),
+            scheduler: self.scheduler.borrow().clone(),
+        }
+    }
+
+    /// restore the machine state from a snapshot, all keys are released
+    pub fn restore(&self, snapshot: &Z1013Snapshot) {
+        self.cpu.borrow_mut().restore(&snapshot.cpu);
+        *self.pio.borrow_mut() = snapshot.pio.clone();
+        *self.state.borrow_mut() = snapshot.state.clone();
+        *self.scheduler.borrow_mut() = snapshot.scheduler.clone();
+        self.kbd.borrow_mut().release_all();
+        // the tape player isn't rewound, schedule its next level change from here
+        self.update_tape();
+    }
+
+    /// press a key (ASCII code)
+    pub fn key_down(&self, ascii: u8) {
+        self.kbd.borrow_mut().key_down(ascii as usize);
+    }
+
+    /// release a key (ASCII code)
+    pub fn key_up(&self, ascii: u8) {
+        self.kbd.borrow_mut().key_up(ascii as usize);
+    }
+
+    /// copy a program file into memory
+    pub fn load_program(&self, prog: &ProgramFile) {
+        self.cpu.borrow_mut().mem.write(prog.load_addr, &prog.data);
+    }
+
+    /// decode the video RAM into a linear RGBA8 framebuffer
+    /// of Z1013_DISPLAY_WIDTH * Z1013_DISPLAY_HEIGHT pixels
+    pub fn decode_framebuffer(&self, fb: &mut [u32]) {
+        let cpu = self.cpu.borrow();
+        TextDisplay::new(VIDEO_ADDR, 32, 32, &self.font).decode(&cpu.mem, fb);
+    }
+
+    /// return the content of the video RAM as text, one line per character row
+    pub fn screen_text(&self) -> String {
+        let cpu = self.cpu.borrow();
+        let mut text = String::new();
+        for y in 0..32 {
+            let line: String = (0..32)
+                .map(|x| {
+                    let c = cpu.mem.r8(VIDEO_ADDR + y * 32 + x) as u8;
+                    if (0x20..0x7F).contains(&c) { c as char } else { ' ' }
+                })
+                .collect();
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text
+    }
+}
+
+impl Bus for Z1013 {
+    // I/O ports: 0x00..0x03 PIO-A/B data and control, 0x08: keyboard column
+    fn cpu_outp(&self, port: RegT, val: RegT) {
+        match port & 0xFF {
+            0x00 => self.pio.borrow_mut().write_data(self, PIO_A, val),
+            0x01 => self.pio.borrow_mut().write_control(PIO_A, val),
+            0x02 => self.pio.borrow_mut().write_data(self, PIO_B, val),
+            0x03 => self.pio.borrow_mut().write_control(PIO_B, val),
+            0x08 => {
+                if val == 0 {
+                    // the monitor starts a new keyboard scan
+                    self.kbd.borrow_mut().update();
+                }
+                self.state.borrow_mut().kbd_column = (val & 7) as usize;
+            }
+            _ => (),
+        }
+    }
+
+    fn cpu_inp(&self, port: RegT) -> RegT {
+        match port & 0xFF {
+            0x00 => self.pio.borrow_mut().read_data(self, PIO_A),
+            0x01 => self.pio.borrow().read_control(),
+            0x02 => self.pio.borrow_mut().read_data(self, PIO_B),
+            0x03 => self.pio.borrow().read_control(),
+            _ => 0xFF,
+        }
+    }
+
+    // PIO-B bit 4 selects the upper 4 lines of the 8x8 keyboard matrix,
+    // bit 7 is the cassette tape output
+    fn pio_outp(&self, _: usize, chn: usize, data: RegT) {
+        if chn == PIO_B {
+            self.state.borrow_mut().kbd_high_lines = (data & PIO_B_KBD_HIGH_LINES) != 0;
+            self.update_tape();
+            self.recorder.borrow_mut().write((data & PIO_B_TAPE_OUT) != 0);
+        }
+    }
+
+    // PIO-B bits 0..3 are the active-low keyboard matrix lines,
+    // bit 6 is the cassette tape input
+    fn pio_inp(&self, _: usize, chn: usize) -> RegT {
+        if chn == PIO_B {
+            let state = self.state.borrow();
+            let mut lines = self.kbd.borrow().scan_columns(1 << state.kbd_column);
+            if state.kbd_high_lines && self.monitor == Z1013Monitor::VA2 {
+                lines >>= 4;
+            }
+            let mut val = (!lines & 0xF) as RegT;
+            if self.tape.borrow().level() {
+                val |= PIO_B_TAPE_IN;
+            }
+            val
+        } else {
+            0xFF
+        }
+    }
+}
+
+impl Machine for Z1013 {
+    fn freq_khz(&self) -> i64 {
+        self.freq_khz()
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles()
+    }
+
+    fn step_cycles(&self, num_cycles: i64) {
+        self.step_cycles(num_cycles);
+    }
+
+    fn input(&self, event: InputEvent) {
+        match event {
+            InputEvent::KeyDown(key) => self.key_down(key),
+            InputEvent::KeyUp(key) => self.key_up(key),
+            InputEvent::TapePlay => self.tape.borrow_mut().play(),
+            InputEvent::TapeStop => self.tape.borrow_mut().stop(),
+            InputEvent::TapeRewind => self.tape.borrow_mut().rewind(),
+            InputEvent::Joystick(_) => (),
+        }
+    }
+
+    fn cpu_state_hash(&self) -> u64 {
+        self.cpu.borrow().state_hash()
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use super::*;
+    use Bus;
+
+    static MON_A2: &'static [u8] = include_bytes!("../examples/dumps/z1013_mon_a2.bin");
+    static FONT: &'static [u8] = include_bytes!("../examples/dumps/z1013_font.bin");
+    static BASIC: &'static [u8] = include_bytes!("../examples/dumps/kc_basic.z80");
+
+    fn type_text(z1013: &Z1013, text: &[u8]) {
+        for ch in text {
+            z1013.key_down(*ch);
+            z1013.step(50000);
+            z1013.key_up(*ch);
+            z1013.step(50000);
+        }
+    }
+
+    #[test]
+    fn memory_map() {
+        for &(model, ram_end) in &[(Z1013Model::Z1013_01, 0x4000),
+                                   (Z1013Model::Z1013_12, 0x0400),
+                                   (Z1013Model::Z1013_16, 0x4000),
+                                   (Z1013Model::Z1013_64, 0xEC00)] {
+            let z1013 = Z1013::new(model, Z1013Monitor::VA2, MON_A2, FONT);
+            z1013.poweron();
+            let mut cpu = z1013.cpu.borrow_mut();
+            cpu.mem.w8(ram_end - 1, 0x33);
+            assert_eq!(0x33, cpu.mem.r8(ram_end - 1));
+            if ram_end < VIDEO_ADDR {
+                cpu.mem.w8(ram_end, 0x33);
+                assert_ne!(0x33, cpu.mem.r8(ram_end));
+            }
+            cpu.mem.w8(0xF000, 0x33);
+            assert_eq!(MON_A2[0] as RegT, cpu.mem.r8(0xF000));
+        }
+        let z1013 = Z1013::new(Z1013Model::Z1013_01, Z1013Monitor::V202, MON_A2, FONT);
+        assert_eq!(1000, z1013.freq_khz());
+    }
+
+    #[test]
+    fn keyboard_8x4() {
+        let z1013 = Z1013::new(Z1013Model::Z1013_01, Z1013Monitor::V202, MON_A2, FONT);
+        z1013.poweron();
+        // PIO-B input mode
+        z1013.cpu_outp(0x03, 0x4F);
+        // 'B' is at column 2, line 0
+        z1013.key_down(b'B');
+        z1013.cpu_outp(0x08, 2);
+        assert_eq!(0xE, z1013.cpu_inp(0x02));
+        z1013.cpu_outp(0x08, 1);
+        assert_eq!(0xF, z1013.cpu_inp(0x02));
+        z1013.key_up(b'B');
+        // '1' is at column 1, line 1, with S1 at column 0, line 3
+        z1013.key_down(b'1');
+        z1013.cpu_outp(0x08, 1);
+        assert_eq!(0xD, z1013.cpu_inp(0x02));
+        z1013.cpu_outp(0x08, 0);
+        assert_eq!(0x7, z1013.cpu_inp(0x02));
+    }
+
+    #[test]
+    fn tape_edges() {
+        let z1013 = Z1013::new(Z1013Model::Z1013_64, Z1013Monitor::VA2, MON_A2, FONT);
+        z1013.poweron();
+        *z1013.tape.borrow_mut() = Tape::new(vec![1000, 1000, 1000]);
+        z1013.input(InputEvent::TapePlay);
+        z1013.step_cycles(500);
+        assert_eq!(0, z1013.pio_inp(0, PIO_B) & PIO_B_TAPE_IN);
+        z1013.step_cycles(1000);
+        assert_eq!(PIO_B_TAPE_IN, z1013.pio_inp(0, PIO_B) & PIO_B_TAPE_IN);
+        z1013.step_cycles(1000);
+        assert_eq!(0, z1013.pio_inp(0, PIO_B) & PIO_B_TAPE_IN);
+        z1013.step_cycles(10000);
+        assert!(z1013.tape.borrow().is_finished());
+    }
+
+    #[test]
+    fn basic() {
+        let z1013 = Z1013::new(Z1013Model::Z1013_64, Z1013Monitor::VA2, MON_A2, FONT);
+        z1013.poweron();
+        z1013.load_program(&ProgramFile::from_z80(BASIC).unwrap());
+        z1013.step(1000000);
+        type_text(&z1013, b"J 300\r");
+        z1013.step(500000);
+        type_text(&z1013, b"\r");
+        z1013.step(3000000);
+        type_text(&z1013, b"PRINT 12*3+1\r");
+        z1013.step(500000);
+        let text = z1013.screen_text();
+        assert!(text.contains("PRINT 12*3+1\n 37"), "{}", text);
+    }
+}
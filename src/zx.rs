@@ -0,0 +1,881 @@
+use std::cell::RefCell;
+use RegT;
+use bus::{Bus, BusCycle};
+use cpu::CPU;
+use memory::Memory;
+use input::{Machine, InputEvent};
+use scheduler::Scheduler;
+use keyboard::Keyboard;
+use beeper::Beeper;
+use psg::PSG;
+
+/// width of the decoded ZX Spectrum display in pixels (including border)
+pub const ZX_DISPLAY_WIDTH: usize = 256 + 2 * BORDER;
+/// height of the decoded ZX Spectrum display in pixels (including border)
+pub const ZX_DISPLAY_HEIGHT: usize = 192 + 2 * BORDER;
+
+const BORDER: usize = 32;
+const SAMPLE_RATE: i64 = 44100;
+const ROM_SIZE: usize = 0x4000;
+const BANK_SIZE: usize = 0x4000;
+// heap layout: 8 RAM banks followed by the ROMs
+const ROM_HEAP_OFFSET: usize = 8 * BANK_SIZE;
+const HEAP_SIZE: usize = ROM_HEAP_OFFSET + 2 * ROM_SIZE;
+// offset of the character set in the 48K BASIC ROM
+const FONT_OFFSET: usize = 0x3D00;
+
+// the ULA delays CPU access to contended memory in a repeating 8 cycle pattern
+static CONTENTION: [i64; 8] = [6, 5, 4, 3, 2, 1, 0, 0];
+
+// 128K memory paging register bits (port 0x7FFD)
+const PAGING_RAM_MASK: u8 = 0x07;
+const PAGING_SCREEN: u8 = 1 << 3;
+const PAGING_ROM: u8 = 1 << 4;
+const PAGING_LOCK: u8 = 1 << 5;
+
+// ULA port output bits
+const ULA_BORDER_MASK: RegT = 0x07;
+const ULA_BEEPER: RegT = 1 << 4;
+
+// attribute byte bits
+const ATTR_FLASH: u8 = 1 << 7;
+const ATTR_BRIGHT: u8 = 1 << 6;
+
+// the flash flip-flop toggles every 16 frames
+const FLASH_FRAMES: u32 = 16;
+
+/// Kempston joystick bit for right
+pub const ZX_JOY_RIGHT: u8 = 1 << 0;
+/// Kempston joystick bit for left
+pub const ZX_JOY_LEFT: u8 = 1 << 1;
+/// Kempston joystick bit for down
+pub const ZX_JOY_DOWN: u8 = 1 << 2;
+/// Kempston joystick bit for up
+pub const ZX_JOY_UP: u8 = 1 << 3;
+/// Kempston joystick bit for the fire button
+pub const ZX_JOY_FIRE: u8 = 1 << 4;
+
+/// the 8 normal colors followed by the 8 bright colors
+static PALETTE: [u32; 16] = [
+    0xFF000000, 0xFF0000D7, 0xFFD70000, 0xFFD700D7,
+    0xFF00D700, 0xFF00D7D7, 0xFFD7D700, 0xFFD7D7D7,
+    0xFF000000, 0xFF0000FF, 0xFFFF0000, 0xFFFF00FF,
+    0xFF00FF00, 0xFF00FFFF, 0xFFFFFF00, 0xFFFFFFFF,
+];
+
+// keyboard matrix, 5 keys by 8 half-rows, on the unshifted, caps shift
+// and symbol shift layers
+static KEYS_UNSHIFTED: &'static str = concat!(" zxcv", "asdfg", "qwert", "12345",
+                                              "09876", "poiuy", " lkjh", "  mnb");
+static KEYS_CAPS_SHIFT: &'static str = concat!(" ZXCV", "ASDFG", "QWERT", "     ",
+                                               "     ", "POIUY", " LKJH", "  MNB");
+static KEYS_SYMBOL_SHIFT: &'static str = concat!(" : ?/", "     ", "   <>", "!@#$%",
+                                                 "_)('&", "\";   ", " =+-^", "  .,*");
+
+/// ZX Spectrum models
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ZXModel {
+    /// the original 48 KByte Spectrum
+    Spectrum48K,
+    /// the Spectrum 128 with paged memory and an AY-3-8912 sound chip
+    Spectrum128,
+}
+
+// model-specific video timings, all in CPU cycles and scanlines
+struct Timing {
+    freq_khz: i64,
+    cycles_per_line: i64,
+    num_lines: usize,
+    display_line: usize,
+    int_cycles: i64,
+    contention_start: i64,
+}
+
+// events on the ULA timeline
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Event {
+    FrameEnd,
+}
+
+struct State {
+    frame_start: u64,
+    frame_count: u32,
+    scanline: usize,
+    border: u8,
+    border_lines: Vec<u8>,
+    flash: bool,
+    paging: u8,
+    pending_paging: Option<u8>,
+    /// cycles into the current instruction at the next bus cycle,
+    /// estimated from the nominal length of the previous bus cycles
+    bus_offset: i64,
+    joystick: u8,
+}
+
+/// ZX Spectrum 48K and 128 home computer emulation
+///
+/// The ZX Spectrum has a Z80 CPU running at 3.5 MHz and a custom ULA
+/// chip which generates the video signal from a 256x192 pixel bitmap
+/// with 32x24 color attributes, scans the keyboard, and drives the
+/// beeper. The ULA interrupts the CPU once per frame (IM 1) and delays
+/// the CPU when it accesses memory which is shared with the video
+/// output while the screen is drawn (contended memory). The Spectrum
+/// 128 adds 128 KByte of paged RAM, a second ROM and an AY-3-8912
+/// sound chip.
+///
+/// A Kempston joystick interface is attached at port 0x1F.
+///
+/// Memory contention is applied to each memory access and to ULA port
+/// accesses through Bus::cpu_wait(), the position of an access in the
+/// frame is estimated from the nominal length of the preceding bus
+/// cycles of the instruction.
+///
+/// While the CPU waits for the next interrupt (in HALT, or in an idle
+/// loop registered with CPU::add_idle_loop()) in uncontended memory,
+/// the emulation skips forward to the next frame in one go.
+///
+/// The ROM image must be provided by the caller, 16 KByte for the
+/// 48K model, and 32 KByte (editor ROM followed by the BASIC ROM)
+/// for the 128 model.
+///
+/// # Examples
+///
+/// ```
+/// use rz80::{ZXSpectrum, ZXModel};
+///
+/// // a tiny ROM which puts a character cell pattern into the
+/// // top-left corner of the screen with bright white ink
+/// let mut rom = vec![0u8; 0x4000];
+/// rom[..11].copy_from_slice(&[0x3E, 0xAA,         // LD A,0xAA
+///                             0x32, 0x00, 0x40,   // LD (0x4000),A
+///                             0x3E, 0x47,         // LD A,0x47
+///                             0x32, 0x00, 0x58,   // LD (0x5800),A
+///                             0x76]);             // HALT
+///
+/// let zx = ZXSpectrum::new(ZXModel::Spectrum48K, &rom);
+/// zx.poweron();
+/// zx.step(20000);
+///
+/// let mut fb = vec![0u32; rz80::ZX_DISPLAY_WIDTH * rz80::ZX_DISPLAY_HEIGHT];
+/// zx.decode_framebuffer(&mut fb);
+/// let top_left = 32 * rz80::ZX_DISPLAY_WIDTH + 32;
+/// assert_eq!(fb[top_left], 0xFFFFFFFF);
+/// assert_eq!(fb[top_left + 1], 0xFF000000);
+/// ```
+pub struct ZXSpectrum {
+    pub model: ZXModel,
+    pub cpu: RefCell<CPU>,
+    pub kbd: RefCell<Keyboard>,
+    pub beeper: RefCell<Beeper>,
+    pub psg: RefCell<PSG>,
+    scheduler: RefCell<Scheduler<Event>>,
+    state: RefCell<State>,
+    timing: Timing,
+    rom: Vec<u8>,
+}
+
+impl ZXSpectrum {
+    /// initialize a new ZX Spectrum with model and ROM image
+    pub fn new(model: ZXModel, rom: &[u8]) -> ZXSpectrum {
+        let timing = match model {
+            ZXModel::Spectrum48K => {
+                Timing {
+                    freq_khz: 3500,
+                    cycles_per_line: 224,
+                    num_lines: 312,
+                    display_line: 64,
+                    int_cycles: 32,
+                    contention_start: 14335,
+                }
+            }
+            ZXModel::Spectrum128 => {
+                Timing {
+                    freq_khz: 3547,
+                    cycles_per_line: 228,
+                    num_lines: 311,
+                    display_line: 63,
+                    int_cycles: 36,
+                    contention_start: 14361,
+                }
+            }
+        };
+        let num_roms = if model == ZXModel::Spectrum48K { 1 } else { 2 };
+        assert_eq!(rom.len(), num_roms * ROM_SIZE);
+
+        let mut kbd = Keyboard::new(5, 8);
+        kbd.min_hold = 2;
+        // caps shift is key 0 in half-row 0, symbol shift key 1 in half-row 7
+        kbd.add_modifier(1, 0, 0);
+        kbd.add_modifier(2, 1, 7);
+        kbd.add_key_table(0, KEYS_UNSHIFTED.as_bytes());
+        kbd.add_key_table(1, KEYS_CAPS_SHIFT.as_bytes());
+        kbd.add_key_table(2, KEYS_SYMBOL_SHIFT.as_bytes());
+        for &(key, layer, col, line) in &[(0x03, 1, 0, 7),    // break
+                                          (0x08, 1, 4, 3),    // cursor left
+                                          (0x09, 1, 2, 4),    // cursor right
+                                          (0x0A, 1, 4, 4),    // cursor down
+                                          (0x0B, 1, 3, 4),    // cursor up
+                                          (0x0C, 1, 0, 4),    // delete
+                                          (0x0D, 0, 0, 6),    // enter
+                                          (0x20, 0, 0, 7)] {  // space
+            kbd.add_key(key, layer, col, line);
+        }
+        let freq_khz = timing.freq_khz;
+        let num_lines = timing.num_lines;
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(timing.cycles_per_line as u64 * num_lines as u64, Event::FrameEnd);
+        ZXSpectrum {
+            model: model,
+            cpu: RefCell::new({
+                let mut cpu = CPU::new();
+                cpu.mem = Memory::with_heap_size(HEAP_SIZE);
+                cpu.set_wait_callback(true);
+                cpu
+            }),
+            kbd: RefCell::new(kbd),
+            beeper: RefCell::new(Beeper::new(freq_khz, SAMPLE_RATE)),
+            psg: RefCell::new(PSG::new(freq_khz, freq_khz / 2, SAMPLE_RATE)),
+            scheduler: RefCell::new(scheduler),
+            state: RefCell::new(State {
+                frame_start: 0,
+                frame_count: 0,
+                scanline: 0,
+                border: 0,
+                border_lines: vec![0; num_lines],
+                flash: false,
+                paging: 0,
+                pending_paging: None,
+                bus_offset: 0,
+                joystick: 0,
+            }),
+            timing: timing,
+            rom: rom.to_vec(),
+        }
+    }
+
+    /// return the CPU frequency of the current model in kHz
+    pub fn freq_khz(&self) -> i64 {
+        self.timing.freq_khz
+    }
+
+    /// power-on the Spectrum, initializes the memory map and starts at address 0
+    pub fn poweron(&self) {
+        {
+            let mut cpu = self.cpu.borrow_mut();
+            cpu.mem.unmap_all();
+            for b in cpu.mem.heap[..ROM_HEAP_OFFSET].iter_mut() {
+                *b = 0;
+            }
+            let rom_end = ROM_HEAP_OFFSET + self.rom.len();
+            cpu.mem.heap[ROM_HEAP_OFFSET..rom_end].copy_from_slice(&self.rom);
+        }
+        self.scheduler.borrow_mut().reset();
+        self.reset();
+    }
+
+    /// reset the Spectrum (RAM content is preserved)
+    pub fn reset(&self) {
+        self.cpu.borrow_mut().reset();
+        self.kbd.borrow_mut().release_all();
+        self.psg.borrow_mut().reset();
+        {
+            // a new frame starts at reset
+            let mut scheduler = self.scheduler.borrow_mut();
+            scheduler.cancel(Event::FrameEnd);
+            scheduler.schedule_in(self.frame_len(), Event::FrameEnd);
+            let mut state = self.state.borrow_mut();
+            state.frame_start = scheduler.cycles();
+            state.scanline = 0;
+            state.border = 0;
+            state.pending_paging = None;
+            state.bus_offset = 0;
+            state.joystick = 0;
+        }
+        self.update_paging(0);
+    }
+
+    /// update the memory map from the 128K paging register
+    ///
+    /// The CPU always sees RAM bank 5 at 0x4000 and RAM bank 2
+    /// at 0x8000, the paging register selects the RAM bank at
+    /// 0xC000, and the ROM at 0x0000. The 48K model has a
+    /// fixed memory map which is identical to the initial 128K mapping.
+    fn update_paging(&self, paging: u8) {
+        let mut cpu = self.cpu.borrow_mut();
+        let ram_bank = (paging & PAGING_RAM_MASK) as usize;
+        let rom = if (paging & PAGING_ROM) != 0 { 1 } else { 0 };
+        cpu.mem.map(0, ROM_HEAP_OFFSET + rom * ROM_SIZE, 0x0000, false, ROM_SIZE);
+        cpu.mem.map(0, 5 * BANK_SIZE, 0x4000, true, BANK_SIZE);
+        cpu.mem.map(0, 2 * BANK_SIZE, 0x8000, true, BANK_SIZE);
+        cpu.mem.map(0, ram_bank * BANK_SIZE, 0xC000, true, BANK_SIZE);
+        self.state.borrow_mut().paging = paging;
+    }
+
+    /// run the emulation for a number of microseconds
+    pub fn step(&self, micro_seconds: i64) {
+        self.step_cycles((self.timing.freq_khz * micro_seconds) / 1000);
+    }
+
+    /// run the emulation for at least a number of CPU cycles
+    ///
+    /// The emulation stops at the first instruction boundary after
+    /// the number of cycles has been reached.
+    pub fn step_cycles(&self, num_cycles: i64) {
+        let mut cur_cycles = 0;
+        while cur_cycles < num_cycles {
+            let (idle, pc) = {
+                let cpu = self.cpu.borrow();
+                (cpu.is_idle(), cpu.reg.pc())
+            };
+            self.state.borrow_mut().bus_offset = 0;
+            let cycles = if idle && !self.is_contended(pc) {
+                // fast-forward a waiting CPU to the next ULA event
+                let remaining = num_cycles - cur_cycles;
+                let budget = self.scheduler
+                    .borrow()
+                    .cycles_to_next_event()
+                    .map_or(remaining, |c| ::std::cmp::min(c as i64, remaining));
+                budget + self.cpu.borrow_mut().run(self, budget)
+            } else {
+                self.cpu.borrow_mut().step(self)
+            };
+
+            // paging changes are applied after the OUT instruction
+            let pending_paging = self.state.borrow_mut().pending_paging.take();
+            if let Some(paging) = pending_paging {
+                self.update_paging(paging);
+            }
+
+            self.scheduler.borrow_mut().advance(cycles);
+            self.dispatch_events();
+            self.beeper.borrow_mut().update(cycles);
+            if self.model == ZXModel::Spectrum128 {
+                self.psg.borrow_mut().update(cycles);
+            }
+            if self.frame_cycles() < self.timing.int_cycles {
+                // the ULA holds the INT line active for a few cycles
+                // at the start of each frame
+                self.cpu.borrow_mut().irq();
+            }
+            cur_cycles += cycles;
+        }
+    }
+
+    /// number of CPU cycles executed since power-on
+    pub fn cycles(&self) -> u64 {
+        self.scheduler.borrow().cycles()
+    }
+
+    /// length of a video frame in CPU cycles
+    fn frame_len(&self) -> i64 {
+        self.timing.cycles_per_line * self.timing.num_lines as i64
+    }
+
+    /// CPU cycles since the start of the current video frame
+    fn frame_cycles(&self) -> i64 {
+        (self.scheduler.borrow().cycles() - self.state.borrow().frame_start) as i64
+    }
+
+    /// dispatch the ULA events which are due
+    fn dispatch_events(&self) {
+        loop {
+            let event = self.scheduler.borrow_mut().pop_due();
+            match event {
+                Some((at, Event::FrameEnd)) => self.frame_end(at),
+                None => break,
+            }
+        }
+    }
+
+    /// finish the border scanlines, advance the flash flip-flop and
+    /// keyboard, and start the next frame
+    fn frame_end(&self, at: u64) {
+        self.update_border_lines();
+        {
+            let mut state = self.state.borrow_mut();
+            state.frame_start = at;
+            state.scanline = 0;
+            state.frame_count = state.frame_count.wrapping_add(1);
+            if state.frame_count % FLASH_FRAMES == 0 {
+                state.flash = !state.flash;
+            }
+        }
+        self.kbd.borrow_mut().update();
+        self.scheduler.borrow_mut().schedule(at + self.frame_len() as u64, Event::FrameEnd);
+    }
+
+    /// set the border color of all completed scanlines which haven't
+    /// been written yet to the current border color
+    fn update_border_lines(&self) {
+        let line = (self.frame_cycles() / self.timing.cycles_per_line) as usize;
+        let mut state = self.state.borrow_mut();
+        while state.scanline < line && state.scanline < self.timing.num_lines {
+            let (scanline, border) = (state.scanline, state.border);
+            state.border_lines[scanline] = border;
+            state.scanline += 1;
+        }
+    }
+
+    /// the ULA delay for a contended access a number of cycles after
+    /// the current frame position
+    fn ula_delay(&self, offset: i64) -> i64 {
+        let t = self.frame_cycles() + offset - self.timing.contention_start;
+        if t < 0 || t >= 192 * self.timing.cycles_per_line {
+            return 0;
+        }
+        let x = t % self.timing.cycles_per_line;
+        if x < 128 { CONTENTION[(x & 7) as usize] } else { 0 }
+    }
+
+    /// return true if an address is in memory which is shared with the ULA
+    fn is_contended(&self, addr: RegT) -> bool {
+        match addr & 0xC000 {
+            0x4000 => true,
+            0xC000 => {
+                // on the 128, the odd RAM banks are contended
+                self.model == ZXModel::Spectrum128 && (self.state.borrow().paging & 1) != 0
+            }
+            _ => false,
+        }
+    }
+
+    /// keyboard half-row port value for an I/O address (all active-low)
+    fn kbd_port(&self, port: RegT) -> RegT {
+        let lines = !(port >> 8) as u32 & 0xFF;
+        let keys = self.kbd.borrow().scan_lines(lines) as RegT;
+        0xE0 | (!keys & 0x1F)
+    }
+
+    /// press a key (ASCII code)
+    pub fn key_down(&self, ascii: u8) {
+        self.kbd.borrow_mut().key_down(ascii as usize);
+    }
+
+    /// release a key (ASCII code)
+    pub fn key_up(&self, ascii: u8) {
+        self.kbd.borrow_mut().key_up(ascii as usize);
+    }
+
+    /// set the Kempston joystick state as a combination of the ZX_JOY_* bits
+    pub fn set_joystick(&self, bits: u8) {
+        self.state.borrow_mut().joystick = bits & 0x1F;
+    }
+
+    /// get the current border color as RGBA8
+    pub fn border_color(&self) -> u32 {
+        PALETTE[self.state.borrow().border as usize]
+    }
+
+    /// get the generated audio samples (44.1 kHz mono), and clear the sample buffer
+    pub fn audio_samples(&self) -> Vec<f32> {
+        let mut samples = self.beeper.borrow_mut().take_samples();
+        if self.model == ZXModel::Spectrum128 {
+            let psg_samples = self.psg.borrow_mut().take_samples();
+            for (s, p) in samples.iter_mut().zip(psg_samples.iter()) {
+                *s += *p;
+            }
+        }
+        samples
+    }
+
+    /// the RAM bank which is currently displayed
+    fn screen_bank(&self) -> usize {
+        if (self.state.borrow().paging & PAGING_SCREEN) != 0 { 7 } else { 5 }
+    }
+
+    /// heap offset of the pixel byte at a pixel line and character column
+    fn pixel_offset(&self, y: usize, x: usize) -> usize {
+        self.screen_bank() * BANK_SIZE +
+        (((y & 0xC0) << 5) | ((y & 0x07) << 8) | ((y & 0x38) << 2) | x)
+    }
+
+    /// heap offset of the attribute byte of a character cell
+    fn attr_offset(&self, row: usize, x: usize) -> usize {
+        self.screen_bank() * BANK_SIZE + 0x1800 + row * 32 + x
+    }
+
+    /// decode the video RAM into a linear RGBA8 framebuffer of
+    /// ZX_DISPLAY_WIDTH * ZX_DISPLAY_HEIGHT pixels
+    ///
+    /// The border color is tracked per scanline, so that border
+    /// effects (for instance while loading from tape) are visible.
+    pub fn decode_framebuffer(&self, fb: &mut [u32]) {
+        self.update_border_lines();
+        let cpu = self.cpu.borrow();
+        let state = self.state.borrow();
+        let first_line = self.timing.display_line - BORDER;
+        for (y, line) in fb.chunks_mut(ZX_DISPLAY_WIDTH).enumerate().take(ZX_DISPLAY_HEIGHT) {
+            let border = PALETTE[state.border_lines[first_line + y] as usize];
+            if !(BORDER..BORDER + 192).contains(&y) {
+                for pixel in line.iter_mut() {
+                    *pixel = border;
+                }
+                continue;
+            }
+            line[..BORDER].copy_from_slice(&[border; BORDER]);
+            line[BORDER + 256..].copy_from_slice(&[border; BORDER]);
+            let py = y - BORDER;
+            for x in 0..32 {
+                let pixels = cpu.mem.heap[self.pixel_offset(py, x)];
+                let attr = cpu.mem.heap[self.attr_offset(py / 8, x)];
+                let bright = if (attr & ATTR_BRIGHT) != 0 { 8 } else { 0 };
+                let mut ink = PALETTE[(attr & 7) as usize + bright];
+                let mut paper = PALETTE[((attr >> 3) & 7) as usize + bright];
+                if (attr & ATTR_FLASH) != 0 && state.flash {
+                    ::std::mem::swap(&mut ink, &mut paper);
+                }
+                for bit in 0..8 {
+                    let set = (pixels & (0x80 >> bit)) != 0;
+                    line[BORDER + x * 8 + bit] = if set { ink } else { paper };
+                }
+            }
+        }
+    }
+
+    /// return the screen content as text, one line per character row
+    ///
+    /// Each character cell is compared against the character set in
+    /// the BASIC ROM (also in inverse video), cells which don't match
+    /// a character are returned as spaces.
+    pub fn screen_text(&self) -> String {
+        let cpu = self.cpu.borrow();
+        let basic_rom = if self.model == ZXModel::Spectrum48K { 0 } else { 1 };
+        let font_start = basic_rom * ROM_SIZE + FONT_OFFSET;
+        let font = &self.rom[font_start..font_start + 96 * 8];
+        let mut text = String::new();
+        for row in 0..24 {
+            let line: String = (0..32)
+                .map(|x| {
+                    let mut cell = [0u8; 8];
+                    for (i, b) in cell.iter_mut().enumerate() {
+                        *b = cpu.mem.heap[self.pixel_offset(row * 8 + i, x)];
+                    }
+                    let inverse: Vec<u8> = cell.iter().map(|b| !*b).collect();
+                    font.chunks(8)
+                        .position(|glyph| glyph == cell || glyph == &inverse[..])
+                        .map_or(' ', |i| (i as u8 + 0x20) as char)
+                })
+                .collect();
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text
+    }
+}
+
+impl Bus for ZXSpectrum {
+    fn cpu_outp(&self, port: RegT, val: RegT) {
+        if (port & 1) == 0 {
+            // ULA port: border color and beeper
+            // the scanlines so far still have the previous border color
+            self.update_border_lines();
+            self.state.borrow_mut().border = (val & ULA_BORDER_MASK) as u8;
+            self.beeper.borrow_mut().write((val & ULA_BEEPER) != 0);
+        }
+        if self.model == ZXModel::Spectrum128 {
+            if (port & 0x8002) == 0 {
+                let mut state = self.state.borrow_mut();
+                if (state.paging & PAGING_LOCK) == 0 {
+                    state.pending_paging = Some(val as u8);
+                }
+            } else if (port & 0xC002) == 0xC000 {
+                self.psg.borrow_mut().select(val as usize);
+            } else if (port & 0xC002) == 0x8000 {
+                self.psg.borrow_mut().write(val as u8);
+            }
+        }
+    }
+
+    fn cpu_inp(&self, port: RegT) -> RegT {
+        if (port & 1) == 0 {
+            self.kbd_port(port)
+        } else if self.model == ZXModel::Spectrum128 && (port & 0xC002) == 0xC000 {
+            self.psg.borrow().read() as RegT
+        } else if (port & 0xFF) == 0x1F {
+            // Kempston joystick interface (active-high)
+            self.state.borrow().joystick as RegT
+        } else {
+            0xFF
+        }
+    }
+
+    fn cpu_wait(&self, cycle: BusCycle, addr: RegT) -> i64 {
+        let (contended, len) = match cycle {
+            BusCycle::M1 => (self.is_contended(addr), 4),
+            BusCycle::MemRead | BusCycle::MemWrite => (self.is_contended(addr), 3),
+            BusCycle::IoRead | BusCycle::IoWrite => ((addr & 1) == 0, 4),
+        };
+        let offset = self.state.borrow().bus_offset;
+        let delay = if contended { self.ula_delay(offset) } else { 0 };
+        self.state.borrow_mut().bus_offset += delay + len;
+        delay
+    }
+}
+
+impl Machine for ZXSpectrum {
+    fn freq_khz(&self) -> i64 {
+        self.freq_khz()
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles()
+    }
+
+    fn step_cycles(&self, num_cycles: i64) {
+        self.step_cycles(num_cycles);
+    }
+
+    fn input(&self, event: InputEvent) {
+        match event {
+            InputEvent::KeyDown(key) => self.key_down(key),
+            InputEvent::KeyUp(key) => self.key_up(key),
+            InputEvent::Joystick(bits) => self.set_joystick(bits),
+            _ => (),
+        }
+    }
+
+    fn cpu_state_hash(&self) -> u64 {
+        self.cpu.borrow().state_hash()
+    }
+}
+
+// ------------------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cpu::TrapAction;
+
+    // a test ROM which draws 'HI' into the top-left corner, sets a red
+    // border and counts interrupts at 0x9000 in an IM 1 handler
+    fn test_rom() -> Vec<u8> {
+        let mut rom = vec![0u8; ROM_SIZE];
+        rom[0x0000..0x002A].copy_from_slice(&[
+            0xF3,               // DI
+            0x31, 0x00, 0x80,   // LD SP,0x8000
+            0xED, 0x56,         // IM 1
+            0x3E, 0x02,         // LD A,2
+            0xD3, 0xFE,         // OUT (0xFE),A
+            0x21, 0x40, 0x3E,   // LD HL,0x3E40 ('H')
+            0x11, 0x00, 0x40,   // LD DE,0x4000
+            0xCD, 0x00, 0x01,   // CALL 0x0100
+            0x21, 0x48, 0x3E,   // LD HL,0x3E48 ('I')
+            0x11, 0x01, 0x40,   // LD DE,0x4001
+            0xCD, 0x00, 0x01,   // CALL 0x0100
+            0x3E, 0x47,         // LD A,0x47 (bright white on black)
+            0x32, 0x00, 0x58,   // LD (0x5800),A
+            0x3E, 0xC7,         // LD A,0xC7 (flashing)
+            0x32, 0x01, 0x58,   // LD (0x5801),A
+            0xFB,               // EI
+            0x76,               // HALT
+            0x18, 0xFD,         // JR -3
+        ]);
+        rom[0x0038..0x0043].copy_from_slice(&[
+            0xE5,               // PUSH HL
+            0x2A, 0x00, 0x90,   // LD HL,(0x9000)
+            0x23,               // INC HL
+            0x22, 0x00, 0x90,   // LD (0x9000),HL
+            0xE1,               // POP HL
+            0xFB,               // EI
+            0xC9,               // RET
+        ]);
+        rom[0x0100..0x0109].copy_from_slice(&[
+            0x06, 0x08,         // LD B,8
+            0x7E,               // LD A,(HL)
+            0x12,               // LD (DE),A
+            0x23,               // INC HL
+            0x14,               // INC D
+            0x10, 0xFA,         // DJNZ -6
+            0xC9,               // RET
+        ]);
+        // the glyphs for 'H' and 'I'
+        rom[0x3E40..0x3E50].copy_from_slice(&[0x00, 0x42, 0x42, 0x7E, 0x42, 0x42, 0x42, 0x00,
+                                              0x00, 0x3E, 0x08, 0x08, 0x08, 0x08, 0x3E, 0x00]);
+        rom
+    }
+
+    #[test]
+    fn boot() {
+        let zx = ZXSpectrum::new(ZXModel::Spectrum48K, &test_rom());
+        zx.poweron();
+        zx.step(1000000);
+        assert_eq!(1, zx.cpu.borrow().reg.im);
+        let num_irqs = zx.cpu.borrow().mem.r16(0x9000);
+        assert!(num_irqs >= 49 && num_irqs <= 50);
+        assert!(zx.screen_text().starts_with("HI\n"));
+
+        let mut fb = vec![0u32; ZX_DISPLAY_WIDTH * ZX_DISPLAY_HEIGHT];
+        zx.decode_framebuffer(&mut fb);
+        assert_eq!(0xFFD70000, zx.border_color());
+        assert_eq!(zx.border_color(), fb[0]);
+        assert_eq!(zx.border_color(), fb[fb.len() - 1]);
+        // second pixel line of the 'H'
+        let top_left = (BORDER + 1) * ZX_DISPLAY_WIDTH + BORDER;
+        assert_eq!(0xFF000000, fb[top_left]);
+        assert_eq!(0xFFFFFFFF, fb[top_left + 1]);
+        assert_eq!(0xFF000000, fb[top_left + 2]);
+
+        // the 'I' is flashing
+        let i_pixel = top_left + 8 + 2;
+        let before = fb[i_pixel];
+        zx.step(320000);
+        zx.decode_framebuffer(&mut fb);
+        assert!(fb[i_pixel] != before);
+        assert_eq!(0xFFFFFFFF, fb[top_left + 1]);
+    }
+
+    #[test]
+    fn keyboard() {
+        let zx = ZXSpectrum::new(ZXModel::Spectrum48K, &test_rom());
+        zx.poweron();
+        assert_eq!(0xFF, zx.cpu_inp(0x00FE));
+        zx.key_down(b'a');
+        assert_eq!(0xFE, zx.cpu_inp(0xFDFE));
+        assert_eq!(0xFF, zx.cpu_inp(0xFEFE));
+        assert_eq!(0xFE, zx.cpu_inp(0x00FE));
+        zx.kbd.borrow_mut().release_all();
+        // uppercase letters are pressed with caps shift
+        zx.key_down(b'V');
+        assert_eq!(0xEE, zx.cpu_inp(0xFEFE));
+        zx.kbd.borrow_mut().release_all();
+        // symbols are pressed with symbol shift
+        zx.key_down(b'"');
+        assert_eq!(0xFD, zx.cpu_inp(0x7FFE));
+        assert_eq!(0xFE, zx.cpu_inp(0xDFFE));
+        zx.kbd.borrow_mut().release_all();
+        zx.key_down(0x0D);
+        assert_eq!(0xFE, zx.cpu_inp(0xBFFE));
+    }
+
+    #[test]
+    fn joystick() {
+        let zx = ZXSpectrum::new(ZXModel::Spectrum48K, &test_rom());
+        zx.poweron();
+        assert_eq!(0x00, zx.cpu_inp(0x001F));
+        zx.set_joystick(ZX_JOY_UP | ZX_JOY_FIRE);
+        assert_eq!(0x18, zx.cpu_inp(0x001F));
+        assert_eq!(0xFF, zx.cpu_inp(0x00FD));
+        zx.reset();
+        assert_eq!(0x00, zx.cpu_inp(0x001F));
+    }
+
+    #[test]
+    fn fast_forward() {
+        // the test ROM waits in HALT, a trap disables fast-forwarding,
+        // both runs must end up in the same state
+        let run = |fast_forward: bool| {
+            let zx = ZXSpectrum::new(ZXModel::Spectrum48K, &test_rom());
+            if !fast_forward {
+                zx.cpu.borrow_mut().add_trap(0x3FFF, |_| TrapAction::Continue);
+            }
+            zx.poweron();
+            for _ in 0..50 {
+                zx.step(19968);
+            }
+            let (hash, num_irqs) = {
+                let cpu = zx.cpu.borrow();
+                (cpu.state_hash(), cpu.mem.r16(0x9000))
+            };
+            let border_lines = zx.state.borrow().border_lines.clone();
+            (zx.cycles(), hash, num_irqs, zx.audio_samples(), border_lines)
+        };
+        let fast = run(true);
+        assert!(fast.2 >= 49);
+        assert!(fast == run(false));
+    }
+
+    #[test]
+    fn contention() {
+        // an endless INC HL loop runs slower in contended memory
+        let count = |addr: RegT| {
+            let zx = ZXSpectrum::new(ZXModel::Spectrum48K, &test_rom());
+            zx.poweron();
+            let mut cpu = zx.cpu.borrow_mut();
+            cpu.mem.write(addr, &[0xF3, 0x23, 0x18, 0xFD]);
+            cpu.reg.set_pc(addr);
+            cpu.reg.set_hl(0);
+            drop(cpu);
+            zx.step(20000);
+            let hl = zx.cpu.borrow().reg.hl();
+            hl
+        };
+        let uncontended = count(0x8000);
+        let contended = count(0x4000);
+        assert!(uncontended >= 3880 && uncontended <= 3890);
+        assert!(contended < uncontended - 300);
+    }
+
+    #[test]
+    fn di_int_window() {
+        // the INT line doesn't slow down code which runs with disabled interrupts:
+        // DI; INC HL; JR -3
+        let zx = ZXSpectrum::new(ZXModel::Spectrum48K, &test_rom());
+        zx.poweron();
+        {
+            let mut cpu = zx.cpu.borrow_mut();
+            cpu.mem.write(0x8000, &[0xF3, 0x23, 0x18, 0xFD]);
+            cpu.reg.set_pc(0x8000);
+            cpu.reg.set_hl(0);
+        }
+        let start = zx.cycles();
+        zx.step_cycles(3 * zx.frame_len());
+        let cycles = (zx.cycles() - start) as i64 - 4;
+        let cpu = zx.cpu.borrow();
+        let hl = cpu.reg.hl() as i64;
+        assert!(hl > 3 * zx.frame_len() / 18 - 1);
+        assert!(cycles == hl * 18 || cycles == hl * 18 - 12);
+        assert_eq!(0x8001, cpu.reg.wz());
+    }
+
+    #[test]
+    fn paging_128() {
+        let mut rom = test_rom();
+        rom.extend(test_rom());
+        rom[0x0000] = 0xAA;
+        rom[0x4000] = 0xBB;
+        rom[0x1000..0x100C].copy_from_slice(&[
+            0x01, 0xFD, 0x7F,   // LD BC,0x7FFD
+            0x3E, 0x3B,         // LD A,0x3B (ROM 1, RAM 3, screen 7, lock)
+            0xED, 0x79,         // OUT (C),A
+            0x3E, 0x00,         // LD A,0x00
+            0xED, 0x79,         // OUT (C),A
+            0x76,               // HALT
+        ]);
+        let zx = ZXSpectrum::new(ZXModel::Spectrum128, &rom);
+        zx.poweron();
+        // the RAM banks and both ROMs fit into the memory heap
+        assert_eq!(HEAP_SIZE, zx.cpu.borrow().mem.heap.len());
+        assert_eq!(0xAA, zx.cpu.borrow().mem.r8(0x0000));
+        zx.cpu.borrow_mut().reg.set_pc(0x1000);
+        zx.step(100);
+        // the second write is ignored because paging is locked
+        assert_eq!(0x3B, zx.state.borrow().paging);
+        {
+            let mut cpu = zx.cpu.borrow_mut();
+            assert_eq!(0xBB, cpu.mem.r8(0x0000));
+            cpu.mem.w8(0xC000, 0x12);
+            assert_eq!(0x12, cpu.mem.heap[3 * BANK_SIZE]);
+        }
+
+        // the screen is displayed from RAM bank 7
+        zx.cpu.borrow_mut().mem.heap[7 * BANK_SIZE] = 0x80;
+        zx.cpu.borrow_mut().mem.heap[7 * BANK_SIZE + 0x1800] = 0x07;
+        let mut fb = vec![0u32; ZX_DISPLAY_WIDTH * ZX_DISPLAY_HEIGHT];
+        zx.decode_framebuffer(&mut fb);
+        let top_left = BORDER * ZX_DISPLAY_WIDTH + BORDER;
+        assert_eq!(0xFFD7D7D7, fb[top_left]);
+    }
+
+    #[test]
+    fn psg_128() {
+        let mut rom = test_rom();
+        rom.extend(test_rom());
+        let zx = ZXSpectrum::new(ZXModel::Spectrum128, &rom);
+        zx.poweron();
+        zx.cpu_outp(0xFFFD, 8);
+        zx.cpu_outp(0xBFFD, 15);
+        assert_eq!(15, zx.psg.borrow().reg[8]);
+        assert_eq!(15, zx.cpu_inp(0xFFFD));
+        zx.step(20000);
+        assert!(zx.audio_samples().iter().any(|s| *s != 0.0));
+    }
+}
@@ -0,0 +1,277 @@
+extern crate rz80;
+
+// Runs the Z80 core tests of the FUSE emulator, which consist of 2 text files:
+// tests.in with the CPU state, memory contents and number of T-states to run
+// for each test, and tests.expected with the bus events, the CPU state and the
+// changed memory after the test has run.
+//
+// The files are not part of the repository, copy them from the FUSE sources
+// (fuse/z80/tests) to tests/fuse, or point the RZ80_FUSE_DIR environment
+// variable to their directory, and run:
+//
+// > cargo test --release --test test_fuse -- --ignored --nocapture
+//
+// The bus events in tests.expected are skipped, since rz80 doesn't expose
+// its individual memory accesses, all registers (including MEMPTR), the
+// halt state, the number of T-states and the complete memory are checked.
+
+#[cfg(test)]
+mod test_fuse {
+    use std::env;
+    use std::fs::File;
+    use std::io::Read;
+    use std::path::PathBuf;
+    use rz80::{CPU, Bus, RegT};
+
+    /// the CPU state, memory and T-states of a test
+    #[derive(Debug, Default)]
+    struct State {
+        regs: Vec<RegT>,
+        i: RegT,
+        r: RegT,
+        iff1: bool,
+        iff2: bool,
+        im: RegT,
+        halted: bool,
+        tstates: i64,
+        mem: Vec<(RegT, Vec<u8>)>,
+    }
+
+    /// the FUSE test harness returns the upper byte of the port address on input
+    struct TestBus;
+    impl Bus for TestBus {
+        fn cpu_inp(&self, port: RegT) -> RegT {
+            port >> 8
+        }
+    }
+
+    fn hex(s: &str) -> RegT {
+        RegT::from_str_radix(s, 16).expect("invalid hex number")
+    }
+
+    /// parse the 2 register lines, followed by memory blocks
+    fn parse_state<'a, I: Iterator<Item = &'a str>>(lines: &mut I, mem_end_marker: bool) -> State {
+        let mut state = State::default();
+        state.regs = lines.next().unwrap().split_whitespace().map(hex).collect();
+        let misc: Vec<&str> = lines.next().unwrap().split_whitespace().collect();
+        state.i = hex(misc[0]);
+        state.r = hex(misc[1]);
+        state.iff1 = misc[2] != "0";
+        state.iff2 = misc[3] != "0";
+        state.im = misc[4].parse().unwrap();
+        state.halted = misc[5] != "0";
+        state.tstates = misc[6].parse().unwrap();
+        // memory blocks: 'addr byte byte ... -1', tests.in ends the list with '-1',
+        // tests.expected with an empty line
+        while let Some(line) = lines.next() {
+            let line = line.trim();
+            if line.is_empty() || (mem_end_marker && line == "-1") {
+                break;
+            }
+            let mut tokens = line.split_whitespace();
+            let addr = hex(tokens.next().unwrap());
+            let bytes = tokens.take_while(|t| *t != "-1").map(|t| hex(t) as u8).collect();
+            state.mem.push((addr, bytes));
+        }
+        state
+    }
+
+    /// parse tests.in into (name, state) pairs
+    fn parse_input(text: &str) -> Vec<(String, State)> {
+        let mut tests = Vec::new();
+        let mut lines = text.lines();
+        while let Some(line) = lines.next() {
+            let name = line.trim();
+            if name.is_empty() {
+                continue;
+            }
+            if name == "-1" {
+                break;
+            }
+            tests.push((name.to_string(), parse_state(&mut lines, true)));
+        }
+        tests
+    }
+
+    /// parse tests.expected into (name, state) pairs, skipping the bus events
+    fn parse_expected(text: &str) -> Vec<(String, State)> {
+        let mut tests = Vec::new();
+        let mut lines = text.lines().peekable();
+        while let Some(line) = lines.next() {
+            let name = line.trim();
+            if name.is_empty() {
+                continue;
+            }
+            while lines.peek().map_or(false, |l| l.starts_with(' ') || l.starts_with('\t')) {
+                lines.next();
+            }
+            tests.push((name.to_string(), parse_state(&mut lines, false)));
+        }
+        tests
+    }
+
+    /// the initial memory contents of the FUSE test harness
+    fn fill_memory(cpu: &mut CPU) {
+        let pattern = [0xDE, 0xAD, 0xBE, 0xEF];
+        for addr in 0..0x10000 {
+            cpu.mem.w8(addr, pattern[addr as usize & 3]);
+        }
+    }
+
+    /// run one test, return the differences to the expected state
+    fn run_test(cpu: &mut CPU, input: &State, expected: &State) -> Vec<String> {
+        cpu.reset();
+        fill_memory(cpu);
+        for &(addr, ref bytes) in &input.mem {
+            cpu.mem.write(addr, bytes);
+        }
+        let r = &input.regs;
+        cpu.reg.set_af(r[0]);
+        cpu.reg.set_bc(r[1]);
+        cpu.reg.set_de(r[2]);
+        cpu.reg.set_hl(r[3]);
+        cpu.reg.set_af_(r[4]);
+        cpu.reg.set_bc_(r[5]);
+        cpu.reg.set_de_(r[6]);
+        cpu.reg.set_hl_(r[7]);
+        cpu.reg.set_ix(r[8]);
+        cpu.reg.set_iy(r[9]);
+        cpu.reg.set_sp(r[10]);
+        cpu.reg.set_pc(r[11]);
+        cpu.reg.set_wz(r[12]);
+        cpu.reg.i = input.i;
+        cpu.reg.r = input.r;
+        cpu.iff1 = input.iff1;
+        cpu.iff2 = input.iff2;
+        cpu.reg.im = input.im;
+        cpu.halt = input.halted;
+        if input.halted {
+            cpu.reg.set_pc(r[11] + 1);
+        }
+
+        let mut tstates = 0;
+        while tstates < input.tstates {
+            tstates += cpu.step(&TestBus);
+        }
+
+        let mut errors = Vec::new();
+        let names = ["AF", "BC", "DE", "HL", "AF'", "BC'", "DE'", "HL'", "IX", "IY", "SP", "PC",
+                     "MEMPTR"];
+        let reg = &cpu.reg;
+        // FUSE keeps the PC on the HALT instruction in HALT state, rz80 behind it
+        let pc = if cpu.halt { (reg.pc() - 1) & 0xFFFF } else { reg.pc() };
+        let found = [reg.af(), reg.bc(), reg.de(), reg.hl(), reg.af_(), reg.bc_(), reg.de_(),
+                     reg.hl_(), reg.ix(), reg.iy(), reg.sp(), pc, reg.wz()];
+        for i in 0..names.len() {
+            if expected.regs[i] != found[i] {
+                errors.push(format!("{}: expected {:04X}, found {:04X}",
+                                    names[i], expected.regs[i], found[i]));
+            }
+        }
+        let misc = [("I", expected.i, reg.i),
+                    ("R", expected.r, reg.r),
+                    ("IFF1", expected.iff1 as RegT, cpu.iff1 as RegT),
+                    ("IFF2", expected.iff2 as RegT, cpu.iff2 as RegT),
+                    ("IM", expected.im, reg.im),
+                    ("halted", expected.halted as RegT, cpu.halt as RegT)];
+        for &(name, e, f) in misc.iter() {
+            if e != f {
+                errors.push(format!("{}: expected {:02X}, found {:02X}", name, e, f));
+            }
+        }
+        if expected.tstates != tstates {
+            errors.push(format!("tstates: expected {}, found {}", expected.tstates, tstates));
+        }
+
+        // compare the complete memory, the expected state only lists changed bytes
+        let mut image: Vec<u8> = (0..0x10000).map(|a| [0xDE, 0xAD, 0xBE, 0xEF][a & 3]).collect();
+        for &(addr, ref bytes) in input.mem.iter().chain(expected.mem.iter()) {
+            for (i, b) in bytes.iter().enumerate() {
+                image[(addr as usize + i) & 0xFFFF] = *b;
+            }
+        }
+        for addr in 0..0x10000 {
+            let found = cpu.mem.r8(addr as RegT) as u8;
+            if image[addr] != found {
+                errors.push(format!("memory {:04X}: expected {:02X}, found {:02X}",
+                                    addr, image[addr], found));
+            }
+        }
+        errors
+    }
+
+    /// run all tests, return the failed test names with their differences
+    fn run_tests(input: &str, expected: &str) -> (usize, Vec<(String, Vec<String>)>) {
+        let inputs = parse_input(input);
+        let expected = parse_expected(expected);
+        assert_eq!(inputs.len(), expected.len(), "tests.in and tests.expected don't match");
+        let mut cpu = CPU::new_64k();
+        let mut failures = Vec::new();
+        for (&(ref name, ref input), &(ref exp_name, ref exp)) in inputs.iter().zip(expected.iter()) {
+            assert_eq!(name, exp_name);
+            let errors = run_test(&mut cpu, input, exp);
+            if !errors.is_empty() {
+                failures.push((name.clone(), errors));
+            }
+        }
+        (inputs.len(), failures)
+    }
+
+    #[test]
+    fn fuse_format() {
+        let input = "00\n\
+                     0000 0000 0000 0000 0000 0000 0000 0000 0000 0000 0000 0000 0000\n\
+                     00 00 0 0 0 0     1\n\
+                     0000 00 -1\n\
+                     -1\n\
+                     \n\
+                     02\n\
+                     5600 0001 0000 0000 0000 0000 0000 0000 0000 0000 0000 0000 0000\n\
+                     00 00 0 0 0 0     1\n\
+                     0000 02 -1\n\
+                     -1\n\
+                     \n\
+                     -1\n";
+        let expected = "00\n    0 MC 0000\n    0 MR 0000 00\n\
+                        0000 0000 0000 0000 0000 0000 0000 0000 0000 0000 0000 0001 0000\n\
+                        00 01 0 0 0 0     4\n\
+                        \n\
+                        02\n    0 MC 0000\n    0 MR 0000 02\n    3 MC 0001\n    3 MW 0001 56\n\
+                        5600 0001 0000 0000 0000 0000 0000 0000 0000 0000 0000 0001 5602\n\
+                        00 01 0 0 0 0     7\n\
+                        0001 56 -1\n\
+                        \n";
+        let (num, failures) = run_tests(input, expected);
+        assert_eq!(2, num);
+        assert!(failures.is_empty(), "{:?}", failures);
+
+        // a wrong expected flag register must fail
+        let (_, failures) = run_tests(input, &expected.replace("5600 0001", "5601 0001"));
+        assert_eq!(1, failures.len());
+        assert_eq!("02", failures[0].0);
+        assert_eq!(vec!["AF: expected 5601, found 5600"], failures[0].1);
+    }
+
+    #[test]
+    #[ignore]
+    fn fuse() {
+        let dir = PathBuf::from(env::var("RZ80_FUSE_DIR").unwrap_or(String::from("tests/fuse")));
+        let mut input = String::new();
+        let mut expected = String::new();
+        if File::open(dir.join("tests.in"))
+            .and_then(|mut f| f.read_to_string(&mut input))
+            .and_then(|_| File::open(dir.join("tests.expected")))
+            .and_then(|mut f| f.read_to_string(&mut expected))
+            .is_err() {
+            println!("FUSE tests not found in '{}', skipping", dir.display());
+            return;
+        }
+        let (num, failures) = run_tests(&input, &expected);
+        for &(ref name, ref errors) in &failures {
+            println!("{}: {}", name, errors.join(", "));
+        }
+        println!("{} tests, {} failed", num, failures.len());
+        let names: Vec<&str> = failures.iter().map(|f| f.0.as_str()).collect();
+        assert!(failures.is_empty(), "failed tests: {}", names.join(" "));
+    }
+}
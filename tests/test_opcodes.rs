@@ -1400,9 +1400,9 @@ mod test_opcodes {
             0x76,       // HALT
         ];
         cpu.mem.write(0x0000, &prog);
-        assert_eq!(4, cpu.step(bus)); assert_eq!(0x0000, cpu.reg.pc()); assert!(cpu.halt);
-        assert_eq!(4, cpu.step(bus)); assert_eq!(0x0000, cpu.reg.pc()); assert!(cpu.halt);
-        assert_eq!(4, cpu.step(bus)); assert_eq!(0x0000, cpu.reg.pc()); assert!(cpu.halt);
+        assert_eq!(4, cpu.step(bus)); assert_eq!(0x0001, cpu.reg.pc()); assert!(cpu.halt);
+        assert_eq!(4, cpu.step(bus)); assert_eq!(0x0001, cpu.reg.pc()); assert!(cpu.halt);
+        assert_eq!(4, cpu.step(bus)); assert_eq!(0x0001, cpu.reg.pc()); assert!(cpu.halt);
     }
 
     #[test]
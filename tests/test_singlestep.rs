@@ -0,0 +1,416 @@
+extern crate rz80;
+
+// Runs the SingleStepTests Z80 test vectors (https://github.com/SingleStepTests/z80),
+// one JSON file per opcode with the CPU state and RAM contents before and after
+// each test case, the port accesses and one entry per clock cycle.
+//
+// The test vectors are not part of the repository, copy the 'v1' directory to
+// tests/singlestep, or point the RZ80_SINGLESTEP_DIR environment variable to it,
+// and run:
+//
+// > cargo test --release --test test_singlestep -- --ignored --nocapture
+//
+// Single files can be selected with RZ80_SINGLESTEP_FILTER (e.g. "ed b0").
+// Each test case checks all registers (including WZ/MEMPTR, R, IM and the
+// IFFs), the final RAM contents, the port accesses and the number of cycles.
+// The memory and I/O accesses recorded through Bus::cpu_wait() are compared
+// in order against the read/write clock cycles of the test case (address
+// and access kind, opcode fetches count as memory reads).
+
+#[cfg(test)]
+mod test_singlestep {
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
+    use rz80::{CPU, Bus, BusCycle, RegT};
+
+    /// a minimal JSON value, good enough for the test vectors
+    #[derive(Debug)]
+    enum Json {
+        Null,
+        Bool(bool),
+        Num(f64),
+        Str(String),
+        Arr(Vec<Json>),
+        Obj(BTreeMap<String, Json>),
+    }
+
+    impl Json {
+        fn get(&self, key: &str) -> Option<&Json> {
+            match *self {
+                Json::Obj(ref map) => map.get(key),
+                _ => None,
+            }
+        }
+        fn num(&self) -> Option<RegT> {
+            match *self {
+                Json::Num(n) => Some(n as RegT),
+                Json::Bool(b) => Some(b as RegT),
+                _ => None,
+            }
+        }
+        fn arr(&self) -> &[Json] {
+            match *self {
+                Json::Arr(ref v) => v,
+                _ => &[],
+            }
+        }
+        fn str(&self) -> &str {
+            match *self {
+                Json::Str(ref s) => s,
+                _ => "",
+            }
+        }
+    }
+
+    struct Parser<'a> {
+        s: &'a [u8],
+        pos: usize,
+    }
+
+    impl<'a> Parser<'a> {
+        fn ws(&mut self) {
+            while self.pos < self.s.len() && (self.s[self.pos] as char).is_whitespace() {
+                self.pos += 1;
+            }
+        }
+        fn expect(&mut self, c: u8) {
+            self.ws();
+            assert_eq!(c as char, self.s[self.pos] as char, "JSON syntax error at {}", self.pos);
+            self.pos += 1;
+        }
+        fn string(&mut self) -> String {
+            self.expect(b'"');
+            let start = self.pos;
+            while self.s[self.pos] != b'"' {
+                // the test vectors don't contain escaped characters
+                self.pos += 1;
+            }
+            self.pos += 1;
+            String::from_utf8_lossy(&self.s[start..self.pos - 1]).into_owned()
+        }
+        fn value(&mut self) -> Json {
+            self.ws();
+            match self.s[self.pos] {
+                b'{' => {
+                    self.pos += 1;
+                    let mut map = BTreeMap::new();
+                    self.ws();
+                    if self.s[self.pos] == b'}' {
+                        self.pos += 1;
+                        return Json::Obj(map);
+                    }
+                    loop {
+                        let key = self.string();
+                        self.expect(b':');
+                        let val = self.value();
+                        map.insert(key, val);
+                        self.ws();
+                        self.pos += 1;
+                        if self.s[self.pos - 1] == b'}' {
+                            return Json::Obj(map);
+                        }
+                    }
+                }
+                b'[' => {
+                    self.pos += 1;
+                    let mut vec = Vec::new();
+                    self.ws();
+                    if self.s[self.pos] == b']' {
+                        self.pos += 1;
+                        return Json::Arr(vec);
+                    }
+                    loop {
+                        vec.push(self.value());
+                        self.ws();
+                        self.pos += 1;
+                        if self.s[self.pos - 1] == b']' {
+                            return Json::Arr(vec);
+                        }
+                    }
+                }
+                b'"' => Json::Str(self.string()),
+                b'n' => {
+                    self.pos += 4;
+                    Json::Null
+                }
+                b't' => {
+                    self.pos += 4;
+                    Json::Bool(true)
+                }
+                b'f' => {
+                    self.pos += 5;
+                    Json::Bool(false)
+                }
+                _ => {
+                    let start = self.pos;
+                    while self.pos < self.s.len() &&
+                          (self.s[self.pos] == b'-' || self.s[self.pos] == b'.' ||
+                           self.s[self.pos] == b'e' || self.s[self.pos] == b'E' ||
+                           self.s[self.pos] == b'+' ||
+                           (self.s[self.pos] as char).is_digit(10)) {
+                        self.pos += 1;
+                    }
+                    let text = String::from_utf8_lossy(&self.s[start..self.pos]).into_owned();
+                    Json::Num(text.parse().expect("invalid JSON number"))
+                }
+            }
+        }
+    }
+
+    fn parse_json(text: &[u8]) -> Json {
+        Parser { s: text, pos: 0 }.value()
+    }
+
+    /// replays the port accesses of a test case, and records the bus cycles
+    struct TestBus {
+        ports: RefCell<Vec<(RegT, RegT, bool)>>,
+        accesses: RefCell<Vec<(&'static str, RegT)>>,
+        errors: RefCell<Vec<String>>,
+    }
+
+    impl Bus for TestBus {
+        fn cpu_wait(&self, cycle: BusCycle, addr: RegT) -> i64 {
+            let kind = match cycle {
+                BusCycle::M1 | BusCycle::MemRead => "mem read",
+                BusCycle::MemWrite => "mem write",
+                BusCycle::IoRead => "io read",
+                BusCycle::IoWrite => "io write",
+            };
+            self.accesses.borrow_mut().push((kind, addr));
+            0
+        }
+        fn cpu_inp(&self, port: RegT) -> RegT {
+            let mut ports = self.ports.borrow_mut();
+            match ports.iter().position(|p| !p.2) {
+                Some(i) => {
+                    let (addr, val, _) = ports.remove(i);
+                    if addr != port {
+                        self.errors.borrow_mut()
+                            .push(format!("IN port: expected {:04X}, found {:04X}", addr, port));
+                    }
+                    val
+                }
+                None => {
+                    self.errors.borrow_mut().push(format!("unexpected IN from {:04X}", port));
+                    0xFF
+                }
+            }
+        }
+        fn cpu_outp(&self, port: RegT, val: RegT) {
+            let mut ports = self.ports.borrow_mut();
+            match ports.iter().position(|p| p.2) {
+                Some(i) => {
+                    let expected = ports.remove(i);
+                    if (expected.0, expected.1) != (port, val) {
+                        self.errors.borrow_mut()
+                            .push(format!("OUT: expected {:04X},{:02X}, found {:04X},{:02X}",
+                                          expected.0, expected.1, port, val));
+                    }
+                }
+                None => {
+                    self.errors.borrow_mut().push(format!("unexpected OUT to {:04X}", port));
+                }
+            }
+        }
+    }
+
+    /// the memory and I/O accesses in the clock cycles of a test case, in
+    /// order, an access which spans several clock cycles is only counted once
+    fn expected_accesses(cycles: &[Json]) -> Vec<(&'static str, RegT)> {
+        let mut accesses = Vec::new();
+        let mut prev = None;
+        for c in cycles {
+            let c = c.arr();
+            let flags = c[2].str();
+            let (read, write) = (flags.contains('r'), flags.contains('w'));
+            let kind = if flags.contains('m') {
+                if read { Some("mem read") } else if write { Some("mem write") } else { None }
+            } else if flags.contains('i') {
+                if read { Some("io read") } else if write { Some("io write") } else { None }
+            } else {
+                None
+            };
+            let access = kind.map(|k| (k, c[0].num().unwrap()));
+            if access.is_some() && access != prev {
+                accesses.push(access.unwrap());
+            }
+            prev = access;
+        }
+        accesses
+    }
+
+    fn field(state: &Json, key: &str) -> RegT {
+        state.get(key).and_then(|v| v.num()).unwrap_or(0)
+    }
+
+    fn set_state(cpu: &mut CPU, state: &Json) {
+        cpu.reset();
+        cpu.reg.set_a(field(state, "a"));
+        cpu.reg.set_f(field(state, "f"));
+        cpu.reg.set_b(field(state, "b"));
+        cpu.reg.set_c(field(state, "c"));
+        cpu.reg.set_d(field(state, "d"));
+        cpu.reg.set_e(field(state, "e"));
+        cpu.reg.set_h(field(state, "h"));
+        cpu.reg.set_l(field(state, "l"));
+        cpu.reg.set_ix(field(state, "ix"));
+        cpu.reg.set_iy(field(state, "iy"));
+        cpu.reg.set_sp(field(state, "sp"));
+        cpu.reg.set_pc(field(state, "pc"));
+        cpu.reg.set_wz(field(state, "wz"));
+        cpu.reg.set_af_(field(state, "af_"));
+        cpu.reg.set_bc_(field(state, "bc_"));
+        cpu.reg.set_de_(field(state, "de_"));
+        cpu.reg.set_hl_(field(state, "hl_"));
+        cpu.reg.i = field(state, "i");
+        cpu.reg.r = field(state, "r");
+        cpu.reg.im = field(state, "im");
+        cpu.iff1 = field(state, "iff1") != 0;
+        cpu.iff2 = field(state, "iff2") != 0;
+        for entry in state.get("ram").map_or(&[][..], |r| r.arr()) {
+            let e = entry.arr();
+            cpu.mem.w8(e[0].num().unwrap(), e[1].num().unwrap());
+        }
+    }
+
+    fn compare_state(cpu: &CPU, state: &Json, errors: &mut Vec<String>) {
+        let r = &cpu.reg;
+        let regs = [("a", r.a()), ("f", r.f()), ("b", r.b()), ("c", r.c()), ("d", r.d()),
+                    ("e", r.e()), ("h", r.h()), ("l", r.l()), ("ix", r.ix()), ("iy", r.iy()),
+                    ("sp", r.sp()), ("pc", r.pc()), ("wz", r.wz()), ("af_", r.af_()),
+                    ("bc_", r.bc_()), ("de_", r.de_()), ("hl_", r.hl_()), ("i", r.i),
+                    ("r", r.r), ("im", r.im), ("iff1", cpu.iff1 as RegT),
+                    ("iff2", cpu.iff2 as RegT)];
+        for &(name, found) in regs.iter() {
+            if let Some(expected) = state.get(name).and_then(|v| v.num()) {
+                if expected != found {
+                    errors.push(format!("{}: expected {:02X}, found {:02X}", name, expected, found));
+                }
+            }
+        }
+        for entry in state.get("ram").map_or(&[][..], |r| r.arr()) {
+            let e = entry.arr();
+            let (addr, expected) = (e[0].num().unwrap(), e[1].num().unwrap());
+            let found = cpu.mem.r8(addr);
+            if expected != found {
+                errors.push(format!("ram {:04X}: expected {:02X}, found {:02X}", addr, expected, found));
+            }
+        }
+    }
+
+    /// run all test cases in a file, return (number of cases, failure descriptions)
+    fn run_file(cpu: &mut CPU, path: &Path) -> (usize, Vec<String>) {
+        let mut text = Vec::new();
+        File::open(path).and_then(|mut f| f.read_to_end(&mut text)).unwrap();
+        let json = parse_json(&text);
+        let mut failures = Vec::new();
+        for case in json.arr() {
+            let name = case.get("name").map_or("", |n| n.str());
+            let initial = case.get("initial").unwrap();
+            let final_state = case.get("final").unwrap();
+            set_state(cpu, initial);
+            let ports = case.get("ports").map_or(&[][..], |p| p.arr()).iter()
+                .map(|p| {
+                    let p = p.arr();
+                    (p[0].num().unwrap(), p[1].num().unwrap(), p[2].str() == "w")
+                })
+                .collect();
+            let bus = TestBus {
+                ports: RefCell::new(ports),
+                accesses: RefCell::new(Vec::new()),
+                errors: RefCell::new(Vec::new()),
+            };
+            let cycles = cpu.step(&bus) as usize;
+            let mut errors = bus.errors.borrow().clone();
+            compare_state(cpu, final_state, &mut errors);
+            let case_cycles = case.get("cycles").map_or(&[][..], |c| c.arr());
+            if cycles != case_cycles.len() {
+                errors.push(format!("cycles: expected {}, found {}", case_cycles.len(), cycles));
+            }
+            let expected = expected_accesses(case_cycles);
+            let found = bus.accesses.borrow();
+            if expected != *found {
+                errors.push(format!("bus cycles: expected {:?}, found {:?}", expected, *found));
+            }
+            if !errors.is_empty() {
+                failures.push(format!("{}: {}", name, errors.join(", ")));
+            }
+            // clear the RAM for the next test case
+            for state in &[initial, final_state] {
+                for entry in state.get("ram").map_or(&[][..], |r| r.arr()) {
+                    cpu.mem.w8(entry.arr()[0].num().unwrap(), 0);
+                }
+            }
+        }
+        (json.arr().len(), failures)
+    }
+
+    #[test]
+    fn json_parser() {
+        let json = parse_json(b" { \"name\": \"ed b0 0\", \"initial\": { \"pc\": 4660, \
+                                 \"ram\": [[1, 237], [2, 176]] }, \"ports\": [], \
+                                 \"flag\": true, \"none\": null, \"cycles\": [[1, null, \"r-m-\"]] }");
+        assert_eq!("ed b0 0", json.get("name").unwrap().str());
+        assert_eq!(Some(0x1234), json.get("initial").and_then(|i| i.get("pc")).and_then(|p| p.num()));
+        assert_eq!(2, json.get("initial").unwrap().get("ram").unwrap().arr().len());
+        assert_eq!(0, json.get("ports").unwrap().arr().len());
+        assert_eq!(Some(1), json.get("flag").unwrap().num());
+        assert!(json.get("none").unwrap().num().is_none());
+        assert_eq!("r-m-", json.get("cycles").unwrap().arr()[0].arr()[2].str());
+    }
+
+    #[test]
+    fn bus_accesses() {
+        // OUT (C),A: opcode fetches with refresh cycles, then an I/O write
+        // which is active for 2 clock cycles
+        let json = parse_json(b"[[0, null, \"----\"], [0, 237, \"r-m-\"], [0, null, \"----\"], \
+                                [0, null, \"----\"], [1, null, \"----\"], [1, 121, \"r-m-\"], \
+                                [1, null, \"----\"], [1, null, \"----\"], [4660, null, \"----\"], \
+                                [4660, 5, \"-w-i\"], [4660, 5, \"-w-i\"], [4660, null, \"----\"]]");
+        assert_eq!(vec![("mem read", 0), ("mem read", 1), ("io write", 0x1234)],
+                   expected_accesses(json.arr()));
+    }
+
+    #[test]
+    #[ignore]
+    fn singlestep() {
+        let dir = PathBuf::from(env::var("RZ80_SINGLESTEP_DIR")
+            .unwrap_or(String::from("tests/singlestep")));
+        let filter = env::var("RZ80_SINGLESTEP_FILTER").unwrap_or(String::new());
+        let mut paths: Vec<PathBuf> = match fs::read_dir(&dir) {
+            Ok(entries) => {
+                entries.filter_map(|e| e.ok().map(|e| e.path()))
+                    .filter(|p| p.extension().map_or(false, |e| e == "json"))
+                    .filter(|p| p.to_string_lossy().contains(&filter))
+                    .collect()
+            }
+            Err(_) => {
+                println!("SingleStepTests not found in '{}', skipping", dir.display());
+                return;
+            }
+        };
+        paths.sort();
+
+        let mut cpu = CPU::new_64k();
+        // call Bus::cpu_wait() for each bus cycle to record the memory accesses
+        cpu.set_wait_callback(true);
+        let mut num_cases = 0;
+        let mut failed_ops = Vec::new();
+        for path in &paths {
+            let (num, failures) = run_file(&mut cpu, path);
+            num_cases += num;
+            if !failures.is_empty() {
+                let op = path.file_stem().unwrap().to_string_lossy().into_owned();
+                println!("{}: {} of {} failed, first: {}", op, failures.len(), num, failures[0]);
+                failed_ops.push(op);
+            }
+        }
+        println!("{} files, {} test cases, {} opcodes failed", paths.len(), num_cases,
+                 failed_ops.len());
+        assert!(failed_ops.is_empty(), "failed opcodes: {}", failed_ops.join(", "));
+    }
+}
@@ -1,99 +1,209 @@
 extern crate rz80;
 extern crate time;
 
+// Runs the ZEXDOC and ZEXALL instruction exercisers on the CP/M runtime.
+//
+// The complete runs take a while and are ignored by default:
+//
+// > cargo test --release -- --nocapture --ignored
+//
+// To iterate on a fix, single instruction groups can be selected with the
+// RZ80_ZEX_GROUP environment variable, which is matched against the group
+// names printed by ZEX (several patterns can be separated by ';'):
+//
+// > RZ80_ZEX_GROUP="daa" cargo test --release --test test_zex -- --ignored --nocapture
+
 #[cfg(test)]
 mod test_zex {
+    use std::env;
+    use std::io::{self, Write};
     use time::PreciseTime;
     use rz80;
-    
+
     static ZEXDOC: &'static [u8] = include_bytes!("zexdoc.com");
     static ZEXALL: &'static [u8] = include_bytes!("zexall.com");
 
-    struct DummyBus { }
-    impl rz80::Bus for DummyBus { }
-
-    // emulates a CP/M BDOS call, only what's needed by ZEX
-    fn cpm_bdos(cpu: &mut rz80::CPU) {
-        match cpu.reg.c() {
-            2 => {
-                // output a character
-                print!("{}", cpu.reg.e() as u8 as char);
-            },
-            9 => {
-                // output a string
-                let mut addr = cpu.reg.de();
-                loop {
-                    let c = cpu.mem.r8(addr) as u8;
-                    addr = (addr + 1) & 0xFFFF;
-                    if c != b'$' {
-                        print!("{}", c as char);
-                    }
-                    else {
-                        break;
-                    }
-                }
-            },
-            _ => {
-                panic!("Unknown CP/M call {}!", cpu.reg.c());
+    // a descriptor is a flag mask, 3 test vectors of 20 bytes and a 4 byte CRC,
+    // followed by the '$' terminated group name
+    const DESC_NAME_OFFSET: usize = 1 + 3 * 20 + 4;
+
+    /// the result of one instruction group
+    #[derive(Debug, PartialEq)]
+    struct GroupResult {
+        name: String,
+        ok: bool,
+        expected_crc: Option<String>,
+        found_crc: Option<String>,
+    }
+
+    /// find the test table, the program starts it with 'ld hl,tests; ld a,(hl); inc hl; or (hl)'
+    fn find_test_table(prog: &[u8]) -> usize {
+        let pos = prog.windows(6)
+            .position(|w| w[0] == 0x21 && w[3] == 0x7E && w[4] == 0x23 && w[5] == 0xB6)
+            .expect("ZEX test table not found");
+        (prog[pos + 1] as usize | (prog[pos + 2] as usize) << 8) - 0x100
+    }
+
+    /// read the group names from the test table, as (address, name) pairs
+    fn groups(prog: &[u8]) -> Vec<(u16, String)> {
+        let mut res = Vec::new();
+        let mut entry = find_test_table(prog);
+        loop {
+            let addr = prog[entry] as usize | (prog[entry + 1] as usize) << 8;
+            if addr == 0 {
+                break;
+            }
+            let name: String = prog[addr - 0x100 + DESC_NAME_OFFSET..]
+                .iter()
+                .take_while(|c| **c != b'$')
+                .map(|c| *c as char)
+                .collect();
+            res.push((addr as u16, name.trim_end_matches('.').to_string()));
+            entry += 2;
+        }
+        res
+    }
+
+    /// patch the test table to only contain the groups matching one of the patterns
+    fn select_groups(prog: &[u8], patterns: &[&str]) -> Vec<u8> {
+        let mut patched = prog.to_vec();
+        let mut entry = find_test_table(prog);
+        for (addr, name) in groups(prog) {
+            if patterns.iter().any(|p| name.contains(p)) {
+                patched[entry] = addr as u8;
+                patched[entry + 1] = (addr >> 8) as u8;
+                entry += 2;
+            }
+        }
+        patched[entry] = 0;
+        patched[entry + 1] = 0;
+        patched
+    }
+
+    /// parse the ZEX console output into group results
+    fn parse_output(output: &str) -> Vec<GroupResult> {
+        let mut res = Vec::new();
+        for line in output.split(|c| c == '\n' || c == '\r') {
+            let line = line.trim();
+            if let Some(pos) = line.find("  OK") {
+                res.push(GroupResult {
+                    name: line[..pos].trim_end_matches('.').to_string(),
+                    ok: true,
+                    expected_crc: None,
+                    found_crc: None,
+                });
+            } else if let Some(pos) = line.find("  ERROR") {
+                let crc = |key: &str| {
+                    line.find(key).map(|p| line[p + key.len()..].chars().take(8).collect())
+                };
+                res.push(GroupResult {
+                    name: line[..pos].trim_end_matches('.').to_string(),
+                    ok: false,
+                    expected_crc: crc("expected:"),
+                    found_crc: crc("found:"),
+                });
             }
         }
-        cpu.ret();
+        res
+    }
+
+    /// collect the console output, and print it to show the progress
+    fn capture_output(cpm: &rz80::CPM, output: &mut String) {
+        let text = String::from_utf8_lossy(&cpm.take_output()).into_owned();
+        print!("{}", text);
+        io::stdout().flush().unwrap();
+        output.push_str(&text);
     }
 
-    fn run_test(prog: &[u8]) -> (i64, i64) {
+    /// run a ZEX program, return the group results, number of ops and cycles
+    fn run_test(prog: &[u8]) -> (Vec<GroupResult>, i64, i64) {
         let mut num_ops = 0;
         let mut num_cycles = 0;
-        let mut cpu = rz80::CPU::new_64k();
-        let mut bus = DummyBus { };
-        cpu.mem.write(0x0100, prog);
-        cpu.reg.set_sp(0xF000);
-        cpu.reg.set_pc(0x0100);
-        loop {
+        let mut output = String::new();
+        let cpm = rz80::CPM::new();
+        cpm.load_com(prog, "");
+        while cpm.status() == rz80::CPMStatus::Running {
             num_ops += 1;
-            num_cycles += cpu.step(&mut bus);
-            match cpu.reg.pc() {
-                0x0005 => { cpm_bdos(&mut cpu); },  // emulated CP/M BDOS call
-                0x0000 => { break; },
-                _ => { },
+            num_cycles += cpm.step();
+            if num_ops & 0xFFFFF == 0 {
+                capture_output(&cpm, &mut output);
             }
         }
-        (num_ops, num_cycles)
+        capture_output(&cpm, &mut output);
+        (parse_output(&output), num_ops, num_cycles)
     }
 
-    fn test_zexdoc() {
-        println!(">>> RUNNING ZEXDOC");
+    /// run a ZEX program (or the selected groups), return the names of the failed groups
+    fn run_zex(name: &str, prog: &[u8]) -> Vec<String> {
+        println!(">>> RUNNING {}", name);
+        let prog = match env::var("RZ80_ZEX_GROUP") {
+            Ok(patterns) => {
+                let patterns: Vec<&str> = patterns.split(';').collect();
+                select_groups(prog, &patterns)
+            }
+            Err(_) => prog.to_vec(),
+        };
+        let num_groups = groups(&prog).len();
 
         let start = PreciseTime::now();
-        let (num_ops, num_cycles) = run_test(&ZEXDOC);
+        let (results, num_ops, num_cycles) = run_test(&prog);
         let end = PreciseTime::now();
-        let ms = start.to(end).num_milliseconds();
+        let ms = start.to(end).num_milliseconds().max(1);
         let mips = (num_ops / ms)/1000;
         let mhz  = (num_cycles / ms)/1000;
-        
+
         println!("\n\nops: {}, cycles: {}, duration: {}ms", num_ops, num_cycles, ms);
         println!("mips: {}, MHz: {}\n\n", mips, mhz);
+
+        assert_eq!(num_groups, results.len(), "{}: missing group results", name);
+        results.iter()
+            .filter(|r| !r.ok)
+            .map(|r| {
+                format!("{}: {} (expected crc {}, found {})", name, r.name,
+                        r.expected_crc.as_ref().map_or("?", |s| s),
+                        r.found_crc.as_ref().map_or("?", |s| s))
+            })
+            .collect()
     }
-    
-    fn test_zexall() {
-        println!(">>> RUNNING ZEXALL");
 
-        let start = PreciseTime::now();
-        let (num_ops, num_cycles) = run_test(&ZEXALL);
-        let end = PreciseTime::now();
-        let ms = start.to(end).num_milliseconds();
-        let mips = (num_ops / ms)/1000;
-        let mhz  = (num_cycles / ms)/1000;
-        
-        println!("\n\nops: {}, cycles: {}, duration: {}ms", num_ops, num_cycles, ms);
-        println!("mips: {}, MHz: {}", mips, mhz);
+    #[test]
+    fn zex_harness() {
+        assert_eq!(67, groups(ZEXDOC).len());
+        assert_eq!("<adc,sbc> hl,<bc,de,hl,sp>", groups(ZEXDOC)[0].1);
+        let results = parse_output("Z80doc instruction exerciser\n\r\
+                                    aluop a,nn....................  OK\n\r\
+                                    daa,cpl,scf,ccf...............  ERROR **** crc \
+                                    expected:9b4ba675 found:12345678\n\r\
+                                    Tests complete");
+        assert_eq!(vec![GroupResult {
+                            name: "aluop a,nn".to_string(),
+                            ok: true,
+                            expected_crc: None,
+                            found_crc: None,
+                        },
+                        GroupResult {
+                            name: "daa,cpl,scf,ccf".to_string(),
+                            ok: false,
+                            expected_crc: Some("9b4ba675".to_string()),
+                            found_crc: Some("12345678".to_string()),
+                        }],
+                   results);
+
+        // run a single short instruction group
+        let prog = select_groups(ZEXDOC, &["bit n,(<ix,iy>+1)"]);
+        assert_eq!(1, groups(&prog).len());
+        let (results, _, _) = run_test(&prog);
+        assert_eq!(1, results.len());
+        assert!(results[0].ok, "{:?}", results[0]);
     }
-    
+
     #[test]
     #[ignore]
     fn test_zex() {
         // have 1 test function run both sub-tests, we don't want to
         // run them in parallel
-        test_zexdoc();
-        test_zexall();
+        let mut failed = run_zex("ZEXDOC", ZEXDOC);
+        failed.extend(run_zex("ZEXALL", ZEXALL));
+        assert!(failed.is_empty(), "failed instruction groups:\n{}", failed.join("\n"));
     }
 }